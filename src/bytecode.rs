@@ -0,0 +1,1039 @@
+//! Serialization of compiled scripts into a binary format that can be run without parsing the
+//! source code again.
+//!
+//! All integers are stored in little-endian order, and strings are stored as their length as a
+//! `u32` followed by their UTF-8 bytes. A file has the following layout:
+//!
+//! ```text
+//! file       --> "LOXC" version:u16 path global_count:u32 global* function ;
+//! path       --> 0 | 1 string ;
+//! global     --> slot:u32 flags:u8 name:string ;
+//! function   --> name arity:u8 upvalue_count:u32 code span_count:u32 span*
+//!                constant_count:u32 constant* ;
+//! name       --> 0 | 1 string ;
+//! code       --> length:u32 byte* ;
+//! span       --> start:u32 line:u32 column:u32 offset:u32 length:u32 ;
+//! constant   --> 0 | 1 | 2 | 3 number:f64 | 4 string | 5 function ;
+//! ```
+//!
+//! Global variables are referred to by slot indices that are only meaningful to the virtual
+//! machine that compiled the script, so the file also keeps the name of every slot used by its
+//! instructions. The slots are given out again by the virtual machine loading the file.
+//!
+//! Files are verified before they're loaded, so a malformed file is rejected instead of making the
+//! virtual machine misbehave. Besides checking the structure of the file, the verifier follows
+//! every path through the instructions to make sure that they never use more values than what's
+//! on the stack, and that the stack has the same height whenever paths meet.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    error, fmt,
+    rc::Rc,
+};
+
+use crate::{
+    chunk::{Chunk, MAX_CACHES, MAX_CONSTANTS},
+    global::Globals,
+    heap::Heap,
+    module::Source,
+    object::{ObjFun, Object},
+    opcode::Opcode,
+    scan::Span,
+    value::{Unpacked, Value},
+};
+
+/// The bytes that every bytecode file starts with.
+const MAGIC: &[u8; 4] = b"LOXC";
+
+/// The version of the format, which is increased whenever the format or the instruction set
+/// changes.
+const VERSION: u16 = 2;
+
+/// Max number of functions that can be nested in one another.
+const MAX_NESTING: usize = 256;
+
+/// Max number of upvalues a function can capture.
+const MAX_UPVALUES: usize = u16::MAX as usize + 1;
+
+/// An enumeration of the reasons for rejecting a bytecode file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BytecodeError {
+    /// The data doesn't start with the bytes identifying a bytecode file.
+    NotBytecode,
+    /// The file was written using another version of the format.
+    UnsupportedVersion(u16),
+    /// The file ended before all of its content was read.
+    Truncated,
+    /// There's more data after the script function.
+    TrailingData,
+    /// A string isn't valid UTF-8.
+    InvalidUtf8,
+    /// A constant has an unknown tag.
+    InvalidConstant(u8),
+    /// Functions are nested too deeply.
+    TooDeep,
+    /// Can't give out a slot for a global variable.
+    TooManyGlobals,
+    /// A function contains an invalid instruction.
+    InvalidInstruction {
+        /// The function containing the instruction.
+        function: String,
+        /// The offset of the instruction in the function's code.
+        offset: usize,
+        /// The reason for rejecting the instruction.
+        reason: &'static str,
+    },
+}
+
+impl error::Error for BytecodeError {}
+
+impl fmt::Display for BytecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotBytecode => f.write_str("Not a bytecode file."),
+            Self::UnsupportedVersion(version) => write!(
+                f,
+                "Unsupported bytecode version {version}, expected version {VERSION}."
+            ),
+            Self::Truncated => f.write_str("Unexpected end of bytecode."),
+            Self::TrailingData => f.write_str("Unexpected data after the script."),
+            Self::InvalidUtf8 => f.write_str("Invalid UTF-8 in bytecode string."),
+            Self::InvalidConstant(tag) => write!(f, "Unknown constant tag {tag}."),
+            Self::TooDeep => f.write_str("Functions are nested too deeply."),
+            Self::TooManyGlobals => f.write_str("Too many global variables."),
+            Self::InvalidInstruction {
+                function,
+                offset,
+                reason,
+            } => write!(f, "Invalid instruction at {offset} in {function}: {reason}"),
+        }
+    }
+}
+
+/// Write the script function and everything it references into bytes.
+pub(crate) fn serialize(script: &ObjFun, globals: &Globals) -> Vec<u8> {
+    let mut slots = BTreeSet::new();
+    collect_globals(script, &mut slots);
+
+    let mut writer = Writer::default();
+    writer.bytes.extend_from_slice(MAGIC);
+    writer.u16(VERSION);
+    writer.optional_str(script.source.path.as_deref());
+    writer.len(slots.len());
+    for slot in slots {
+        // SAFETY: The slots were given out by the globals when the script was compiled.
+        let global = unsafe { globals.at(slot) };
+        writer.len(slot);
+        writer.u8(u8::from(global.is_exported));
+        writer.str(&global.name.data);
+    }
+    writer.fun(script);
+    writer.bytes
+}
+
+/// Read the script function from the bytes, giving out slots for its global variables in the
+/// given namespace. The functions are verified before they're returned.
+pub(crate) fn deserialize(
+    bytes: &[u8],
+    heap: &mut Heap,
+    globals: &mut Globals,
+    namespace: usize,
+) -> Result<ObjFun, BytecodeError> {
+    let mut reader = Reader { bytes };
+    if reader.take(MAGIC.len()).ok() != Some(MAGIC.as_slice()) {
+        return Err(BytecodeError::NotBytecode);
+    }
+    let version = reader.u16()?;
+    if version != VERSION {
+        return Err(BytecodeError::UnsupportedVersion(version));
+    }
+    let path = reader.optional_str()?.map(String::from);
+
+    let mut slots = BTreeMap::new();
+    for _ in 0..reader.u32()? {
+        let slot = reader.u32()? as usize;
+        let is_exported = reader.u8()? != 0;
+        let name = heap.intern(String::from(reader.str()?));
+        let new_slot = globals
+            .slot(namespace, name)
+            .ok_or(BytecodeError::TooManyGlobals)?;
+        // SAFETY: The slot was just given out by the globals.
+        unsafe { globals.at_mut(new_slot) }.is_exported |= is_exported;
+        slots.insert(slot, new_slot);
+    }
+
+    let mut loader = Loader {
+        reader,
+        heap,
+        slots,
+        namespace,
+        source: Rc::new(Source {
+            path,
+            text: String::new(),
+        }),
+    };
+    let script = loader.fun(0)?;
+    if !loader.reader.bytes.is_empty() {
+        return Err(BytecodeError::TrailingData);
+    }
+    if script.arity != 0 || script.upvalue_count != 0 {
+        return Err(invalid(
+            &script,
+            0,
+            "A script can't have parameters or upvalues.",
+        ));
+    }
+    Ok(script)
+}
+
+/// Add the global slots used by the function and its nested functions to the map.
+fn collect_globals(fun: &ObjFun, slots: &mut BTreeSet<usize>) {
+    let chunk = &fun.chunk;
+    let mut offset = 0;
+    while offset < chunk.instructions.len() {
+        let opcode = Opcode::from(chunk.instructions[offset]);
+        let layout = Layout::of(opcode);
+        if let Layout::Global(width) = layout {
+            slots.insert(read_index(&chunk.instructions[offset + 1..], width));
+        }
+        offset += 1 + layout.operand_len(chunk, offset);
+    }
+    for constant in &chunk.constants {
+        if let Ok(nested) = constant.as_fun() {
+            collect_globals(&nested, slots);
+        }
+    }
+}
+
+/// Read a big-endian index of the given width, as written by the compiler.
+pub(crate) fn read_index(bytes: &[u8], width: usize) -> usize {
+    bytes[..width]
+        .iter()
+        .fold(0, |index, byte| index << 8 | *byte as usize)
+}
+
+/// Create the error for an invalid instruction in the given function.
+fn invalid(fun: &ObjFun, offset: usize, reason: &'static str) -> BytecodeError {
+    BytecodeError::InvalidInstruction {
+        function: fun.to_string(),
+        offset,
+        reason,
+    }
+}
+
+/// The shape of the operands that follow an opcode.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Layout {
+    /// No operand.
+    Simple,
+    /// A count of values or arguments of the given width.
+    Count(usize),
+    /// A constant index of the given width.
+    Constant(usize),
+    /// A constant index of the given width followed by a 16-bit inline cache index.
+    Property(usize),
+    /// A constant index of the given width followed by an argument count and a 16-bit inline
+    /// cache index.
+    Invoke(usize),
+    /// A constant index of the given width followed by an argument count.
+    SuperInvoke(usize),
+    /// A constant index of the given width followed by the upvalues captured by the closure.
+    Closure(usize),
+    /// A global slot of the given width.
+    Global(usize),
+    /// A local slot of the given width.
+    Local(usize),
+    /// An upvalue index of the given width.
+    Upvalue(usize),
+    /// A 16-bit jump offset.
+    Jump,
+}
+
+impl Layout {
+    pub(crate) fn of(opcode: Opcode) -> Self {
+        match opcode {
+            Opcode::Nil
+            | Opcode::True
+            | Opcode::False
+            | Opcode::Pop
+            | Opcode::NE
+            | Opcode::EQ
+            | Opcode::GT
+            | Opcode::GE
+            | Opcode::LT
+            | Opcode::LE
+            | Opcode::Add
+            | Opcode::Sub
+            | Opcode::Mul
+            | Opcode::Div
+            | Opcode::Not
+            | Opcode::Neg
+            | Opcode::Print
+            | Opcode::CloseUpvalue
+            | Opcode::Ret
+            | Opcode::Inherit
+            | Opcode::Stringify
+            | Opcode::GetIndex
+            | Opcode::SetIndex
+            | Opcode::PopHandler
+            | Opcode::Throw
+            | Opcode::EndFinally
+            | Opcode::FinishImport => Self::Simple,
+            Opcode::Call | Opcode::BuildList | Opcode::BuildMap => Self::Count(1),
+            Opcode::BuildListLong | Opcode::BuildMapLong => Self::Count(2),
+            Opcode::GetProperty | Opcode::SetProperty => Self::Property(1),
+            Opcode::GetPropertyLong | Opcode::SetPropertyLong => Self::Property(3),
+            Opcode::Const | Opcode::GetSuper | Opcode::Class | Opcode::Method | Opcode::Import => {
+                Self::Constant(1)
+            }
+            Opcode::ConstLong
+            | Opcode::GetSuperLong
+            | Opcode::ClassLong
+            | Opcode::MethodLong
+            | Opcode::ImportLong => Self::Constant(3),
+            Opcode::Invoke => Self::Invoke(1),
+            Opcode::InvokeLong => Self::Invoke(3),
+            Opcode::SuperInvoke => Self::SuperInvoke(1),
+            Opcode::SuperInvokeLong => Self::SuperInvoke(3),
+            Opcode::Closure => Self::Closure(1),
+            Opcode::ClosureLong => Self::Closure(3),
+            Opcode::GetGlobal
+            | Opcode::SetGlobal
+            | Opcode::DefineGlobal
+            | Opcode::DefineGlobalConst => Self::Global(1),
+            Opcode::GetGlobalLong
+            | Opcode::SetGlobalLong
+            | Opcode::DefineGlobalLong
+            | Opcode::DefineGlobalConstLong => Self::Global(3),
+            Opcode::GetLocal | Opcode::SetLocal => Self::Local(1),
+            Opcode::GetLocalLong | Opcode::SetLocalLong => Self::Local(2),
+            Opcode::GetUpvalue | Opcode::SetUpvalue => Self::Upvalue(1),
+            Opcode::GetUpvalueLong | Opcode::SetUpvalueLong => Self::Upvalue(2),
+            Opcode::Jump
+            | Opcode::JumpIfTrue
+            | Opcode::JumpIfFalse
+            | Opcode::Loop
+            | Opcode::Try => Self::Jump,
+        }
+    }
+
+    /// Get the number of operand bytes of the instruction at the given offset of a chunk that was
+    /// produced by the compiler.
+    pub(crate) fn operand_len(self, chunk: &Chunk, offset: usize) -> usize {
+        match self {
+            Self::Simple => 0,
+            Self::Count(width)
+            | Self::Constant(width)
+            | Self::Global(width)
+            | Self::Local(width) => width,
+            Self::Upvalue(width) => width,
+            Self::Property(width) => width + 2,
+            Self::Invoke(width) => width + 3,
+            Self::SuperInvoke(width) => width + 1,
+            Self::Jump => 2,
+            Self::Closure(width) => {
+                let index = read_index(&chunk.instructions[offset + 1..], width);
+                let fun = chunk.constants[index]
+                    .as_fun()
+                    .expect("Closures are made from functions.");
+                width + 3 * fun.upvalue_count
+            }
+        }
+    }
+}
+
+/// A writer for the primitive values of the format.
+#[derive(Default)]
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, n: u8) {
+        self.bytes.push(n);
+    }
+
+    fn u16(&mut self, n: u16) {
+        self.bytes.extend_from_slice(&n.to_le_bytes());
+    }
+
+    fn u32(&mut self, n: u32) {
+        self.bytes.extend_from_slice(&n.to_le_bytes());
+    }
+
+    /// Write a length or an index, which always fits in 32 bits because of the limits of the
+    /// compiler.
+    fn len(&mut self, n: usize) {
+        self.u32(u32::try_from(n).expect("Length doesn't fit in 32 bits."));
+    }
+
+    fn str(&mut self, s: &str) {
+        self.len(s.len());
+        self.bytes.extend_from_slice(s.as_bytes());
+    }
+
+    fn optional_str(&mut self, s: Option<&str>) {
+        match s {
+            None => self.u8(0),
+            Some(s) => {
+                self.u8(1);
+                self.str(s);
+            }
+        }
+    }
+
+    fn fun(&mut self, fun: &ObjFun) {
+        self.optional_str(fun.name.as_ref().map(|name| name.data.as_str()));
+        self.u8(fun.arity);
+        self.len(fun.upvalue_count);
+
+        let chunk = &fun.chunk;
+        self.len(chunk.instructions.len());
+        self.bytes.extend_from_slice(&chunk.instructions);
+        self.len(chunk.spans.len());
+        for (start, span) in chunk.spans.iter() {
+            self.len(start);
+            let Span {
+                line,
+                column,
+                offset,
+                len,
+            } = span;
+            for n in [line, column, offset, len] {
+                self.u32(n);
+            }
+        }
+
+        self.len(chunk.constants.len());
+        for constant in &chunk.constants {
+            match constant.unpack() {
+                Unpacked::Nil => self.u8(0),
+                Unpacked::Bool(false) => self.u8(1),
+                Unpacked::Bool(true) => self.u8(2),
+                Unpacked::Number(n) => {
+                    self.u8(3);
+                    self.bytes.extend_from_slice(&n.to_le_bytes());
+                }
+                Unpacked::Object(Object::String(s)) => {
+                    self.u8(4);
+                    self.str(&s.data);
+                }
+                Unpacked::Object(Object::Fun(nested)) => {
+                    self.u8(5);
+                    self.fun(&nested);
+                }
+                Unpacked::Object(_) => unreachable!("Only strings and functions are constants."),
+            }
+        }
+    }
+}
+
+/// A reader for the primitive values of the format.
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], BytecodeError> {
+        if self.bytes.len() < n {
+            return Err(BytecodeError::Truncated);
+        }
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], BytecodeError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+
+    fn u8(&mut self) -> Result<u8, BytecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, BytecodeError> {
+        self.array().map(u16::from_le_bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, BytecodeError> {
+        self.array().map(u32::from_le_bytes)
+    }
+
+    fn f64(&mut self) -> Result<f64, BytecodeError> {
+        self.array().map(f64::from_le_bytes)
+    }
+
+    fn str(&mut self) -> Result<&'a str, BytecodeError> {
+        let len = self.u32()? as usize;
+        std::str::from_utf8(self.take(len)?).map_err(|_| BytecodeError::InvalidUtf8)
+    }
+
+    fn optional_str(&mut self) -> Result<Option<&'a str>, BytecodeError> {
+        match self.u8()? {
+            0 => Ok(None),
+            _ => self.str().map(Some),
+        }
+    }
+}
+
+/// The state for reading and verifying the functions of a file.
+struct Loader<'a, 'vm> {
+    reader: Reader<'a>,
+    heap: &'vm mut Heap,
+    /// The slots given out by the loading virtual machine, keyed by the slots used in the file.
+    slots: BTreeMap<usize, usize>,
+    namespace: usize,
+    source: Rc<Source>,
+}
+
+impl Loader<'_, '_> {
+    fn fun(&mut self, depth: usize) -> Result<ObjFun, BytecodeError> {
+        if depth == MAX_NESTING {
+            return Err(BytecodeError::TooDeep);
+        }
+        let name = self
+            .reader
+            .optional_str()?
+            .map(|name| self.heap.intern(String::from(name)));
+        let mut fun = ObjFun::new(name, self.namespace, Rc::clone(&self.source));
+        fun.arity = self.reader.u8()?;
+        fun.upvalue_count = self.reader.u32()? as usize;
+        if fun.upvalue_count > MAX_UPVALUES {
+            return Err(invalid(&fun, 0, "Too many upvalues."));
+        }
+
+        let len = self.reader.u32()? as usize;
+        fun.chunk.instructions = self.reader.take(len)?.to_vec();
+        for _ in 0..self.reader.u32()? {
+            let start = self.reader.u32()? as usize;
+            let span = Span {
+                line: self.reader.u32()?,
+                column: self.reader.u32()?,
+                offset: self.reader.u32()?,
+                len: self.reader.u32()?,
+            };
+            let is_sorted = fun.chunk.spans.last().is_none_or(|(last, _)| last < start);
+            if !is_sorted || start >= len {
+                return Err(invalid(&fun, start, "Source locations are out of order."));
+            }
+            fun.chunk.spans.push(start, span);
+        }
+
+        let count = self.reader.u32()? as usize;
+        if count > MAX_CONSTANTS {
+            return Err(invalid(&fun, 0, "Too many constants."));
+        }
+        for _ in 0..count {
+            let constant = match self.reader.u8()? {
+                0 => Value::NIL,
+                1 => Value::bool(false),
+                2 => Value::bool(true),
+                3 => Value::number(self.reader.f64()?),
+                4 => {
+                    let s = self.reader.str()?;
+                    Value::object(Object::String(self.heap.intern(String::from(s))))
+                }
+                5 => {
+                    let nested = self.fun(depth + 1)?;
+                    Value::object(self.heap.alloc(nested, Object::Fun).0)
+                }
+                tag => return Err(BytecodeError::InvalidConstant(tag)),
+            };
+            fun.chunk.write_constant(constant);
+        }
+
+        let (instructions, globals) = self.decode(&fun)?;
+        verify_flow(&fun, &instructions)?;
+        // Caches start out empty, so only their number is needed. The largest index means that
+        // an instruction has no cache.
+        let caches = instructions
+            .iter()
+            .flatten()
+            .filter_map(|instruction| instruction.cache)
+            .filter(|index| *index < MAX_CACHES)
+            .max()
+            .map_or(0, |index| index + 1);
+        fun.chunk.caches.resize_with(caches, Default::default);
+        // Refer to the slots of the loading virtual machine instead of the ones in the file.
+        for (at, width, slot) in globals {
+            let bytes = &mut fun.chunk.instructions[at..at + width];
+            for (i, byte) in bytes.iter_mut().rev().enumerate() {
+                *byte = (slot >> (8 * i)) as u8;
+            }
+        }
+        Ok(fun)
+    }
+
+    /// Check the operands of every instruction in the function. Return the decoded instructions
+    /// indexed by their offsets, along with the position, width, and new value of every global
+    /// slot operand.
+    #[allow(clippy::type_complexity)]
+    fn decode(
+        &self,
+        fun: &ObjFun,
+    ) -> Result<(Vec<Option<Instruction>>, Vec<(usize, usize, usize)>), BytecodeError> {
+        let len = fun.chunk.instructions.len();
+        let mut instructions = Vec::new();
+        instructions.resize_with(len, || None);
+        let mut globals = Vec::new();
+
+        let mut offset = 0;
+        while offset < len {
+            let byte = fun.chunk.instructions[offset];
+            let opcode =
+                Opcode::decode(byte).ok_or_else(|| invalid(fun, offset, "Unknown opcode."))?;
+            let operands = offset + 1;
+            let operand = |width: usize| {
+                fun.chunk
+                    .instructions
+                    .get(operands..operands + width)
+                    .map(|bytes| read_index(bytes, width))
+                    .ok_or_else(|| invalid(fun, offset, "Missing operand."))
+            };
+
+            let mut instruction = Instruction {
+                opcode,
+                next: operands,
+                operand: 0,
+                captures: Vec::new(),
+                cache: None,
+            };
+            match Layout::of(opcode) {
+                Layout::Simple => {}
+                Layout::Count(width) => {
+                    instruction.operand = operand(width)?;
+                    instruction.next += width;
+                }
+                Layout::Constant(width) => {
+                    let constant = self.constant(fun, offset, operand(width)?)?;
+                    let is_name = constant.as_string().is_ok();
+                    if opcode != Opcode::Const && opcode != Opcode::ConstLong && !is_name {
+                        return Err(invalid(fun, offset, "Expect a name constant."));
+                    }
+                    instruction.next += width;
+                }
+                Layout::Property(width) => {
+                    let constant = self.constant(fun, offset, operand(width)?)?;
+                    if constant.as_string().is_err() {
+                        return Err(invalid(fun, offset, "Expect a name constant."));
+                    }
+                    instruction.cache = Some(operand(width + 2)? & 0xffff);
+                    instruction.next += width + 2;
+                }
+                Layout::Invoke(width) => {
+                    let constant = self.constant(fun, offset, operand(width)?)?;
+                    if constant.as_string().is_err() {
+                        return Err(invalid(fun, offset, "Expect a name constant."));
+                    }
+                    instruction.operand = operand(width + 1)? & 0xff;
+                    instruction.cache = Some(operand(width + 3)? & 0xffff);
+                    instruction.next += width + 3;
+                }
+                Layout::SuperInvoke(width) => {
+                    let constant = self.constant(fun, offset, operand(width)?)?;
+                    if constant.as_string().is_err() {
+                        return Err(invalid(fun, offset, "Expect a name constant."));
+                    }
+                    instruction.operand = operand(width + 1)? & 0xff;
+                    instruction.next += width + 1;
+                }
+                Layout::Closure(width) => {
+                    let constant = self.constant(fun, offset, operand(width)?)?;
+                    let Ok(nested) = constant.as_fun() else {
+                        return Err(invalid(fun, offset, "Expect a function constant."));
+                    };
+                    instruction.next += width;
+                    for _ in 0..nested.upvalue_count {
+                        let capture = fun
+                            .chunk
+                            .instructions
+                            .get(instruction.next..instruction.next + 3)
+                            .ok_or_else(|| invalid(fun, offset, "Missing operand."))?;
+                        let index = read_index(&capture[1..], 2);
+                        match capture[0] {
+                            0 if index < fun.upvalue_count => {}
+                            0 => return Err(invalid(fun, offset, "Upvalue doesn't exist.")),
+                            1 => instruction.captures.push(index),
+                            _ => return Err(invalid(fun, offset, "Invalid upvalue kind.")),
+                        }
+                        instruction.next += 3;
+                    }
+                }
+                Layout::Global(width) => {
+                    let slot =
+                        self.slots.get(&operand(width)?).copied().ok_or_else(|| {
+                            invalid(fun, offset, "Global variable doesn't exist.")
+                        })?;
+                    if slot >= 1 << (8 * width) {
+                        return Err(BytecodeError::TooManyGlobals);
+                    }
+                    globals.push((operands, width, slot));
+                    instruction.next += width;
+                }
+                Layout::Local(width) => {
+                    instruction.operand = operand(width)?;
+                    instruction.next += width;
+                }
+                Layout::Upvalue(width) => {
+                    if operand(width)? >= fun.upvalue_count {
+                        return Err(invalid(fun, offset, "Upvalue doesn't exist."));
+                    }
+                    instruction.next += width;
+                }
+                Layout::Jump => {
+                    instruction.operand = operand(2)?;
+                    instruction.next += 2;
+                }
+            }
+            let next = instruction.next;
+            instructions[offset] = Some(instruction);
+            offset = next;
+        }
+        Ok((instructions, globals))
+    }
+
+    fn constant<'f>(
+        &self,
+        fun: &'f ObjFun,
+        offset: usize,
+        index: usize,
+    ) -> Result<&'f Value, BytecodeError> {
+        fun.chunk
+            .constants
+            .get(index)
+            .ok_or_else(|| invalid(fun, offset, "Constant doesn't exist."))
+    }
+}
+
+/// An instruction whose operands have been checked.
+#[derive(Debug)]
+struct Instruction {
+    opcode: Opcode,
+    /// The offset of the following instruction.
+    next: usize,
+    /// The count, local slot, or jump offset of the instruction.
+    operand: usize,
+    /// The local slots captured by a closure.
+    captures: Vec<usize>,
+    /// The inline cache index of an instruction accessing a property or calling a method.
+    cache: Option<usize>,
+}
+
+/// The state of the stack before running an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct StackState {
+    /// The number of values in the call frame, including the called function.
+    height: usize,
+    /// The number of installed exception handlers.
+    handlers: usize,
+}
+
+/// The paths through the instructions of a function that are being followed.
+struct Flow<'a> {
+    fun: &'a ObjFun,
+    instructions: &'a [Option<Instruction>],
+    /// The state of the stack before each instruction that has been reached.
+    states: Vec<Option<StackState>>,
+    /// The offsets of the reached instructions that haven't been followed.
+    pending: Vec<usize>,
+}
+
+impl Flow<'_> {
+    /// Reach the instruction at the target offset from the instruction at the given offset.
+    fn visit(
+        &mut self,
+        target: usize,
+        state: StackState,
+        from: usize,
+    ) -> Result<(), BytecodeError> {
+        match self.instructions.get(target) {
+            Some(Some(instruction)) if instruction.opcode != Opcode::FinishImport => {}
+            Some(Some(_)) => return Err(invalid(self.fun, from, "Imports must be finished.")),
+            Some(None) => return Err(invalid(self.fun, from, "Jump into an instruction.")),
+            None => return Err(invalid(self.fun, from, "Code runs past the end.")),
+        }
+        match self.states[target] {
+            None => {
+                self.states[target] = Some(state);
+                self.pending.push(target);
+                Ok(())
+            }
+            Some(seen) if seen == state => Ok(()),
+            Some(_) => Err(invalid(
+                self.fun,
+                target,
+                "Stack height differs between paths.",
+            )),
+        }
+    }
+
+    /// Get the target offset of the jump instruction at the given offset.
+    fn target(&self, offset: usize, instruction: &Instruction) -> Result<usize, BytecodeError> {
+        match instruction.opcode {
+            Opcode::Loop => instruction.next.checked_sub(instruction.operand),
+            _ => Some(instruction.next + instruction.operand),
+        }
+        .ok_or_else(|| invalid(self.fun, offset, "Code runs past the start."))
+    }
+
+    /// Follow the instruction at the given offset, reaching the instructions that can run after
+    /// it.
+    fn follow(&mut self, offset: usize) -> Result<(), BytecodeError> {
+        let fun = self.fun;
+        let instruction = self.instructions[offset]
+            .as_ref()
+            .expect("Only instructions are reached.");
+        let mut state = self.states[offset].expect("The instruction was reached.");
+        let require = |height: usize| {
+            if height > state.height {
+                Err(invalid(fun, offset, "Stack underflow."))
+            } else {
+                Ok(())
+            }
+        };
+
+        let (pops, pushes) = match instruction.opcode {
+            Opcode::Const
+            | Opcode::ConstLong
+            | Opcode::Nil
+            | Opcode::True
+            | Opcode::False
+            | Opcode::GetGlobal
+            | Opcode::GetGlobalLong
+            | Opcode::GetUpvalue
+            | Opcode::GetUpvalueLong
+            | Opcode::Class
+            | Opcode::ClassLong => (0, 1),
+            Opcode::GetLocal | Opcode::GetLocalLong => {
+                require(instruction.operand + 1)?;
+                (0, 1)
+            }
+            Opcode::SetLocal | Opcode::SetLocalLong => {
+                require(instruction.operand + 1)?;
+                (1, 1)
+            }
+            Opcode::Closure | Opcode::ClosureLong => {
+                for capture in &instruction.captures {
+                    require(capture + 1)?;
+                }
+                (0, 1)
+            }
+            Opcode::SetGlobal
+            | Opcode::SetGlobalLong
+            | Opcode::SetUpvalue
+            | Opcode::SetUpvalueLong
+            | Opcode::GetProperty
+            | Opcode::GetPropertyLong
+            | Opcode::Not
+            | Opcode::Neg
+            | Opcode::Stringify
+            | Opcode::JumpIfTrue
+            | Opcode::JumpIfFalse => (1, 1),
+            Opcode::Pop
+            | Opcode::DefineGlobal
+            | Opcode::DefineGlobalLong
+            | Opcode::DefineGlobalConst
+            | Opcode::DefineGlobalConstLong
+            | Opcode::Print
+            | Opcode::CloseUpvalue
+            | Opcode::Ret
+            | Opcode::Throw => (1, 0),
+            Opcode::SetProperty
+            | Opcode::SetPropertyLong
+            | Opcode::GetSuper
+            | Opcode::GetSuperLong
+            | Opcode::NE
+            | Opcode::EQ
+            | Opcode::GT
+            | Opcode::GE
+            | Opcode::LT
+            | Opcode::LE
+            | Opcode::Add
+            | Opcode::Sub
+            | Opcode::Mul
+            | Opcode::Div
+            | Opcode::Inherit
+            | Opcode::Method
+            | Opcode::MethodLong
+            | Opcode::GetIndex => (2, 1),
+            Opcode::SetIndex => (3, 1),
+            Opcode::EndFinally => (2, 0),
+            Opcode::Call | Opcode::Invoke | Opcode::InvokeLong => (instruction.operand + 1, 1),
+            Opcode::SuperInvoke | Opcode::SuperInvokeLong => (instruction.operand + 2, 1),
+            Opcode::BuildList | Opcode::BuildListLong => (instruction.operand, 1),
+            Opcode::BuildMap | Opcode::BuildMapLong => (2 * instruction.operand, 1),
+            // The result of the imported module is pushed by the import, then popped by the
+            // instruction that must come right after it.
+            Opcode::Jump
+            | Opcode::Loop
+            | Opcode::Try
+            | Opcode::PopHandler
+            | Opcode::Import
+            | Opcode::ImportLong
+            | Opcode::FinishImport => (0, 0),
+        };
+        require(pops)?;
+        state.height = state.height - pops + pushes;
+
+        let next = instruction.next;
+        match instruction.opcode {
+            Opcode::Ret | Opcode::Throw => {}
+            Opcode::Jump | Opcode::Loop => {
+                self.visit(self.target(offset, instruction)?, state, offset)?
+            }
+            Opcode::JumpIfTrue | Opcode::JumpIfFalse => {
+                self.visit(self.target(offset, instruction)?, state, offset)?;
+                self.visit(next, state, offset)?;
+            }
+            Opcode::Try => {
+                // The handler runs with the thrown value on top of the values that were on the
+                // stack when it was installed.
+                let handler = StackState {
+                    height: state.height + 1,
+                    handlers: state.handlers,
+                };
+                self.visit(self.target(offset, instruction)?, handler, offset)?;
+                state.handlers += 1;
+                self.visit(next, state, offset)?;
+            }
+            Opcode::PopHandler => {
+                if state.handlers == 0 {
+                    return Err(invalid(fun, offset, "No handler to remove."));
+                }
+                state.handlers -= 1;
+                self.visit(next, state, offset)?;
+            }
+            Opcode::Import | Opcode::ImportLong => match self.instructions.get(next) {
+                Some(Some(finish)) if finish.opcode == Opcode::FinishImport => {
+                    self.visit(finish.next, state, next)?
+                }
+                _ => return Err(invalid(fun, offset, "Imports must be finished.")),
+            },
+            _ => self.visit(next, state, offset)?,
+        }
+        Ok(())
+    }
+}
+
+/// Follow every path through the instructions of the function, making sure that the stack always
+/// holds the values used by each instruction.
+fn verify_flow(fun: &ObjFun, instructions: &[Option<Instruction>]) -> Result<(), BytecodeError> {
+    let mut flow = Flow {
+        fun,
+        instructions,
+        states: vec![None; instructions.len()],
+        pending: Vec::new(),
+    };
+    let entry = StackState {
+        height: fun.arity as usize + 1,
+        handlers: 0,
+    };
+    flow.visit(0, entry, 0)?;
+    while let Some(offset) = flow.pending.pop() {
+        flow.follow(offset)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use crate::{compile::Parser, global::Globals, heap::Heap, module::Source};
+
+    use super::{deserialize, serialize, BytecodeError};
+
+    /// Compile the source and load the serialized script into a fresh namespace.
+    fn roundtrip(src: &str, corrupt: impl FnOnce(&mut Vec<u8>)) -> Result<(), BytecodeError> {
+        let mut heap = Heap::default();
+        let mut globals = Globals::default();
+        let namespace = globals.add_namespace();
+        let source = Rc::new(Source {
+            path: Some(String::from("main.lox")),
+            text: String::from(src),
+        });
+        let script = Parser::new(&source, &mut heap, &mut globals, namespace)
+            .compile()
+            .expect("Source should compile.");
+        let mut bytes = serialize(&script, &globals);
+        corrupt(&mut bytes);
+        let namespace = globals.add_namespace();
+        deserialize(&bytes, &mut heap, &mut globals, namespace).map(|_| ())
+    }
+
+    #[test]
+    fn compiled_scripts_are_accepted() {
+        let src = r#"
+            import "lib.lox";
+            class A { init(x) { this.x = x; } get() { return this.x; } }
+            class B < A { get() { return super.get() + 1; } }
+            fun counter() { var n = 0; fun inc() { n = n + 1; return n; } return inc; }
+            var xs = [1, 2, 3];
+            var m = {"a": 1};
+            for (var i = 0; i < xs.len(); i = i + 1) { if (i == 1) continue; print xs[i]; }
+            try { throw Error("e"); } catch (e) { print e.message; } finally { print "done"; }
+            switch (m["a"]) { case 1: print "one"; default: print "other"; }
+            const c = "${B(1).get()} ${counter()()}";
+        "#;
+        assert_eq!(Ok(()), roundtrip(src, |_| {}));
+    }
+
+    #[test]
+    fn malformed_files_are_rejected() {
+        assert_eq!(
+            Err(BytecodeError::NotBytecode),
+            roundtrip("print 1;", |bytes| bytes[0] = b'X')
+        );
+        assert_eq!(
+            Err(BytecodeError::UnsupportedVersion(3)),
+            roundtrip("print 1;", |bytes| bytes[4] = 3)
+        );
+        assert_eq!(
+            Err(BytecodeError::Truncated),
+            roundtrip("print 1;", |bytes| {
+                bytes.pop();
+            })
+        );
+        assert_eq!(
+            Err(BytecodeError::TrailingData),
+            roundtrip("print 1;", |bytes| bytes.push(0))
+        );
+    }
+
+    #[test]
+    fn invalid_instructions_are_rejected() {
+        // Find the code of the script, which follows its name, arity and upvalue count.
+        let code_of = |bytes: &mut Vec<u8>| {
+            let path = bytes.windows(8).position(|w| w == b"main.lox").unwrap();
+            // Skip the path, the global count, and the name, arity, upvalue count, and code
+            // length of the script.
+            let start = path + 8 + 4 + 1 + 1 + 4 + 4;
+            start..bytes.len()
+        };
+        let reason_of = |result: Result<(), BytecodeError>| match result {
+            Err(BytecodeError::InvalidInstruction { reason, .. }) => reason,
+            result => panic!("Expect an invalid instruction, got {result:?}."),
+        };
+
+        // "print 1;" compiles to: CONST 0, PRINT, NIL, RET.
+        let src = "print 1;";
+        let patch = |at: usize, byte: u8| {
+            move |bytes: &mut Vec<u8>| {
+                let code = code_of(bytes);
+                bytes[code.start + at] = byte;
+            }
+        };
+        assert_eq!("Unknown opcode.", reason_of(roundtrip(src, patch(2, 255))));
+        assert_eq!(
+            "Constant doesn't exist.",
+            reason_of(roundtrip(src, patch(1, 9)))
+        );
+        assert_eq!("Stack underflow.", reason_of(roundtrip(src, patch(3, 4))));
+        assert_eq!(
+            "Code runs past the end.",
+            reason_of(roundtrip(src, patch(4, 4)))
+        );
+    }
+}
@@ -1,29 +1,47 @@
-use crate::{opcode::Opcode, scan::Line, stack::Stack, value::Value};
+use std::{cell::Cell, fmt};
 
-#[cfg(feature = "dbg-execution")]
-use crate::vm::JumpDirection;
+use crate::{
+    global::Globals,
+    object::{ObjFun, Object, RefClass, RefClosure},
+    opcode::Opcode,
+    scan::Span,
+    shape::Shapes,
+    value::Value,
+    vm::{JumpDirection, OperandWidth},
+};
 
-/// Max number of constants a chunk can contain.
-pub const MAX_CONSTANTS: usize = u8::MAX as usize + 1;
+/// Max number of constants a chunk can contain. Constants are indexed using at most 24 bits.
+pub const MAX_CONSTANTS: usize = 1 << 24;
+
+/// Max number of inline caches a chunk can contain. Caches are indexed using 16 bits, and the
+/// largest index is used by instructions that don't have a cache.
+pub const MAX_CACHES: usize = u16::MAX as usize;
 
 /// A chunk holds a sequence of instructions to be executes and their data.
 #[derive(Debug, Default)]
 pub(crate) struct Chunk {
-    pub(crate) constants: Stack<Value, MAX_CONSTANTS>,
+    pub(crate) constants: Vec<Value>,
     pub(crate) instructions: Vec<u8>,
-    lines: Vec<RunLength<Line>>,
+    /// The source locations of the instructions. A new run is only added when an instruction is
+    /// at a different location than the one before it, so the runs are sorted by their offsets.
+    pub(crate) spans: Spans,
+    /// The inline caches of the instructions that access properties or call methods.
+    pub(crate) caches: Vec<Cell<InlineCache>>,
 }
 
 impl Chunk {
-    /// Write an opcode into the chunk.
-    pub(crate) fn write(&mut self, opcode: Opcode, line: Line) {
-        self.write_byte(opcode.into(), line)
+    /// Write an opcode located at the given span into the chunk.
+    pub(crate) fn write(&mut self, opcode: Opcode, span: Span) {
+        match self.spans.last() {
+            Some((_, last)) if last == span => {}
+            _ => self.spans.push(self.instructions.len(), span),
+        }
+        self.instructions.push(opcode.into());
     }
 
-    /// Write an arbitrarily byte into the chunk.
-    pub(crate) fn write_byte(&mut self, byte: u8, line: Line) {
+    /// Write an operand into the chunk. Operands share the location of their opcode.
+    pub(crate) fn write_byte(&mut self, byte: u8) {
         self.instructions.push(byte);
-        self.add_line(line);
     }
 
     /// Write a constant into the chunk.
@@ -32,160 +50,640 @@ impl Chunk {
         self.constants.len() - 1
     }
 
-    /// Get the line information of the bytecode at a specific offset.
-    pub(crate) fn add_line(&mut self, line: Line) {
-        match self.lines.last_mut() {
-            Some(last_line) if last_line.data == line => last_line.length += 1,
-            _ => self.lines.push(RunLength::new(line)),
+    /// Add an empty inline cache into the chunk, and return its index. The largest index is
+    /// returned once the chunk can't hold more caches.
+    pub(crate) fn add_cache(&mut self) -> usize {
+        if self.caches.len() == MAX_CACHES {
+            return MAX_CACHES;
+        }
+        self.caches.push(Cell::default());
+        self.caches.len() - 1
+    }
+
+    /// Mark the objects referenced by the inline caches.
+    pub(crate) fn mark_caches(&self, grey_objects: &mut Vec<Object>) {
+        for cache in &self.caches {
+            match cache.get() {
+                InlineCache::Empty | InlineCache::Field { .. } | InlineCache::Transition { .. } => {
+                }
+                InlineCache::Method { class, method, .. } => {
+                    Object::Class(class).mark(grey_objects);
+                    Object::Closure(method).mark(grey_objects);
+                }
+            }
         }
     }
 
-    /// Get the line information of the bytecode at a specific offset.
-    pub(crate) fn get_line(&self, offset: usize) -> Line {
-        let mut length = 0;
-        for line in self.lines.iter() {
-            length += line.length;
-            if length > offset {
-                return line.data;
+    /// Mark the shapes remembered by the inline caches.
+    pub(crate) fn mark_shapes(&self, shapes: &mut Shapes) {
+        for cache in &self.caches {
+            match cache.get() {
+                InlineCache::Empty => {}
+                InlineCache::Field { shape, .. } | InlineCache::Method { shape, .. } => {
+                    shapes.mark(shape);
+                }
+                InlineCache::Transition { from, to } => {
+                    shapes.mark(from);
+                    shapes.mark(to);
+                }
             }
         }
-        Line::default()
     }
+
+    /// Get the source location of the bytecode at a specific offset.
+    pub(crate) fn get_span(&self, offset: usize) -> Span {
+        self.spans.find(offset)
+    }
+}
+
+/// The result of the last lookup made by an instruction accessing a property or calling a method.
+/// The result is reused while the receivers have the same shape and class.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) enum InlineCache {
+    /// Nothing has been looked up.
+    #[default]
+    Empty,
+    /// A field was found at the given slot of instances of a shape.
+    Field { shape: usize, slot: usize },
+    /// A field was added to instances of a shape, moving them to another shape.
+    Transition { from: usize, to: usize },
+    /// A method was found in the given version of a class, and instances of the given shape
+    /// don't have a field shadowing it.
+    Method {
+        shape: usize,
+        class: RefClass,
+        version: usize,
+        method: RefClosure,
+    },
+}
+
+/// Number of runs between 2 checkpoints of the source locations.
+const RUNS_PER_CHECKPOINT: usize = 16;
+
+/// The source locations of the runs of instructions sharing the same location. Each run is stored
+/// as the differences between its start offset and location and the ones of the run before it,
+/// using variable-length integers, so most runs only take a few bytes. Every few runs a checkpoint
+/// records the decoded run, so a lookup only has to decode the runs following a checkpoint.
+#[derive(Debug, Default)]
+pub(crate) struct Spans {
+    bytes: Vec<u8>,
+    /// The decoded runs at the start of each group of runs, sorted by their offsets.
+    checkpoints: Vec<Checkpoint>,
+    /// The start offset and the location of the last run.
+    last: Option<(usize, Span)>,
+    /// The number of runs.
+    len: usize,
 }
 
+/// A run decoded in advance, along with the position of the run following it in the bytes.
 #[derive(Debug)]
-struct RunLength<T> {
-    data: T,
-    length: usize,
+struct Checkpoint {
+    start: usize,
+    span: Span,
+    next: usize,
+}
+
+impl Spans {
+    /// Add a run of instructions starting at the given offset, which must come after the start of
+    /// the last run.
+    pub(crate) fn push(&mut self, start: usize, span: Span) {
+        let (prev_start, prev) = self.last.unwrap_or_default();
+        write_varint(&mut self.bytes, (start - prev_start) as u64);
+        write_varint(&mut self.bytes, zigzag(span.line, prev.line));
+        write_varint(&mut self.bytes, span.column.into());
+        write_varint(&mut self.bytes, zigzag(span.offset, prev.offset));
+        write_varint(&mut self.bytes, span.len.into());
+        if self.len.is_multiple_of(RUNS_PER_CHECKPOINT) {
+            self.checkpoints.push(Checkpoint {
+                start,
+                span,
+                next: self.bytes.len(),
+            });
+        }
+        self.last = Some((start, span));
+        self.len += 1;
+    }
+
+    /// Get the start offset and the location of the last run.
+    pub(crate) fn last(&self) -> Option<(usize, Span)> {
+        self.last
+    }
+
+    /// Get the number of runs.
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Remove all runs.
+    pub(crate) fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Return an iterator over the start offsets and the locations of the runs.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (usize, Span)> + '_ {
+        let mut bytes = self.bytes.as_slice();
+        let mut run = (0, Span::default());
+        std::iter::from_fn(move || {
+            if bytes.is_empty() {
+                return None;
+            }
+            run = decode_run(&mut bytes, run);
+            Some(run)
+        })
+    }
+
+    /// Get the location of the run containing the given offset. The checkpoint before the offset
+    /// is found with a binary search, and at most a group of runs is decoded after it.
+    fn find(&self, offset: usize) -> Span {
+        let checkpoints = self.checkpoints.partition_point(|c| c.start <= offset);
+        let Some(checkpoint) = checkpoints.checked_sub(1).map(|c| &self.checkpoints[c]) else {
+            return Span::default();
+        };
+        let end = match self.checkpoints.get(checkpoints) {
+            Some(next) => next.next,
+            None => self.bytes.len(),
+        };
+        let mut bytes = &self.bytes[checkpoint.next..end];
+        let mut run = (checkpoint.start, checkpoint.span);
+        while !bytes.is_empty() {
+            let next = decode_run(&mut bytes, run);
+            if next.0 > offset {
+                break;
+            }
+            run = next;
+        }
+        run.1
+    }
+}
+
+/// Decode the run following the given one.
+fn decode_run(bytes: &mut &[u8], (start, span): (usize, Span)) -> (usize, Span) {
+    let start = start + read_varint(bytes) as usize;
+    let span = Span {
+        line: unzigzag(read_varint(bytes), span.line),
+        column: read_varint(bytes) as u32,
+        offset: unzigzag(read_varint(bytes), span.offset),
+        len: read_varint(bytes) as u32,
+    };
+    (start, span)
+}
+
+/// Write an unsigned integer using 7 bits per byte, the high bit is set on all bytes but the last.
+fn write_varint(bytes: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        bytes.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    bytes.push(n as u8);
+}
+
+/// Read an unsigned integer written by `write_varint`.
+fn read_varint(bytes: &mut &[u8]) -> u64 {
+    let mut n = 0;
+    let mut shift = 0;
+    while let Some((&byte, rest)) = bytes.split_first() {
+        *bytes = rest;
+        n |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+    n
+}
+
+/// Get the difference between 2 numbers, mapping small negative differences to small unsigned
+/// integers.
+fn zigzag(n: u32, prev: u32) -> u64 {
+    let delta = i64::from(n) - i64::from(prev);
+    ((delta << 1) ^ (delta >> 63)) as u64
+}
+
+/// Add a difference made by `zigzag` to the previous number.
+fn unzigzag(delta: u64, prev: u32) -> u32 {
+    let delta = (delta >> 1) as i64 ^ -((delta & 1) as i64);
+    (i64::from(prev) + delta) as u32
 }
 
-impl<T> RunLength<T> {
-    fn new(data: T) -> Self {
-        Self { data, length: 1 }
+/// Write a function and all functions nested in its constants in human-readable format. Global
+/// variables are named after the ones in the given globals.
+pub(crate) fn disassemble<W>(out: &mut W, fun: &ObjFun, globals: &Globals) -> fmt::Result
+where
+    W: fmt::Write + ?Sized,
+{
+    disassemble_chunk(out, &fun.chunk, globals, &fun.to_string())?;
+    for constant in &fun.chunk.constants {
+        if let Ok(nested) = constant.as_fun() {
+            writeln!(out)?;
+            disassemble(out, &nested, globals)?;
+        }
     }
+    Ok(())
 }
 
-/// Go through the instructions in the chunk and display them in human-readable format.
-#[cfg(feature = "dbg-execution")]
-pub(crate) fn disassemble_chunk(chunk: &Chunk, name: &str) {
-    println!("== {name} ==");
+/// Go through the instructions in the chunk and write them in human-readable format.
+pub(crate) fn disassemble_chunk<W>(
+    out: &mut W,
+    chunk: &Chunk,
+    globals: &Globals,
+    name: &str,
+) -> fmt::Result
+where
+    W: fmt::Write + ?Sized,
+{
+    writeln!(out, "== {name} ==")?;
     let mut offset = 0;
     while offset < chunk.instructions.len() {
-        offset = disassemble_instruction(chunk, offset);
+        offset = disassemble_instruction(out, chunk, globals, offset)?;
     }
+    Ok(())
 }
 
-/// Display an instruction in human readable format.
-#[cfg(feature = "dbg-execution")]
-pub(crate) fn disassemble_instruction(chunk: &Chunk, offset: usize) -> usize {
-    let line_current = chunk.get_line(offset);
-    let line_previous = chunk.get_line(offset.saturating_sub(1));
+/// Write an instruction in human-readable format. Return the offset of the next instruction.
+pub(crate) fn disassemble_instruction<W>(
+    out: &mut W,
+    chunk: &Chunk,
+    globals: &Globals,
+    offset: usize,
+) -> Result<usize, fmt::Error>
+where
+    W: fmt::Write + ?Sized,
+{
+    let line_current = chunk.get_span(offset).line;
+    let line_previous = chunk.get_span(offset.saturating_sub(1)).line;
     // Annotation for seperating instructions from different lines.
-    print!("{offset:04} ");
+    write!(out, "{offset:04} ")?;
     if offset > 0 && line_current == line_previous {
-        print!("   | ");
+        write!(out, "   | ")?;
     } else {
-        print!("{:4} ", *line_current);
+        write!(out, "{line_current:4} ")?;
     }
-    let instruction = match Opcode::try_from(chunk.instructions[offset]) {
-        Ok(inst) => inst,
-        Err(err) => panic!("{}", err),
-    };
-    // Print each individual instruction.
+    let instruction = Opcode::from(chunk.instructions[offset]);
+    // Write each individual instruction.
     match instruction {
-        Opcode::Const => disassemble_constant(chunk, offset, "OP_CONST"),
-        Opcode::Nil => disassemble_simple(offset, "OP_NIL"),
-        Opcode::True => disassemble_simple(offset, "OP_TRUE"),
-        Opcode::False => disassemble_simple(offset, "OP_FALSE"),
-        Opcode::Pop => disassemble_simple(offset, "OP_POP"),
-        Opcode::GetLocal => disassemble_byte(chunk, offset, "OP_GET_LOCAL"),
-        Opcode::SetLocal => disassemble_byte(chunk, offset, "OP_SET_LOCAL"),
-        Opcode::GetGlobal => disassemble_constant(chunk, offset, "OP_GET_GLOBAL"),
-        Opcode::SetGlobal => disassemble_constant(chunk, offset, "OP_SET_GLOBAL"),
-        Opcode::DefineGlobal => disassemble_constant(chunk, offset, "OP_DEFINE_GLOBAL"),
-        Opcode::GetUpvalue => disassemble_byte(chunk, offset, "OP_GET_UPVALUE"),
-        Opcode::SetUpvalue => disassemble_byte(chunk, offset, "OP_SET_UPVALUE"),
-        Opcode::GetProperty => disassemble_constant(chunk, offset, "OP_SET_PROPERTY"),
-        Opcode::SetProperty => disassemble_constant(chunk, offset, "OP_SET_PROPERTY"),
-        Opcode::GetSuper => disassemble_constant(chunk, offset, "OP_GET_SUPER"),
-        Opcode::NE => disassemble_simple(offset, "OP_NE"),
-        Opcode::EQ => disassemble_simple(offset, "OP_EQ"),
-        Opcode::GT => disassemble_simple(offset, "OP_GT"),
-        Opcode::GE => disassemble_simple(offset, "OP_GE"),
-        Opcode::LT => disassemble_simple(offset, "OP_LT"),
-        Opcode::LE => disassemble_simple(offset, "OP_LE"),
-        Opcode::Add => disassemble_simple(offset, "OP_ADD"),
-        Opcode::Sub => disassemble_simple(offset, "OP_SUB"),
-        Opcode::Mul => disassemble_simple(offset, "OP_MUL"),
-        Opcode::Div => disassemble_simple(offset, "OP_DIV"),
-        Opcode::Not => disassemble_simple(offset, "OP_NOT"),
-        Opcode::Neg => disassemble_simple(offset, "OP_NEG"),
-        Opcode::Print => disassemble_simple(offset, "OP_PRINT"),
-        Opcode::Jump => disassemble_jump(chunk, offset, JumpDirection::Forward, "OP_JUMP"),
-        Opcode::JumpIfTrue => {
-            disassemble_jump(chunk, offset, JumpDirection::Forward, "OP_JUMP_IF_TRUE")
-        }
-        Opcode::JumpIfFalse => {
-            disassemble_jump(chunk, offset, JumpDirection::Forward, "OP_JUMP_IF_FALSE")
-        }
-        Opcode::Loop => disassemble_jump(chunk, offset, JumpDirection::Backward, "OP_LOOP"),
-        Opcode::Call => disassemble_byte(chunk, offset, "OP_CALL"),
-        Opcode::Invoke => disassemble_invoke(chunk, offset, "OP_INVOKE"),
-        Opcode::SuperInvoke => disassemble_invoke(chunk, offset, "OP_SUPER_INVOKE"),
+        Opcode::Const => disassemble_constant(out, chunk, offset, OperandWidth::Byte, "OP_CONST"),
+        Opcode::Nil => disassemble_simple(out, offset, "OP_NIL"),
+        Opcode::True => disassemble_simple(out, offset, "OP_TRUE"),
+        Opcode::False => disassemble_simple(out, offset, "OP_FALSE"),
+        Opcode::Pop => disassemble_simple(out, offset, "OP_POP"),
+        Opcode::GetLocal => {
+            disassemble_slot(out, chunk, offset, OperandWidth::Byte, "OP_GET_LOCAL")
+        }
+        Opcode::SetLocal => {
+            disassemble_slot(out, chunk, offset, OperandWidth::Byte, "OP_SET_LOCAL")
+        }
+        Opcode::GetGlobal => disassemble_global(
+            out,
+            chunk,
+            globals,
+            offset,
+            OperandWidth::Byte,
+            "OP_GET_GLOBAL",
+        ),
+        Opcode::SetGlobal => disassemble_global(
+            out,
+            chunk,
+            globals,
+            offset,
+            OperandWidth::Byte,
+            "OP_SET_GLOBAL",
+        ),
+        Opcode::DefineGlobal => disassemble_global(
+            out,
+            chunk,
+            globals,
+            offset,
+            OperandWidth::Byte,
+            "OP_DEFINE_GLOBAL",
+        ),
+        Opcode::GetUpvalue => {
+            disassemble_slot(out, chunk, offset, OperandWidth::Byte, "OP_GET_UPVALUE")
+        }
+        Opcode::SetUpvalue => {
+            disassemble_slot(out, chunk, offset, OperandWidth::Byte, "OP_SET_UPVALUE")
+        }
+        Opcode::GetProperty => {
+            disassemble_constant(out, chunk, offset, OperandWidth::Byte, "OP_GET_PROPERTY")
+                .map(skip_cache)
+        }
+        Opcode::SetProperty => {
+            disassemble_constant(out, chunk, offset, OperandWidth::Byte, "OP_SET_PROPERTY")
+                .map(skip_cache)
+        }
+        Opcode::GetSuper => {
+            disassemble_constant(out, chunk, offset, OperandWidth::Byte, "OP_GET_SUPER")
+        }
+        Opcode::NE => disassemble_simple(out, offset, "OP_NE"),
+        Opcode::EQ => disassemble_simple(out, offset, "OP_EQ"),
+        Opcode::GT => disassemble_simple(out, offset, "OP_GT"),
+        Opcode::GE => disassemble_simple(out, offset, "OP_GE"),
+        Opcode::LT => disassemble_simple(out, offset, "OP_LT"),
+        Opcode::LE => disassemble_simple(out, offset, "OP_LE"),
+        Opcode::Add => disassemble_simple(out, offset, "OP_ADD"),
+        Opcode::Sub => disassemble_simple(out, offset, "OP_SUB"),
+        Opcode::Mul => disassemble_simple(out, offset, "OP_MUL"),
+        Opcode::Div => disassemble_simple(out, offset, "OP_DIV"),
+        Opcode::Not => disassemble_simple(out, offset, "OP_NOT"),
+        Opcode::Neg => disassemble_simple(out, offset, "OP_NEG"),
+        Opcode::Print => disassemble_simple(out, offset, "OP_PRINT"),
+        Opcode::Jump => disassemble_jump(out, chunk, offset, JumpDirection::Forward, "OP_JUMP"),
+        Opcode::JumpIfTrue => disassemble_jump(
+            out,
+            chunk,
+            offset,
+            JumpDirection::Forward,
+            "OP_JUMP_IF_TRUE",
+        ),
+        Opcode::JumpIfFalse => disassemble_jump(
+            out,
+            chunk,
+            offset,
+            JumpDirection::Forward,
+            "OP_JUMP_IF_FALSE",
+        ),
+        Opcode::Loop => disassemble_jump(out, chunk, offset, JumpDirection::Backward, "OP_LOOP"),
+        Opcode::Call => disassemble_byte(out, chunk, offset, "OP_CALL"),
+        Opcode::Invoke => {
+            disassemble_invoke(out, chunk, offset, OperandWidth::Byte, "OP_INVOKE").map(skip_cache)
+        }
+        Opcode::SuperInvoke => {
+            disassemble_invoke(out, chunk, offset, OperandWidth::Byte, "OP_SUPER_INVOKE")
+        }
         Opcode::Closure => {
-            let mut offset = offset + 1;
-            let constant_id = chunk.instructions[offset] as usize;
-            // SAFETY: The compiler must work correctly.
-            let constant = unsafe { chunk.constants.at(constant_id) };
-            offset += 1;
-            println!("{:-16} {constant_id:4} {constant}", "OP_CLOSURE");
-            let fun = constant.as_fun().expect("Expect function object.");
-            let upvalue_count = fun.upvalue_count;
-            for _ in 0..upvalue_count {
-                let is_local = chunk.instructions[offset + 1] == 1;
-                let index = chunk.instructions[offset + 2];
-                let upvalue_type = if is_local { "local" } else { "upvalue" };
-                println!("{offset:04}    |                     {upvalue_type} {index}");
-                offset += 2;
-            }
-            offset
+            disassemble_closure(out, chunk, offset, OperandWidth::Byte, "OP_CLOSURE")
+        }
+        Opcode::CloseUpvalue => disassemble_simple(out, offset, "OP_CLOSE_UPVALUE"),
+        Opcode::Ret => disassemble_simple(out, offset, "OP_RET"),
+        Opcode::Class => disassemble_constant(out, chunk, offset, OperandWidth::Byte, "OP_CLASS"),
+        Opcode::Inherit => disassemble_simple(out, offset, "OP_INHERIT"),
+        Opcode::Method => disassemble_constant(out, chunk, offset, OperandWidth::Byte, "OP_METHOD"),
+        Opcode::Stringify => disassemble_simple(out, offset, "OP_STRINGIFY"),
+        Opcode::ConstLong => {
+            disassemble_constant(out, chunk, offset, OperandWidth::Long, "OP_CONST_LONG")
+        }
+        Opcode::GetGlobalLong => disassemble_global(
+            out,
+            chunk,
+            globals,
+            offset,
+            OperandWidth::Long,
+            "OP_GET_GLOBAL_LONG",
+        ),
+        Opcode::SetGlobalLong => disassemble_global(
+            out,
+            chunk,
+            globals,
+            offset,
+            OperandWidth::Long,
+            "OP_SET_GLOBAL_LONG",
+        ),
+        Opcode::DefineGlobalLong => disassemble_global(
+            out,
+            chunk,
+            globals,
+            offset,
+            OperandWidth::Long,
+            "OP_DEFINE_GLOBAL_LONG",
+        ),
+        Opcode::GetPropertyLong => disassemble_constant(
+            out,
+            chunk,
+            offset,
+            OperandWidth::Long,
+            "OP_GET_PROPERTY_LONG",
+        )
+        .map(skip_cache),
+        Opcode::SetPropertyLong => disassemble_constant(
+            out,
+            chunk,
+            offset,
+            OperandWidth::Long,
+            "OP_SET_PROPERTY_LONG",
+        )
+        .map(skip_cache),
+        Opcode::GetSuperLong => {
+            disassemble_constant(out, chunk, offset, OperandWidth::Long, "OP_GET_SUPER_LONG")
+        }
+        Opcode::InvokeLong => {
+            disassemble_invoke(out, chunk, offset, OperandWidth::Long, "OP_INVOKE_LONG")
+                .map(skip_cache)
         }
-        Opcode::CloseUpvalue => disassemble_simple(offset, "OP_CLOSE_UPVALUE"),
-        Opcode::Ret => disassemble_simple(offset, "OP_RET"),
-        Opcode::Class => disassemble_constant(chunk, offset, "OP_CLASS"),
-        Opcode::Inherit => disassemble_simple(offset, "OP_INHERIT"),
-        Opcode::Method => disassemble_constant(chunk, offset, "OP_METHOD"),
+        Opcode::SuperInvokeLong => disassemble_invoke(
+            out,
+            chunk,
+            offset,
+            OperandWidth::Long,
+            "OP_SUPER_INVOKE_LONG",
+        ),
+        Opcode::ClosureLong => {
+            disassemble_closure(out, chunk, offset, OperandWidth::Long, "OP_CLOSURE_LONG")
+        }
+        Opcode::ClassLong => {
+            disassemble_constant(out, chunk, offset, OperandWidth::Long, "OP_CLASS_LONG")
+        }
+        Opcode::MethodLong => {
+            disassemble_constant(out, chunk, offset, OperandWidth::Long, "OP_METHOD_LONG")
+        }
+        Opcode::GetLocalLong => {
+            disassemble_slot(out, chunk, offset, OperandWidth::Short, "OP_GET_LOCAL_LONG")
+        }
+        Opcode::SetLocalLong => {
+            disassemble_slot(out, chunk, offset, OperandWidth::Short, "OP_SET_LOCAL_LONG")
+        }
+        Opcode::GetUpvalueLong => disassemble_slot(
+            out,
+            chunk,
+            offset,
+            OperandWidth::Short,
+            "OP_GET_UPVALUE_LONG",
+        ),
+        Opcode::SetUpvalueLong => disassemble_slot(
+            out,
+            chunk,
+            offset,
+            OperandWidth::Short,
+            "OP_SET_UPVALUE_LONG",
+        ),
+        Opcode::DefineGlobalConst => disassemble_global(
+            out,
+            chunk,
+            globals,
+            offset,
+            OperandWidth::Byte,
+            "OP_DEFINE_GLOBAL_CONST",
+        ),
+        Opcode::BuildList => disassemble_byte(out, chunk, offset, "OP_BUILD_LIST"),
+        Opcode::BuildListLong => disassemble_slot(
+            out,
+            chunk,
+            offset,
+            OperandWidth::Short,
+            "OP_BUILD_LIST_LONG",
+        ),
+        Opcode::GetIndex => disassemble_simple(out, offset, "OP_GET_INDEX"),
+        Opcode::SetIndex => disassemble_simple(out, offset, "OP_SET_INDEX"),
+        Opcode::BuildMap => disassemble_byte(out, chunk, offset, "OP_BUILD_MAP"),
+        Opcode::BuildMapLong => {
+            disassemble_slot(out, chunk, offset, OperandWidth::Short, "OP_BUILD_MAP_LONG")
+        }
+        Opcode::Try => disassemble_jump(out, chunk, offset, JumpDirection::Forward, "OP_TRY"),
+        Opcode::PopHandler => disassemble_simple(out, offset, "OP_POP_HANDLER"),
+        Opcode::Throw => disassemble_simple(out, offset, "OP_THROW"),
+        Opcode::EndFinally => disassemble_simple(out, offset, "OP_END_FINALLY"),
+        Opcode::Import => disassemble_constant(out, chunk, offset, OperandWidth::Byte, "OP_IMPORT"),
+        Opcode::ImportLong => {
+            disassemble_constant(out, chunk, offset, OperandWidth::Long, "OP_IMPORT_LONG")
+        }
+        Opcode::FinishImport => disassemble_simple(out, offset, "OP_FINISH_IMPORT"),
+        Opcode::DefineGlobalConstLong => disassemble_global(
+            out,
+            chunk,
+            globals,
+            offset,
+            OperandWidth::Long,
+            "OP_DEFINE_GLOBAL_CONST_LONG",
+        ),
     }
 }
 
-/// Display a simple instruction in human-readable format.
-#[cfg(feature = "dbg-execution")]
-fn disassemble_simple(offset: usize, name: &'static str) -> usize {
-    println!("{name}");
-    offset + 1
+/// Write a simple instruction in human-readable format.
+fn disassemble_simple<W>(
+    out: &mut W,
+    offset: usize,
+    name: &'static str,
+) -> Result<usize, fmt::Error>
+where
+    W: fmt::Write + ?Sized,
+{
+    writeln!(out, "{name}")?;
+    Ok(offset + 1)
 }
 
-/// Display a constant instruction in human-readable format.
-#[cfg(feature = "dbg-execution")]
-fn disassemble_constant(chunk: &Chunk, offset: usize, name: &'static str) -> usize {
-    let constant_id = chunk.instructions[offset + 1] as usize;
-    // SAFETY: The compiler must work correctly.
-    let constant = unsafe { chunk.constants.at(constant_id) };
-    println!("{name:-16} {constant_id:4} {constant}");
-    offset + 2
+/// Read the index operand of the instruction at the given offset. Return the index and the
+/// offset of the byte right after it.
+fn read_operand(chunk: &Chunk, offset: usize, width: OperandWidth) -> (usize, usize) {
+    match width {
+        OperandWidth::Byte => (chunk.instructions[offset + 1] as usize, offset + 2),
+        OperandWidth::Short => {
+            let hi = chunk.instructions[offset + 1] as usize;
+            let lo = chunk.instructions[offset + 2] as usize;
+            (hi << 8 | lo, offset + 3)
+        }
+        OperandWidth::Long => {
+            let hi = chunk.instructions[offset + 1] as usize;
+            let mid = chunk.instructions[offset + 2] as usize;
+            let lo = chunk.instructions[offset + 3] as usize;
+            (hi << 16 | mid << 8 | lo, offset + 4)
+        }
+    }
+}
+
+/// Write a constant instruction in human-readable format.
+fn disassemble_constant<W>(
+    out: &mut W,
+    chunk: &Chunk,
+    offset: usize,
+    width: OperandWidth,
+    name: &'static str,
+) -> Result<usize, fmt::Error>
+where
+    W: fmt::Write + ?Sized,
+{
+    let (constant_id, offset) = read_operand(chunk, offset, width);
+    let constant = &chunk.constants[constant_id];
+    writeln!(out, "{name:-16} {constant_id:4} {constant}")?;
+    Ok(offset)
+}
+
+/// Write a closure instruction along with its captured upvalues in human-readable format.
+fn disassemble_closure<W>(
+    out: &mut W,
+    chunk: &Chunk,
+    offset: usize,
+    width: OperandWidth,
+    name: &'static str,
+) -> Result<usize, fmt::Error>
+where
+    W: fmt::Write + ?Sized,
+{
+    let (constant_id, mut offset) = read_operand(chunk, offset, width);
+    let constant = &chunk.constants[constant_id];
+    writeln!(out, "{name:-16} {constant_id:4} {constant}")?;
+    let fun = constant.as_fun().expect("Expect function object.");
+    for _ in 0..fun.upvalue_count {
+        let is_local = chunk.instructions[offset] == 1;
+        let (index, next) = read_operand(chunk, offset, OperandWidth::Short);
+        let upvalue_type = if is_local { "local" } else { "upvalue" };
+        writeln!(
+            out,
+            "{offset:04}    |                     {upvalue_type} {index}"
+        )?;
+        offset = next;
+    }
+    Ok(offset)
 }
 
-/// Display a byte instruction in human-readable format.
-#[cfg(feature = "dbg-execution")]
-fn disassemble_byte(chunk: &Chunk, offset: usize, name: &'static str) -> usize {
+/// Write a byte instruction in human-readable format.
+fn disassemble_byte<W>(
+    out: &mut W,
+    chunk: &Chunk,
+    offset: usize,
+    name: &'static str,
+) -> Result<usize, fmt::Error>
+where
+    W: fmt::Write + ?Sized,
+{
     let slot = chunk.instructions[offset + 1] as usize;
-    println!("{name:-16} {slot:4}");
-    offset + 2
+    writeln!(out, "{name:-16} {slot:4}")?;
+    Ok(offset + 2)
+}
+
+/// Write an instruction accessing a global variable in human-readable format.
+fn disassemble_global<W>(
+    out: &mut W,
+    chunk: &Chunk,
+    globals: &Globals,
+    offset: usize,
+    width: OperandWidth,
+    name: &'static str,
+) -> Result<usize, fmt::Error>
+where
+    W: fmt::Write + ?Sized,
+{
+    let (slot, offset) = read_operand(chunk, offset, width);
+    // SAFETY: The slots in a compiled chunk were given out by the globals.
+    let global = unsafe { globals.at(slot) };
+    writeln!(out, "{name:-16} {slot:4} {}", global.name.data)?;
+    Ok(offset)
+}
+
+/// Write an instruction accessing a local variable or an upvalue in human-readable format.
+fn disassemble_slot<W>(
+    out: &mut W,
+    chunk: &Chunk,
+    offset: usize,
+    width: OperandWidth,
+    name: &'static str,
+) -> Result<usize, fmt::Error>
+where
+    W: fmt::Write + ?Sized,
+{
+    let (slot, offset) = read_operand(chunk, offset, width);
+    writeln!(out, "{name:-16} {slot:4}")?;
+    Ok(offset)
 }
 
-/// Display a jump instruction in human-readable format.
-#[cfg(feature = "dbg-execution")]
-fn disassemble_jump(chunk: &Chunk, offset: usize, dir: JumpDirection, name: &'static str) -> usize {
+/// Write a jump instruction in human-readable format.
+fn disassemble_jump<W>(
+    out: &mut W,
+    chunk: &Chunk,
+    offset: usize,
+    dir: JumpDirection,
+    name: &'static str,
+) -> Result<usize, fmt::Error>
+where
+    W: fmt::Write + ?Sized,
+{
     let hi = chunk.instructions[offset + 1] as u16;
     let lo = chunk.instructions[offset + 2] as u16;
     let jump = hi << 8 | lo;
@@ -193,17 +691,89 @@ fn disassemble_jump(chunk: &Chunk, offset: usize, dir: JumpDirection, name: &'st
         JumpDirection::Forward => offset + 3 + jump as usize,
         JumpDirection::Backward => offset + 3 - jump as usize,
     };
-    println!("{name:-16} {offset:4} -> {target}");
-    offset + 3
-}
-
-/// Display a invoke instruction in human-readable format.
-#[cfg(feature = "dbg-execution")]
-fn disassemble_invoke(chunk: &Chunk, offset: usize, name: &'static str) -> usize {
-    let slot = chunk.instructions[offset + 1];
-    let argc = chunk.instructions[offset + 2];
-    // SAFETY: The compiler must work correctly.
-    let fname = unsafe { chunk.constants.at(slot as usize) };
-    println!("{name:-16} {slot:4} ({argc} args) {fname}",);
-    offset + 3
+    writeln!(out, "{name:-16} {offset:4} -> {target}")?;
+    Ok(offset + 3)
+}
+
+/// Write a invoke instruction in human-readable format.
+fn disassemble_invoke<W>(
+    out: &mut W,
+    chunk: &Chunk,
+    offset: usize,
+    width: OperandWidth,
+    name: &'static str,
+) -> Result<usize, fmt::Error>
+where
+    W: fmt::Write + ?Sized,
+{
+    let (slot, offset) = read_operand(chunk, offset, width);
+    let argc = chunk.instructions[offset];
+    let fname = &chunk.constants[slot];
+    writeln!(out, "{name:-16} {slot:4} ({argc} args) {fname}")?;
+    Ok(offset + 1)
+}
+
+/// Skip the 16-bit inline cache index that follows the operands of an instruction.
+fn skip_cache(offset: usize) -> usize {
+    offset + 2
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{opcode::Opcode, scan::Span};
+
+    use super::{Chunk, Spans, RUNS_PER_CHECKPOINT};
+
+    #[test]
+    fn span_of_an_offset_is_the_one_of_its_run() {
+        let mut chunk = Chunk::default();
+        let first = Span {
+            line: 1,
+            column: 1,
+            offset: 0,
+            len: 1,
+        };
+        let second = Span {
+            line: 300,
+            column: 3,
+            offset: 70_000,
+            len: 2,
+        };
+        chunk.write(Opcode::Nil, first);
+        chunk.write(Opcode::Nil, first);
+        chunk.write(Opcode::Const, second);
+        chunk.write_byte(0);
+        assert_eq!(2, chunk.spans.len);
+        assert_eq!(first, chunk.get_span(1));
+        assert_eq!(second, chunk.get_span(2));
+        assert_eq!(second, chunk.get_span(3));
+
+        // Runs that are close to each other take a byte per field.
+        let mut spans = Spans::default();
+        spans.push(0, first);
+        spans.push(2, Span { column: 5, ..first });
+        assert_eq!(10, spans.bytes.len());
+    }
+
+    #[test]
+    fn spans_in_the_middle_of_a_large_chunk() {
+        let mut chunk = Chunk::default();
+        let span = |n: u32| Span {
+            line: n / 3 + 1,
+            column: n % 3 * 4 + 1,
+            offset: n * 4,
+            len: 3,
+        };
+        // Every instruction takes 2 bytes and has its own location.
+        for n in 0..10_000 {
+            chunk.write(Opcode::Const, span(n));
+            chunk.write_byte(0);
+        }
+        assert_eq!(10_000 / RUNS_PER_CHECKPOINT, chunk.spans.checkpoints.len());
+        for n in [0, 15, 16, 17, 4_999, 5_000, 5_001, 9_999] {
+            assert_eq!(span(n), chunk.get_span(2 * n as usize));
+            assert_eq!(span(n), chunk.get_span(2 * n as usize + 1));
+        }
+        assert_eq!(span(9_999), chunk.get_span(1_000_000));
+    }
 }
@@ -1,11 +1,17 @@
 //! Implementation of the bytecode compiler for the Lox lanaguage.
 
+use std::{collections::HashSet, rc::Rc};
+
 use crate::{
     chunk::MAX_CONSTANTS,
+    diagnostic::{Diagnostic, Severity},
+    global::Globals,
     heap::Heap,
+    module::Source,
     object::{ObjFun, Object},
     opcode::Opcode,
-    scan::{Kind, Line, Scanner, Token},
+    optimize::optimize,
+    scan::{Kind, Scanner, Token},
     stack::Stack,
     value::Value,
 };
@@ -13,17 +19,23 @@ use crate::{
 #[cfg(feature = "dbg-execution")]
 use crate::chunk::disassemble_chunk;
 
-/// The max number of call frames can be handled by the virtual machine.
-pub(crate) const MAX_FRAMES: usize = 64;
+/// The max number of nested functions and classes can be handled by the compiler.
+const MAX_NESTING: usize = 64;
 
 /// Max number of parameters a function can accept.
 const MAX_PARAMS: usize = u8::MAX as usize;
 
-/// Max number of local variables a function can contain.
-const MAX_LOCALS: usize = u8::MAX as usize + 1;
+/// Max number of items a list literal can contain.
+const MAX_LIST_ITEMS: usize = u16::MAX as usize;
+
+/// Max number of entries a map literal can contain.
+const MAX_MAP_ENTRIES: usize = u16::MAX as usize;
+
+/// Max number of local variables a function can contain. Locals are indexed using at most 16 bits.
+const MAX_LOCALS: usize = u16::MAX as usize + 1;
 
-/// Max number of upvalues a function can contain.
-const MAX_UPVALUES: usize = u8::MAX as usize + 1;
+/// Max number of upvalues a function can contain. Upvalues are indexed using at most 16 bits.
+const MAX_UPVALUES: usize = u16::MAX as usize + 1;
 
 /// Scan for tokens and emit corresponding bytecodes.
 ///
@@ -32,30 +44,49 @@ const MAX_UPVALUES: usize = u8::MAX as usize + 1;
 /// ```text
 /// program    --> decl* EOF ;
 /// decl       --> classDecl
+///              | constDecl
+///              | exportDecl
 ///              | funDecl
+///              | importDecl
 ///              | varDecl
 ///              | stmt ;
+/// exportDecl --> "export" ( classDecl | constDecl | funDecl | varDecl ) ;
+/// importDecl --> "import" STRING ";" ;
 /// classDecl  --> "class" IDENT ( "<" IDENT )? "{" function* "}" ;
+/// constDecl  --> "const" IDENT "=" expr ";" ;
 /// funDecl    --> "fun" function ;
 /// function   --> IDENT "(" params? ")" block ;
 /// params     --> IDENT ( "," IDENT )* ;
 /// varDecl    --> "var" IDENT ( "=" expr )? ";" ;
 /// stmt       --> block
+///              | breakStmt
+///              | continueStmt
 ///              | exprStmt
 ///              | forStmt
 ///              | ifStmt
 ///              | printStmt
 ///              | returnStmt
+///              | switchStmt
+///              | throwStmt
+///              | tryStmt
 ///              | whileStmt ;
 /// block      --> "{" decl* "}" ;
+/// breakStmt  --> "break" ";" ;
+/// continueStmt --> "continue" ";" ;
 /// exprStmt   --> expr ";" ;
 /// forStmt    --> "for" "(" ( varDecl | exprStmt | ";" ) expr? ";" expr? ")" stmt ;
 /// ifStmt     --> "if" "(" expr ")" stmt ( "else" stmt )? ;
 /// printStmt  --> "print" expr ";" ;
 /// returnStmt --> "return" expr? ";" ;
+/// switchStmt --> "switch" "(" expr ")" "{" switchCase* defaultCase? "}" ;
+/// switchCase --> "case" expr ":" decl* ;
+/// defaultCase --> "default" ":" decl* ;
+/// throwStmt  --> "throw" expr ";" ;
+/// tryStmt    --> "try" block ( "catch" "(" IDENT ")" block )? ( "finally" block )? ;
 /// whileStmt  --> "while" "(" expr ")" stmt ;
 /// expr       --> assign ;
 /// assign     --> ( call "." )? IDENT "=" expr ";"
+///              | call "[" expr "]" "=" expr ";"
 ///              | or ;
 /// or         --> and ( "or" and )* ;
 /// and        --> equality ( "and" equality )* ;
@@ -65,12 +96,18 @@ const MAX_UPVALUES: usize = u8::MAX as usize + 1;
 /// factor     --> unary ( ( "/" | "*" ) unary )* ;
 /// unary      --> ( "!" | "-" ) unary
 ///              | call ;
-/// call       --> primary ( "(" args? ")" | "." IDENT )* ;
+/// call       --> primary ( "(" args? ")" | "." IDENT | "[" expr "]" )* ;
 /// args       --> expr ( "," expr )* ;
-/// primary    --> IDENT | NUMBER | STRING
+/// primary    --> IDENT | NUMBER | STRING | interp
 ///              | "this" | "super" "." IDENT
 ///              | "true" | "false" | "nil"
-///              | "(" expr ")" ;
+///              | "(" expr ")" | list | map | lambda ;
+/// interp     --> ( INTERPOLATION expr )+ STRING ;
+/// lambda     --> "fun" "(" params? ")" block
+///              | "(" params? ")" "=>" ( block | expr ) ;
+/// list       --> "[" ( expr ( "," expr )* )? "]" ;
+/// map        --> "{" ( entry ( "," entry )* )? "}" ;
+/// entry      --> expr ":" expr ;
 /// ```
 pub(crate) struct Parser<'src, 'vm> {
     /// The flag to indicate that the compilation process had error(s).
@@ -84,17 +121,38 @@ pub(crate) struct Parser<'src, 'vm> {
     /// The scanner for turning source bytes into tokens.
     scanner: Scanner<'src>,
     /// The compiler's state for tracking classes.
-    classes: Stack<ClassCompiler, MAX_FRAMES>,
+    classes: Stack<ClassCompiler, MAX_NESTING>,
     /// The compiler's state for tracking scopes.
-    compilers: Stack<Compiler<'src>, MAX_FRAMES>,
+    compilers: Stack<Compiler<'src>, MAX_NESTING>,
+    /// The names of the global variables that have been declared as constants.
+    const_globals: HashSet<&'src str>,
     /// The heap of the currently running virtual machine.
     heap: &'vm mut Heap,
+    /// The global variables of the currently running virtual machine.
+    globals: &'vm mut Globals,
+    /// The problems found in the source code.
+    diagnostics: Vec<Diagnostic>,
+    /// The global namespace of the module being compiled.
+    namespace: usize,
+    /// The source code being compiled, which is shared by all the compiled functions.
+    source: Rc<Source>,
+    /// The flag to indicate that the next global variable being declared is exported.
+    exporting: bool,
+    /// The flag to indicate that the instructions of each function are optimized once it's
+    /// compiled.
+    optimizing: bool,
 }
 
 impl<'src, 'vm> Parser<'src, 'vm> {
-    /// Create a new parser that reads the given source string.
-    pub(crate) fn new(src: &'src str, heap: &'vm mut Heap) -> Self {
-        let fun = ObjFun::new(None);
+    /// Create a new parser that reads the given source code. Global variables are resolved in
+    /// the given namespace.
+    pub(crate) fn new(
+        source: &'src Rc<Source>,
+        heap: &'vm mut Heap,
+        globals: &'vm mut Globals,
+        namespace: usize,
+    ) -> Self {
+        let fun = ObjFun::new(None, namespace, Rc::clone(source));
         let mut compilers = Stack::default();
         compilers.push(Compiler::new(fun, FunctionType::Script));
         Self {
@@ -102,34 +160,56 @@ impl<'src, 'vm> Parser<'src, 'vm> {
             panicking: false,
             token_prev: Token::placeholder(),
             token_curr: Token::placeholder(),
-            scanner: Scanner::new(src),
+            scanner: Scanner::new(&source.text),
             classes: Stack::default(),
             compilers,
+            const_globals: HashSet::new(),
             heap,
+            globals,
+            diagnostics: Vec::new(),
+            namespace,
+            source: Rc::clone(source),
+            exporting: false,
+            optimizing: false,
         }
     }
 
-    /// Compile the source and returns its chunk.
-    pub(crate) fn compile(mut self) -> Option<ObjFun> {
+    /// Set whether the instructions of each function are optimized once it's compiled.
+    pub(crate) fn optimize(mut self, optimizing: bool) -> Self {
+        self.optimizing = optimizing;
+        self
+    }
+
+    /// Compile the source and returns its chunk, or the problems found in the source if it can't
+    /// be compiled.
+    pub(crate) fn compile(mut self) -> Result<ObjFun, Vec<Diagnostic>> {
         self.build();
         let compiler = self.take();
         if self.had_error {
-            None
+            Err(self.diagnostics)
         } else {
-            Some(compiler.fun)
+            Ok(compiler.fun)
         }
     }
 
     fn take(&mut self) -> Compiler<'src> {
         self.emit_return();
         let mut compiler = self.compilers.pop();
-        compiler.fun.upvalue_count = compiler.upvalues.len() as u8;
+        compiler.fun.upvalue_count = compiler.upvalues.len();
+        if self.optimizing && !self.had_error {
+            optimize(&mut compiler.fun.chunk, self.heap);
+        }
 
         #[cfg(feature = "dbg-execution")]
-        match &compiler.fun.name {
-            None => disassemble_chunk(&compiler.fun.chunk, "code"),
-            Some(s) => disassemble_chunk(&compiler.fun.chunk, &s.data),
-        };
+        {
+            let mut out = String::new();
+            let name = match &compiler.fun.name {
+                None => "code",
+                Some(s) => &s.data,
+            };
+            disassemble_chunk(&mut out, &compiler.fun.chunk, self.globals, name).ok();
+            print!("{out}");
+        }
 
         compiler
     }
@@ -148,17 +228,26 @@ impl<'src, 'vm> Parser<'src, 'vm> {
     ///
     /// ```text
     /// decl       --> classDecl
+    ///              | constDecl
+    ///              | exportDecl
     ///              | funDecl
+    ///              | importDecl
     ///              | varDecl
     ///              | stmt ;
     /// ```
     fn declaration(&mut self) {
         if self.advance_if(Kind::Var) {
             self.var_declaration();
+        } else if self.advance_if(Kind::Const) {
+            self.const_declaration();
         } else if self.advance_if(Kind::Fun) {
             self.fun_declaration();
         } else if self.advance_if(Kind::Class) {
             self.class_declaration();
+        } else if self.advance_if(Kind::Export) {
+            self.export_declaration();
+        } else if self.advance_if(Kind::Import) {
+            self.import_declaration();
         } else {
             self.statement();
         }
@@ -168,6 +257,54 @@ impl<'src, 'vm> Parser<'src, 'vm> {
         }
     }
 
+    /// Parse an exported declaration assuming that we've already consumed the 'export' keyword.
+    /// Only top-level declarations of a module can be exported.
+    ///
+    /// ## Grammar
+    ///
+    /// ```text
+    /// exportDecl --> "export" ( classDecl | constDecl | funDecl | varDecl ) ;
+    /// ```
+    fn export_declaration(&mut self) {
+        if self.compilers.len() > 1 || self.compiler(0).scope_depth > 0 {
+            self.error_prev("Can only export top-level declarations.");
+        }
+        // The flag is cleared once the declared name gets its global slot.
+        self.exporting = true;
+        if self.advance_if(Kind::Var) {
+            self.var_declaration();
+        } else if self.advance_if(Kind::Const) {
+            self.const_declaration();
+        } else if self.advance_if(Kind::Fun) {
+            self.fun_declaration();
+        } else if self.advance_if(Kind::Class) {
+            self.class_declaration();
+        } else {
+            self.error_curr("Expect declaration after 'export'.");
+        }
+        self.exporting = false;
+    }
+
+    /// Parse an import declaration assuming that we've already consumed the 'import' keyword.
+    /// The imported module is run once, then the variables it exports are copied into the
+    /// namespace of the current module.
+    ///
+    /// ## Grammar
+    ///
+    /// ```text
+    /// importDecl --> "import" STRING ";" ;
+    /// ```
+    fn import_declaration(&mut self) {
+        self.consume(Kind::String, "Expect module path after 'import'.");
+        let path = String::from(Self::segment(self.token_prev));
+        let path = self.heap.intern(path);
+        let path_const = self.make_constant(Value::object(Object::String(path)));
+        self.consume(Kind::Semicolon, "Expect ';' after module path.");
+        // The module leaves its namespace and the result of running it on the stack.
+        self.emit_with_index(Opcode::Import, path_const);
+        self.emit(Opcode::FinishImport);
+    }
+
     /// Parse a variable declaration assuming that we've already consumed the 'var' keyword.
     ///
     /// ## Grammar
@@ -218,6 +355,27 @@ impl<'src, 'vm> Parser<'src, 'vm> {
         self.define_variable(global_id);
     }
 
+    /// Parse a constant declaration assuming that we've already consumed the 'const' keyword.
+    /// Unlike variables, constants must be initialized and can't be assigned to afterward.
+    ///
+    /// ## Grammar
+    ///
+    /// ```text
+    /// constDecl  --> "const" IDENT "=" expr ";" ;
+    /// ```
+    fn const_declaration(&mut self) {
+        let global_id = self.parse_variable("Expect constant name.");
+        self.mark_const();
+        self.consume(Kind::Equal, "Const variable must be initialized.");
+        self.expression();
+        self.consume(Kind::Semicolon, "Expect ';' after constant declaration.");
+        if self.compiler(0).scope_depth > 0 {
+            self.mark_initialized();
+        } else {
+            self.emit_with_index(Opcode::DefineGlobalConst, global_id);
+        }
+    }
+
     /// Parse a variable declaration assuming that we've already consumed the 'fun' keyword.
     ///
     /// ## Grammar
@@ -243,15 +401,13 @@ impl<'src, 'vm> Parser<'src, 'vm> {
     /// classDecl  --> "class" IDENT ( "<" IDENT )? "{" function* "}" ;
     /// ```
     fn class_declaration(&mut self) {
-        self.consume(Kind::Ident, "Expect class name.");
+        let global_id = self.parse_variable("Expect class name.");
         // Track the identifier token, so we can load it after finish parsing all methods.
         let class_name = self.token_prev;
         let name_const = self.identifier_constant(class_name);
         // Emit instructions for declaring a class definition with the given name.
-        self.declare_variable();
-        self.emit(Opcode::Class);
-        self.emit_byte(name_const);
-        self.define_variable(name_const);
+        self.emit_with_index(Opcode::Class, name_const);
+        self.define_variable(global_id);
 
         // Keep track of the number of nesting class declarations.
         self.classes.push(ClassCompiler::new());
@@ -268,11 +424,7 @@ impl<'src, 'vm> Parser<'src, 'vm> {
             // Add a synthetic token to represent the super class and define a variable
             // using that token.
             self.begin_scope();
-            self.add_local(Token {
-                kind: Kind::Ident,
-                line: self.token_curr.line,
-                lexeme: "super",
-            });
+            self.add_local(Token::synthetic("super", self.token_curr));
             self.define_variable(0);
 
             // Load subclass onto the stack.
@@ -313,8 +465,7 @@ impl<'src, 'vm> Parser<'src, 'vm> {
             self.function(FunctionType::Method);
         }
 
-        self.emit(Opcode::Method);
-        self.emit_byte(name_const);
+        self.emit_with_index(Opcode::Method, name_const);
     }
 
     /// Parse a function block assuming that we've already consumed its name.
@@ -325,13 +476,33 @@ impl<'src, 'vm> Parser<'src, 'vm> {
     /// function   --> IDENT "(" params? ")" block ;
     /// ```
     fn function(&mut self, fun_type: FunctionType) {
-        // Interned the function name and allocate a new function.
-        let fun_name = self.heap.intern(String::from(self.token_prev.lexeme));
-        self.compilers
-            .push(Compiler::new(ObjFun::new(Some(fun_name)), fun_type));
+        self.begin_function(self.token_prev.lexeme, fun_type);
+        self.consume(Kind::LParen, "Expect '(' after function name.");
+        self.parameters();
+        self.consume(Kind::LBrace, "Expect '{' before function body.");
+        self.block();
+        self.end_function();
+    }
 
+    /// Start compiling a new function with the given name.
+    fn begin_function(&mut self, name: &str, fun_type: FunctionType) {
+        // Interned the function name and allocate a new function.
+        let fun_name = self.heap.intern(String::from(name));
+        self.compilers.push(Compiler::new(
+            ObjFun::new(Some(fun_name), self.namespace, Rc::clone(&self.source)),
+            fun_type,
+        ));
         self.begin_scope();
-        self.consume(Kind::LParen, "Expect '(' after function name.");
+    }
+
+    /// Parse the parameters of a function assuming that we've already consumed the '('.
+    ///
+    /// ## Grammar
+    ///
+    /// ```text
+    /// params     --> IDENT ( "," IDENT )* ;
+    /// ```
+    fn parameters(&mut self) {
         if !self.check_curr(Kind::RParen) {
             loop {
                 let compiler = self.compiler_mut(0);
@@ -351,15 +522,15 @@ impl<'src, 'vm> Parser<'src, 'vm> {
             }
         }
         self.consume(Kind::RParen, "Expect ')' after parameters.");
-        self.consume(Kind::LBrace, "Expect '{' before function body.");
-        self.block();
+    }
 
+    /// Finish compiling the current function and emit the instructions for creating its closure.
+    fn end_function(&mut self) {
         // Create a constant for the compiled function.
         let compiler = self.take();
         let (fun_object, _) = self.heap.alloc(compiler.fun, Object::Fun);
-        let constant_id = self.make_constant(Value::Object(fun_object));
-        self.emit(Opcode::Closure);
-        self.emit_byte(constant_id);
+        let constant_id = self.make_constant(Value::object(fun_object));
+        self.emit_with_index(Opcode::Closure, constant_id);
 
         for upvalue in &compiler.upvalues {
             if upvalue.is_local {
@@ -367,21 +538,28 @@ impl<'src, 'vm> Parser<'src, 'vm> {
             } else {
                 self.emit_byte(0);
             }
-            self.emit_byte(upvalue.index);
+            // Upvalue indices always take 2 bytes since they can't be patched later.
+            self.emit_byte(((upvalue.index >> 8) & 0xff) as u8);
+            self.emit_byte((upvalue.index & 0xff) as u8);
         }
     }
 
     /// Parse the identifier and declare it as the variable name.
-    fn parse_variable(&mut self, message: &str) -> u8 {
+    fn parse_variable(&mut self, message: &str) -> usize {
         self.consume(Kind::Ident, message);
         self.declare_variable();
         if self.compiler(0).scope_depth > 0 {
-            // Return a dummy constant id if we're in a local scope. Local variable don't
-            // need to store their names as constants because we access them at runtime
-            // through the stack index.
+            // Return a dummy slot if we're in a local scope. Local variable don't need a global
+            // slot because we access them at runtime through the stack index.
             0
         } else {
-            self.identifier_constant(self.token_prev)
+            let slot = self.global_slot(self.token_prev);
+            if self.exporting {
+                self.exporting = false;
+                // SAFETY: The slot was just given out by the globals.
+                unsafe { self.globals.at_mut(slot) }.is_exported = true;
+            }
+            slot
         }
     }
 
@@ -391,7 +569,7 @@ impl<'src, 'vm> Parser<'src, 'vm> {
         let compiler = self.compiler_mut(0);
         // Skip this step for global scope.
         if compiler.scope_depth > 0 {
-            for local in compiler.locals.into_iter().rev() {
+            for local in compiler.locals.iter().rev() {
                 if local.depth != -1 && local.depth < compiler.scope_depth {
                     // Stop if we've gone through all initialized variable in the current scope.
                     break;
@@ -420,20 +598,33 @@ impl<'src, 'vm> Parser<'src, 'vm> {
             name: name.lexeme,
             depth: -1,
             is_captured: false,
+            is_const: false,
         };
         compiler.locals.push(local);
     }
 
     /// Put an identifier as a string object in the list of constant.
-    fn identifier_constant(&mut self, name: Token<'_>) -> u8 {
+    fn identifier_constant(&mut self, name: Token<'_>) -> usize {
         let s = String::from(name.lexeme.trim_matches('"'));
         let s = self.heap.intern(s);
-        let value = Value::Object(Object::String(s));
+        let value = Value::object(Object::String(s));
         self.make_constant(value)
     }
 
+    /// Get the slot of the global variable with the given name.
+    fn global_slot(&mut self, name: Token<'_>) -> usize {
+        let name = self.heap.intern(String::from(name.lexeme));
+        match self.globals.slot(self.namespace, name) {
+            Some(slot) => slot,
+            None => {
+                self.error_prev("Too many global variables.");
+                0
+            }
+        }
+    }
+
     /// Emit bytecodes for defining a global variable.
-    fn define_variable(&mut self, global_id: u8) {
+    fn define_variable(&mut self, global_id: usize) {
         // If we are in a local scope, we don't need to emit bytecodes for loading a variable's
         // value. We have already executed the code for the variable’s initializer (or the
         // implicit nil), and that value is sitting right on top of the stack as the only
@@ -442,8 +633,7 @@ impl<'src, 'vm> Parser<'src, 'vm> {
             // Mark declared variable as initialized
             self.mark_initialized();
         } else {
-            self.emit(Opcode::DefineGlobal);
-            self.emit_byte(global_id);
+            self.emit_with_index(Opcode::DefineGlobal, global_id);
         }
     }
 
@@ -475,7 +665,20 @@ impl<'src, 'vm> Parser<'src, 'vm> {
         let compiler = self.compiler_mut(0);
         // Do nothing if we are in the global scope.
         if compiler.scope_depth > 0 {
-            compiler.locals.top_mut(0).depth = compiler.scope_depth;
+            if let Some(local) = compiler.locals.last_mut() {
+                local.depth = compiler.scope_depth;
+            }
+        }
+    }
+
+    /// Mark the variable that was just declared as a constant.
+    fn mark_const(&mut self) {
+        if self.compiler(0).scope_depth > 0 {
+            if let Some(local) = self.compiler_mut(0).locals.last_mut() {
+                local.is_const = true;
+            }
+        } else {
+            self.const_globals.insert(self.token_prev.lexeme);
         }
     }
 
@@ -486,11 +689,16 @@ impl<'src, 'vm> Parser<'src, 'vm> {
     ///
     /// ```text
     /// stmt       --> block
+    ///              | breakStmt
+    ///              | continueStmt
     ///              | exprStmt
     ///              | forStmt
     ///              | ifStmt
     ///              | printStmt
     ///              | returnStmt
+    ///              | switchStmt
+    ///              | throwStmt
+    ///              | tryStmt
     ///              | whileStmt ;
     /// ```
     fn statement(&mut self) {
@@ -498,6 +706,10 @@ impl<'src, 'vm> Parser<'src, 'vm> {
             self.begin_scope();
             self.block();
             self.end_scope();
+        } else if self.advance_if(Kind::Break) {
+            self.break_statement();
+        } else if self.advance_if(Kind::Continue) {
+            self.continue_statement();
         } else if self.advance_if(Kind::Print) {
             self.print_statement();
         } else if self.advance_if(Kind::If) {
@@ -508,6 +720,12 @@ impl<'src, 'vm> Parser<'src, 'vm> {
             self.for_statement();
         } else if self.advance_if(Kind::Return) {
             self.return_statement();
+        } else if self.advance_if(Kind::Switch) {
+            self.switch_statement();
+        } else if self.advance_if(Kind::Throw) {
+            self.throw_statement();
+        } else if self.advance_if(Kind::Try) {
+            self.try_statement();
         } else {
             self.expression_statement();
         }
@@ -590,7 +808,8 @@ impl<'src, 'vm> Parser<'src, 'vm> {
         let jump_exit = self.emit_jump(Opcode::JumpIfFalse);
         // Pop the temporary value on stack created by the conditional expression.
         self.emit(Opcode::Pop);
-        // Loop body.
+        // Loop body. A 'continue' goes back to re-evaluate the condition.
+        self.begin_loop(loop_start);
         self.statement();
         // Emit instructions for jumping back to the start of the loop.
         self.emit_loop(loop_start);
@@ -598,6 +817,8 @@ impl<'src, 'vm> Parser<'src, 'vm> {
         self.patch_jump(jump_exit);
         // Pop the temporary value on stack created by the conditional expression.
         self.emit(Opcode::Pop);
+        // Any 'break' lands after the condition has been cleaned up.
+        self.end_loop();
     }
 
     /// Parse an if statement assuming that we've already consumed the 'for' keyword.
@@ -654,24 +875,287 @@ impl<'src, 'vm> Parser<'src, 'vm> {
             self.patch_jump(jump_to_body);
             Some(increment_start)
         };
-        // Loop's body.
+        // Loop's body. Jump back to the incrementer so its expression can be run after the body,
+        // or to the first instruction in the loop if there's no incrementer. A 'continue' follows
+        // the same path.
+        let continue_start = increment_start.unwrap_or(loop_start);
+        self.begin_loop(continue_start);
         self.statement();
-        match increment_start {
-            // Jump back to the first instruction in the loop if there's no incrementer.
-            None => self.emit_loop(loop_start),
-            // Jump back to the incrementer so its expression can be run after the body.
-            Some(offset) => self.emit_loop(offset),
-        }
+        self.emit_loop(continue_start);
         // Patch loop exit jump if we have an exit condition.
         if let Some(jump_exit) = jump_exit {
             self.patch_jump(jump_exit);
             // Clear out the result of the expression.
             self.emit(Opcode::Pop);
         }
+        // Any 'break' lands here, right before the loop's variables are discarded.
+        self.end_loop();
         // End the scope one we finish with the for loop
         self.end_scope();
     }
 
+    /// Parse a switch statement assuming that we've already consumed the 'switch' keyword.
+    ///
+    /// ## Grammar
+    ///
+    /// ```text
+    /// switchStmt --> "switch" "(" expr ")" "{" switchCase* defaultCase? "}" ;
+    /// switchCase --> "case" expr ":" decl* ;
+    /// defaultCase --> "default" ":" decl* ;
+    /// ```
+    ///
+    /// Cases are checked in order, and only the statements of the first matching case get run.
+    /// There's no fallthrough, each case jumps to the end of the statement once it's done.
+    fn switch_statement(&mut self) {
+        // The switch value is kept on the stack as a hidden local, so that each case can load it
+        // again for comparison. The local is named after a keyword so that it can't be
+        // referenced by the user's code.
+        self.begin_scope();
+        self.consume(Kind::LParen, "Expect '(' after 'switch'.");
+        self.expression();
+        self.consume(Kind::RParen, "Expect ')' after value.");
+        self.add_local(Token::synthetic("switch", self.token_prev));
+        self.mark_initialized();
+        let value_slot = self.compiler(0).locals.len() - 1;
+
+        self.consume(Kind::LBrace, "Expect '{' before switch cases.");
+        let mut jumps_to_end = Vec::new();
+        let mut has_default = false;
+        while !self.check_curr(Kind::RBrace) && !self.check_curr(Kind::Eof) {
+            if self.advance_if(Kind::Case) {
+                if has_default {
+                    self.error_prev("Can't have a case after the default case.");
+                }
+                // Compare the case's value against the switch value.
+                self.emit_with_slot(Opcode::GetLocal, value_slot);
+                self.expression();
+                self.consume(Kind::Colon, "Expect ':' after case value.");
+                self.emit(Opcode::EQ);
+                // Jump to the next case if the values are not equal.
+                let jump_next_case = self.emit_jump(Opcode::JumpIfFalse);
+                // Pop the temporary value on stack created by the comparison.
+                self.emit(Opcode::Pop);
+                self.switch_case_body();
+                // Jump out of the switch statement once the case is done.
+                jumps_to_end.push(self.emit_jump(Opcode::Jump));
+                self.patch_jump(jump_next_case);
+                // Pop the temporary value on stack created by the comparison.
+                self.emit(Opcode::Pop);
+            } else if self.advance_if(Kind::Default) {
+                if has_default {
+                    self.error_prev("Can't have more than one default case.");
+                }
+                has_default = true;
+                self.consume(Kind::Colon, "Expect ':' after 'default'.");
+                // The default case is the last one, so it can fall through to the end.
+                self.switch_case_body();
+            } else {
+                self.error_curr("Expect 'case' or 'default' in switch body.");
+                break;
+            }
+        }
+        self.consume(Kind::RBrace, "Expect '}' after switch cases.");
+        for jump in jumps_to_end {
+            self.patch_jump(jump);
+        }
+        // Discard the switch value.
+        self.end_scope();
+    }
+
+    /// Parse the declarations under a 'case' or 'default' label. Each case has its own scope.
+    fn switch_case_body(&mut self) {
+        self.begin_scope();
+        while !self.check_curr(Kind::Case)
+            && !self.check_curr(Kind::Default)
+            && !self.check_curr(Kind::RBrace)
+            && !self.check_curr(Kind::Eof)
+        {
+            self.declaration();
+        }
+        self.end_scope();
+    }
+
+    /// Parse a break statement assuming that we've consumed the `break` keyword.
+    ///
+    /// ## Grammar
+    ///
+    /// ```text
+    /// breakStmt  --> "break" ";" ;
+    /// ```
+    fn break_statement(&mut self) {
+        let Some((scope_depth, handlers, finallies)) = self
+            .compiler(0)
+            .loops
+            .last()
+            .map(|l| (l.scope_depth, l.handlers, l.finallies))
+        else {
+            self.error_prev("Can't use 'break' outside of a loop.");
+            return;
+        };
+        self.consume(Kind::Semicolon, "Expect ';' after 'break'.");
+        let installed = self.emit_finally_blocks(finallies);
+        // Discard the locals declared inside the loop body before leaving it. The compiler
+        // still tracks them because the scopes after this statement are not closed yet.
+        self.discard_locals(scope_depth);
+        self.discard_handlers(handlers);
+        self.compiler_mut(0).handlers = installed;
+        let jump = self.emit_jump(Opcode::Jump);
+        if let Some(innermost) = self.compiler_mut(0).loops.last_mut() {
+            innermost.breaks.push(jump);
+        }
+    }
+
+    /// Parse a continue statement assuming that we've consumed the `continue` keyword.
+    ///
+    /// ## Grammar
+    ///
+    /// ```text
+    /// continueStmt --> "continue" ";" ;
+    /// ```
+    fn continue_statement(&mut self) {
+        let Some((start, scope_depth, handlers, finallies)) = self
+            .compiler(0)
+            .loops
+            .last()
+            .map(|l| (l.start, l.scope_depth, l.handlers, l.finallies))
+        else {
+            self.error_prev("Can't use 'continue' outside of a loop.");
+            return;
+        };
+        self.consume(Kind::Semicolon, "Expect ';' after 'continue'.");
+        let installed = self.emit_finally_blocks(finallies);
+        self.discard_locals(scope_depth);
+        self.discard_handlers(handlers);
+        self.compiler_mut(0).handlers = installed;
+        self.emit_loop(start);
+    }
+
+    /// Parse a throw statement assuming that we've consumed the `throw` keyword.
+    ///
+    /// ## Grammar
+    ///
+    /// ```text
+    /// throwStmt  --> "throw" expr ";" ;
+    /// ```
+    fn throw_statement(&mut self) {
+        let keyword = self.token_prev;
+        self.expression();
+        self.consume(Kind::Semicolon, "Expect ';' after thrown value.");
+        self.emit_at(Opcode::Throw, keyword);
+    }
+
+    /// Parse a try statement assuming that we've consumed the `try` keyword. At least one of the
+    /// catch and finally blocks must be given.
+    ///
+    /// ## Grammar
+    ///
+    /// ```text
+    /// tryStmt    --> "try" block ( "catch" "(" IDENT ")" block )? ( "finally" block )? ;
+    /// ```
+    ///
+    /// ## Exception handlers
+    ///
+    /// Each try block installs a handler that the virtual machine jumps to when a value is thrown.
+    /// The handler restores the stack to the height it had when the handler was installed, then
+    /// pushes the thrown value. A catch block is protected by another handler so the finally block
+    /// still runs when the catch block throws.
+    ///
+    /// The finally block starts with 2 values on the stack: the thrown value and `true` if it was
+    /// reached by an exception, or `nil` and `false` otherwise. Opcode::EndFinally pops both and
+    /// rethrows the value if needed.
+    ///
+    /// When the other blocks are left through 'break', 'continue', or 'return', the finally block
+    /// is compiled once more right before the jump or the return. The finally block comes after
+    /// those statements, so its tokens are found ahead of time when the try statement starts.
+    fn try_statement(&mut self) {
+        let finally = self.find_finally();
+        let has_finally = finally.is_some();
+        self.compiler_mut(0).finallies.extend(finally);
+        let try_handler = self.emit_try();
+        self.consume(Kind::LBrace, "Expect '{' after 'try'.");
+        self.begin_scope();
+        self.block();
+        self.end_scope();
+        self.emit_pop_handler();
+        let mut exits = vec![self.emit_jump(Opcode::Jump)];
+
+        // The thrown value is on top of the stack when we get here.
+        self.patch_jump(try_handler);
+        let has_catch = self.advance_if(Kind::Catch);
+        if has_catch {
+            // Track the thrown value as an unnamed local.
+            self.begin_scope();
+            let slot = self.compiler(0).locals.len();
+            self.add_local(Token::synthetic("", self.token_prev));
+            self.mark_initialized();
+            let catch_handler = self.emit_try();
+
+            // The variable holds a copy of the thrown value. It's put above the stack height of
+            // the catch block's handler, so it's closed over correctly when the handler runs.
+            self.consume(Kind::LParen, "Expect '(' after 'catch'.");
+            self.consume(Kind::Ident, "Expect exception variable name.");
+            self.begin_scope();
+            self.emit_variable(Opcode::GetLocal, slot);
+            self.declare_variable();
+            self.mark_initialized();
+            self.consume(Kind::RParen, "Expect ')' after exception variable.");
+            self.consume(Kind::LBrace, "Expect '{' before catch body.");
+            self.block();
+            self.end_scope();
+            self.emit_pop_handler();
+            self.end_scope();
+            exits.push(self.emit_jump(Opcode::Jump));
+
+            // Replace the unnamed local with the value thrown from the catch block.
+            self.patch_jump(catch_handler);
+            self.emit_variable(Opcode::SetLocal, slot);
+            self.emit(Opcode::Pop);
+        }
+
+        // Leaving the finally block doesn't run it again.
+        if has_finally {
+            self.compiler_mut(0).finallies.pop();
+        }
+        if self.advance_if(Kind::Finally) {
+            // Run the finally block and rethrow the thrown value.
+            self.emit(Opcode::True);
+            let throw_exit = self.emit_jump(Opcode::Jump);
+            // Run the finally block without any thrown value.
+            for exit in exits {
+                self.patch_jump(exit);
+            }
+            self.emit(Opcode::Nil);
+            self.emit(Opcode::False);
+            self.patch_jump(throw_exit);
+
+            // Track the 2 values on the stack as unnamed locals, so the locals declared in the
+            // finally block get the correct slots.
+            self.begin_scope();
+            for _ in 0..2 {
+                self.add_local(Token::synthetic("", self.token_prev));
+                self.mark_initialized();
+            }
+            self.consume(Kind::LBrace, "Expect '{' before finally body.");
+            self.begin_scope();
+            self.block();
+            self.end_scope();
+            // The unnamed locals are popped by Opcode::EndFinally.
+            let compiler = self.compiler_mut(0);
+            compiler.scope_depth -= 1;
+            compiler.locals.truncate(compiler.locals.len() - 2);
+            self.emit(Opcode::EndFinally);
+        } else {
+            if !has_catch {
+                self.error_curr("Expect 'catch' or 'finally' after try block.");
+            }
+            // Rethrow the value thrown from the catch block.
+            self.emit(Opcode::Throw);
+            for exit in exits {
+                self.patch_jump(exit);
+            }
+        }
+    }
+
     /// Parse a return statement assuming that we've consumed the `return` keyword.
     ///
     /// ## Grammar
@@ -686,6 +1170,8 @@ impl<'src, 'vm> Parser<'src, 'vm> {
         }
         // Empty return put nil as the value of the function.
         if self.advance_if(Kind::Semicolon) {
+            let installed = self.emit_finally_blocks(0);
+            self.compiler_mut(0).handlers = installed;
             self.emit_return();
         } else {
             if self.compiler(0).fun_type == FunctionType::Initializer {
@@ -694,6 +1180,15 @@ impl<'src, 'vm> Parser<'src, 'vm> {
             // Returned the value of an expression.
             self.expression();
             self.consume(Kind::Semicolon, "Expect ';' after return value.");
+            if !self.compiler(0).finallies.is_empty() {
+                // Track the value as an unnamed local while the finally blocks run.
+                self.add_local(Token::synthetic("", self.token_prev));
+                self.mark_initialized();
+                let installed = self.emit_finally_blocks(0);
+                let compiler = self.compiler_mut(0);
+                compiler.handlers = installed;
+                compiler.locals.pop();
+            }
             self.emit(Opcode::Ret);
         }
     }
@@ -739,7 +1234,7 @@ impl<'src, 'vm> Parser<'src, 'vm> {
     /// factor     --> unary ( ( "/" | "*" ) unary )* ;
     /// unary      --> ( "!" | "-" ) unary
     ///              | call ;
-    /// call       --> primary ( "(" args? ")" | "." IDENT )* ;
+    /// call       --> primary ( "(" args? ")" | "." IDENT | "[" expr "]" )* ;
     /// args       --> expr ( "," expr )* ;
     /// primary    --> IDENT | NUMBER | STRING
     ///              | "this" | "super" "." IDENT
@@ -766,12 +1261,17 @@ impl<'src, 'vm> Parser<'src, 'vm> {
     /// Parse a prefix expression if the consumed token can be used in a prefix operation.
     fn prefix_rule(&mut self, can_assign: bool) {
         match self.token_prev.kind {
+            Kind::LParen if self.check_arrow() => self.arrow(),
             Kind::LParen => self.grouping(),
+            Kind::Fun => self.lambda(),
+            Kind::LBracket => self.list(),
+            Kind::LBrace => self.map(),
             Kind::Minus | Kind::Bang => self.unary(),
             Kind::This => self.this(),
             Kind::Super => self.super_(),
             Kind::Ident => self.variable(can_assign),
             Kind::String => self.string(),
+            Kind::Interpolation => self.interpolation(),
             Kind::Number => self.number(),
             Kind::True | Kind::False | Kind::Nil => self.literal(),
             _ => self.error_prev("Expect expression."),
@@ -783,6 +1283,7 @@ impl<'src, 'vm> Parser<'src, 'vm> {
         match self.token_prev.kind {
             Kind::LParen => self.call(),
             Kind::Dot => self.dot(can_assign),
+            Kind::LBracket => self.index(can_assign),
             Kind::Or => self.or(),
             Kind::And => self.and(),
             Kind::Minus
@@ -814,6 +1315,117 @@ impl<'src, 'vm> Parser<'src, 'vm> {
         self.consume(Kind::RParen, "Expect ')' after expression.");
     }
 
+    /// Parse an anonymous function assuming that the 'fun' token has been consumed.
+    ///
+    /// ## Grammar
+    ///
+    /// ```text
+    /// lambda     --> "fun" "(" params? ")" block ;
+    /// ```
+    fn lambda(&mut self) {
+        self.begin_function("lambda", FunctionType::Function);
+        self.consume(Kind::LParen, "Expect '(' after 'fun'.");
+        self.parameters();
+        self.consume(Kind::LBrace, "Expect '{' before function body.");
+        self.block();
+        self.end_function();
+    }
+
+    /// Parse an arrow function assuming that the '(' token has been consumed. The body can either
+    /// be a block or a single expression whose value is returned.
+    ///
+    /// ## Grammar
+    ///
+    /// ```text
+    /// lambda     --> "(" params? ")" "=>" ( block | expr ) ;
+    /// ```
+    fn arrow(&mut self) {
+        self.begin_function("lambda", FunctionType::Function);
+        self.parameters();
+        self.consume(Kind::Arrow, "Expect '=>' after parameters.");
+        if self.advance_if(Kind::LBrace) {
+            self.block();
+        } else {
+            self.expression();
+            self.emit(Opcode::Ret);
+        }
+        self.end_function();
+    }
+
+    /// Check whether the consumed '(' token starts the parameters of an arrow function by looking
+    /// ahead for the "=>" token after the closing ')'.
+    fn check_arrow(&self) -> bool {
+        let mut scanner = self.scanner.clone();
+        let mut next = || scanner.scan().map_or(Kind::Eof, |token| token.kind);
+        if self.token_curr.kind == Kind::Ident {
+            loop {
+                match next() {
+                    Kind::RParen => break,
+                    Kind::Comma if next() == Kind::Ident => {}
+                    _ => return false,
+                }
+            }
+        } else if self.token_curr.kind != Kind::RParen {
+            return false;
+        }
+        next() == Kind::Arrow
+    }
+
+    /// Parse a list literal assuming that the '[' token has been consumed.
+    ///
+    /// ## Grammar
+    ///
+    /// ```text
+    /// list       --> "[" ( expr ( "," expr )* )? "]" ;
+    /// ```
+    fn list(&mut self) {
+        let mut count = 0;
+        if !self.check_curr(Kind::RBracket) {
+            loop {
+                self.expression();
+                if count == MAX_LIST_ITEMS {
+                    self.error_prev("Can't have more than 65535 items in a list literal.");
+                    break;
+                }
+                count += 1;
+                if !self.advance_if(Kind::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(Kind::RBracket, "Expect ']' after list items.");
+        self.emit_with_slot(Opcode::BuildList, count);
+    }
+
+    /// Parse a map literal assuming that the '{' token has been consumed.
+    ///
+    /// ## Grammar
+    ///
+    /// ```text
+    /// map        --> "{" ( entry ( "," entry )* )? "}" ;
+    /// entry      --> expr ":" expr ;
+    /// ```
+    fn map(&mut self) {
+        let mut count = 0;
+        if !self.check_curr(Kind::RBrace) {
+            loop {
+                self.expression();
+                self.consume(Kind::Colon, "Expect ':' after map key.");
+                self.expression();
+                if count == MAX_MAP_ENTRIES {
+                    self.error_prev("Can't have more than 65535 entries in a map literal.");
+                    break;
+                }
+                count += 1;
+                if !self.advance_if(Kind::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(Kind::RBrace, "Expect '}' after map entries.");
+        self.emit_with_slot(Opcode::BuildMap, count);
+    }
+
     /// Parse a unary operation assuming that the operator token has been consumed.
     ///
     /// ## Grammar
@@ -823,11 +1435,11 @@ impl<'src, 'vm> Parser<'src, 'vm> {
     ///              | call ;
     /// ```
     fn unary(&mut self) {
-        let token_kind = self.token_prev.kind;
+        let operator = self.token_prev;
         self.parse_precedence(Precedence::Unary);
-        match token_kind {
-            Kind::Bang => self.emit(Opcode::Not),
-            Kind::Minus => self.emit(Opcode::Neg),
+        match operator.kind {
+            Kind::Bang => self.emit_at(Opcode::Not, operator),
+            Kind::Minus => self.emit_at(Opcode::Neg, operator),
             _ => unreachable!(),
         }
     }
@@ -844,21 +1456,23 @@ impl<'src, 'vm> Parser<'src, 'vm> {
     /// factor     --> unary ( ( "/" | "*" ) unary )* ;
     /// ```
     fn binary(&mut self) {
-        let token_kind = self.token_prev.kind;
-        self.parse_precedence(Precedence::of(token_kind).next());
-        match token_kind {
-            Kind::BangEqual => self.emit(Opcode::NE),
-            Kind::EqualEqual => self.emit(Opcode::EQ),
-            Kind::Greater => self.emit(Opcode::GT),
-            Kind::GreaterEqual => self.emit(Opcode::GE),
-            Kind::Less => self.emit(Opcode::LT),
-            Kind::LessEqual => self.emit(Opcode::LE),
-            Kind::Plus => self.emit(Opcode::Add),
-            Kind::Minus => self.emit(Opcode::Sub),
-            Kind::Star => self.emit(Opcode::Mul),
-            Kind::Slash => self.emit(Opcode::Div),
+        let operator = self.token_prev;
+        self.parse_precedence(Precedence::of(operator.kind).next());
+        let opcode = match operator.kind {
+            Kind::BangEqual => Opcode::NE,
+            Kind::EqualEqual => Opcode::EQ,
+            Kind::Greater => Opcode::GT,
+            Kind::GreaterEqual => Opcode::GE,
+            Kind::Less => Opcode::LT,
+            Kind::LessEqual => Opcode::LE,
+            Kind::Plus => Opcode::Add,
+            Kind::Minus => Opcode::Sub,
+            Kind::Star => Opcode::Mul,
+            Kind::Slash => Opcode::Div,
             _ => unreachable!(),
-        }
+        };
+        // Errors from the operation are reported at the operator rather than the RHS.
+        self.emit_at(opcode, operator);
     }
 
     /// Parse the 'or' operator with short-circuiting.
@@ -902,11 +1516,12 @@ impl<'src, 'vm> Parser<'src, 'vm> {
     /// ## Grammar
     ///
     /// ```text
-    /// call       --> primary ( "(" args? ")" | "." IDENT )* ;
+    /// call       --> primary ( "(" args? ")" | "." IDENT | "[" expr "]" )* ;
     /// ```
     fn call(&mut self) {
+        let paren = self.token_prev;
         let argc = self.argument_list();
-        self.emit(Opcode::Call);
+        self.emit_at(Opcode::Call, paren);
         self.emit_byte(argc);
     }
 
@@ -916,18 +1531,36 @@ impl<'src, 'vm> Parser<'src, 'vm> {
 
         if can_assign && self.advance_if(Kind::Equal) {
             self.expression();
-            self.emit(Opcode::SetProperty);
-            self.emit_byte(name);
+            self.emit_with_index(Opcode::SetProperty, name);
+            self.emit_cache();
         } else if self.advance_if(Kind::LParen) {
             // If we found an open parenthesis after a dotted identifier,
             // it's must be a method call.
             let argc = self.argument_list();
-            self.emit(Opcode::Invoke);
-            self.emit_byte(name);
+            self.emit_with_index(Opcode::Invoke, name);
             self.emit_byte(argc);
+            self.emit_cache();
         } else {
-            self.emit(Opcode::GetProperty);
-            self.emit_byte(name);
+            self.emit_with_index(Opcode::GetProperty, name);
+            self.emit_cache();
+        }
+    }
+
+    /// Parse an index expression when the indexed expression and '[' have been consumed.
+    ///
+    /// ## Grammar
+    ///
+    /// ```text
+    /// call       --> primary ( "(" args? ")" | "." IDENT | "[" expr "]" )* ;
+    /// ```
+    fn index(&mut self, can_assign: bool) {
+        self.expression();
+        self.consume(Kind::RBracket, "Expect ']' after index.");
+        if can_assign && self.advance_if(Kind::Equal) {
+            self.expression();
+            self.emit(Opcode::SetIndex);
+        } else {
+            self.emit(Opcode::GetIndex);
         }
     }
 
@@ -974,32 +1607,19 @@ impl<'src, 'vm> Parser<'src, 'vm> {
         let name = self.identifier_constant(self.token_prev);
 
         // Load 'this' value onto the stack.
-        self.named_variable(
-            Token {
-                kind: Kind::Ident,
-                line: self.token_curr.line,
-                lexeme: "this",
-            },
-            false,
-        );
-        let superclass_token = Token {
-            kind: Kind::Ident,
-            line: self.token_curr.line,
-            lexeme: "super",
-        };
+        self.named_variable(Token::synthetic("this", self.token_curr), false);
+        let superclass_token = Token::synthetic("super", self.token_curr);
         if self.advance_if(Kind::LParen) {
             // Optimization so that we don't have to create ObjBoundMethod if the super access is
             // called immediately.
             let argc = self.argument_list();
             self.named_variable(superclass_token, false);
-            self.emit(Opcode::SuperInvoke);
-            self.emit_byte(name);
+            self.emit_with_index(Opcode::SuperInvoke, name);
             self.emit_byte(argc);
         } else {
             // Create ObjBoundMethod that can be assigned to some identitier.
             self.named_variable(superclass_token, false);
-            self.emit(Opcode::GetSuper);
-            self.emit_byte(name);
+            self.emit_with_index(Opcode::GetSuper, name);
         }
     }
 
@@ -1029,38 +1649,63 @@ impl<'src, 'vm> Parser<'src, 'vm> {
                     .map(|upval| (upval, Opcode::GetUpvalue, Opcode::SetUpvalue))
             })
             .unwrap_or_else(|| {
-                // Find value in globals table.
-                let id = self.identifier_constant(name);
-                (id, Opcode::GetGlobal, Opcode::SetGlobal)
+                // Find value in globals slots.
+                let slot = self.global_slot(name);
+                (slot, Opcode::GetGlobal, Opcode::SetGlobal)
             });
 
         if can_assign && self.advance_if(Kind::Equal) {
             // The LHS can be used as an assignment target.
+            if self.is_const(op_set, arg, name) {
+                self.error_prev("Can't assign to a const variable.");
+            }
             self.expression();
-            self.emit(op_set);
-            self.emit_byte(arg);
+            self.emit_variable(op_set, arg);
         } else {
             // The LHS can't be used as an assignment target.
-            self.emit(op_get);
-            self.emit_byte(arg);
+            self.emit_variable(op_get, arg);
+        }
+    }
+
+    /// Check whether the variable that is set by the given instruction is a constant. A global
+    /// is known to be a constant if it's declared in this script or it has been defined by a
+    /// previous script. Otherwise, the assignment is only rejected at runtime.
+    fn is_const(&self, op_set: Opcode, arg: usize, name: Token<'_>) -> bool {
+        match op_set {
+            Opcode::SetLocal => self.compiler(0).locals[arg].is_const,
+            Opcode::SetUpvalue => self.compiler(0).upvalues[arg].is_const,
+            _ => {
+                // SAFETY: The slot was given out by the globals when resolving the variable.
+                let global = unsafe { self.globals.at(arg) };
+                global.is_const || self.const_globals.contains(name.lexeme)
+            }
+        }
+    }
+
+    /// Emit an instruction for accessing a variable. Global variables are accessed through their
+    /// global slot, local variables and upvalues through their stack or upvalue slot.
+    fn emit_variable(&mut self, opcode: Opcode, arg: usize) {
+        match opcode {
+            Opcode::GetGlobal | Opcode::SetGlobal => self.emit_with_index(opcode, arg),
+            _ => self.emit_with_slot(opcode, arg),
         }
     }
 
     /// Find the stack index the hold the local variable with the given name.
-    fn resolve_local(&mut self, name: Token<'_>, height: usize) -> Option<u8> {
+    fn resolve_local(&mut self, name: Token<'_>, height: usize) -> Option<usize> {
         if height >= self.compilers.len() {
             // There's no compiler at this height.
             return None;
         }
         // Walk up from low scope to high scope to find a local with the given name.
         let compiler = self.compiler(height);
-        for (id, local) in compiler.locals.into_iter().enumerate().rev() {
+        for (id, local) in compiler.locals.iter().enumerate().rev() {
             if local.name == name.lexeme {
                 if local.depth == -1 {
                     self.error_prev("Can't read local variable in its own initializer.");
                 }
                 // Found a valid value for the variable.
-                return Some(id as u8);
+                return Some(id);
             }
         }
         None
@@ -1070,7 +1715,7 @@ impl<'src, 'vm> Parser<'src, 'vm> {
     ///
     /// An upvalue refers to a local variable in an enclosing function. Every closure maintains
     /// an array of upvalues, one for each surrounding local variable that the closure uses.
-    fn resolve_upvalue(&mut self, name: Token<'_>, height: usize) -> Option<u8> {
+    fn resolve_upvalue(&mut self, name: Token<'_>, height: usize) -> Option<usize> {
         if height >= self.compilers.len() {
             // There's no compiler at this height
             return None;
@@ -1079,31 +1724,35 @@ impl<'src, 'vm> Parser<'src, 'vm> {
         if let Some(local) = self.resolve_local(name, height + 1) {
             // Mark the variable in the enclosing function as captured so we know to emit the
             // correct opcode for hoisting up the upvalue.
-            unsafe {
-                self.compiler_mut(height + 1)
-                    .locals
-                    .at_mut(local as usize)
-                    .is_captured = true;
-            }
-            return Some(self.add_upvalue(height, local, true));
+            let enclosing = self.compiler_mut(height + 1);
+            enclosing.locals[local].is_captured = true;
+            let is_const = enclosing.locals[local].is_const;
+            return Some(self.add_upvalue(height, local, true, is_const));
         }
         // Find a matching upvalue in the enclosing function. An upvalue is like a node in a linked
         // list where its can references:
         // 1. Local variable of the immediately enclosing function.
         // 2. Upvalues of enclosing functions that are not the immediately enclosing one..
         if let Some(upvalue) = self.resolve_upvalue(name, height + 1) {
-            return Some(self.add_upvalue(height, upvalue, false));
+            let is_const = self.compiler(height + 1).upvalues[upvalue].is_const;
+            return Some(self.add_upvalue(height, upvalue, false, is_const));
         }
         None
     }
 
     /// An an upvalue to the chunk. If we reference a value that has been captured, the index of
     /// corresponding upvalue is returned instead of adding a new upvalue.
-    fn add_upvalue(&mut self, height: usize, index: u8, is_local: bool) -> u8 {
+    fn add_upvalue(
+        &mut self,
+        height: usize,
+        index: usize,
+        is_local: bool,
+        is_const: bool,
+    ) -> usize {
         // Find an upvalue that references the same index.
-        for (upval_index, upval) in self.compiler(height).upvalues.into_iter().enumerate() {
+        for (upval_index, upval) in self.compiler(height).upvalues.iter().enumerate() {
             if upval.index == index && upval.is_local == is_local {
-                return upval_index as u8;
+                return upval_index;
             }
         }
         let compiler = self.compiler_mut(height);
@@ -1113,9 +1762,13 @@ impl<'src, 'vm> Parser<'src, 'vm> {
             return 0;
         }
         // Add the upvalue.
-        let upvalue = Upvalue { is_local, index };
+        let upvalue = Upvalue {
+            is_local,
+            index,
+            is_const,
+        };
         compiler.upvalues.push(upvalue);
-        upvalue_count as u8
+        upvalue_count
     }
 
     /// Create a string literal and emit bytecodes to load it value.
@@ -1129,12 +1782,66 @@ impl<'src, 'vm> Parser<'src, 'vm> {
     ///              | "(" expr ")" ;
     /// ```
     fn string(&mut self) {
-        let s = String::from(self.token_prev.lexeme.trim_matches('"'));
+        self.emit_string_segment();
+    }
+
+    /// Create a string literal containing interpolated expressions and emit bytecodes to load it
+    /// value. The segments and the string representations of the expressions are concatenated.
+    ///
+    /// ## Grammar
+    ///
+    /// ```text
+    /// interp     --> ( INTERPOLATION expr )+ STRING ;
+    /// ```
+    fn interpolation(&mut self) {
+        // Empty segments are skipped, so we track whether there's anything to concatenate with.
+        let mut has_prefix = false;
+        loop {
+            if !Self::segment(self.token_prev).is_empty() {
+                self.emit_string_segment();
+                if has_prefix {
+                    self.emit(Opcode::Add);
+                }
+                has_prefix = true;
+            }
+            self.expression();
+            self.emit(Opcode::Stringify);
+            if has_prefix {
+                self.emit(Opcode::Add);
+            }
+            has_prefix = true;
+            if !self.advance_if(Kind::Interpolation) {
+                break;
+            }
+        }
+        if !self.advance_if(Kind::String) {
+            self.error_curr("Expect '}' after interpolated expression.");
+            return;
+        }
+        if !Self::segment(self.token_prev).is_empty() {
+            self.emit_string_segment();
+            self.emit(Opcode::Add);
+        }
+    }
+
+    /// Emit bytecodes for loading the content of the previous string token.
+    fn emit_string_segment(&mut self) {
+        let s = String::from(Self::segment(self.token_prev));
         let s = self.heap.intern(s);
-        let value = Value::Object(Object::String(s));
+        let value = Value::object(Object::String(s));
         self.emit_constant(value);
     }
 
+    /// Get the content of a string token without the delimiters that surround it. A string token
+    /// starts with either '"' or '}', and ends with either '"' or '${'.
+    fn segment(token: Token<'src>) -> &'src str {
+        let lexeme = token.lexeme;
+        match token.kind {
+            Kind::Interpolation => &lexeme[1..lexeme.len() - 2],
+            _ => &lexeme[1..lexeme.len() - 1],
+        }
+    }
+
     /// Create a number literal and emit bytecodes to load it value.
     ///
     /// ## Grammar
@@ -1146,7 +1853,7 @@ impl<'src, 'vm> Parser<'src, 'vm> {
     ///              | "(" expr ")" ;
     /// ```
     fn number(&mut self) {
-        let value = Value::Number(self.token_prev.lexeme.parse().expect("Expect digits."));
+        let value = Value::number(self.token_prev.lexeme.parse().expect("Expect digits."));
         self.emit_constant(value);
     }
 
@@ -1171,14 +1878,25 @@ impl<'src, 'vm> Parser<'src, 'vm> {
 
     /// Write the byte representing the given opcode into the current compiling chunk.
     fn emit(&mut self, opcode: Opcode) {
-        let line = self.token_prev.line;
-        self.compiler_mut(0).fun.chunk.write(opcode, line);
+        self.emit_at(opcode, self.token_prev);
+    }
+
+    /// Write the byte representing the given opcode into the current compiling chunk, and
+    /// attribute it to the given token instead of the previous one.
+    fn emit_at(&mut self, opcode: Opcode, token: Token<'_>) {
+        self.compiler_mut(0).fun.chunk.write(opcode, token.span());
     }
 
-    /// Write the byte into the current compiling chunk.
+    /// Write the operand byte into the current compiling chunk.
     fn emit_byte(&mut self, byte: u8) {
-        let line = self.token_prev.line;
-        self.compiler_mut(0).fun.chunk.write_byte(byte, line);
+        self.compiler_mut(0).fun.chunk.write_byte(byte);
+    }
+
+    /// Add an inline cache for the instruction that was just emitted, and emit its 16-bit index.
+    fn emit_cache(&mut self) {
+        let index = self.compiler_mut(0).fun.chunk.add_cache();
+        self.emit_byte(((index >> 8) & 0xff) as u8);
+        self.emit_byte((index & 0xff) as u8);
     }
 
     /// Emit a jump instruction along with a 16-byte placeholder for the offset.
@@ -1189,6 +1907,19 @@ impl<'src, 'vm> Parser<'src, 'vm> {
         self.compiler(0).fun.chunk.instructions.len() - 2
     }
 
+    /// Emit an instruction for installing an exception handler along with a 16-bit placeholder for
+    /// the offset to the handler's code.
+    fn emit_try(&mut self) -> usize {
+        self.compiler_mut(0).handlers += 1;
+        self.emit_jump(Opcode::Try)
+    }
+
+    /// Emit an instruction for removing the innermost exception handler.
+    fn emit_pop_handler(&mut self) {
+        self.compiler_mut(0).handlers -= 1;
+        self.emit(Opcode::PopHandler);
+    }
+
     /// Emit a loop instruction along with a 16-byte placeholder for the offset.
     fn emit_loop(&mut self, start: usize) {
         self.emit(Opcode::Loop);
@@ -1221,8 +1952,35 @@ impl<'src, 'vm> Parser<'src, 'vm> {
     /// the currently compiling chunk.
     fn emit_constant(&mut self, value: Value) {
         let constant_id = self.make_constant(value);
-        self.emit(Opcode::Const);
-        self.emit_byte(constant_id);
+        self.emit_with_index(Opcode::Const, constant_id);
+    }
+
+    /// Emit an instruction whose operand is the slot of a local variable or an upvalue, or a count
+    /// of values. The long variant of the instruction is used when the operand doesn't fit in a
+    /// single byte.
+    fn emit_with_slot(&mut self, opcode: Opcode, slot: usize) {
+        if slot <= u8::MAX as usize {
+            self.emit(opcode);
+            self.emit_byte(slot as u8);
+        } else {
+            self.emit(opcode.long());
+            self.emit_byte(((slot >> 8) & 0xff) as u8);
+            self.emit_byte((slot & 0xff) as u8);
+        }
+    }
+
+    /// Emit an instruction whose operand is an index into the constant table or the global slots.
+    /// The long variant of the instruction is used when the index doesn't fit in a single byte.
+    fn emit_with_index(&mut self, opcode: Opcode, index: usize) {
+        if index <= u8::MAX as usize {
+            self.emit(opcode);
+            self.emit_byte(index as u8);
+        } else {
+            self.emit(opcode.long());
+            self.emit_byte(((index >> 16) & 0xff) as u8);
+            self.emit_byte(((index >> 8) & 0xff) as u8);
+            self.emit_byte((index & 0xff) as u8);
+        }
     }
 
     fn patch_jump(&mut self, offset: usize) {
@@ -1239,12 +1997,12 @@ impl<'src, 'vm> Parser<'src, 'vm> {
     }
 
     /// Write a constant to the currently compiling chunk.
-    fn make_constant(&mut self, value: Value) -> u8 {
+    fn make_constant(&mut self, value: Value) -> usize {
         if self.compiler_mut(0).fun.chunk.constants.len() >= MAX_CONSTANTS {
             self.error_prev("Too many constants in one chunk.");
             return 0;
         }
-        self.compiler_mut(0).fun.chunk.write_constant(value) as u8
+        self.compiler_mut(0).fun.chunk.write_constant(value)
     }
 
     /// Start a new scope.
@@ -1257,10 +2015,22 @@ impl<'src, 'vm> Parser<'src, 'vm> {
     fn end_scope(&mut self) {
         // Update the current scope.
         self.compiler_mut(0).scope_depth -= 1;
-        while self.compiler(0).locals.len() > 0 {
-            let local = self.compiler(0).locals.top(0);
-            // End once we reach the current scope.
-            if local.depth <= self.compiler(0).scope_depth {
+        let scope_depth = self.compiler(0).scope_depth;
+        let discarded = self.discard_locals(scope_depth);
+        let locals = &mut self.compiler_mut(0).locals;
+        locals.truncate(locals.len() - discarded);
+    }
+
+    /// Emit bytecodes for removing all local variables declared deeper than the given scope depth
+    /// from the stack. The variables are still tracked by the compiler, and the number of
+    /// discarded variables is returned.
+    fn discard_locals(&mut self, scope_depth: isize) -> usize {
+        let mut discarded = 0;
+        while discarded < self.compiler(0).locals.len() {
+            let locals = &self.compiler(0).locals;
+            let local = &locals[locals.len() - 1 - discarded];
+            // End once we reach the given scope.
+            if local.depth <= scope_depth {
                 break;
             }
             // Variables at the scope bellow get popped out of the stack. If the variable is
@@ -1270,28 +2040,141 @@ impl<'src, 'vm> Parser<'src, 'vm> {
             } else {
                 self.emit(Opcode::Pop);
             }
-            self.compiler_mut(0).locals.pop();
+            discarded += 1;
+        }
+        discarded
+    }
+
+    /// Emit bytecodes for removing all exception handlers installed after the given number of
+    /// handlers was reached.
+    fn discard_handlers(&mut self, handlers: usize) {
+        for _ in handlers..self.compiler(0).handlers {
+            self.emit(Opcode::PopHandler);
+        }
+    }
+
+    /// Emit bytecodes for running the finally blocks that are left by a 'break', 'continue', or
+    /// 'return' statement, from the innermost one to the outermost one. Only the finally blocks
+    /// found after the given number of finally blocks are run. The exception handlers of each try
+    /// statement are removed before its finally block runs, so the compiler counts fewer handlers
+    /// afterwards. The number of handlers before this call is returned so it can be restored.
+    fn emit_finally_blocks(&mut self, finallies: usize) -> usize {
+        let compiler = self.compiler(0);
+        let installed = compiler.handlers;
+        if compiler.finallies.len() <= finallies {
+            return installed;
+        }
+        let names: Vec<_> = compiler.locals.iter().map(|local| local.name).collect();
+        let scanner = self.scanner.clone();
+        let (token_prev, token_curr) = (self.token_prev, self.token_curr);
+        let (had_error, panicking) = (self.had_error, self.panicking);
+        let diagnostics = self.diagnostics.len();
+
+        let mut left = Vec::new();
+        while self.compiler(0).finallies.len() > finallies {
+            let Some(finally) = self.compiler_mut(0).finallies.pop() else {
+                break;
+            };
+            // A value thrown from the finally block isn't caught by its own try statement.
+            self.discard_handlers(finally.handlers);
+            let compiler = self.compiler_mut(0);
+            compiler.handlers = finally.handlers;
+            // The variables declared inside the try statement can't be seen from the finally
+            // block, hide them without changing the slots of the other variables.
+            for local in &mut compiler.locals[finally.locals..] {
+                local.name = "";
+            }
+            self.scanner = finally.scanner.clone();
+            self.token_prev = finally.keyword;
+            self.token_curr = finally.brace;
+            self.consume(Kind::LBrace, "Expect '{' before finally body.");
+            self.begin_scope();
+            self.block();
+            self.end_scope();
+            left.push(finally);
+        }
+
+        // The problems in the finally block are reported when it's compiled in place.
+        self.scanner = scanner;
+        (self.token_prev, self.token_curr) = (token_prev, token_curr);
+        (self.had_error, self.panicking) = (had_error, panicking);
+        self.diagnostics.truncate(diagnostics);
+        let compiler = self.compiler_mut(0);
+        for (local, name) in compiler.locals.iter_mut().zip(names) {
+            local.name = name;
+        }
+        compiler.finallies.extend(left.into_iter().rev());
+        installed
+    }
+
+    /// Find the finally block of the try statement whose try block starts at the current token,
+    /// without consuming any token.
+    fn find_finally(&self) -> Option<Finally<'src>> {
+        let mut scanner = self.scanner.clone();
+        let mut token = skip_block(&mut scanner, self.token_curr)?;
+        if token.kind == Kind::Catch {
+            while token.kind != Kind::LBrace {
+                if token.kind == Kind::Eof {
+                    return None;
+                }
+                token = next_token(&mut scanner);
+            }
+            token = skip_block(&mut scanner, token)?;
+        }
+        if token.kind != Kind::Finally {
+            return None;
+        }
+        let brace = next_token(&mut scanner);
+        if brace.kind != Kind::LBrace {
+            return None;
+        }
+        let compiler = self.compiler(0);
+        Some(Finally {
+            scanner,
+            keyword: token,
+            brace,
+            locals: compiler.locals.len(),
+            handlers: compiler.handlers,
+        })
+    }
+
+    /// Start tracking a loop whose body is about to be compiled. The given offset is where a
+    /// 'continue' statement jumps back to.
+    fn begin_loop(&mut self, start: usize) {
+        let compiler = self.compiler_mut(0);
+        compiler.loops.push(Loop {
+            start,
+            scope_depth: compiler.scope_depth,
+            handlers: compiler.handlers,
+            finallies: compiler.finallies.len(),
+            breaks: Vec::new(),
+        });
+    }
+
+    /// Stop tracking the innermost loop and make all of its 'break' statements jump to the
+    /// current position.
+    fn end_loop(&mut self) {
+        if let Some(innermost) = self.compiler_mut(0).loops.pop() {
+            for jump in innermost.breaks {
+                self.patch_jump(jump);
+            }
         }
     }
 
     fn class_compiler(&self, height: usize) -> &ClassCompiler {
-        let index = self.classes.len() - height - 1;
-        unsafe { self.classes.at(index) }
+        self.classes.top(height)
     }
 
     fn class_compiler_mut(&mut self, height: usize) -> &mut ClassCompiler {
-        let index = self.classes.len() - height - 1;
-        unsafe { self.classes.at_mut(index) }
+        self.classes.top_mut(height)
     }
 
     fn compiler(&self, height: usize) -> &Compiler<'src> {
-        let index = self.compilers.len() - height - 1;
-        unsafe { self.compilers.at(index) }
+        self.compilers.top(height)
     }
 
     fn compiler_mut(&mut self, height: usize) -> &mut Compiler<'src> {
-        let index = self.compilers.len() - height - 1;
-        unsafe { self.compilers.at_mut(index) }
+        self.compilers.top_mut(height)
     }
 
     /// Synchronize the parser to a normal state where we can continue parsing
@@ -1303,13 +2186,19 @@ impl<'src, 'vm> Parser<'src, 'vm> {
             // a statement, we can be confident that compilation can go back to normal.
             if self.check_prev(Kind::Semicolon)
                 || self.check_curr(Kind::Class)
+                || self.check_curr(Kind::Const)
                 || self.check_curr(Kind::Fun)
                 || self.check_curr(Kind::Var)
+                || self.check_curr(Kind::Export)
+                || self.check_curr(Kind::Import)
                 || self.check_curr(Kind::For)
                 || self.check_curr(Kind::If)
                 || self.check_curr(Kind::While)
                 || self.check_curr(Kind::Print)
                 || self.check_curr(Kind::Return)
+                || self.check_curr(Kind::Switch)
+                || self.check_curr(Kind::Throw)
+                || self.check_curr(Kind::Try)
             {
                 return;
             }
@@ -1322,13 +2211,7 @@ impl<'src, 'vm> Parser<'src, 'vm> {
     fn advance(&mut self) {
         loop {
             match self.scanner.scan() {
-                Err(err) => {
-                    self.had_error = true;
-                    if !self.panicking {
-                        self.panicking = true;
-                        eprintln!("{err}");
-                    }
-                }
+                Err(err) => self.error_at(err.token(), &err.to_string()),
                 Ok(token) => {
                     self.token_prev = std::mem::replace(&mut self.token_curr, token);
                     break;
@@ -1366,28 +2249,32 @@ impl<'src, 'vm> Parser<'src, 'vm> {
         self.token_prev.kind == kind
     }
 
-    /// Create an compilation error pointing at the line of the previous token.
+    /// Create an compilation error pointing at the previous token.
     fn error_prev(&mut self, message: &str) {
-        self.error_at(self.token_prev.line, self.token_prev.lexeme, message);
+        self.error_at(self.token_prev, message);
     }
 
-    /// Create an compilation error pointing at the line of the current token.
+    /// Create an compilation error pointing at the current token.
     fn error_curr(&mut self, message: &str) {
-        self.error_at(self.token_curr.line, self.token_curr.lexeme, message)
+        self.error_at(self.token_curr, message)
     }
 
-    /// Create an compilation error pointing at a particular line and lexeme.
-    fn error_at(&mut self, line: Line, lexeme: &str, message: &str) {
+    /// Create an compilation error pointing at a particular token.
+    fn error_at(&mut self, token: Token<'_>, message: &str) {
         if self.panicking {
             return;
         }
         self.had_error = true;
         self.panicking = true;
-        if lexeme.is_empty() {
-            eprintln!("{line} Error at end: {message}");
-        } else {
-            eprintln!("{line} Error at '{lexeme}': {message}");
-        }
+        self.diagnostics.push(Diagnostic {
+            path: self.source.path.clone(),
+            line: *token.line,
+            column: token.column,
+            span: token.offset..token.offset + token.lexeme.len(),
+            lexeme: String::from(token.lexeme),
+            message: String::from(message),
+            severity: Severity::Error,
+        });
     }
 }
 
@@ -1411,9 +2298,17 @@ struct Compiler<'src> {
     /// The number of "blocks" surrounding the current piece of code that we're compiling.
     scope_depth: isize,
     /// A stack of local variables sorted by the order in which they are declared.
-    locals: Stack<Local<'src>, MAX_LOCALS>,
+    locals: Vec<Local<'src>>,
     /// A stack of local variables sorted by the order in which they are declared.
-    upvalues: Stack<Upvalue, MAX_UPVALUES>,
+    upvalues: Vec<Upvalue>,
+    /// A stack of loops enclosing the current piece of code that we're compiling.
+    loops: Vec<Loop>,
+    /// The number of exception handlers installed around the current piece of code that we're
+    /// compiling.
+    handlers: usize,
+    /// A stack of the finally blocks of the try statements enclosing the current piece of code
+    /// that we're compiling.
+    finallies: Vec<Finally<'src>>,
 }
 
 impl<'src> Compiler<'src> {
@@ -1425,19 +2320,22 @@ impl<'src> Compiler<'src> {
             _ => "",
         };
 
-        let mut locals = Stack::default();
-        locals.push(Local {
+        let locals = vec![Local {
             name: first_slot_name,
             depth: 0,
             is_captured: false,
-        });
+            is_const: false,
+        }];
 
         Self {
             fun,
             fun_type,
             scope_depth: 0,
             locals,
-            upvalues: Stack::default(),
+            upvalues: Vec::new(),
+            loops: Vec::new(),
+            handlers: 0,
+            finallies: Vec::new(),
         }
     }
 }
@@ -1453,7 +2351,67 @@ enum FunctionType {
 #[derive(Debug)]
 struct Upvalue {
     is_local: bool,
-    index: u8,
+    index: usize,
+    /// The flag to check whether the captured variable is a constant.
+    is_const: bool,
+}
+
+/// A structure for tracking a loop whose body is being compiled.
+#[derive(Debug)]
+struct Loop {
+    /// The offset of the instruction that a 'continue' statement jumps back to.
+    start: usize,
+    /// The scope depth in which the loop was declared.
+    scope_depth: isize,
+    /// The number of exception handlers installed when the loop was declared.
+    handlers: usize,
+    /// The number of finally blocks enclosing the loop.
+    finallies: usize,
+    /// The offsets of the jumps emitted by 'break' statements.
+    breaks: Vec<usize>,
+}
+
+/// A structure for tracking the finally block of a try statement whose other blocks are being
+/// compiled.
+#[derive(Debug)]
+struct Finally<'src> {
+    /// The scanner positioned right after the '{' token that starts the finally block.
+    scanner: Scanner<'src>,
+    /// The 'finally' token.
+    keyword: Token<'src>,
+    /// The '{' token that starts the finally block.
+    brace: Token<'src>,
+    /// The number of local variables declared before the try statement.
+    locals: usize,
+    /// The number of exception handlers installed before the try statement.
+    handlers: usize,
+}
+
+/// Get the next token from the scanner, skipping the invalid ones.
+fn next_token<'src>(scanner: &mut Scanner<'src>) -> Token<'src> {
+    loop {
+        if let Ok(token) = scanner.scan() {
+            return token;
+        }
+    }
+}
+
+/// Skip the block starting at the given token and return the token after it. Nothing is returned
+/// if the token doesn't start a block or the block isn't closed.
+fn skip_block<'src>(scanner: &mut Scanner<'src>, token: Token<'src>) -> Option<Token<'src>> {
+    if token.kind != Kind::LBrace {
+        return None;
+    }
+    let mut depth = 1;
+    while depth > 0 {
+        match next_token(scanner).kind {
+            Kind::LBrace => depth += 1,
+            Kind::RBrace => depth -= 1,
+            Kind::Eof => return None,
+            _ => {}
+        }
+    }
+    Some(next_token(scanner))
 }
 
 /// A structure the representing local variables during compilation time.
@@ -1465,6 +2423,8 @@ struct Local<'src> {
     depth: isize,
     /// The flag to check where this local variable is captured by some closure.
     is_captured: bool,
+    /// The flag to check whether this local variable is a constant.
+    is_const: bool,
 }
 
 /// All precedence levels in Lox.
@@ -1523,8 +2483,132 @@ impl Precedence {
             }
             Kind::Minus | Kind::Plus => Precedence::Term,
             Kind::Slash | Kind::Star => Precedence::Factor,
-            Kind::LParen | Kind::Dot => Precedence::Call,
+            Kind::LParen | Kind::Dot | Kind::LBracket => Precedence::Call,
             _ => Self::None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{chunk::Chunk, global::Globals, heap::Heap, opcode::Opcode};
+
+    use super::{Diagnostic, Parser, Rc, Severity, Source};
+
+    /// Find the instructions of the function with the given name by walking through the constants
+    /// of the compiled function and its nested functions.
+    fn instructions_of(src: &str, name: &str) -> Option<Vec<u8>> {
+        fn find(chunk: &Chunk, name: &str) -> Option<Vec<u8>> {
+            chunk.constants.iter().find_map(|constant| {
+                let fun = constant.as_fun().ok()?;
+                match &fun.name {
+                    Some(s) if s.data == name => Some(fun.chunk.instructions.clone()),
+                    _ => find(&fun.chunk, name),
+                }
+            })
+        }
+        let mut heap = Heap::default();
+        let mut globals = Globals::default();
+        let namespace = globals.add_namespace();
+        let source = Rc::new(Source {
+            path: None,
+            text: String::from(src),
+        });
+        let fun = Parser::new(&source, &mut heap, &mut globals, namespace)
+            .compile()
+            .ok()?;
+        find(&fun.chunk, name)
+    }
+
+    fn diagnostics_of(src: &str) -> Vec<Diagnostic> {
+        let mut heap = Heap::default();
+        let mut globals = Globals::default();
+        let namespace = globals.add_namespace();
+        let source = Rc::new(Source {
+            path: None,
+            text: String::from(src),
+        });
+        match Parser::new(&source, &mut heap, &mut globals, namespace).compile() {
+            Ok(_) => Vec::new(),
+            Err(diagnostics) => diagnostics,
+        }
+    }
+
+    fn declare_locals(count: usize) -> String {
+        (0..count).map(|i| format!("var l{i} = {i};")).collect()
+    }
+
+    fn contains(instructions: &[u8], expected: &[u8]) -> bool {
+        instructions.windows(expected.len()).any(|w| w == expected)
+    }
+
+    #[test]
+    fn locals_beyond_single_byte_slot() {
+        let locals = declare_locals(300);
+        let src = format!("fun f() {{ {locals} l299 = l0; return l299; }}");
+        let instructions = instructions_of(&src, "f").expect("Expect function 'f'.");
+        // Slot 0 is reserved, so 'l299' is at slot 300.
+        assert!(contains(
+            &instructions,
+            &[Opcode::SetLocalLong.into(), 0x01, 0x2c]
+        ));
+        assert!(contains(
+            &instructions,
+            &[Opcode::GetLocalLong.into(), 0x01, 0x2c]
+        ));
+        assert!(contains(&instructions, &[Opcode::GetLocal.into(), 0x01]));
+    }
+
+    #[test]
+    fn upvalues_beyond_single_byte_slot() {
+        let locals = declare_locals(300);
+        let captures = (0..300)
+            .map(|i| format!("l{i}"))
+            .collect::<Vec<_>>()
+            .join(" + ");
+        let src = format!(
+            "fun outer() {{ {locals} fun inner() {{ var sum = {captures}; l299 = sum; }} }}"
+        );
+        let instructions = instructions_of(&src, "inner").expect("Expect function 'inner'.");
+        // Upvalues are numbered in the order they are first used.
+        assert!(contains(
+            &instructions,
+            &[Opcode::GetUpvalueLong.into(), 0x01, 0x2b]
+        ));
+        assert!(contains(
+            &instructions,
+            &[Opcode::SetUpvalueLong.into(), 0x01, 0x2b]
+        ));
+        assert!(contains(&instructions, &[Opcode::GetUpvalue.into(), 0xff]));
+    }
+
+    #[test]
+    fn diagnostics_point_at_the_offending_lexeme() {
+        let diagnostic = |line, column, span, lexeme: &str, message: &str| Diagnostic {
+            path: None,
+            line,
+            column,
+            span,
+            lexeme: String::from(lexeme),
+            message: String::from(message),
+            severity: Severity::Error,
+        };
+        let src = "var x = ;\nprint 1 +\n  2 + \"é\" #;\nprint \"abc\nprint 1";
+        assert_eq!(
+            vec![
+                diagnostic(1, 9, 8..9, ";", "Expect expression."),
+                diagnostic(3, 11, 31..32, "#", "Unexpected character."),
+                diagnostic(4, 7, 40..52, "\"abc\nprint 1", "Unterminated string."),
+            ],
+            diagnostics_of(src)
+        );
+        assert_eq!(
+            vec![diagnostic(1, 8, 7..7, "", "Expect ';' after value.")],
+            diagnostics_of("print 1")
+        );
+        assert_eq!(
+            "[<script>:1:8] Error at end: Expect ';' after value.",
+            diagnostics_of("print 1")[0].to_string()
+        );
+    }
+}
@@ -0,0 +1,59 @@
+//! Diagnostics reported while compiling Lox source code.
+
+use std::{fmt, ops::Range};
+
+/// The severity of a diagnostic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// The source code can't be compiled.
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Error => f.write_str("Error"),
+        }
+    }
+}
+
+/// A problem found in the source code, pointing at the offending lexeme.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// The path of the module in which the problem was found, `None` if the source code wasn't
+    /// loaded from a path.
+    pub path: Option<String>,
+    /// The line of the lexeme, counted from 1.
+    pub line: usize,
+    /// The column of the lexeme, counted in characters from 1.
+    pub column: usize,
+    /// The byte range of the lexeme in the source code.
+    pub span: Range<usize>,
+    /// The offending lexeme, which is empty at the end of the source code.
+    pub lexeme: String,
+    /// The description of the problem.
+    pub message: String,
+    /// The severity of the problem.
+    pub severity: Severity,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self {
+            path,
+            line,
+            column,
+            lexeme,
+            message,
+            severity,
+            ..
+        } = self;
+        let path = path.as_deref().unwrap_or("<script>");
+        write!(f, "[{path}:{line}:{column}] {severity} at ")?;
+        if lexeme.is_empty() {
+            write!(f, "end: {message}")
+        } else {
+            write!(f, "'{lexeme}': {message}")
+        }
+    }
+}
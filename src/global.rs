@@ -0,0 +1,194 @@
+//! Implementation of the storage for global variables.
+
+use crate::{object::RefString, table::Table, value::Value};
+
+/// Max number of global variables a virtual machine can contain. Globals are indexed using at
+/// most 24 bits.
+pub(crate) const MAX_GLOBALS: usize = 1 << 24;
+
+/// A global variable stored in a slot.
+#[derive(Debug)]
+pub(crate) struct Global {
+    /// The name of the variable.
+    pub(crate) name: RefString,
+    /// The value of the variable, `None` if the variable hasn't been defined.
+    pub(crate) value: Option<Value>,
+    /// The flag to check whether the variable was defined as a constant.
+    pub(crate) is_const: bool,
+    /// The flag to check whether the variable is exported from its module.
+    pub(crate) is_exported: bool,
+}
+
+/// The global variables of a virtual machine. Each module has its own namespace, and each name in
+/// a namespace is assigned a stable slot index when it is first seen by the compiler, so variables
+/// can be accessed at runtime without looking up their names. The slot indices are unique across
+/// all namespaces.
+#[derive(Debug, Default)]
+pub(crate) struct Globals {
+    namespaces: Vec<Table<usize>>,
+    globals: Vec<Global>,
+}
+
+impl Globals {
+    /// Add an empty namespace and return its index.
+    pub(crate) fn add_namespace(&mut self) -> usize {
+        self.namespaces.push(Table::default());
+        self.namespaces.len() - 1
+    }
+
+    /// Get the slot index of the global variable with the given name in the given namespace. A
+    /// new undefined slot is added if the name hasn't been seen before, and `None` is returned if
+    /// there's no slot left.
+    pub(crate) fn slot(&mut self, namespace: usize, name: RefString) -> Option<usize> {
+        let slots = &mut self.namespaces[namespace];
+        if let Some(slot) = slots.get(name) {
+            return Some(*slot);
+        }
+        let slot = self.globals.len();
+        if slot == MAX_GLOBALS {
+            return None;
+        }
+        slots.set(name, slot);
+        self.globals.push(Global {
+            name,
+            value: None,
+            is_const: false,
+            is_exported: false,
+        });
+        Some(slot)
+    }
+
+    /// Get the slot index of the global variable with the given name in the given namespace
+    /// without adding a new slot. Return `None` if the name hasn't been seen before.
+    pub(crate) fn find(&self, namespace: usize, name: RefString) -> Option<usize> {
+        self.namespaces[namespace].get(name).copied()
+    }
+
+    /// Remove the namespace that was added last along with its global variables. The slots of the
+    /// namespace must be the last ones that were given out.
+    pub(crate) fn remove_last_namespace(&mut self) {
+        let Some(slots) = self.namespaces.pop() else {
+            return;
+        };
+        if let Some(first) = slots.iter().map(|(_, slot)| *slot).min() {
+            debug_assert_eq!(self.globals.len() - first, slots.len());
+            self.globals.truncate(first);
+        }
+    }
+
+    /// Return the number of namespaces.
+    pub(crate) fn namespace_count(&self) -> usize {
+        self.namespaces.len()
+    }
+
+    /// Return the slot indices of all global variables in the given namespace.
+    pub(crate) fn slots(&self, namespace: usize) -> Vec<usize> {
+        self.namespaces[namespace]
+            .iter()
+            .map(|(_, slot)| *slot)
+            .collect()
+    }
+
+    /// Get the global variable at the given slot.
+    ///
+    /// ## Safety
+    ///
+    /// Caller must ensure that the slot was given out by `Globals::slot`.
+    pub(crate) unsafe fn at(&self, slot: usize) -> &Global {
+        self.globals.get_unchecked(slot)
+    }
+
+    /// Get the mutable global variable at the given slot.
+    ///
+    /// ## Safety
+    ///
+    /// Caller must ensure that the slot was given out by `Globals::slot`.
+    pub(crate) unsafe fn at_mut(&mut self, slot: usize) -> &mut Global {
+        self.globals.get_unchecked_mut(slot)
+    }
+
+    /// Return an iterator over all global variables.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &Global> {
+        self.globals.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{heap::Heap, value::Value};
+
+    use super::Globals;
+
+    #[test]
+    fn slot_is_stable_for_the_same_name() {
+        let mut heap = Heap::default();
+        let mut globals = Globals::default();
+        let namespace = globals.add_namespace();
+        let a = heap.intern(String::from("a"));
+        let b = heap.intern(String::from("b"));
+        assert_eq!(Some(0), globals.slot(namespace, a));
+        assert_eq!(Some(1), globals.slot(namespace, b));
+        assert_eq!(Some(0), globals.slot(namespace, a));
+        assert_eq!(2, globals.iter().count());
+    }
+
+    #[test]
+    fn find_does_not_add_a_slot() {
+        let mut heap = Heap::default();
+        let mut globals = Globals::default();
+        let namespace = globals.add_namespace();
+        let a = heap.intern(String::from("a"));
+        let b = heap.intern(String::from("b"));
+        assert_eq!(None, globals.find(namespace, a));
+        assert_eq!(0, globals.iter().count());
+        assert_eq!(Some(0), globals.slot(namespace, a));
+        assert_eq!(Some(0), globals.find(namespace, a));
+        assert_eq!(None, globals.find(namespace, b));
+        assert_eq!(1, globals.iter().count());
+    }
+
+    #[test]
+    fn slot_is_unique_across_namespaces() {
+        let mut heap = Heap::default();
+        let mut globals = Globals::default();
+        let first = globals.add_namespace();
+        let second = globals.add_namespace();
+        let a = heap.intern(String::from("a"));
+        assert_eq!(Some(0), globals.slot(first, a));
+        assert_eq!(Some(1), globals.slot(second, a));
+        assert_eq!(vec![0], globals.slots(first));
+        assert_eq!(vec![1], globals.slots(second));
+    }
+
+    #[test]
+    fn last_namespace_is_removed_with_its_slots() {
+        let mut heap = Heap::default();
+        let mut globals = Globals::default();
+        let first = globals.add_namespace();
+        let a = heap.intern(String::from("a"));
+        let b = heap.intern(String::from("b"));
+        globals.slot(first, a);
+        let second = globals.add_namespace();
+        globals.slot(second, a);
+        globals.slot(second, b);
+
+        globals.remove_last_namespace();
+        assert_eq!(1, globals.iter().count());
+        let third = globals.add_namespace();
+        assert_eq!(second, third);
+        assert_eq!(Some(1), globals.slot(third, b));
+    }
+
+    #[test]
+    fn slot_starts_undefined() {
+        let mut heap = Heap::default();
+        let mut globals = Globals::default();
+        let namespace = globals.add_namespace();
+        let a = heap.intern(String::from("a"));
+        let slot = globals.slot(namespace, a).unwrap();
+        assert!(unsafe { globals.at(slot) }.value.is_none());
+
+        unsafe { globals.at_mut(slot).value = Some(Value::number(1.0)) };
+        assert_eq!(Some(Value::number(1.0)), unsafe { globals.at(slot) }.value);
+    }
+}
@@ -0,0 +1,116 @@
+//! Handles to the values of a virtual machine that are held by the host program.
+
+use std::{
+    fmt,
+    rc::{Rc, Weak},
+};
+
+use crate::value::{Unpacked, Value};
+
+/// A Lox value held by the host program. Objects referenced by a handle are kept alive by the
+/// garbage collector of the virtual machine that gave out the handle until all clones of it are
+/// dropped. The content of an object can't be read after its virtual machine is dropped.
+#[derive(Clone)]
+pub struct Handle(Rc<Root>);
+
+/// The value of a handle, which is marked by the virtual machine as a GC root.
+pub(crate) struct Root {
+    pub(crate) value: Value,
+    /// The token of the virtual machine that owns the objects referenced by the value.
+    vm: Weak<()>,
+}
+
+impl Handle {
+    /// Create a handle that holds nil.
+    pub fn nil() -> Self {
+        Self::from_value(Value::NIL, Weak::new())
+    }
+
+    /// Create a handle to a value owned by the virtual machine with the given token.
+    pub(crate) fn from_value(value: Value, vm: Weak<()>) -> Self {
+        Self(Rc::new(Root { value, vm }))
+    }
+
+    /// Create a weak reference to the value of the handle, which is used for marking the value
+    /// while the handle is alive.
+    pub(crate) fn root(&self) -> Weak<Root> {
+        Rc::downgrade(&self.0)
+    }
+
+    /// Get the held value if it can be used by the virtual machine with the given token.
+    pub(crate) fn value_for(&self, vm: &Rc<()>) -> Option<Value> {
+        let value = self.0.value;
+        if value.as_object().is_some() && !Weak::ptr_eq(&self.0.vm, &Rc::downgrade(vm)) {
+            return None;
+        }
+        Some(value)
+    }
+
+    /// Check whether the held value is nil.
+    pub fn is_nil(&self) -> bool {
+        self.0.value.is_nil()
+    }
+
+    /// Get the held boolean.
+    pub fn as_bool(&self) -> Option<bool> {
+        match self.0.value.unpack() {
+            Unpacked::Bool(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    /// Get the held number.
+    pub fn as_number(&self) -> Option<f64> {
+        self.0.value.as_number()
+    }
+
+    /// Get a copy of the held string. Return `None` if the value isn't a string, or if the
+    /// virtual machine that owns the string was dropped.
+    pub fn as_string(&self) -> Option<String> {
+        match self.0.value.as_string() {
+            Ok(s) if self.is_attached() => Some(s.data.clone()),
+            _ => None,
+        }
+    }
+
+    /// Check whether the virtual machine owning the held value is still alive.
+    fn is_attached(&self) -> bool {
+        self.0.vm.strong_count() > 0
+    }
+}
+
+impl Default for Handle {
+    fn default() -> Self {
+        Self::nil()
+    }
+}
+
+impl From<bool> for Handle {
+    fn from(b: bool) -> Self {
+        Self::from_value(Value::bool(b), Weak::new())
+    }
+}
+
+impl From<f64> for Handle {
+    fn from(n: f64) -> Self {
+        Self::from_value(Value::number(n), Weak::new())
+    }
+}
+
+impl fmt::Display for Handle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = self.0.value;
+        if value.as_object().is_some() && !self.is_attached() {
+            return f.write_str("<detached>");
+        }
+        write!(f, "{value}")
+    }
+}
+
+impl fmt::Debug for Handle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Handle")
+            .field(&format_args!("{self}"))
+            .finish()
+    }
+}
@@ -1,3 +1,5 @@
+use std::cell::RefCell;
+
 use crate::{
     object::{Gc, GcData, GcSized, ObjString, Object, RefString},
     table::Table,
@@ -63,6 +65,19 @@ impl Heap {
         (object, content)
     }
 
+    /// Applies a change to a mutable object and accounts for the bytes that the object grows or
+    /// shrinks by, so it is freed with the same size that was counted for it.
+    pub(crate) fn update<T: GcSized, R, F>(&mut self, object: &RefCell<T>, change: F) -> R
+    where
+        F: FnOnce(&mut T) -> R,
+    {
+        let mut object = object.borrow_mut();
+        let before = object.size();
+        let result = change(&mut object);
+        self.alloc_bytes = self.alloc_bytes + object.size() - before;
+        result
+    }
+
     /// Interned a string and returned a reference to it. The same reference is returned for 2
     /// equal strings.
     pub(crate) fn intern(&mut self, data: String) -> RefString {
@@ -149,9 +164,9 @@ impl Heap {
     /// ## Safety
     ///
     /// + We must ensure that no other piece of our code will ever use this reference, otherwise we'll
-    /// invalidate a reference that is in used.
+    ///   invalidate a reference that is in used.
     /// + Before calling this method, we must ensure that the object is removed from the linked list of
-    /// heap-allocated objects.
+    ///   heap-allocated objects.
     unsafe fn dealloc(&mut self, object: Object) {
         let size = object.size();
 
@@ -183,6 +198,12 @@ impl Heap {
             Object::BoundMethod(m) => {
                 m.release();
             }
+            Object::List(l) => {
+                l.release();
+            }
+            Object::Map(m) => {
+                m.release();
+            }
         };
         self.alloc_bytes -= size;
     }
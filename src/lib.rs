@@ -2,13 +2,25 @@
 
 #![deny(rust_2018_idioms, rust_2021_compatibility, clippy::all)]
 #![warn(missing_docs)]
+#![cfg_attr(
+    test,
+    allow(clippy::redundant_pattern_matching, clippy::useless_conversion)
+)]
 
+mod bytecode;
 mod chunk;
 mod compile;
+mod diagnostic;
+mod global;
+mod handle;
 mod heap;
+mod module;
+mod native;
 mod object;
 mod opcode;
+mod optimize;
 mod scan;
+mod shape;
 mod stack;
 mod table;
 mod value;
@@ -16,24 +28,49 @@ mod vm;
 
 use std::{error, fmt};
 
-pub use vm::{RuntimeError, VirtualMachine};
+pub use bytecode::BytecodeError;
+pub use diagnostic::{Diagnostic, Severity};
+pub use handle::Handle;
+pub use module::{FileResolver, MemoryResolver, ModuleResolver};
+pub use native::{Arity, NativeContext, NativeError};
+pub use vm::{RuntimeError, StackFrame, VirtualMachine, VirtualMachineBuilder};
 
 /// A enumeration of all potential errors that might occur when working with the virtual machine.
 #[derive(Debug)]
 pub enum InterpretError {
-    /// Error with compiling the source code.
-    Compile,
-    /// Error with running the bytecode.
-    Runtime,
+    /// Error with compiling the source code, holding the problems found in the source.
+    Compile(Vec<Diagnostic>),
+    /// Error with loading bytecode that was rejected by the verifier.
+    Bytecode(BytecodeError),
+    /// Error with running the bytecode, holding the calls that were active when it occurred.
+    Runtime {
+        /// The error that stopped the program.
+        error: RuntimeError,
+        /// The active calls, starting from the innermost one.
+        trace: Vec<StackFrame>,
+    },
+    /// Error with writing the output of the virtual machine to a writer.
+    Write(fmt::Error),
 }
 
-impl error::Error for InterpretError {}
+impl error::Error for InterpretError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::Compile(_) => None,
+            Self::Bytecode(error) => Some(error),
+            Self::Runtime { error, .. } => Some(error),
+            Self::Write(error) => Some(error),
+        }
+    }
+}
 
 impl fmt::Display for InterpretError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::Compile => f.write_str("Compile error."),
-            Self::Runtime => f.write_str("Runtime error."),
+            Self::Compile(_) => f.write_str("Compile error."),
+            Self::Bytecode(_) => f.write_str("Bytecode error."),
+            Self::Runtime { .. } => f.write_str("Runtime error."),
+            Self::Write(_) => f.write_str("Write error."),
         }
     }
 }
@@ -1,25 +1,34 @@
 use std::{
-    env,
+    env, fs,
     io::{self, BufRead, BufReader, Write},
+    path::Path,
     process,
 };
 
 use rox::{InterpretError, VirtualMachine};
 
 fn main() {
-    let args: Vec<String> = env::args().skip(1).collect();
-    if args.is_empty() {
-        run_repl()
-    } else if args.len() == 1 {
-        run_file(&args[0])
-    } else {
-        println!("Usage: rlox [path]\n");
-        process::exit(64);
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    // The optimizer can be turned on for every command, so the results can be compared.
+    let optimizing = args.iter().any(|arg| arg == "--optimize");
+    args.retain(|arg| arg != "--optimize");
+    let vm = VirtualMachine::builder().optimize(optimizing).build();
+    match args.as_slice() {
+        [] => run_repl(vm),
+        [command, path] if command == "compile" => compile_file(vm, path, None),
+        [command, path] if command == "disasm" => disassemble_file(vm, path),
+        [command, path, output] if command == "compile" => compile_file(vm, path, Some(output)),
+        [path] => run_file(vm, path),
+        _ => {
+            println!("Usage: rlox [--optimize] [path]");
+            println!("       rlox [--optimize] compile <path> [output]");
+            println!("       rlox [--optimize] disasm <path>\n");
+            process::exit(64);
+        }
     }
 }
 
-fn run_repl() {
-    let mut vm = VirtualMachine::new();
+fn run_repl(mut vm: VirtualMachine) {
     let mut reader = BufReader::new(io::stdin());
     loop {
         print!("> ");
@@ -43,18 +52,69 @@ fn run_repl() {
     }
 }
 
-fn run_file(path: &str) {
-    let src = match std::fs::read_to_string(path) {
-        Ok(s) => s,
+fn run_file(mut vm: VirtualMachine, path: &str) {
+    let result = if Path::new(path).extension().is_some_and(|ext| ext == "loxc") {
+        vm.load_bytecode(&read_file(path))
+    } else {
+        match String::from_utf8(read_file(path)) {
+            Ok(src) => vm.interpret_script(path, &src),
+            Err(err) => {
+                eprintln!("{err}");
+                process::exit(65);
+            }
+        }
+    };
+    match result {
+        Ok(()) => {}
+        Err(InterpretError::Compile(_) | InterpretError::Bytecode(_)) => process::exit(65),
+        Err(InterpretError::Runtime { .. }) => process::exit(70),
+        Err(InterpretError::Write(_)) => process::exit(74),
+    }
+}
+
+/// Compile the script at the given path into bytecode. The bytecode is written next to the script
+/// with the `.loxc` extension if no output path is given.
+fn compile_file(mut vm: VirtualMachine, path: &str, output: Option<&str>) {
+    let src = match String::from_utf8(read_file(path)) {
+        Ok(src) => src,
         Err(err) => {
             eprintln!("{err}");
-            process::exit(74);
+            process::exit(65);
         }
     };
-    let mut vm = VirtualMachine::new();
-    match vm.interpret(&src) {
-        Ok(()) => {}
-        Err(InterpretError::Compile) => process::exit(65),
-        Err(InterpretError::Runtime) => process::exit(70),
+    let bytes = match vm.compile(Some(path), &src) {
+        Ok(bytes) => bytes,
+        Err(_) => process::exit(65),
+    };
+    let output = output.map_or_else(|| Path::new(path).with_extension("loxc"), Into::into);
+    if let Err(err) = fs::write(output, bytes) {
+        eprintln!("{err}");
+        process::exit(74);
+    }
+}
+
+/// Compile the script at the given path and print its instructions without running it.
+fn disassemble_file(mut vm: VirtualMachine, path: &str) {
+    let src = match String::from_utf8(read_file(path)) {
+        Ok(src) => src,
+        Err(err) => {
+            eprintln!("{err}");
+            process::exit(65);
+        }
+    };
+    let mut out = String::new();
+    if vm.disassemble(&src, &mut out).is_err() {
+        process::exit(65);
+    }
+    print!("{out}");
+}
+
+fn read_file(path: &str) -> Vec<u8> {
+    match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            eprintln!("{err}");
+            process::exit(74);
+        }
     }
 }
@@ -0,0 +1,165 @@
+//! Loading the source code of the modules imported by a script.
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Component, Path, PathBuf},
+};
+
+use crate::scan::Span;
+
+/// A resolver finds and loads the source code of the modules given in import statements. Embedders
+/// can implement this trait to serve modules from somewhere other than the file system.
+pub trait ModuleResolver {
+    /// Resolve the path given in an import statement into the path identifying the module. The
+    /// importer is the resolved path of the module containing the import statement, or `None` if
+    /// the statement is in a script that wasn't loaded from a path. Modules with the same resolved
+    /// path are only loaded once.
+    ///
+    /// By default, the path is joined with the directory of the importer, and the `.` and `..`
+    /// components are removed without accessing the file system.
+    fn resolve(&self, importer: Option<&str>, path: &str) -> io::Result<String> {
+        let dir = importer
+            .and_then(|importer| Path::new(importer).parent())
+            .unwrap_or_else(|| Path::new(""));
+        Ok(normalize(&dir.join(path)))
+    }
+
+    /// Load the source code of the module at the given resolved path.
+    fn load(&self, path: &str) -> io::Result<String>;
+}
+
+/// A resolver that loads modules from the file system. This is used when no other resolver is
+/// given to the virtual machine.
+#[derive(Debug, Default)]
+pub struct FileResolver;
+
+impl ModuleResolver for FileResolver {
+    fn load(&self, path: &str) -> io::Result<String> {
+        fs::read_to_string(path)
+    }
+}
+
+/// A resolver that serves modules from memory.
+#[derive(Debug, Default)]
+pub struct MemoryResolver {
+    sources: HashMap<String, String>,
+}
+
+impl MemoryResolver {
+    /// Create a resolver without any module.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a module with the given path and source code. Paths of imported modules are resolved
+    /// the same way as file paths, so the given path shouldn't contain `.` or `..` components.
+    pub fn module(mut self, path: impl Into<String>, src: impl Into<String>) -> Self {
+        self.sources.insert(path.into(), src.into());
+        self
+    }
+}
+
+impl ModuleResolver for MemoryResolver {
+    fn load(&self, path: &str) -> io::Result<String> {
+        self.sources.get(path).cloned().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Module '{path}' not found."),
+            )
+        })
+    }
+}
+
+/// The source code of a compiled script or module. Functions keep their source code so runtime
+/// errors can show where they occurred.
+#[derive(Debug)]
+pub(crate) struct Source {
+    /// The resolved path of the module, or `None` if the script wasn't loaded from a path.
+    pub(crate) path: Option<String>,
+    pub(crate) text: String,
+}
+
+impl Source {
+    /// Get the name used for the source code in error messages.
+    pub(crate) fn name(&self) -> &str {
+        self.path.as_deref().unwrap_or("<script>")
+    }
+
+    /// Get the line containing the start of the span without its indentation, along with a marker
+    /// that puts carets under the part of the line covered by the span. Return `None` if the span
+    /// isn't in the source code.
+    pub(crate) fn underline(&self, span: Span) -> Option<(&str, String)> {
+        // Scripts loaded from bytecode don't keep their source code.
+        if self.text.is_empty() {
+            return None;
+        }
+        let start = span.offset as usize;
+        let before = self.text.get(..start)?;
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        let line_end = self.text[start..]
+            .find('\n')
+            .map_or(self.text.len(), |i| start + i);
+        let line = self.text[line_start..line_end].trim_end_matches('\r');
+        let code = line.trim_start();
+        let indent = line.len() - code.len();
+
+        // Keep the tabs so the carets line up with the source no matter how tabs are displayed.
+        let mut marker: String = before
+            .get(line_start + indent..)
+            .unwrap_or_default()
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let end = (start + span.len as usize).min(line_start + line.len());
+        let width = self.text.get(start..end).map_or(0, |s| s.chars().count());
+        marker.extend(std::iter::repeat_n('^', width.max(1)));
+        Some((code, marker))
+    }
+}
+
+/// Remove the `.` and `..` components of a path. A `..` component is kept if there's nothing to
+/// remove before it.
+fn normalize(path: &Path) -> String {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match normalized.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    normalized.pop();
+                }
+                Some(Component::RootDir | Component::Prefix(_)) => {}
+                _ => normalized.push(".."),
+            },
+            component => normalized.push(component),
+        }
+    }
+    normalized.to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MemoryResolver, ModuleResolver};
+
+    #[test]
+    fn paths_are_resolved_relative_to_the_importer() {
+        let resolver = MemoryResolver::new();
+        let resolve = |importer, path| resolver.resolve(importer, path).unwrap();
+        assert_eq!("a.lox", resolve(None, "a.lox"));
+        assert_eq!("a.lox", resolve(None, "./a.lox"));
+        assert_eq!("lib/b.lox", resolve(Some("lib/a.lox"), "b.lox"));
+        assert_eq!("c.lox", resolve(Some("lib/a.lox"), "../c.lox"));
+        assert_eq!("../c.lox", resolve(Some("a.lox"), "../c.lox"));
+        assert_eq!("../../c.lox", resolve(Some("../a.lox"), "../c.lox"));
+        assert_eq!("/c.lox", resolve(Some("/a.lox"), "../c.lox"));
+        assert_eq!("/c.lox", resolve(Some("lib/a.lox"), "/c.lox"));
+    }
+
+    #[test]
+    fn missing_modules_are_not_found() {
+        let resolver = MemoryResolver::new().module("a.lox", "print 1;");
+        assert_eq!("print 1;", resolver.load("a.lox").unwrap());
+        assert!(resolver.load("b.lox").is_err());
+    }
+}
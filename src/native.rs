@@ -0,0 +1,78 @@
+//! Types for defining native functions that can be called by Lox code.
+
+use std::{error, fmt};
+
+use crate::{handle::Handle, VirtualMachine};
+
+/// The signature of the closures that implement native functions.
+pub(crate) type NativeFn =
+    dyn FnMut(&mut NativeContext<'_>, &[Handle]) -> Result<Handle, NativeError>;
+
+/// The number of arguments a native function accepts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arity {
+    /// Accept exactly the given number of arguments.
+    Fixed(u8),
+    /// Accept the given number of arguments or more.
+    AtLeast(u8),
+}
+
+/// An error returned by a native function. The error is raised as a runtime error at the place
+/// where the native function was called, so it can be caught by Lox code.
+#[derive(Debug)]
+pub struct NativeError {
+    message: String,
+}
+
+impl NativeError {
+    /// Create an error with the given message.
+    pub fn new(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+        }
+    }
+}
+
+impl error::Error for NativeError {}
+
+impl fmt::Display for NativeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl From<String> for NativeError {
+    fn from(message: String) -> Self {
+        Self { message }
+    }
+}
+
+impl From<&str> for NativeError {
+    fn from(message: &str) -> Self {
+        Self::new(message)
+    }
+}
+
+/// The context given to a native function while it's running, which gives access to the heap of
+/// the virtual machine.
+pub struct NativeContext<'vm> {
+    vm: &'vm mut VirtualMachine,
+}
+
+impl<'vm> NativeContext<'vm> {
+    pub(crate) fn new(vm: &'vm mut VirtualMachine) -> Self {
+        Self { vm }
+    }
+
+    /// Allocate a string on the heap. Strings are interned, so equal strings share the same
+    /// object.
+    pub fn new_string(&mut self, s: &str) -> Handle {
+        self.vm.new_string(s)
+    }
+}
+
+impl fmt::Debug for NativeContext<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NativeContext").finish_non_exhaustive()
+    }
+}
@@ -5,9 +5,17 @@ use std::{
     mem,
     ops::{self, BitXor, Deref},
     ptr::NonNull,
+    rc::Rc,
 };
 
-use crate::{chunk::Chunk, table::Table, value::Value};
+use crate::{
+    chunk::Chunk,
+    module::Source,
+    native::{Arity, NativeFn},
+    shape::{Shapes, EMPTY_SHAPE},
+    table::Table,
+    value::Value,
+};
 
 /// A type alias for a heap-allocated string.
 pub type RefString = Gc<ObjString>;
@@ -33,6 +41,12 @@ pub(crate) type RefInstance = Gc<RefCell<ObjInstance>>;
 /// A type alias for a heap-allocated bound method.
 pub(crate) type RefBoundMethod = Gc<ObjBoundMethod>;
 
+/// A type alias for a heap-allocated list.
+pub(crate) type RefList = Gc<RefCell<ObjList>>;
+
+/// A type alias for a heap-allocated map.
+pub(crate) type RefMap = Gc<RefCell<ObjMap>>;
+
 /// An enumeration of all potential errors that occur when working with objects.
 #[derive(Debug)]
 pub enum ObjectError {
@@ -68,6 +82,10 @@ pub(crate) enum Object {
     Instance(RefInstance),
     /// A bound method object
     BoundMethod(RefBoundMethod),
+    /// A list object
+    List(RefList),
+    /// A map object
+    Map(RefMap),
 }
 
 impl Object {
@@ -82,6 +100,8 @@ impl Object {
             Self::Class(c) => c.mark(),
             Self::Instance(i) => i.mark(),
             Self::BoundMethod(m) => m.mark(),
+            Self::List(l) => l.mark(),
+            Self::Map(m) => m.mark(),
         };
         if marked {
             grey_objects.push(*self);
@@ -99,6 +119,8 @@ impl Object {
             Self::Class(c) => c.unmark(),
             Self::Instance(i) => i.unmark(),
             Self::BoundMethod(m) => m.unmark(),
+            Self::List(l) => l.unmark(),
+            Self::Map(m) => m.unmark(),
         }
     }
 
@@ -113,6 +135,8 @@ impl Object {
             Self::Class(c) => c.is_marked(),
             Self::Instance(i) => i.is_marked(),
             Self::BoundMethod(m) => m.is_marked(),
+            Self::List(l) => l.is_marked(),
+            Self::Map(m) => m.is_marked(),
         }
     }
 
@@ -126,7 +150,24 @@ impl Object {
             Object::Class(class) => class.borrow().mark_references(grey_objects),
             Object::Instance(instance) => instance.borrow().mark_references(grey_objects),
             Object::BoundMethod(method) => method.mark_references(grey_objects),
-            Object::String(_) | Object::NativeFun(_) => {}
+            Object::List(list) => list.borrow().mark_references(grey_objects),
+            Object::Map(map) => map.borrow().mark_references(grey_objects),
+            Object::NativeFun(fun) => {
+                if fun.name.mark() {
+                    grey_objects.push(Object::String(fun.name));
+                }
+            }
+            Object::String(_) => {}
+        }
+    }
+
+    /// Mark the shapes used by the current object, so they aren't freed while the object can
+    /// still be reached.
+    pub(crate) fn mark_shapes(&self, shapes: &mut Shapes) {
+        match &self {
+            Object::Fun(fun) => fun.chunk.mark_shapes(shapes),
+            Object::Instance(instance) => shapes.mark(instance.borrow().shape),
+            _ => {}
         }
     }
 
@@ -141,6 +182,8 @@ impl Object {
             Self::Class(c) => c.get_next(),
             Self::Instance(i) => i.get_next(),
             Self::BoundMethod(m) => m.get_next(),
+            Self::List(l) => l.get_next(),
+            Self::Map(m) => m.get_next(),
         }
     }
 
@@ -155,10 +198,12 @@ impl Object {
             Self::Class(c) => c.set_next(next),
             Self::Instance(i) => i.set_next(next),
             Self::BoundMethod(m) => m.set_next(next),
+            Self::List(l) => l.set_next(next),
+            Self::Map(m) => m.set_next(next),
         }
     }
 
-    #[cfg(feature = "dbg-heap")]
+    /// Get the address of the object, which is used as its identity.
     pub(crate) fn addr(&self) -> usize {
         match self {
             Self::String(s) => s.as_ptr() as usize,
@@ -169,6 +214,8 @@ impl Object {
             Self::Class(c) => c.as_ptr() as usize,
             Self::Instance(i) => i.as_ptr() as usize,
             Self::BoundMethod(m) => m.as_ptr() as usize,
+            Self::List(l) => l.as_ptr() as usize,
+            Self::Map(m) => m.as_ptr() as usize,
         }
     }
 }
@@ -184,6 +231,8 @@ impl GcSized for Object {
             Object::Class(c) => c.size(),
             Object::Instance(i) => i.size(),
             Object::BoundMethod(m) => m.size(),
+            Object::List(l) => l.size(),
+            Object::Map(m) => m.size(),
         }
     }
 }
@@ -199,6 +248,8 @@ impl fmt::Display for Object {
             Object::Class(c) => write!(f, "{}", (***c).borrow()),
             Object::Instance(i) => write!(f, "{}", (***i).borrow()),
             Object::BoundMethod(m) => write!(f, "{}", ***m),
+            Object::List(l) => write!(f, "{}", (***l).borrow()),
+            Object::Map(m) => write!(f, "{}", (***m).borrow()),
         }
     }
 }
@@ -290,8 +341,10 @@ pub(crate) enum ObjUpvalue {
 impl ObjUpvalue {
     /// Mark all object references that can be directly access by the current object.
     pub(crate) fn mark_references(&self, grey_objects: &mut Vec<Object>) {
-        if let ObjUpvalue::Closed(Value::Object(obj)) = self {
-            obj.mark(grey_objects);
+        if let ObjUpvalue::Closed(value) = self {
+            if let Some(obj) = value.as_object() {
+                obj.mark(grey_objects);
+            }
         }
     }
 }
@@ -316,19 +369,26 @@ pub(crate) struct ObjFun {
     /// Number of parameters the function has
     pub(crate) arity: u8,
     /// Number of upvalues captured by the function
-    pub(crate) upvalue_count: u8,
+    pub(crate) upvalue_count: usize,
     /// The bytecode chunk of this function
     pub(crate) chunk: Chunk,
+    /// The global namespace of the module in which the function was defined
+    pub(crate) namespace: usize,
+    /// The source code from which the function was compiled
+    pub(crate) source: Rc<Source>,
 }
 
 impl ObjFun {
-    /// Create a new function object given its name.
-    pub(crate) fn new(name: Option<RefString>) -> Self {
+    /// Create a new function object given its name, and the namespace and source code of its
+    /// module.
+    pub(crate) fn new(name: Option<RefString>, namespace: usize, source: Rc<Source>) -> Self {
         Self {
             name,
             arity: 0,
             upvalue_count: 0,
             chunk: Chunk::default(),
+            namespace,
+            source,
         }
     }
 
@@ -340,10 +400,11 @@ impl ObjFun {
             }
         }
         for constant in &self.chunk.constants {
-            if let Value::Object(obj) = constant {
+            if let Some(obj) = constant.as_object() {
                 obj.mark(grey_objects);
             }
         }
+        self.chunk.mark_caches(grey_objects);
     }
 }
 
@@ -364,10 +425,12 @@ impl fmt::Display for ObjFun {
 
 /// The content of an heap-allocated native function object.
 pub(crate) struct ObjNativeFun {
+    /// The name of the function
+    pub(crate) name: RefString,
     /// Number of parameters
-    pub(crate) arity: u8,
-    /// Native function reference
-    pub(crate) call: fn(&[Value]) -> Value,
+    pub(crate) arity: Arity,
+    /// The closure implementing the function
+    pub(crate) call: RefCell<Box<NativeFn>>,
 }
 
 impl GcSized for ObjNativeFun {
@@ -395,6 +458,11 @@ pub(crate) struct ObjClass {
     pub(crate) name: RefString,
     /// A the methods defined in the class.
     pub(crate) methods: Table<RefClosure>,
+    /// The class that this class inherits from.
+    pub(crate) superclass: Option<RefClass>,
+    /// The number of times the methods have been changed, so lookups cached by the instructions
+    /// can tell whether they're out of date.
+    pub(crate) version: usize,
 }
 
 impl ObjClass {
@@ -402,14 +470,27 @@ impl ObjClass {
         Self {
             name,
             methods: Table::default(),
+            superclass: None,
+            version: 0,
         }
     }
 
+    /// Define or redefine a method of the class.
+    pub(crate) fn set_method(&mut self, name: RefString, method: RefClosure) {
+        self.methods.set(name, method);
+        self.version += 1;
+    }
+
     /// Mark all object references that can be directly access by the current object.
     pub(crate) fn mark_references(&self, grey_objects: &mut Vec<Object>) {
         if self.name.mark() {
             grey_objects.push(Object::String(self.name));
         }
+        if let Some(superclass) = self.superclass {
+            if superclass.mark() {
+                grey_objects.push(Object::Class(superclass));
+            }
+        }
         for (k, v) in self.methods.iter() {
             if k.mark() {
                 grey_objects.push(Object::String(k));
@@ -423,7 +504,7 @@ impl ObjClass {
 
 impl GcSized for ObjClass {
     fn size(&self) -> usize {
-        mem::size_of_val(&self.name) + self.methods.size()
+        mem::size_of::<Self>() + self.methods.size()
     }
 }
 
@@ -437,7 +518,10 @@ impl fmt::Display for ObjClass {
 #[derive(Debug)]
 pub(crate) struct ObjInstance {
     pub(crate) class: RefClass,
-    pub(crate) fields: Table<Value>,
+    /// The shape giving the slot of each field.
+    pub(crate) shape: usize,
+    /// The values of the fields in the order of their slots.
+    pub(crate) fields: Vec<Value>,
 }
 
 impl ObjInstance {
@@ -445,7 +529,26 @@ impl ObjInstance {
     pub(crate) fn new(class: RefClass) -> Self {
         Self {
             class,
-            fields: Table::default(),
+            shape: EMPTY_SHAPE,
+            fields: Vec::new(),
+        }
+    }
+
+    /// Get the value of the field with the given name.
+    pub(crate) fn field(&self, shapes: &Shapes, name: RefString) -> Option<Value> {
+        let slot = shapes.slot(self.shape, name)?;
+        Some(self.fields[slot])
+    }
+
+    /// Set the value of the field with the given name, moving the instance to a new shape if
+    /// the field doesn't exist yet.
+    pub(crate) fn set_field(&mut self, shapes: &mut Shapes, name: RefString, value: Value) {
+        match shapes.slot(self.shape, name) {
+            Some(slot) => self.fields[slot] = value,
+            None => {
+                self.shape = shapes.transition(self.shape, name);
+                self.fields.push(value);
+            }
         }
     }
 
@@ -454,11 +557,9 @@ impl ObjInstance {
         if self.class.mark() {
             grey_objects.push(Object::Class(self.class))
         }
-        for (k, v) in self.fields.iter() {
-            if k.mark() {
-                grey_objects.push(Object::String(k));
-            }
-            if let Value::Object(obj) = v {
+        // The names of the fields are kept alive by the shapes.
+        for v in &self.fields {
+            if let Some(obj) = v.as_object() {
                 obj.mark(grey_objects);
             }
         }
@@ -467,7 +568,7 @@ impl ObjInstance {
 
 impl GcSized for ObjInstance {
     fn size(&self) -> usize {
-        mem::size_of_val(&self.class) + self.fields.size()
+        mem::size_of::<Self>() + mem::size_of::<Value>() * self.fields.capacity()
     }
 }
 
@@ -487,7 +588,7 @@ pub(crate) struct ObjBoundMethod {
 impl ObjBoundMethod {
     /// Mark all object references that can be directly access by the current object.
     pub(crate) fn mark_references(&self, grey_objects: &mut Vec<Object>) {
-        if let Value::Object(o) = self.receiver {
+        if let Some(o) = self.receiver.as_object() {
             o.mark(grey_objects);
         }
         if self.method.mark() {
@@ -508,6 +609,113 @@ impl fmt::Display for ObjBoundMethod {
     }
 }
 
+/// The content of an heap-allocated list object.
+#[derive(Debug, Default)]
+pub(crate) struct ObjList {
+    pub(crate) items: Vec<Value>,
+}
+
+impl ObjList {
+    /// Mark all object references that can be directly access by the current object.
+    pub(crate) fn mark_references(&self, grey_objects: &mut Vec<Object>) {
+        for item in &self.items {
+            if let Some(obj) = item.as_object() {
+                obj.mark(grey_objects);
+            }
+        }
+    }
+}
+
+impl GcSized for ObjList {
+    fn size(&self) -> usize {
+        mem::size_of::<Self>() + mem::size_of::<Value>() * self.items.capacity()
+    }
+}
+
+impl fmt::Display for ObjList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_once(f, self as *const Self as usize, "[...]", |f| {
+            write!(f, "[")?;
+            for (i, item) in self.items.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write!(f, "{item}")?;
+            }
+            write!(f, "]")
+        })
+    }
+}
+
+/// The content of an heap-allocated map object.
+#[derive(Debug, Default)]
+pub(crate) struct ObjMap {
+    pub(crate) entries: Table<Value, Value>,
+}
+
+impl ObjMap {
+    /// Mark all object references that can be directly access by the current object.
+    pub(crate) fn mark_references(&self, grey_objects: &mut Vec<Object>) {
+        for (key, value) in self.entries.iter() {
+            if let Some(obj) = key.as_object() {
+                obj.mark(grey_objects);
+            }
+            if let Some(obj) = value.as_object() {
+                obj.mark(grey_objects);
+            }
+        }
+    }
+}
+
+impl GcSized for ObjMap {
+    fn size(&self) -> usize {
+        mem::size_of::<Self>() + self.entries.size()
+    }
+}
+
+impl fmt::Display for ObjMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_once(f, self as *const Self as usize, "{...}", |f| {
+            write!(f, "{{")?;
+            for (i, (key, value)) in self.entries.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write!(f, "{key}: {value}")?;
+            }
+            write!(f, "}}")
+        })
+    }
+}
+
+thread_local! {
+    /// The addresses of the containers that are being written, so a container that contains
+    /// itself isn't written forever.
+    static WRITING: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Write a container using the given function, or write `cycle` instead if the container at the
+/// given address is already being written further up.
+fn write_once<F>(f: &mut fmt::Formatter<'_>, addr: usize, cycle: &str, write: F) -> fmt::Result
+where
+    F: FnOnce(&mut fmt::Formatter<'_>) -> fmt::Result,
+{
+    let is_writing = WRITING.with(|writing| {
+        let mut writing = writing.borrow_mut();
+        if writing.contains(&addr) {
+            return true;
+        }
+        writing.push(addr);
+        false
+    });
+    if is_writing {
+        return f.write_str(cycle);
+    }
+    let result = write(f);
+    WRITING.with(|writing| writing.borrow_mut().pop());
+    result
+}
+
 pub trait GcSized {
     fn size(&self) -> usize;
 }
@@ -594,10 +802,22 @@ impl<T> Gc<T> {
         self.ptr.eq(&other.ptr)
     }
 
-    #[cfg(feature = "dbg-heap")]
     pub(crate) fn as_ptr(&self) -> *const GcData<T> {
         self.ptr.as_ptr()
     }
+
+    /// Recreate a reference from the address of its object.
+    ///
+    /// ## Safety
+    ///
+    /// `addr` must be the address of a live object of type `T`.
+    #[cfg(feature = "nan-boxing")]
+    pub(crate) unsafe fn from_addr(addr: usize) -> Self {
+        Self {
+            ptr: NonNull::new_unchecked(addr as *mut GcData<T>),
+            ptr_: PhantomData,
+        }
+    }
 }
 
 impl<T: GcSized> GcSized for Gc<T> {
@@ -617,9 +837,6 @@ impl<T> ops::Deref for Gc<T> {
 impl<T> Copy for Gc<T> {}
 impl<T> Clone for Gc<T> {
     fn clone(&self) -> Self {
-        Self {
-            ptr: self.ptr,
-            ptr_: self.ptr_,
-        }
+        *self
     }
 }
@@ -15,7 +15,7 @@
 /// are implementation details that we should keep in mind when making a real language.
 ///
 /// [IEEE 754]: https://en.wikipedia.org/wiki/IEEE_754
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
 #[repr(u8)]
 pub enum Opcode {
     /// Load a constant
@@ -100,6 +100,101 @@ pub enum Opcode {
     Inherit = 39,
     /// Define a method
     Method = 40,
+    /// Convert the value on top of the stack into a string
+    Stringify = 41,
+    /// Load a constant using a 24-bit constant index
+    ConstLong = 42,
+    /// Get the value of a global variable using a 24-bit slot index
+    GetGlobalLong = 43,
+    /// Set the value of a global variable using a 24-bit slot index
+    SetGlobalLong = 44,
+    /// Define a global variable using a 24-bit slot index
+    DefineGlobalLong = 45,
+    /// Get the value of a property using a 24-bit constant index
+    GetPropertyLong = 46,
+    /// Set the value of a property using a 24-bit constant index
+    SetPropertyLong = 47,
+    /// Get the super class method using a 24-bit constant index
+    GetSuperLong = 48,
+    /// Invoke a method using a 24-bit constant index
+    InvokeLong = 49,
+    /// Invoke a super class method using a 24-bit constant index
+    SuperInvokeLong = 50,
+    /// Add a new closure using a 24-bit constant index
+    ClosureLong = 51,
+    /// Create a class using a 24-bit constant index
+    ClassLong = 52,
+    /// Define a method using a 24-bit constant index
+    MethodLong = 53,
+    /// Get the value of a local variable using a 16-bit slot index
+    GetLocalLong = 54,
+    /// Set the value of a local variable using a 16-bit slot index
+    SetLocalLong = 55,
+    /// Get the value of an upvalue using a 16-bit slot index
+    GetUpvalueLong = 56,
+    /// Set the value of an upvalue using a 16-bit slot index
+    SetUpvalueLong = 57,
+    /// Define a constant global variable
+    DefineGlobalConst = 58,
+    /// Define a constant global variable using a 24-bit slot index
+    DefineGlobalConstLong = 59,
+    /// Create a list from the values on top of the stack
+    BuildList = 60,
+    /// Get the item at an index
+    GetIndex = 61,
+    /// Set the item at an index
+    SetIndex = 62,
+    /// Create a list from the values on top of the stack using a 16-bit count
+    BuildListLong = 63,
+    /// Create a map from the key-value pairs on top of the stack
+    BuildMap = 64,
+    /// Create a map from the key-value pairs on top of the stack using a 16-bit count
+    BuildMapLong = 65,
+    /// Install an exception handler for the current call frame
+    Try = 66,
+    /// Remove the innermost exception handler of the current call frame
+    PopHandler = 67,
+    /// Throw the value on top of the stack
+    Throw = 68,
+    /// Rethrow the pending exception at the end of a finally block
+    EndFinally = 69,
+    /// Load and run a module, then push its namespace
+    Import = 70,
+    /// Load and run a module using a 24-bit constant index
+    ImportLong = 71,
+    /// Copy the exported variables of an imported module into the current module
+    FinishImport = 72,
+}
+
+impl Opcode {
+    /// Get the variant of the instruction that takes a 24-bit constant or global index, or a
+    /// 16-bit local or upvalue index or count instead of a single byte. This method panics if the instruction doesn't take an
+    /// index as its operand.
+    pub(crate) fn long(self) -> Self {
+        match self {
+            Self::Const => Self::ConstLong,
+            Self::GetGlobal => Self::GetGlobalLong,
+            Self::SetGlobal => Self::SetGlobalLong,
+            Self::DefineGlobal => Self::DefineGlobalLong,
+            Self::DefineGlobalConst => Self::DefineGlobalConstLong,
+            Self::GetProperty => Self::GetPropertyLong,
+            Self::SetProperty => Self::SetPropertyLong,
+            Self::GetSuper => Self::GetSuperLong,
+            Self::Invoke => Self::InvokeLong,
+            Self::SuperInvoke => Self::SuperInvokeLong,
+            Self::Closure => Self::ClosureLong,
+            Self::Class => Self::ClassLong,
+            Self::Method => Self::MethodLong,
+            Self::Import => Self::ImportLong,
+            Self::GetLocal => Self::GetLocalLong,
+            Self::SetLocal => Self::SetLocalLong,
+            Self::GetUpvalue => Self::GetUpvalueLong,
+            Self::SetUpvalue => Self::SetUpvalueLong,
+            Self::BuildList => Self::BuildListLong,
+            Self::BuildMap => Self::BuildMapLong,
+            op => panic!("Opcode '{op:?}' has no long variant."),
+        }
+    }
 }
 
 impl From<Opcode> for u8 {
@@ -110,7 +205,14 @@ impl From<Opcode> for u8 {
 
 impl From<u8> for Opcode {
     fn from(byte: u8) -> Self {
-        match byte {
+        Self::decode(byte).unwrap_or_else(|| panic!("Unknown byte-code '{byte}'"))
+    }
+}
+
+impl Opcode {
+    /// Decode an opcode, returning `None` if the byte isn't a valid opcode.
+    pub(crate) fn decode(byte: u8) -> Option<Self> {
+        let opcode = match byte {
             0 => Opcode::Const,
             1 => Opcode::Nil,
             2 => Opcode::True,
@@ -152,7 +254,40 @@ impl From<u8> for Opcode {
             38 => Opcode::Class,
             39 => Opcode::Inherit,
             40 => Opcode::Method,
-            b => panic!("Unknown byte-code '{b}'"),
-        }
+            41 => Opcode::Stringify,
+            42 => Opcode::ConstLong,
+            43 => Opcode::GetGlobalLong,
+            44 => Opcode::SetGlobalLong,
+            45 => Opcode::DefineGlobalLong,
+            46 => Opcode::GetPropertyLong,
+            47 => Opcode::SetPropertyLong,
+            48 => Opcode::GetSuperLong,
+            49 => Opcode::InvokeLong,
+            50 => Opcode::SuperInvokeLong,
+            51 => Opcode::ClosureLong,
+            52 => Opcode::ClassLong,
+            53 => Opcode::MethodLong,
+            54 => Opcode::GetLocalLong,
+            55 => Opcode::SetLocalLong,
+            56 => Opcode::GetUpvalueLong,
+            57 => Opcode::SetUpvalueLong,
+            58 => Opcode::DefineGlobalConst,
+            59 => Opcode::DefineGlobalConstLong,
+            60 => Opcode::BuildList,
+            61 => Opcode::GetIndex,
+            62 => Opcode::SetIndex,
+            63 => Opcode::BuildListLong,
+            64 => Opcode::BuildMap,
+            65 => Opcode::BuildMapLong,
+            66 => Opcode::Try,
+            67 => Opcode::PopHandler,
+            68 => Opcode::Throw,
+            69 => Opcode::EndFinally,
+            70 => Opcode::Import,
+            71 => Opcode::ImportLong,
+            72 => Opcode::FinishImport,
+            _ => return None,
+        };
+        Some(opcode)
     }
 }
@@ -0,0 +1,639 @@
+//! An optimizer that rewrites the instructions of compiled functions.
+//!
+//! The instructions of a chunk are decoded into a list where jumps refer to the instructions they
+//! land on, so instructions can be added and removed without recomputing jump offsets. The
+//! following passes are run over the list until none of them changes it:
+//!
+//! - Operations whose operands are all literals are replaced by their results.
+//! - A negation that only decides which way a jump goes is removed by flipping the jump.
+//! - Jumps that land on other jumps are sent straight to where the other jumps go.
+//! - Instructions that can't be reached are removed, e.g. the ones after a return.
+//!
+//! The list is then encoded back into the chunk, keeping the source location of every instruction.
+//! Folding can make the code longer when a result needs a long constant index, so the chunk is
+//! left as it was if a jump no longer fits in its 16-bit offset.
+
+use std::mem;
+
+use crate::{
+    bytecode::{read_index, Layout},
+    chunk::{Chunk, MAX_CONSTANTS},
+    heap::Heap,
+    object::Object,
+    opcode::Opcode,
+    scan::Span,
+    value::{Unpacked, Value},
+};
+
+/// The max number of jumps followed when looking for where a jump ends up, so jumps that form a
+/// cycle don't keep the optimizer busy.
+const MAX_THREADING: usize = 16;
+
+/// Optimize the instructions of a chunk. Strings created by constant folding are interned in the
+/// given heap.
+pub(crate) fn optimize(chunk: &mut Chunk, heap: &mut Heap) {
+    let code = decode(chunk);
+    let mut optimizer = Optimizer {
+        constants: &mut chunk.constants,
+        heap,
+        code,
+    };
+    loop {
+        let mut changed = optimizer.fold_constants();
+        changed |= optimizer.remove_negations();
+        changed |= optimizer.thread_jumps();
+        changed |= optimizer.remove_unreachable();
+        if !changed {
+            break;
+        }
+    }
+    let code = optimizer.code;
+    encode(chunk, &code);
+}
+
+/// An instruction decoded from a chunk.
+#[derive(Debug)]
+struct Instruction {
+    opcode: Opcode,
+    /// The operand bytes. Jump offsets are not included, jumps refer to `target` instead.
+    operands: Vec<u8>,
+    /// The index of the instruction that a jump lands on, or where a handler starts for a try.
+    target: Option<usize>,
+    span: Span,
+}
+
+impl Instruction {
+    /// Get the number of bytes of the encoded instruction.
+    fn len(&self) -> usize {
+        let offset = if self.target.is_some() { 2 } else { 0 };
+        1 + self.operands.len() + offset
+    }
+
+    /// Create an instruction without operands.
+    fn simple(opcode: Opcode, span: Span) -> Self {
+        Self {
+            opcode,
+            operands: Vec::new(),
+            target: None,
+            span,
+        }
+    }
+
+    /// Check whether the instruction never continues with the one after it.
+    fn is_terminator(&self) -> bool {
+        matches!(
+            self.opcode,
+            Opcode::Ret | Opcode::Throw | Opcode::Jump | Opcode::Loop
+        )
+    }
+
+    /// Check whether the instruction jumps depending on the value on top of the stack.
+    fn is_conditional(&self) -> bool {
+        matches!(self.opcode, Opcode::JumpIfFalse | Opcode::JumpIfTrue)
+    }
+}
+
+/// The state of the optimizer while it's running its passes over a chunk.
+struct Optimizer<'a> {
+    constants: &'a mut Vec<Value>,
+    heap: &'a mut Heap,
+    code: Vec<Instruction>,
+}
+
+impl Optimizer<'_> {
+    /// Replace operations on literals with instructions loading their results. Operations that
+    /// would fail at runtime are left alone so their errors are still raised.
+    fn fold_constants(&mut self) -> bool {
+        let targets = self.targets();
+        let code = mem::take(&mut self.code);
+        let mut folded: Vec<Instruction> = Vec::with_capacity(code.len());
+        // Whether the instructions in `folded` are landed on by a jump.
+        let mut labels = Vec::with_capacity(code.len());
+        let mut indices = Vec::with_capacity(code.len() + 1);
+        let mut changed = false;
+        for (index, instruction) in code.into_iter().enumerate() {
+            indices.push(folded.len());
+            let operand_count = match instruction.opcode {
+                Opcode::Neg | Opcode::Not | Opcode::Stringify => 1,
+                Opcode::NE
+                | Opcode::EQ
+                | Opcode::GT
+                | Opcode::GE
+                | Opcode::LT
+                | Opcode::LE
+                | Opcode::Add
+                | Opcode::Sub
+                | Opcode::Mul
+                | Opcode::Div => 2,
+                _ => 0,
+            };
+            // The operands can only be folded if they're always run right before the operation.
+            let start = match folded.len().checked_sub(operand_count) {
+                Some(start) if operand_count > 0 && !targets[index] => start,
+                _ => {
+                    folded.push(instruction);
+                    labels.push(targets[index]);
+                    continue;
+                }
+            };
+            let load = if labels[start + 1..].contains(&true) {
+                None
+            } else {
+                folded[start..]
+                    .iter()
+                    .map(|operand| self.literal(operand))
+                    .collect::<Option<Vec<_>>>()
+                    .and_then(|operands| self.evaluate(instruction.opcode, &operands))
+                    .and_then(|value| self.load(value, instruction.span))
+            };
+            match load {
+                Some(load) => {
+                    folded.truncate(start + 1);
+                    folded[start] = load;
+                    labels.truncate(start + 1);
+                    changed = true;
+                }
+                None => {
+                    folded.push(instruction);
+                    labels.push(targets[index]);
+                }
+            }
+        }
+        indices.push(folded.len());
+        self.replace(folded, &indices);
+        changed
+    }
+
+    /// Remove a negation that is followed by a conditional jump whose condition is discarded on
+    /// both paths, and flip the jump instead.
+    fn remove_negations(&mut self) -> bool {
+        let targets = self.targets();
+        let mut removed = vec![false; self.code.len()];
+        let mut index = 0;
+        while index + 2 < self.code.len() {
+            let jump = &self.code[index + 1];
+            let discarded = |i: usize| self.code.get(i).map(|i| i.opcode) == Some(Opcode::Pop);
+            if self.code[index].opcode == Opcode::Not
+                && jump.is_conditional()
+                && !targets[index + 1]
+                && discarded(index + 2)
+                && jump.target.is_some_and(discarded)
+            {
+                let jump = &mut self.code[index + 1];
+                jump.opcode = match jump.opcode {
+                    Opcode::JumpIfFalse => Opcode::JumpIfTrue,
+                    _ => Opcode::JumpIfFalse,
+                };
+                removed[index] = true;
+                index += 2;
+            } else {
+                index += 1;
+            }
+        }
+        self.remove(&removed)
+    }
+
+    /// Send jumps that land on other jumps to the place where the other jumps go. A conditional
+    /// jump landing on another conditional jump knows which way the other one goes, because the
+    /// condition is still on the stack.
+    fn thread_jumps(&mut self) -> bool {
+        let offsets = offsets(&self.code);
+        let mut changed = false;
+        for index in 0..self.code.len() {
+            let jump = &self.code[index];
+            let Some(mut target) = jump.target else {
+                continue;
+            };
+            if jump.opcode == Opcode::Try {
+                continue;
+            }
+            for _ in 0..MAX_THREADING {
+                let Some(next) = self.code.get(target) else {
+                    break;
+                };
+                let next = match next.opcode {
+                    Opcode::Jump | Opcode::Loop => next.target,
+                    opcode if jump.is_conditional() && opcode == jump.opcode => next.target,
+                    _ if jump.is_conditional() && next.is_conditional() => Some(target + 1),
+                    _ => None,
+                };
+                let Some(next) = next else {
+                    break;
+                };
+                // Conditional jumps can only go forward, and no jump can go further than what
+                // its offset can hold.
+                let forward = next > index;
+                let distance = offsets[next].abs_diff(offsets[index] + 3);
+                if (jump.is_conditional() && !forward) || distance > u16::MAX as usize {
+                    break;
+                }
+                target = next;
+            }
+            if jump.target != Some(target) {
+                self.code[index].target = Some(target);
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Remove the instructions that can't be reached from the start of the chunk.
+    fn remove_unreachable(&mut self) -> bool {
+        let mut reached = vec![false; self.code.len()];
+        let mut pending = vec![0];
+        while let Some(index) = pending.pop() {
+            if index >= self.code.len() || reached[index] {
+                continue;
+            }
+            reached[index] = true;
+            let instruction = &self.code[index];
+            pending.extend(instruction.target);
+            if !instruction.is_terminator() {
+                pending.push(index + 1);
+            }
+        }
+        let removed: Vec<_> = reached.iter().map(|reached| !reached).collect();
+        self.remove(&removed)
+    }
+
+    /// Mark the instructions that are landed on by a jump. The list has an extra entry for the end
+    /// of the chunk.
+    fn targets(&self) -> Vec<bool> {
+        let mut targets = vec![false; self.code.len() + 1];
+        for target in self.code.iter().filter_map(|i| i.target) {
+            targets[target] = true;
+        }
+        targets
+    }
+
+    /// Remove the marked instructions. Jumps landing on a removed instruction land on the next
+    /// instruction that is kept.
+    fn remove(&mut self, removed: &[bool]) -> bool {
+        if !removed.contains(&true) {
+            return false;
+        }
+        let code = mem::take(&mut self.code);
+        let mut kept = Vec::with_capacity(code.len());
+        let mut indices = Vec::with_capacity(code.len() + 1);
+        for (instruction, removed) in code.into_iter().zip(removed) {
+            indices.push(kept.len());
+            if !removed {
+                kept.push(instruction);
+            }
+        }
+        indices.push(kept.len());
+        self.replace(kept, &indices);
+        true
+    }
+
+    /// Replace the instructions with the given ones, where `indices` maps the index of each old
+    /// instruction to the index of its replacement.
+    fn replace(&mut self, mut code: Vec<Instruction>, indices: &[usize]) {
+        for instruction in code.iter_mut() {
+            if let Some(target) = &mut instruction.target {
+                *target = indices[*target];
+            }
+        }
+        self.code = code;
+    }
+
+    /// Get the value loaded by an instruction if it loads a literal.
+    fn literal(&self, instruction: &Instruction) -> Option<Value> {
+        let width = match instruction.opcode {
+            Opcode::Nil => return Some(Value::NIL),
+            Opcode::True => return Some(Value::bool(true)),
+            Opcode::False => return Some(Value::bool(false)),
+            Opcode::Const => 1,
+            Opcode::ConstLong => 3,
+            _ => return None,
+        };
+        let value = self.constants[read_index(&instruction.operands, width)];
+        match value.unpack() {
+            Unpacked::Number(_) | Unpacked::Object(Object::String(_)) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Compute the result of an operation the same way the virtual machine does.
+    fn evaluate(&mut self, opcode: Opcode, operands: &[Value]) -> Option<Value> {
+        let value = match (opcode, operands) {
+            (Opcode::Neg, [v]) => (-v).ok()?,
+            (Opcode::Not, [v]) => !v,
+            (Opcode::Stringify, [v]) if v.as_string().is_ok() => *v,
+            (Opcode::Stringify, [v]) => {
+                Value::object(Object::String(self.heap.intern(v.to_string())))
+            }
+            (Opcode::Add, [lhs, rhs]) => match (lhs.as_string(), rhs.as_string()) {
+                (Ok(s1), Ok(s2)) => {
+                    let s = format!("{}{}", s1.data, s2.data);
+                    Value::object(Object::String(self.heap.intern(s)))
+                }
+                _ => (lhs + rhs).ok()?,
+            },
+            (Opcode::Sub, [lhs, rhs]) => (lhs - rhs).ok()?,
+            (Opcode::Mul, [lhs, rhs]) => (lhs * rhs).ok()?,
+            (Opcode::Div, [lhs, rhs]) => (lhs / rhs).ok()?,
+            (Opcode::NE, [lhs, rhs]) => Value::bool(lhs != rhs),
+            (Opcode::EQ, [lhs, rhs]) => Value::bool(lhs == rhs),
+            (Opcode::GT, [lhs, rhs]) => Value::bool(lhs.gt(rhs).ok()?),
+            (Opcode::GE, [lhs, rhs]) => Value::bool(lhs.ge(rhs).ok()?),
+            (Opcode::LT, [lhs, rhs]) => Value::bool(lhs.lt(rhs).ok()?),
+            (Opcode::LE, [lhs, rhs]) => Value::bool(lhs.le(rhs).ok()?),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    /// Create an instruction loading the given literal. Constants are shared with the ones that
+    /// are already in the chunk when possible.
+    fn load(&mut self, value: Value, span: Span) -> Option<Instruction> {
+        let index = match value.unpack() {
+            Unpacked::Nil => return Some(Instruction::simple(Opcode::Nil, span)),
+            Unpacked::Bool(true) => return Some(Instruction::simple(Opcode::True, span)),
+            Unpacked::Bool(false) => return Some(Instruction::simple(Opcode::False, span)),
+            unpacked => {
+                let existing = self
+                    .constants
+                    .iter()
+                    .position(|c| match (c.unpack(), unpacked) {
+                        // Zeros with different signs are equal, but they're different constants.
+                        (Unpacked::Number(n1), Unpacked::Number(n2)) => {
+                            n1.to_bits() == n2.to_bits()
+                        }
+                        (Unpacked::Object(Object::String(_)), _) => *c == value,
+                        _ => false,
+                    });
+                match existing {
+                    Some(index) => index,
+                    None if self.constants.len() < MAX_CONSTANTS => {
+                        self.constants.push(value);
+                        self.constants.len() - 1
+                    }
+                    None => return None,
+                }
+            }
+        };
+        let (opcode, operands) = if index <= u8::MAX as usize {
+            (Opcode::Const, vec![index as u8])
+        } else {
+            let bytes = (index as u32).to_be_bytes();
+            (Opcode::ConstLong, bytes[1..].to_vec())
+        };
+        Some(Instruction {
+            opcode,
+            operands,
+            target: None,
+            span,
+        })
+    }
+}
+
+/// Decode the instructions of a chunk produced by the compiler.
+fn decode(chunk: &Chunk) -> Vec<Instruction> {
+    let mut offsets = Vec::new();
+    let mut offset = 0;
+    while offset < chunk.instructions.len() {
+        offsets.push(offset);
+        let opcode = Opcode::from(chunk.instructions[offset]);
+        offset += 1 + Layout::of(opcode).operand_len(chunk, offset);
+    }
+    offsets
+        .iter()
+        .map(|&offset| {
+            let opcode = Opcode::from(chunk.instructions[offset]);
+            let span = chunk.get_span(offset);
+            let operand_len = Layout::of(opcode).operand_len(chunk, offset);
+            let operands = &chunk.instructions[offset + 1..offset + 1 + operand_len];
+            match Layout::of(opcode) {
+                Layout::Jump => {
+                    let jump = read_index(operands, 2);
+                    let target = match opcode {
+                        Opcode::Loop => offset + 3 - jump,
+                        _ => offset + 3 + jump,
+                    };
+                    Instruction {
+                        opcode,
+                        operands: Vec::new(),
+                        target: Some(offsets.partition_point(|&start| start < target)),
+                        span,
+                    }
+                }
+                _ => Instruction {
+                    opcode,
+                    operands: operands.to_vec(),
+                    target: None,
+                    span,
+                },
+            }
+        })
+        .collect()
+}
+
+/// Get the byte offsets of the given instructions once they're encoded. The list has an extra
+/// entry for the end of the chunk.
+fn offsets(code: &[Instruction]) -> Vec<usize> {
+    let mut offsets = Vec::with_capacity(code.len() + 1);
+    let mut offset = 0;
+    for instruction in code {
+        offsets.push(offset);
+        offset += instruction.len();
+    }
+    offsets.push(offset);
+    offsets
+}
+
+/// Encode the instructions into the chunk, replacing its previous instructions. The chunk isn't
+/// changed if a jump is too long to be encoded.
+fn encode(chunk: &mut Chunk, code: &[Instruction]) {
+    let offsets = offsets(code);
+    let jump = |index: usize, target: usize| offsets[target].abs_diff(offsets[index] + 3);
+    let fits = code.iter().enumerate().all(|(index, instruction)| {
+        instruction
+            .target
+            .is_none_or(|target| jump(index, target) <= u16::MAX as usize)
+    });
+    if !fits {
+        return;
+    }
+    chunk.instructions.clear();
+    chunk.spans.clear();
+    for (index, instruction) in code.iter().enumerate() {
+        let Some(target) = instruction.target else {
+            chunk.write(instruction.opcode, instruction.span);
+            for byte in &instruction.operands {
+                chunk.write_byte(*byte);
+            }
+            continue;
+        };
+        // Unconditional jumps might have been sent backward, or the other way around.
+        let opcode = match instruction.opcode {
+            Opcode::Jump | Opcode::Loop if target <= index => Opcode::Loop,
+            Opcode::Jump | Opcode::Loop => Opcode::Jump,
+            opcode => opcode,
+        };
+        let jump = jump(index, target) as u16;
+        chunk.write(opcode, instruction.span);
+        for byte in jump.to_be_bytes() {
+            chunk.write_byte(byte);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use crate::{
+        chunk::Chunk, compile::Parser, global::Globals, heap::Heap, module::Source, object::ObjFun,
+        opcode::Opcode, scan::Span, value::Value,
+    };
+
+    use super::{decode, encode, Instruction};
+
+    /// Compile the source with the optimizer, and give the script to the check.
+    fn optimized(src: &str, check: impl FnOnce(&ObjFun)) {
+        let mut heap = Heap::default();
+        let mut globals = Globals::default();
+        let namespace = globals.add_namespace();
+        let source = Rc::new(Source {
+            path: None,
+            text: String::from(src),
+        });
+        let script = Parser::new(&source, &mut heap, &mut globals, namespace)
+            .optimize(true)
+            .compile()
+            .expect("Source should compile.");
+        check(&script);
+    }
+
+    fn opcodes(fun: &ObjFun) -> Vec<Opcode> {
+        decode(&fun.chunk).iter().map(|i| i.opcode).collect()
+    }
+
+    #[test]
+    fn constant_expressions_are_folded() {
+        optimized(
+            r#"print -(1 + 2 * 3); print "a" + "b" + "${1 + 1}"; print !nil == (2 >= 1);"#,
+            |script| {
+                assert_eq!(
+                    vec![
+                        Opcode::Const,
+                        Opcode::Print,
+                        Opcode::Const,
+                        Opcode::Print,
+                        Opcode::True,
+                        Opcode::Print,
+                        Opcode::Nil,
+                        Opcode::Ret,
+                    ],
+                    opcodes(script)
+                );
+                let chunk = &script.chunk;
+                assert!(chunk.constants[chunk.instructions[1] as usize] == Value::number(-7.0));
+                assert_eq!(
+                    "ab2",
+                    chunk.constants[chunk.instructions[4] as usize].to_string()
+                );
+            },
+        );
+    }
+
+    #[test]
+    fn failing_or_unknown_operations_are_kept() {
+        optimized(r#"print -"a"; print 1 + "a"; print 1 < 0 / 0;"#, |script| {
+            let ops = opcodes(script);
+            assert!(ops.contains(&Opcode::Neg));
+            assert!(ops.contains(&Opcode::Add));
+            assert!(ops.contains(&Opcode::LT));
+            assert!(!ops.contains(&Opcode::Div));
+        });
+        // The right operand is landed on by the jump of 'or', so it doesn't always follow the
+        // left one.
+        optimized("print (nil or 1) + 2;", |script| {
+            assert!(opcodes(script).contains(&Opcode::Add));
+        });
+    }
+
+    #[test]
+    fn negated_conditions_flip_the_jump() {
+        optimized("var x; if (!x) print 1; while (!x) x = true;", |script| {
+            let ops = opcodes(script);
+            assert!(!ops.contains(&Opcode::Not));
+            assert!(!ops.contains(&Opcode::JumpIfFalse));
+        });
+        // The result of 'and' is the negated value, so it must be kept.
+        optimized("var x; print !x and x;", |script| {
+            assert!(opcodes(script).contains(&Opcode::Not));
+        });
+    }
+
+    #[test]
+    fn jumps_to_jumps_are_threaded() {
+        optimized(
+            "var a; var b; var c; if (a and b and c) print 1;",
+            |script| {
+                let code = decode(&script.chunk);
+                let targets: Vec<_> = code
+                    .iter()
+                    .filter(|i| i.opcode == Opcode::JumpIfFalse)
+                    .map(|i| i.target)
+                    .collect();
+                assert_eq!(3, targets.len());
+                // Every failed condition goes straight to the else branch.
+                assert!(targets.iter().all(|t| *t == targets[2]));
+            },
+        );
+    }
+
+    #[test]
+    fn unreachable_code_is_removed() {
+        optimized(
+            "fun f(x) { if (x) { return 1; } else { return 2; } print 3; }",
+            |script| {
+                let f = script.chunk.constants[0].as_fun().unwrap();
+                assert_eq!(
+                    vec![
+                        Opcode::GetLocal,
+                        Opcode::JumpIfFalse,
+                        Opcode::Pop,
+                        Opcode::Const,
+                        Opcode::Ret,
+                        Opcode::Pop,
+                        Opcode::Const,
+                        Opcode::Ret,
+                    ],
+                    opcodes(&f)
+                );
+            },
+        );
+    }
+
+    #[test]
+    fn chunk_is_kept_when_jumps_get_too_long() {
+        let mut chunk = Chunk::default();
+        chunk.write(Opcode::Nil, Span::default());
+        chunk.write(Opcode::Ret, Span::default());
+        let before = chunk.instructions.clone();
+
+        // Folding results that need long constant indices can push the target of a jump that
+        // was close to the limit out of reach.
+        let loads = 20_000;
+        let mut code = vec![Instruction {
+            opcode: Opcode::Jump,
+            operands: Vec::new(),
+            target: Some(loads + 1),
+            span: Span::default(),
+        }];
+        for _ in 0..loads {
+            code.push(Instruction {
+                opcode: Opcode::ConstLong,
+                operands: vec![0, 1, 0],
+                target: None,
+                span: Span::default(),
+            });
+        }
+        code.push(Instruction::simple(Opcode::Ret, Span::default()));
+        encode(&mut chunk, &code);
+        assert_eq!(before, chunk.instructions);
+    }
+}
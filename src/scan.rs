@@ -1,35 +1,56 @@
 use std::{error, fmt, ops};
 
-/// An enumeration of all the potential errors occur while scanning.
+/// An enumeration of all the potential errors occur while scanning. Each error holds the token
+/// containing the characters that can't be scanned.
 #[derive(Debug)]
-pub enum ScanError {
+pub enum ScanError<'src> {
     /// A string literal is unterminated.
-    UnterminatedString(Line),
+    UnterminatedString(Token<'src>),
     /// Encounter an unexpected character while scanning.
-    UnexpectedCharacter(Line),
+    UnexpectedCharacter(Token<'src>),
 }
 
-impl error::Error for ScanError {}
+impl<'src> ScanError<'src> {
+    /// Get the token containing the characters that can't be scanned.
+    pub(crate) fn token(&self) -> Token<'src> {
+        match self {
+            Self::UnterminatedString(token) | Self::UnexpectedCharacter(token) => *token,
+        }
+    }
+}
 
-impl fmt::Display for ScanError {
+impl error::Error for ScanError<'_> {}
+
+impl fmt::Display for ScanError<'_> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::UnterminatedString(line) => write!(f, "{line} Error: Unterminated string."),
-            Self::UnexpectedCharacter(line) => write!(f, "{line} Error: Unexpected character."),
+            Self::UnterminatedString(_) => f.write_str("Unterminated string."),
+            Self::UnexpectedCharacter(_) => f.write_str("Unexpected character."),
         }
     }
 }
 
 /// Scanner reads characters from the source code and groups them in to a sequence of tokens.
+#[derive(Debug, Clone)]
 pub(crate) struct Scanner<'src> {
     /// The original source string used when we need to make references for the tokens' lexeme.
     src: &'src str,
     /// The number of the current line through which the scanner is going.
     line: Line,
+    /// The byte position of the start of the current line.
+    line_head: usize,
+    /// The line in which the current lexeme starts.
+    lexeme_line: Line,
+    /// The column at which the current lexeme starts, counted in characters from 1.
+    lexeme_column: usize,
     /// The first byte postition of a lexeme.
     lexeme_head: usize,
     /// The last byte postition of a lexeme.
     lexeme_tail: usize,
+    /// A stack of interpolated expressions that have not been closed. Each item counts the
+    /// number of unmatched '{' within the interpolated expression, so we know which '}' marks
+    /// the end of the expression.
+    interpolations: Vec<usize>,
 }
 
 impl<'src> Scanner<'src> {
@@ -38,22 +59,27 @@ impl<'src> Scanner<'src> {
         Self {
             src,
             line: Line::default(),
+            line_head: 0,
+            lexeme_line: Line::default(),
+            lexeme_column: 1,
             lexeme_head: 0,
             lexeme_tail: 0,
+            interpolations: Vec::new(),
         }
     }
 
     /// Consume and return the next token from source. When there's no token left, subsequent calls
     /// will always return the EOF token.
-    pub(crate) fn scan(&mut self) -> Result<Token<'src>, ScanError> {
+    pub(crate) fn scan(&mut self) -> Result<Token<'src>, ScanError<'src>> {
         self.skip_whitespace();
         let c = match self.advance() {
             None => {
-                return Ok(Token {
-                    kind: Kind::Eof,
-                    line: self.line,
-                    lexeme: "",
-                });
+                if !self.interpolations.is_empty() {
+                    // The string literal containing the interpolated expression was not closed.
+                    self.interpolations.clear();
+                    return Err(ScanError::UnterminatedString(self.make_token(Kind::Error)));
+                }
+                return Ok(self.make_token(Kind::Eof));
             }
             Some(c) => c,
         };
@@ -61,9 +87,29 @@ impl<'src> Scanner<'src> {
         let token = match c {
             b'(' => self.make_token(Kind::LParen),
             b')' => self.make_token(Kind::RParen),
-            b'{' => self.make_token(Kind::LBrace),
-            b'}' => self.make_token(Kind::RBrace),
+            b'{' => {
+                if let Some(braces) = self.interpolations.last_mut() {
+                    *braces += 1;
+                }
+                self.make_token(Kind::LBrace)
+            }
+            b'[' => self.make_token(Kind::LBracket),
+            b']' => self.make_token(Kind::RBracket),
+            b'}' => match self.interpolations.last_mut() {
+                // This brace ends the interpolated expression, continue with the rest of the
+                // string literal.
+                Some(0) => {
+                    self.interpolations.pop();
+                    self.string()?
+                }
+                Some(braces) => {
+                    *braces -= 1;
+                    self.make_token(Kind::RBrace)
+                }
+                None => self.make_token(Kind::RBrace),
+            },
             b';' => self.make_token(Kind::Semicolon),
+            b':' => self.make_token(Kind::Colon),
             b',' => self.make_token(Kind::Comma),
             b'.' => self.make_token(Kind::Dot),
             b'-' => self.make_token(Kind::Minus),
@@ -80,6 +126,8 @@ impl<'src> Scanner<'src> {
             b'=' => {
                 if self.consume(b'=') {
                     self.make_token(Kind::EqualEqual)
+                } else if self.consume(b'>') {
+                    self.make_token(Kind::Arrow)
                 } else {
                     self.make_token(Kind::Equal)
                 }
@@ -102,7 +150,11 @@ impl<'src> Scanner<'src> {
             n if Self::is_digit(n) => self.number(),
             c if Self::is_valid_ident(c) => self.identity(),
             _ => {
-                return Err(ScanError::UnexpectedCharacter(self.line));
+                // Include the rest of the character if it takes more than one byte.
+                while self.peek_check(|c| c & 0b1100_0000 == 0b1000_0000) {
+                    self.advance();
+                }
+                return Err(ScanError::UnexpectedCharacter(self.make_token(Kind::Error)));
             }
         };
 
@@ -117,19 +169,31 @@ impl<'src> Scanner<'src> {
         // Make token based on the characters that we've consumed.
         let kind = match &self.src[self.lexeme_head..self.lexeme_tail] {
             "and" => Kind::And,
+            "break" => Kind::Break,
+            "case" => Kind::Case,
+            "catch" => Kind::Catch,
             "class" => Kind::Class,
+            "const" => Kind::Const,
+            "continue" => Kind::Continue,
+            "default" => Kind::Default,
             "else" => Kind::Else,
+            "export" => Kind::Export,
             "false" => Kind::False,
+            "finally" => Kind::Finally,
             "for" => Kind::For,
             "fun" => Kind::Fun,
             "if" => Kind::If,
+            "import" => Kind::Import,
             "nil" => Kind::Nil,
             "or" => Kind::Or,
             "print" => Kind::Print,
             "return" => Kind::Return,
             "super" => Kind::Super,
+            "switch" => Kind::Switch,
             "this" => Kind::This,
+            "throw" => Kind::Throw,
             "true" => Kind::True,
+            "try" => Kind::Try,
             "var" => Kind::Var,
             "while" => Kind::While,
             _ => Kind::Ident,
@@ -152,18 +216,31 @@ impl<'src> Scanner<'src> {
         self.make_token(Kind::Number)
     }
 
-    fn string(&mut self) -> Result<Token<'src>, ScanError> {
-        // Go through all characters that are not a double-quote.
-        while self.peek_check(|c| c != b'"') {
+    fn string(&mut self) -> Result<Token<'src>, ScanError<'src>> {
+        // Go through all characters until we find a double-quote or the start of an
+        // interpolated expression.
+        while self.peek_check(|c| c != b'"')
+            && !(self.peek_check(|c| c == b'$') && self.peek_next_check(|c| c == b'{'))
+        {
             self.advance();
         }
-        // Check if we reach EOF or an actual double-quote.
-        if self.peek().is_none() {
-            return Err(ScanError::UnterminatedString(self.line));
+        match self.peek() {
+            // Reach EOF without finding the end of the string.
+            None => Err(ScanError::UnterminatedString(self.make_token(Kind::Error))),
+            // Consume the terminating double-quote.
+            Some(b'"') => {
+                self.advance();
+                Ok(self.make_token(Kind::String))
+            }
+            // Consume the '${' and let the scanner produce the tokens of the interpolated
+            // expression until its closing '}' is found.
+            Some(_) => {
+                self.advance();
+                self.advance();
+                self.interpolations.push(0);
+                Ok(self.make_token(Kind::Interpolation))
+            }
         }
-        // Consume the terminating double-quote.
-        self.advance();
-        Ok(self.make_token(Kind::String))
     }
 
     fn skip_whitespace(&mut self) {
@@ -187,6 +264,8 @@ impl<'src> Scanner<'src> {
             }
         }
         self.lexeme_head = self.lexeme_tail;
+        self.lexeme_line = self.line;
+        self.lexeme_column = self.src[self.line_head..self.lexeme_head].chars().count() + 1;
     }
 
     /// Return the result of applying the given function to the next character in the iterator
@@ -217,6 +296,7 @@ impl<'src> Scanner<'src> {
             self.lexeme_tail += 1;
             if c == b'\n' {
                 self.line += 1;
+                self.line_head = self.lexeme_tail;
             }
             c
         })
@@ -239,7 +319,9 @@ impl<'src> Scanner<'src> {
         Token {
             kind,
             lexeme: &self.src[self.lexeme_head..self.lexeme_tail],
-            line: self.line,
+            line: self.lexeme_line,
+            column: self.lexeme_column,
+            offset: self.lexeme_head,
         }
     }
 
@@ -259,8 +341,12 @@ impl<'src> Scanner<'src> {
 pub(crate) struct Token<'src> {
     /// The kind of token.
     pub(crate) kind: Kind,
-    /// The line in which this token was found.
+    /// The line in which this token starts.
     pub(crate) line: Line,
+    /// The column at which this token starts, counted in characters from 1.
+    pub(crate) column: usize,
+    /// The byte position at which this token starts.
+    pub(crate) offset: usize,
     /// The string segment in source that corresponds to this token.
     pub(crate) lexeme: &'src str,
 }
@@ -271,9 +357,34 @@ impl<'src> Token<'src> {
         Self {
             kind: Kind::Eof,
             line: Line::default(),
+            column: 1,
+            offset: 0,
             lexeme: "",
         }
     }
+
+    /// Get the location of the token in the source code.
+    pub(crate) fn span(&self) -> Span {
+        let narrow = |n: usize| u32::try_from(n).unwrap_or(u32::MAX);
+        Span {
+            line: narrow(*self.line),
+            column: narrow(self.column),
+            offset: narrow(self.offset),
+            len: narrow(self.lexeme.len()),
+        }
+    }
+
+    /// Create an identifier token that doesn't appear in the source, placed at the position of
+    /// the given token.
+    pub(crate) fn synthetic(lexeme: &'src str, at: Token<'_>) -> Self {
+        Self {
+            kind: Kind::Ident,
+            line: at.line,
+            column: at.column,
+            offset: at.offset,
+            lexeme,
+        }
+    }
 }
 
 /// Lox token types
@@ -287,8 +398,14 @@ pub(crate) enum Kind {
     LBrace,
     /// Single character '}'
     RBrace,
+    /// Single character '['
+    LBracket,
+    /// Single character ']'
+    RBracket,
     /// Single character ';'
     Semicolon,
+    /// Single character ':'
+    Colon,
     /// Single character ','
     Comma,
     /// Single character '.'
@@ -309,6 +426,8 @@ pub(crate) enum Kind {
     Equal,
     /// Double character '=='
     EqualEqual,
+    /// Double character '=>'
+    Arrow,
     /// Single character '>'
     Greater,
     /// Double character '>='
@@ -319,24 +438,44 @@ pub(crate) enum Kind {
     LessEqual,
     /// Named entity
     Ident,
-    /// String literal
+    /// String literal, or the last segment of a string literal containing interpolations
     String,
+    /// A segment of a string literal that is followed by an interpolated expression
+    Interpolation,
     /// Number literal
     Number,
     /// Keyword 'and'
     And,
+    /// Keyword 'break'
+    Break,
+    /// Keyword 'case'
+    Case,
+    /// Keyword 'catch'
+    Catch,
     /// Keyword 'class'
     Class,
+    /// Keyword 'const'
+    Const,
+    /// Keyword 'continue'
+    Continue,
+    /// Keyword 'default'
+    Default,
     /// Keyword 'else'
     Else,
+    /// Keyword 'export'
+    Export,
     /// Boolean literal 'false'
     False,
+    /// Keyword 'finally'
+    Finally,
     /// Keyword 'for'
     For,
     /// Keyword 'fun'
     Fun,
     /// Keyword 'if'
     If,
+    /// Keyword 'import'
+    Import,
     /// Nothing literal 'nil'
     Nil,
     /// Keyword 'or'
@@ -347,18 +486,40 @@ pub(crate) enum Kind {
     Return,
     /// Keyword 'super'
     Super,
+    /// Keyword 'switch'
+    Switch,
     /// Keyword 'this'
     This,
+    /// Keyword 'throw'
+    Throw,
     /// Boolean literal 'true'
     True,
+    /// Keyword 'try'
+    Try,
     /// Keyword 'var'
     Var,
     /// Keyword 'while'
     While,
+    /// Special token for characters that can't be scanned
+    Error,
     /// Special token for indicating end-of-file
     Eof,
 }
 
+/// The location of a lexeme in the source code. The fields are narrowed to 32 bits so the debug
+/// information of a chunk stays small.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Span {
+    /// The line in which the lexeme starts, counted from 1.
+    pub(crate) line: u32,
+    /// The column at which the lexeme starts, counted in characters from 1.
+    pub(crate) column: u32,
+    /// The byte position at which the lexeme starts.
+    pub(crate) offset: u32,
+    /// The length of the lexeme in bytes.
+    pub(crate) len: u32,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Line(usize);
 
@@ -0,0 +1,231 @@
+//! Implementation of the shapes describing where the fields of class instances are stored.
+
+use std::{cell::RefCell, rc::Rc};
+
+use crate::{object::RefString, table::Table};
+
+/// The shape of the instances that don't have any field.
+pub(crate) const EMPTY_SHAPE: usize = 0;
+
+/// The layout of the fields of an instance.
+#[derive(Debug, Default)]
+struct Shape {
+    /// The shape that this shape was reached from and the name of the field that was added, or
+    /// `None` for the empty shape and for the shapes that were freed.
+    parent: Option<(usize, RefString)>,
+    /// The number of fields of the instances of this shape.
+    len: usize,
+    /// The slot of each field. The table is shared with the shapes reached by adding fields one
+    /// after another, so a shape only owns the entries whose slot is less than its number of
+    /// fields.
+    slots: Rc<RefCell<Table<usize>>>,
+    /// The shapes reached by adding a field, keyed by the name of the field.
+    transitions: Table<usize>,
+    /// The flag for whether the shape is still used by an instance or an inline cache.
+    is_marked: bool,
+}
+
+/// The shapes of all instances created by a virtual machine. Instances that were given the same
+/// fields in the same order share the same shape, so a field is found at the same slot in each
+/// of them. Shapes are identified by their index, and the index of a shape that is no longer used
+/// is given to the next new shape after a garbage collection.
+#[derive(Debug)]
+pub(crate) struct Shapes {
+    shapes: Vec<Shape>,
+    /// The indices of the shapes that were freed.
+    free: Vec<usize>,
+}
+
+impl Default for Shapes {
+    fn default() -> Self {
+        Self {
+            shapes: vec![Shape::default()],
+            free: Vec::new(),
+        }
+    }
+}
+
+impl Shapes {
+    /// Get the slot of the field with the given name in instances of the given shape.
+    pub(crate) fn slot(&self, shape: usize, name: RefString) -> Option<usize> {
+        let shape = &self.shapes[shape];
+        let slot = *shape.slots.borrow().get(name)?;
+        (slot < shape.len).then_some(slot)
+    }
+
+    /// Get the shape of the instances of the given shape after a field with the given name is
+    /// added. The field is stored right after the existing fields. A new shape is added if the
+    /// transition hasn't been seen before.
+    pub(crate) fn transition(&mut self, shape: usize, name: RefString) -> usize {
+        if let Some(next) = self.shapes[shape].transitions.get(name) {
+            return *next;
+        }
+        let len = self.shapes[shape].len;
+        let shared = &self.shapes[shape].slots;
+        // The table can only be extended by the shape with the most fields among the ones sharing
+        // it. The other shapes copy the entries that they own.
+        let slots = if shared.borrow().len() == len {
+            Rc::clone(shared)
+        } else {
+            let mut slots = Table::default();
+            for (field, slot) in shared.borrow().iter().filter(|(_, slot)| **slot < len) {
+                slots.set(field, *slot);
+            }
+            Rc::new(RefCell::new(slots))
+        };
+        slots.borrow_mut().set(name, len);
+        let next = Shape {
+            parent: Some((shape, name)),
+            len: len + 1,
+            slots,
+            transitions: Table::default(),
+            is_marked: false,
+        };
+        let next = match self.free.pop() {
+            Some(index) => {
+                self.shapes[index] = next;
+                index
+            }
+            None => {
+                self.shapes.push(next);
+                self.shapes.len() - 1
+            }
+        };
+        self.shapes[shape].transitions.set(name, next);
+        next
+    }
+
+    /// Mark a shape as used, along with the shapes that it was reached from.
+    pub(crate) fn mark(&mut self, shape: usize) {
+        let mut next = Some(shape);
+        while let Some(index) = next {
+            let shape = &mut self.shapes[index];
+            if shape.is_marked {
+                break;
+            }
+            shape.is_marked = true;
+            next = shape.parent.map(|(parent, _)| parent);
+        }
+    }
+
+    /// Free the shapes that weren't marked, and unmark the others. The empty shape is never freed.
+    /// The names of the fields of the freed shapes are removed from the tables of the remaining
+    /// shapes, so they can be freed along with the shapes.
+    pub(crate) fn sweep(&mut self) {
+        self.shapes[EMPTY_SHAPE].is_marked = true;
+        for index in 0..self.shapes.len() {
+            if self.shapes[index].is_marked {
+                self.shapes[index].is_marked = false;
+                continue;
+            }
+            let Some((parent, name)) = self.shapes[index].parent else {
+                // The shape was already freed.
+                continue;
+            };
+            let shape = std::mem::take(&mut self.shapes[index]);
+            shape.slots.borrow_mut().del(name);
+            // A parent that is being freed doesn't need its transitions anymore.
+            self.shapes[parent].transitions.del(name);
+            self.free.push(index);
+        }
+    }
+
+    /// Return an iterator over the names of the fields in all shapes.
+    pub(crate) fn names(&self) -> impl Iterator<Item = RefString> + '_ {
+        self.shapes
+            .iter()
+            .filter_map(|shape| shape.parent.map(|(_, name)| name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::heap::Heap;
+
+    use super::{Shapes, EMPTY_SHAPE};
+
+    #[test]
+    fn fields_added_in_the_same_order_share_a_shape() {
+        let mut heap = Heap::default();
+        let mut shapes = Shapes::default();
+        let x = heap.intern(String::from("x"));
+        let y = heap.intern(String::from("y"));
+
+        let with_x = shapes.transition(EMPTY_SHAPE, x);
+        let xy = shapes.transition(with_x, y);
+        assert_eq!(with_x, shapes.transition(EMPTY_SHAPE, x));
+        assert_eq!(xy, shapes.transition(with_x, y));
+        assert_eq!(Some(0), shapes.slot(xy, x));
+        assert_eq!(Some(1), shapes.slot(xy, y));
+
+        let with_y = shapes.transition(EMPTY_SHAPE, y);
+        let yx = shapes.transition(with_y, x);
+        assert_ne!(xy, yx);
+        assert_eq!(Some(1), shapes.slot(yx, x));
+        assert_eq!(Some(0), shapes.slot(yx, y));
+        assert_eq!(None, shapes.slot(EMPTY_SHAPE, x));
+    }
+
+    #[test]
+    fn shapes_added_one_field_after_another_share_their_slots() {
+        let mut heap = Heap::default();
+        let mut shapes = Shapes::default();
+        let names: Vec<_> = (0..1000)
+            .map(|i| heap.intern(format!("field{i}")))
+            .collect();
+
+        let mut chain = vec![EMPTY_SHAPE];
+        for name in &names {
+            chain.push(shapes.transition(chain[chain.len() - 1], *name));
+        }
+        let last = chain[names.len()];
+        assert!(chain.iter().all(|shape| std::rc::Rc::ptr_eq(
+            &shapes.shapes[*shape].slots,
+            &shapes.shapes[last].slots
+        )));
+        for (slot, name) in names.iter().enumerate() {
+            assert_eq!(Some(slot), shapes.slot(last, *name));
+        }
+        let middle = chain[names.len() / 2];
+        assert_eq!(None, shapes.slot(middle, names[names.len() - 1]));
+        assert_eq!(Some(0), shapes.slot(middle, names[0]));
+
+        // Branching off from a shape in the middle doesn't change the shapes after it.
+        let other = heap.intern(String::from("other"));
+        let branch = shapes.transition(middle, other);
+        assert_eq!(Some(names.len() / 2), shapes.slot(branch, other));
+        assert_eq!(None, shapes.slot(last, other));
+        assert_eq!(
+            Some(names.len() - 1),
+            shapes.slot(last, names[names.len() - 1])
+        );
+    }
+
+    #[test]
+    fn unmarked_shapes_are_freed() {
+        let mut heap = Heap::default();
+        let mut shapes = Shapes::default();
+        let x = heap.intern(String::from("x"));
+        let y = heap.intern(String::from("y"));
+        let z = heap.intern(String::from("z"));
+
+        let with_x = shapes.transition(EMPTY_SHAPE, x);
+        let xy = shapes.transition(with_x, y);
+        let xz = shapes.transition(with_x, z);
+        let with_y = shapes.transition(EMPTY_SHAPE, y);
+        shapes.mark(xz);
+        shapes.sweep();
+        let names: Vec<_> = shapes.names().collect();
+        assert_eq!(2, names.len());
+        assert!(names[0].ptr_eq(&x) && names[1].ptr_eq(&z));
+        assert_eq!(None, shapes.slot(with_x, y));
+        assert_eq!(Some(1), shapes.slot(xz, z));
+
+        // The indices of the freed shapes are reused, and the marks were cleared.
+        let reused = shapes.transition(with_x, y);
+        assert!(reused == xy || reused == with_y);
+        assert_eq!(Some(1), shapes.slot(reused, y));
+        shapes.sweep();
+        assert_eq!(0, shapes.names().count());
+    }
+}
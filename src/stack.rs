@@ -1,6 +1,6 @@
 //! Implementation of a simple static stack structure.
 
-use std::mem::{self, MaybeUninit};
+use std::mem::MaybeUninit;
 
 #[derive(Debug)]
 pub(crate) struct Stack<T, const N: usize> {
@@ -23,29 +23,11 @@ impl<T, const N: usize> Stack<T, N> {
         unsafe { self.items[self.len].assume_init_read() }
     }
 
-    /// Remove `count` values from the top of the stack. This method only adjusts the stack pointer
-    /// and makes no modification to the underlying data array.
-    pub(crate) fn remove(&mut self, count: usize) {
-        self.len -= count;
-    }
-
-    /// Remove all values from the stack. This method only adjusts the stack pointer and makes no
-    /// modification to the underlying data array.
-    pub(crate) fn clear(&mut self) {
-        self.len = 0;
-    }
-
     /// Returns the number of values contained within the stack.
     pub(crate) fn len(&self) -> usize {
         self.len
     }
 
-    /// Get a slice of `count` values at the top of the stack.
-    pub(crate) fn topn(&self, count: usize) -> &[T] {
-        // SAFETY: All items at index below self.len must have been initialized
-        unsafe { mem::transmute(&self.items[self.len - count..self.len]) }
-    }
-
     /// Get a reference to the value at the top of the stack.
     pub(crate) fn top(&self, n: usize) -> &T {
         // SAFETY: All items at index below self.len must have been initialized
@@ -1,17 +1,37 @@
 use std::{alloc, marker::PhantomData, mem, ops::Mul, ptr::NonNull};
 
-use crate::object::{Gc, RefString, GcSized};
+use crate::object::{Gc, GcSized, RefString};
 
-/// A hash table mapping from `RefString` to `V`. Conflicting keys are resolved using linear probing.
+/// A type that can be used as the key of a `Table`.
+pub(crate) trait TableKey: Copy {
+    /// Get the hash of the key.
+    fn hash(&self) -> u32;
+
+    /// Check whether the two keys are the same.
+    fn same(&self, other: &Self) -> bool;
+}
+
+impl TableKey for RefString {
+    fn hash(&self) -> u32 {
+        self.hash
+    }
+
+    fn same(&self, other: &Self) -> bool {
+        // Strings are interned, so they are equal iff they are the same object.
+        Gc::ptr_eq(self, other)
+    }
+}
+
+/// A hash table mapping from `K` to `V`. Conflicting keys are resolved using linear probing.
 #[derive(Debug)]
-pub(crate) struct Table<V> {
-    ptr: NonNull<Entry<V>>,
+pub(crate) struct Table<V, K = RefString> {
+    ptr: NonNull<Entry<K, V>>,
     capacity: usize,
     occupants: usize,
     tombstones: usize,
 }
 
-impl<V> Table<V> {
+impl<V, K: TableKey> Table<V, K> {
     /// Get the number of entries that are currently stored in the table.
     pub(crate) fn len(&self) -> usize {
         self.occupants
@@ -19,7 +39,7 @@ impl<V> Table<V> {
 
     /// Set the value associated with the given key.
     /// If the key is already present, the previous value is returned.
-    pub(crate) fn set(&mut self, key: RefString, val: V) -> Option<V> {
+    pub(crate) fn set(&mut self, key: K, val: V) -> Option<V> {
         if self.occupants + self.tombstones >= self.capacity * 3 / 4 {
             self.resize();
         }
@@ -40,7 +60,7 @@ impl<V> Table<V> {
 
     // Get the value associated with the given key.
     // If the key is not present, `None` is returned.
-    pub(crate) fn get(&self, key: RefString) -> Option<&V> {
+    pub(crate) fn get(&self, key: K) -> Option<&V> {
         if self.occupants == 0 {
             return None;
         }
@@ -53,7 +73,7 @@ impl<V> Table<V> {
 
     // Delete the value associated with the given key and return it.
     // If the key is not present, `None` is returned.
-    pub(crate) fn del(&mut self, key: RefString) -> Option<V> {
+    pub(crate) fn del(&mut self, key: K) -> Option<V> {
         if self.occupants == 0 {
             return None;
         }
@@ -67,33 +87,8 @@ impl<V> Table<V> {
         }
     }
 
-    /// Find the pointer to the key that matches the given string and hash.
-    // If no key matches, `None` is returned.
-    pub(crate) fn find(&self, s: &str, hash: u32) -> Option<RefString> {
-        if self.occupants == 0 {
-            return None;
-        }
-        let mut index = hash as usize & (self.capacity - 1);
-        loop {
-            // SAFETY: `index` is always less than `self.capacity` because `index = x mod self.capacity`,
-            // where `x` is an arbitrary integer value.
-            let entry = unsafe { &*self.ptr.as_ptr().add(index) };
-            match &entry {
-                Entry::Vaccant => return None,
-                Entry::Occupied(e) => {
-                    if e.key.data == s {
-                        return Some(e.key);
-                    }
-                }
-                _ => {}
-            }
-            // Linear probing.
-            index = (index + 1) & (self.capacity - 1);
-        }
-    }
-
     /// Get the iterator over all entries in the table.
-    pub fn iter(&self) -> TableIter<'_, V> {
+    pub fn iter(&self) -> TableIter<'_, V, K> {
         TableIter {
             ptr: self.ptr,
             ptr_: PhantomData,
@@ -103,27 +98,27 @@ impl<V> Table<V> {
     }
 
     /// Get a shared reference the entry associated with the given key.
-    fn find_entry(&self, key: RefString) -> &Entry<V> {
+    fn find_entry(&self, key: K) -> &Entry<K, V> {
         // SAFETY: We make sure `probe` always returns a valid and initialized pointer.
         unsafe { &*self.probe(key) }
     }
 
     /// Get an exclusive reference the entry associated with the given key.
-    fn find_entry_mut(&mut self, key: RefString) -> &mut Entry<V> {
+    fn find_entry_mut(&mut self, key: K) -> &mut Entry<K, V> {
         // SAFETY: We make sure `probe` always returns a valid and initialized pointer.
         unsafe { &mut *self.probe(key) }
     }
 
     /// Find the pointer to the entry associated with the given key. If the 2 different keys
     /// have the same hash, linear probing is used to find the correct entry.
-    fn probe(&self, key: RefString) -> *mut Entry<V> {
+    fn probe(&self, key: K) -> *mut Entry<K, V> {
         let mut tombstone = None;
-        let mut index = key.hash as usize & (self.capacity - 1);
+        let mut index = key.hash() as usize & (self.capacity - 1);
         loop {
             // SAFETY: `index` is always less than `self.capacity` because `index = x mod self.capacity`,
             // where `x` is an arbitrary integer value.
             let entry_ptr = unsafe { self.ptr.as_ptr().add(index) };
-            // SAFETY: `entry_ptr` is always a valid pointer to an initialized `Entry<V>`.
+            // SAFETY: `entry_ptr` is always a valid pointer to an initialized `Entry<K, V>`.
             let entry = unsafe { &*entry_ptr };
             match &entry {
                 Entry::Vaccant => {
@@ -138,7 +133,7 @@ impl<V> Table<V> {
                     }
                 }
                 Entry::Occupied(e) => {
-                    if Gc::ptr_eq(&e.key, &key) {
+                    if e.key.same(&key) {
                         return entry_ptr;
                     }
                 }
@@ -171,16 +166,18 @@ impl<V> Table<V> {
         // that was previously allocated.
         unsafe { Self::dealloc(old_ptr, old_capacity) };
     }
+}
 
-    /// Allocate an array of `Entry<V>` with the given capacity and return the raw pointer
+impl<V, K> Table<V, K> {
+    /// Allocate an array of `Entry<K, V>` with the given capacity and return the raw pointer
     /// to the beginning of the array.
     ///
     /// ## Safety
     ///
     /// `capacity` must be larger than 0, otherwise, it's undefined behaviour.
-    unsafe fn alloc(capacity: usize) -> NonNull<Entry<V>> {
+    unsafe fn alloc(capacity: usize) -> NonNull<Entry<K, V>> {
         // SAFETY: The caller of this function must ensure that `capacity` is larger than 0.
-        let ptr: *mut Entry<V> = unsafe { alloc::alloc(Self::entries_layout(capacity)).cast() };
+        let ptr: *mut Entry<K, V> = unsafe { alloc::alloc(Self::entries_layout(capacity)).cast() };
         for i in 0..capacity {
             // SAFETY: We only access pointers in the range of `ptr` and `ptr + capacity`.
             unsafe { ptr.add(i).write(Entry::Vaccant) };
@@ -189,33 +186,56 @@ impl<V> Table<V> {
         unsafe { NonNull::new_unchecked(ptr) }
     }
 
-    /// Deallocate the array of `Entry<V>` with the given capacity.
+    /// Deallocate the array of `Entry<K, V>` with the given capacity.
     ///
     /// ## Safety
     ///
-    /// + `ptr` must be a valid pointer to the array of `Entry<V>` with the given `capacity`.
+    /// + `ptr` must be a valid pointer to the array of `Entry<K, V>` with the given `capacity`.
     /// + `ptr` must be allocated with the same allocator as `Self::alloc`.
-    unsafe fn dealloc(ptr: *mut Entry<V>, capacity: usize) {
+    unsafe fn dealloc(ptr: *mut Entry<K, V>, capacity: usize) {
         if capacity > 0 {
             // SAFETY: The caller of this function must ensure that `ptr` is a valid pointer to
-            // the array of `Entry<V>` with the given `capacity`.
+            // the array of `Entry<K, V>` with the given `capacity`.
             unsafe { alloc::dealloc(ptr.cast(), Self::entries_layout(capacity)) };
         }
     }
 
-    /// Return the memory layout of an array of `Entry<V>` with the given capacity.
+    /// Return the memory layout of an array of `Entry<K, V>` with the given capacity.
     fn entries_layout(cap: usize) -> alloc::Layout {
-        alloc::Layout::array::<Entry<V>>(cap).expect("Invalid layout.")
+        alloc::Layout::array::<Entry<K, V>>(cap).expect("Invalid layout.")
     }
 }
 
-impl<V> GcSized for Table<V> {
+impl<V> Table<V> {
+    /// Find the pointer to the key that matches the given string and hash.
+    // If no key matches, `None` is returned.
+    pub(crate) fn find(&self, s: &str, hash: u32) -> Option<RefString> {
+        if self.occupants == 0 {
+            return None;
+        }
+        let mut index = hash as usize & (self.capacity - 1);
+        loop {
+            // SAFETY: `index` is always less than `self.capacity` because `index = x mod self.capacity`,
+            // where `x` is an arbitrary integer value.
+            let entry = unsafe { &*self.ptr.as_ptr().add(index) };
+            match &entry {
+                Entry::Vaccant => return None,
+                Entry::Occupied(e) if e.key.data == s => return Some(e.key),
+                _ => {}
+            }
+            // Linear probing.
+            index = (index + 1) & (self.capacity - 1);
+        }
+    }
+}
+
+impl<V, K> GcSized for Table<V, K> {
     fn size(&self) -> usize {
-        mem::size_of::<Self>() + mem::size_of::<Entry<V>>() * self.capacity
+        mem::size_of::<Self>() + mem::size_of::<Entry<K, V>>() * self.capacity
     }
 }
 
-impl<V> Default for Table<V> {
+impl<V, K> Default for Table<V, K> {
     fn default() -> Self {
         Self {
             ptr: NonNull::dangling(),
@@ -226,7 +246,7 @@ impl<V> Default for Table<V> {
     }
 }
 
-impl<V> Drop for Table<V> {
+impl<V, K> Drop for Table<V, K> {
     fn drop(&mut self) {
         // SAFETY: We ensure both `self.ptr` and `self.capacity` represent a valid array.
         unsafe { Self::dealloc(self.ptr.as_ptr(), self.capacity) };
@@ -234,32 +254,32 @@ impl<V> Drop for Table<V> {
 }
 
 #[derive(Debug)]
-enum Entry<V> {
+enum Entry<K, V> {
     Vaccant,
     Tombstone,
-    Occupied(EntryInner<V>),
+    Occupied(EntryInner<K, V>),
 }
 
 #[derive(Debug)]
-struct EntryInner<V> {
-    key: RefString,
+struct EntryInner<K, V> {
+    key: K,
     val: V,
 }
 
-pub struct TableIter<'table, V> {
-    ptr: NonNull<Entry<V>>,
-    ptr_: PhantomData<&'table Entry<V>>,
+pub struct TableIter<'table, V, K = RefString> {
+    ptr: NonNull<Entry<K, V>>,
+    ptr_: PhantomData<&'table Entry<K, V>>,
     offset: usize,
     capacity: usize,
 }
 
-impl<'table, V> Iterator for TableIter<'table, V> {
-    type Item = (RefString, &'table V);
+impl<'table, V, K: Copy> Iterator for TableIter<'table, V, K> {
+    type Item = (K, &'table V);
 
     fn next(&mut self) -> Option<Self::Item> {
         while self.offset < self.capacity {
             // SAFETY: The caller of this function must ensure that `ptr` is a valid pointer to
-            // the array of `Entry<V>` with the given `capacity`. Additionally, `self.offset`
+            // the array of `Entry<K, V>` with the given `capacity`. Additionally, `self.offset`
             // is always less than `self.capacity`.
             let entry = unsafe { &*self.ptr.as_ptr().add(self.offset) };
             self.offset += 1;
@@ -275,7 +295,11 @@ impl<'table, V> Iterator for TableIter<'table, V> {
 mod tests {
     use std::f64::consts::PI;
 
-    use crate::{heap::Heap, object::Gc, value::Value};
+    use crate::{
+        heap::Heap,
+        object::{Gc, Object},
+        value::Value,
+    };
 
     use super::Table;
 
@@ -294,12 +318,12 @@ mod tests {
         let key1 = heap.intern("key1".to_string());
         let key2 = heap.intern("key2".to_string());
 
-        table.set(key1, Value::Number(PI));
+        table.set(key1, Value::number(PI));
         assert_eq!(8, table.capacity);
         assert_eq!(1, table.occupants);
         assert_eq!(0, table.tombstones);
 
-        table.set(key2, Value::Number(PI));
+        table.set(key2, Value::number(PI));
         assert_eq!(8, table.capacity);
         assert_eq!(2, table.occupants);
         assert_eq!(0, table.tombstones);
@@ -311,11 +335,11 @@ mod tests {
         let mut heap = Heap::default();
         let key = heap.intern("key".to_string());
 
-        let prev = table.set(key, Value::Bool(true));
-        assert!(matches!(prev, None));
+        let prev = table.set(key, Value::bool(true));
+        assert!(prev.is_none());
 
-        let prev = table.set(key, Value::Number(PI));
-        assert!(matches!(prev, Some(Value::Bool(true))));
+        let prev = table.set(key, Value::number(PI));
+        assert_eq!(Some(Value::bool(true)), prev);
     }
 
     #[test]
@@ -324,9 +348,9 @@ mod tests {
         let mut heap = Heap::default();
         let key = heap.intern("key".to_string());
 
-        table.set(key, Value::Bool(true));
+        table.set(key, Value::bool(true));
         assert_eq!(1, table.occupants);
-        table.set(key, Value::Number(PI));
+        table.set(key, Value::number(PI));
         assert_eq!(1, table.occupants);
     }
 
@@ -336,7 +360,7 @@ mod tests {
         let mut heap = Heap::default();
         let key = heap.intern("key".to_string());
 
-        table.set(key, Value::Bool(true));
+        table.set(key, Value::bool(true));
         assert_eq!(1, table.occupants);
         assert_eq!(0, table.tombstones);
 
@@ -344,7 +368,7 @@ mod tests {
         assert_eq!(0, table.occupants);
         assert_eq!(1, table.tombstones);
 
-        table.set(key, Value::Bool(true));
+        table.set(key, Value::bool(true));
         assert_eq!(1, table.occupants);
         assert_eq!(0, table.tombstones);
     }
@@ -365,9 +389,9 @@ mod tests {
         let mut heap = Heap::default();
         let key = heap.intern("key".to_string());
 
-        table.set(key, Value::Bool(true));
+        table.set(key, Value::bool(true));
         let val = table.get(key);
-        assert!(matches!(val, Some(Value::Bool(true))));
+        assert_eq!(Some(&Value::bool(true)), val);
     }
 
     #[test]
@@ -376,13 +400,13 @@ mod tests {
         let mut heap = Heap::default();
         let key = heap.intern("key".to_string());
 
-        table.set(key, Value::Bool(true));
+        table.set(key, Value::bool(true));
         let val = table.get(key);
-        assert!(matches!(val, Some(Value::Bool(true))));
+        assert_eq!(Some(&Value::bool(true)), val);
 
-        table.set(key, Value::Bool(false));
+        table.set(key, Value::bool(false));
         let val = table.get(key);
-        assert!(matches!(val, Some(Value::Bool(false))));
+        assert_eq!(Some(&Value::bool(false)), val);
     }
 
     #[test]
@@ -391,12 +415,12 @@ mod tests {
         let mut heap = Heap::default();
         let key = heap.intern("key".to_string());
 
-        table.set(key, Value::Bool(true));
+        table.set(key, Value::bool(true));
         assert_eq!(1, table.occupants);
         assert_eq!(0, table.tombstones);
 
         let prev = table.del(key);
-        assert!(matches!(prev, Some(Value::Bool(true))));
+        assert_eq!(Some(Value::bool(true)), prev);
         assert_eq!(0, table.occupants);
         assert_eq!(1, table.tombstones);
     }
@@ -407,7 +431,7 @@ mod tests {
         let mut heap = Heap::default();
         let key = heap.intern("key".to_string());
 
-        table.set(key, Value::Bool(true));
+        table.set(key, Value::bool(true));
         let get_result = table.get(key).copied();
         let del_result = table.del(key);
         assert_eq!(get_result, del_result);
@@ -432,12 +456,32 @@ mod tests {
         let key1 = heap.intern("key1".to_string());
         let key2 = heap.intern("key2".to_string());
 
-        table.set(key1, Value::Nil);
-        table.set(key2, Value::Nil);
+        table.set(key1, Value::NIL);
+        table.set(key2, Value::NIL);
 
         let s1 = table.find(&key1.data, key1.hash).unwrap();
         let s2 = table.find(&key2.data, key2.hash).unwrap();
         assert!(Gc::ptr_eq(&s1, &key1));
         assert!(Gc::ptr_eq(&s2, &key2));
     }
+
+    #[test]
+    fn value_keys_are_compared_by_value() {
+        let mut table = Table::<Value, Value>::default();
+        let mut heap = Heap::default();
+        let key = Value::object(Object::String(heap.intern("key".to_string())));
+
+        table.set(Value::NIL, Value::number(1.0));
+        table.set(Value::bool(true), Value::number(2.0));
+        table.set(Value::number(0.0), Value::number(3.0));
+        table.set(key, Value::number(4.0));
+        assert_eq!(4, table.len());
+
+        assert_eq!(Some(&Value::number(1.0)), table.get(Value::NIL));
+        assert_eq!(Some(&Value::number(2.0)), table.get(Value::bool(true)));
+        assert_eq!(None, table.get(Value::bool(false)));
+        assert_eq!(Some(&Value::number(3.0)), table.get(Value::number(-0.0)));
+        let same_key = Value::object(Object::String(heap.intern("key".to_string())));
+        assert_eq!(Some(&Value::number(4.0)), table.get(same_key));
+    }
 }
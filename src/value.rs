@@ -1,6 +1,9 @@
 use std::{cmp::Ordering, error, fmt, ops};
 
-use crate::object::{Gc, Object, RefClass, RefClosure, RefFun, RefInstance, RefString};
+use crate::{
+    object::{Gc, Object, RefClass, RefClosure, RefFun, RefInstance, RefList, RefMap, RefString},
+    table::TableKey,
+};
 
 #[derive(Debug, Eq, PartialEq)]
 pub enum ValueError {
@@ -25,9 +28,10 @@ impl fmt::Display for ValueError {
     }
 }
 
-/// A enumeration of all supported primitive types in Lox and their underlying value.
+/// A enumeration of all supported primitive types in Lox and their underlying value. This is the
+/// form of a `Value` that can be matched on.
 #[derive(Debug, Clone, Copy)]
-pub(crate) enum Value {
+pub(crate) enum Unpacked {
     /// A nothing value in Lox
     Nil,
     /// A boolean value in Lox
@@ -38,11 +42,177 @@ pub(crate) enum Value {
     Object(Object),
 }
 
+/// A Lox value. Values are stored as a tagged enum, or packed into the payload of a NaN when the
+/// `nan-boxing` feature is enabled. Either way, they must be unpacked to be inspected.
+#[cfg(not(feature = "nan-boxing"))]
+#[derive(Clone, Copy)]
+pub(crate) struct Value(Unpacked);
+
+#[cfg(not(feature = "nan-boxing"))]
+impl Value {
+    /// The nil value.
+    pub(crate) const NIL: Self = Self(Unpacked::Nil);
+
+    /// Create a boolean value.
+    pub(crate) fn bool(b: bool) -> Self {
+        Self(Unpacked::Bool(b))
+    }
+
+    /// Create a number value.
+    pub(crate) fn number(n: f64) -> Self {
+        Self(Unpacked::Number(n))
+    }
+
+    /// Create an object value.
+    pub(crate) fn object(o: Object) -> Self {
+        Self(Unpacked::Object(o))
+    }
+
+    /// Get the type of the value along with its content.
+    pub(crate) fn unpack(self) -> Unpacked {
+        self.0
+    }
+}
+
+#[cfg(all(feature = "nan-boxing", not(target_pointer_width = "64")))]
+compile_error!("The `nan-boxing` feature requires a 64-bit target.");
+
+/// A Lox value packed into 64 bits.
+///
+/// A number is stored as its bits, with all NaNs turned into the same quiet NaN. Every other value
+/// is a quiet NaN with a 4-bit tag made of the sign bit and the 3 bits right below the quiet bit,
+/// and a 48-bit payload. The tag of nil and booleans is zero, which doesn't conflict with the
+/// number NaN because their payloads aren't zero. Objects are tagged by their type and have their
+/// address as the payload, so they must be allocated in the lower 256 TiB of the address space.
+#[cfg(feature = "nan-boxing")]
+#[derive(Clone, Copy)]
+pub(crate) struct Value(u64);
+
+#[cfg(feature = "nan-boxing")]
 impl Value {
+    /// The bits that are set in all quiet NaNs.
+    const QNAN: u64 = 0x7ff8_0000_0000_0000;
+    /// The bits of the payload.
+    const PAYLOAD: u64 = (1 << 48) - 1;
+
+    /// The nil value.
+    pub(crate) const NIL: Self = Self(Self::QNAN | 1);
+    const FALSE: Self = Self(Self::QNAN | 2);
+    const TRUE: Self = Self(Self::QNAN | 3);
+
+    const TAG_STRING: u64 = 1;
+    const TAG_UPVALUE: u64 = 2;
+    const TAG_CLOSURE: u64 = 3;
+    const TAG_FUN: u64 = 4;
+    const TAG_NATIVE_FUN: u64 = 5;
+    const TAG_CLASS: u64 = 6;
+    const TAG_INSTANCE: u64 = 7;
+    const TAG_BOUND_METHOD: u64 = 8;
+    const TAG_LIST: u64 = 9;
+    const TAG_MAP: u64 = 10;
+
+    /// Create a boolean value.
+    pub(crate) fn bool(b: bool) -> Self {
+        if b {
+            Self::TRUE
+        } else {
+            Self::FALSE
+        }
+    }
+
+    /// Create a number value.
+    pub(crate) fn number(n: f64) -> Self {
+        if n.is_nan() {
+            Self(Self::QNAN)
+        } else {
+            Self(n.to_bits())
+        }
+    }
+
+    /// Create an object value.
+    pub(crate) fn object(o: Object) -> Self {
+        let tag = match o {
+            Object::String(_) => Self::TAG_STRING,
+            Object::Upvalue(_) => Self::TAG_UPVALUE,
+            Object::Closure(_) => Self::TAG_CLOSURE,
+            Object::Fun(_) => Self::TAG_FUN,
+            Object::NativeFun(_) => Self::TAG_NATIVE_FUN,
+            Object::Class(_) => Self::TAG_CLASS,
+            Object::Instance(_) => Self::TAG_INSTANCE,
+            Object::BoundMethod(_) => Self::TAG_BOUND_METHOD,
+            Object::List(_) => Self::TAG_LIST,
+            Object::Map(_) => Self::TAG_MAP,
+        };
+        let addr = o.addr() as u64;
+        debug_assert_eq!(
+            addr & Self::PAYLOAD,
+            addr,
+            "Address doesn't fit in 48 bits."
+        );
+        Self(Self::QNAN | (tag & 0b1000) << 60 | (tag & 0b0111) << 48 | addr)
+    }
+
+    /// Get the type of the value along with its content.
+    pub(crate) fn unpack(self) -> Unpacked {
+        let bits = self.0;
+        if bits & Self::QNAN != Self::QNAN || bits == Self::QNAN {
+            return Unpacked::Number(f64::from_bits(bits));
+        }
+        if bits == Self::NIL.0 {
+            return Unpacked::Nil;
+        }
+        if bits == Self::FALSE.0 || bits == Self::TRUE.0 {
+            return Unpacked::Bool(bits == Self::TRUE.0);
+        }
+        let tag = (bits >> 60) & 0b1000 | (bits >> 48) & 0b0111;
+        let addr = (bits & Self::PAYLOAD) as usize;
+        // SAFETY: Only `Value::object` gives out values with a non-zero tag, so the payload is the
+        // address of a live object whose type matches the tag.
+        let object = unsafe {
+            match tag {
+                Self::TAG_STRING => Object::String(Gc::from_addr(addr)),
+                Self::TAG_UPVALUE => Object::Upvalue(Gc::from_addr(addr)),
+                Self::TAG_CLOSURE => Object::Closure(Gc::from_addr(addr)),
+                Self::TAG_FUN => Object::Fun(Gc::from_addr(addr)),
+                Self::TAG_NATIVE_FUN => Object::NativeFun(Gc::from_addr(addr)),
+                Self::TAG_CLASS => Object::Class(Gc::from_addr(addr)),
+                Self::TAG_INSTANCE => Object::Instance(Gc::from_addr(addr)),
+                Self::TAG_BOUND_METHOD => Object::BoundMethod(Gc::from_addr(addr)),
+                Self::TAG_LIST => Object::List(Gc::from_addr(addr)),
+                Self::TAG_MAP => Object::Map(Gc::from_addr(addr)),
+                _ => unreachable!("Invalid value tag."),
+            }
+        };
+        Unpacked::Object(object)
+    }
+}
+
+impl Value {
+    /// Check whether the value is nil.
+    pub(crate) fn is_nil(&self) -> bool {
+        matches!(self.unpack(), Unpacked::Nil)
+    }
+
+    /// Get the number held by the value.
+    pub(crate) fn as_number(&self) -> Option<f64> {
+        match self.unpack() {
+            Unpacked::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    /// Get the object held by the value.
+    pub(crate) fn as_object(&self) -> Option<Object> {
+        match self.unpack() {
+            Unpacked::Object(o) => Some(o),
+            _ => None,
+        }
+    }
+
     /// Cast the object as a string.
     pub(crate) fn as_string(&self) -> Result<RefString, ValueError> {
-        if let Self::Object(Object::String(s)) = self {
-            Ok(*s)
+        if let Some(Object::String(s)) = self.as_object() {
+            Ok(s)
         } else {
             Err(ValueError::InvalidCast)
         }
@@ -50,8 +220,8 @@ impl Value {
 
     /// Cast the object as a closure.
     pub(crate) fn as_closure(&self) -> Result<RefClosure, ValueError> {
-        if let Self::Object(Object::Closure(c)) = self {
-            Ok(*c)
+        if let Some(Object::Closure(c)) = self.as_object() {
+            Ok(c)
         } else {
             Err(ValueError::InvalidCast)
         }
@@ -59,8 +229,8 @@ impl Value {
 
     /// Cast the object as a fun.
     pub(crate) fn as_fun(&self) -> Result<RefFun, ValueError> {
-        if let Self::Object(Object::Fun(f)) = self {
-            Ok(*f)
+        if let Some(Object::Fun(f)) = self.as_object() {
+            Ok(f)
         } else {
             Err(ValueError::InvalidCast)
         }
@@ -68,8 +238,8 @@ impl Value {
 
     /// Cast the object as a class.
     pub(crate) fn as_class(&self) -> Result<RefClass, ValueError> {
-        if let Self::Object(Object::Class(c)) = self {
-            Ok(*c)
+        if let Some(Object::Class(c)) = self.as_object() {
+            Ok(c)
         } else {
             Err(ValueError::InvalidCast)
         }
@@ -77,25 +247,43 @@ impl Value {
 
     /// Cast the object as an instance.
     pub(crate) fn as_instance(&self) -> Result<RefInstance, ValueError> {
-        if let Self::Object(Object::Instance(i)) = self {
-            Ok(*i)
+        if let Some(Object::Instance(i)) = self.as_object() {
+            Ok(i)
+        } else {
+            Err(ValueError::InvalidCast)
+        }
+    }
+
+    /// Cast the object as a list.
+    pub(crate) fn as_list(&self) -> Result<RefList, ValueError> {
+        if let Some(Object::List(l)) = self.as_object() {
+            Ok(l)
+        } else {
+            Err(ValueError::InvalidCast)
+        }
+    }
+
+    /// Cast the object as a map.
+    pub(crate) fn as_map(&self) -> Result<RefMap, ValueError> {
+        if let Some(Object::Map(m)) = self.as_object() {
+            Ok(m)
         } else {
             Err(ValueError::InvalidCast)
         }
     }
 
     pub(crate) fn is_truthy(&self) -> bool {
-        match self {
-            Value::Bool(b) => *b,
-            Value::Nil => false,
+        match self.unpack() {
+            Unpacked::Bool(b) => b,
+            Unpacked::Nil => false,
             _ => true,
         }
     }
 
     pub(crate) fn is_falsey(&self) -> bool {
-        match self {
-            Value::Bool(b) => !b,
-            Value::Nil => true,
+        match self.unpack() {
+            Unpacked::Bool(b) => !b,
+            Unpacked::Nil => true,
             _ => false,
         }
     }
@@ -129,43 +317,91 @@ impl Value {
     }
 }
 
+impl Default for Value {
+    fn default() -> Self {
+        Self::NIL
+    }
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.unpack().fmt(f)
+    }
+}
+
 impl PartialEq for Value {
     fn eq(&self, other: &Self) -> bool {
-        match (self, other) {
-            (Self::Nil, Self::Nil) => true,
-            (Self::Bool(v1), Self::Bool(v2)) => v1 == v2,
-            (Self::Number(v1), Self::Number(v2)) => v1.eq(v2),
-            (Self::Object(Object::String(s1)), Self::Object(Object::String(s2))) => {
-                Gc::ptr_eq(s1, s2)
+        match (self.unpack(), other.unpack()) {
+            (Unpacked::Nil, Unpacked::Nil) => true,
+            (Unpacked::Bool(v1), Unpacked::Bool(v2)) => v1 == v2,
+            (Unpacked::Number(v1), Unpacked::Number(v2)) => v1.eq(&v2),
+            (Unpacked::Object(Object::String(s1)), Unpacked::Object(Object::String(s2))) => {
+                Gc::ptr_eq(&s1, &s2)
+            }
+            (Unpacked::Object(Object::Upvalue(v1)), Unpacked::Object(Object::Upvalue(v2))) => {
+                Gc::ptr_eq(&v1, &v2)
+            }
+            (Unpacked::Object(Object::Closure(v1)), Unpacked::Object(Object::Closure(v2))) => {
+                Gc::ptr_eq(&v1, &v2)
             }
-            (Self::Object(Object::Upvalue(v1)), Self::Object(Object::Upvalue(v2))) => {
-                Gc::ptr_eq(v1, v2)
+            (Unpacked::Object(Object::Fun(v1)), Unpacked::Object(Object::Fun(v2))) => {
+                Gc::ptr_eq(&v1, &v2)
             }
-            (Self::Object(Object::Closure(v1)), Self::Object(Object::Closure(v2))) => {
-                Gc::ptr_eq(v1, v2)
+            (Unpacked::Object(Object::NativeFun(v1)), Unpacked::Object(Object::NativeFun(v2))) => {
+                Gc::ptr_eq(&v1, &v2)
             }
-            (Self::Object(Object::Fun(v1)), Self::Object(Object::Fun(v2))) => Gc::ptr_eq(v1, v2),
-            (Self::Object(Object::NativeFun(v1)), Self::Object(Object::NativeFun(v2))) => {
-                Gc::ptr_eq(v1, v2)
+            (Unpacked::Object(Object::Class(v1)), Unpacked::Object(Object::Class(v2))) => {
+                Gc::ptr_eq(&v1, &v2)
             }
-            (Self::Object(Object::Class(v1)), Self::Object(Object::Class(v2))) => {
-                Gc::ptr_eq(v1, v2)
+            (Unpacked::Object(Object::Instance(v1)), Unpacked::Object(Object::Instance(v2))) => {
+                Gc::ptr_eq(&v1, &v2)
             }
-            (Self::Object(Object::Instance(v1)), Self::Object(Object::Instance(v2))) => {
-                Gc::ptr_eq(v1, v2)
+            (
+                Unpacked::Object(Object::BoundMethod(v1)),
+                Unpacked::Object(Object::BoundMethod(v2)),
+            ) => Gc::ptr_eq(&v1, &v2),
+            (Unpacked::Object(Object::List(v1)), Unpacked::Object(Object::List(v2))) => {
+                Gc::ptr_eq(&v1, &v2)
             }
-            (Self::Object(Object::BoundMethod(v1)), Self::Object(Object::BoundMethod(v2))) => {
-                Gc::ptr_eq(v1, v2)
+            (Unpacked::Object(Object::Map(v1)), Unpacked::Object(Object::Map(v2))) => {
+                Gc::ptr_eq(&v1, &v2)
             }
             _ => false,
         }
     }
 }
 
+/// Values are hashed so that equal values have the same hash. Strings are hashed by their content,
+/// and other objects are hashed by their identity. A NaN is never equal to itself, so it must not
+/// be used as a key.
+impl TableKey for Value {
+    fn hash(&self) -> u32 {
+        match self.unpack() {
+            Unpacked::Nil => 0,
+            Unpacked::Bool(false) => 1,
+            Unpacked::Bool(true) => 2,
+            Unpacked::Number(n) => {
+                // Adding zero turns -0.0 into 0.0 so both have the same hash.
+                let bits = (n + 0.0).to_bits();
+                (bits ^ (bits >> 32)) as u32
+            }
+            Unpacked::Object(Object::String(s)) => s.hash,
+            Unpacked::Object(obj) => {
+                let addr = obj.addr() as u64;
+                (addr ^ (addr >> 32)) as u32
+            }
+        }
+    }
+
+    fn same(&self, other: &Self) -> bool {
+        self == other
+    }
+}
+
 impl PartialOrd for Value {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        match (self, other) {
-            (Self::Number(v1), Self::Number(v2)) => v1.partial_cmp(v2),
+        match (self.unpack(), other.unpack()) {
+            (Unpacked::Number(v1), Unpacked::Number(v2)) => v1.partial_cmp(&v2),
             _ => None,
         }
     }
@@ -175,8 +411,8 @@ impl ops::Add for &Value {
     type Output = Result<Value, ValueError>;
 
     fn add(self, rhs: Self) -> Self::Output {
-        match (self, rhs) {
-            (Value::Number(n1), Value::Number(n2)) => Ok(Value::Number(n1 + n2)),
+        match (self.unpack(), rhs.unpack()) {
+            (Unpacked::Number(n1), Unpacked::Number(n2)) => Ok(Value::number(n1 + n2)),
             _ => Err(ValueError::BinaryOperandsMustBeNumbersOrStrings),
         }
     }
@@ -186,8 +422,8 @@ impl ops::Sub for &Value {
     type Output = Result<Value, ValueError>;
 
     fn sub(self, rhs: Self) -> Self::Output {
-        match (self, rhs) {
-            (Value::Number(n1), Value::Number(n2)) => Ok(Value::Number(n1 - n2)),
+        match (self.unpack(), rhs.unpack()) {
+            (Unpacked::Number(n1), Unpacked::Number(n2)) => Ok(Value::number(n1 - n2)),
             _ => Err(ValueError::BinaryOperandsMustBeNumbers),
         }
     }
@@ -197,8 +433,8 @@ impl ops::Mul for &Value {
     type Output = Result<Value, ValueError>;
 
     fn mul(self, rhs: Self) -> Self::Output {
-        match (self, rhs) {
-            (Value::Number(n1), Value::Number(n2)) => Ok(Value::Number(n1 * n2)),
+        match (self.unpack(), rhs.unpack()) {
+            (Unpacked::Number(n1), Unpacked::Number(n2)) => Ok(Value::number(n1 * n2)),
             _ => Err(ValueError::BinaryOperandsMustBeNumbers),
         }
     }
@@ -208,8 +444,8 @@ impl ops::Div for &Value {
     type Output = Result<Value, ValueError>;
 
     fn div(self, rhs: Self) -> Self::Output {
-        match (self, rhs) {
-            (Value::Number(n1), Value::Number(n2)) => Ok(Value::Number(n1 / n2)),
+        match (self.unpack(), rhs.unpack()) {
+            (Unpacked::Number(n1), Unpacked::Number(n2)) => Ok(Value::number(n1 / n2)),
             _ => Err(ValueError::BinaryOperandsMustBeNumbers),
         }
     }
@@ -219,8 +455,8 @@ impl ops::Neg for &Value {
     type Output = Result<Value, ValueError>;
 
     fn neg(self) -> Self::Output {
-        match self {
-            Value::Number(n) => Ok(Value::Number(-n)),
+        match self.unpack() {
+            Unpacked::Number(n) => Ok(Value::number(-n)),
             _ => Err(ValueError::UnaryOperandsMustBeNumber),
         }
     }
@@ -230,23 +466,17 @@ impl ops::Not for &Value {
     type Output = Value;
 
     fn not(self) -> Self::Output {
-        Value::Bool(self.is_falsey())
-    }
-}
-
-impl Default for Value {
-    fn default() -> Self {
-        Self::Nil
+        Value::bool(self.is_falsey())
     }
 }
 
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
-        match self {
-            Self::Nil => write!(f, "nil"),
-            Self::Bool(b) => write!(f, "{b}"),
-            Self::Number(n) => {
-                if n.trunc().eq(n) {
+        match self.unpack() {
+            Unpacked::Nil => write!(f, "nil"),
+            Unpacked::Bool(b) => write!(f, "{b}"),
+            Unpacked::Number(n) => {
+                if n.trunc().eq(&n) {
                     // Try to truncate the decimals if `n` is a whole number.
                     write!(f, "{n:.0?}")
                 } else {
@@ -254,7 +484,87 @@ impl fmt::Display for Value {
                     write!(f, "{n:?}")
                 }
             }
-            Self::Object(o) => write!(f, "{o}"),
+            Unpacked::Object(o) => write!(f, "{o}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use crate::{
+        heap::Heap,
+        object::{ObjList, Object},
+    };
+
+    use super::{Unpacked, Value};
+
+    #[test]
+    fn unpacking_gives_back_the_content() {
+        let mut heap = Heap::default();
+        assert!(matches!(Value::NIL.unpack(), Unpacked::Nil));
+        assert!(matches!(Value::bool(true).unpack(), Unpacked::Bool(true)));
+        assert!(matches!(Value::bool(false).unpack(), Unpacked::Bool(false)));
+        for n in [
+            0.0,
+            -0.0,
+            1.5,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+            f64::MIN_POSITIVE,
+        ] {
+            let Unpacked::Number(unpacked) = Value::number(n).unpack() else {
+                panic!("Expect a number.");
+            };
+            assert_eq!(n.to_bits(), unpacked.to_bits());
         }
+        assert!(Value::number(f64::NAN).as_number().unwrap().is_nan());
+        assert!(Value::number(-f64::NAN).as_number().unwrap().is_nan());
+
+        let s = heap.intern(String::from("s"));
+        let (list, _) = heap.alloc(RefCell::new(ObjList { items: Vec::new() }), Object::List);
+        assert!(Value::object(Object::String(s)).as_string().is_ok());
+        assert_eq!(
+            Some(list.addr()),
+            Value::object(list)
+                .as_list()
+                .ok()
+                .map(|l| l.as_ptr() as usize)
+        );
+    }
+
+    #[test]
+    fn equality_follows_lox_semantics() {
+        let mut heap = Heap::default();
+        let a = Value::object(Object::String(heap.intern(String::from("a"))));
+        let b = Value::object(Object::String(heap.intern(String::from("b"))));
+        assert_eq!(Value::NIL, Value::NIL);
+        assert_eq!(Value::number(0.0), Value::number(-0.0));
+        assert_ne!(Value::number(f64::NAN), Value::number(f64::NAN));
+        assert_ne!(Value::bool(true), Value::number(1.0));
+        assert_ne!(Value::bool(false), Value::NIL);
+        assert_eq!(
+            a,
+            Value::object(Object::String(heap.intern(String::from("a"))))
+        );
+        assert_ne!(a, b);
+        assert_eq!(
+            "nil true 1 1.5 a",
+            format!(
+                "{} {} {} {} {}",
+                Value::NIL,
+                Value::bool(true),
+                Value::number(1.0),
+                Value::number(1.5),
+                a
+            )
+        );
+    }
+
+    #[cfg(feature = "nan-boxing")]
+    #[test]
+    fn values_fit_in_a_word() {
+        assert_eq!(8, std::mem::size_of::<Value>());
     }
 }
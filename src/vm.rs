@@ -1,23 +1,33 @@
 //! Implementation of the bytecode virtual machine.
 
 use std::{
-    cell::RefCell,
+    cell::{Cell, RefCell},
+    collections::HashMap,
     error, fmt,
+    io::{self, Write},
     ops::{Add, Deref, DerefMut, Div, Mul, Neg, Not, Sub},
     ptr::NonNull,
+    rc::{Rc, Weak},
 };
 
 use crate::{
-    compile::{Parser, MAX_FRAMES},
+    bytecode,
+    chunk::{self, InlineCache},
+    compile::Parser,
+    diagnostic::Diagnostic,
+    global::Globals,
+    handle::{Handle, Root},
     heap::Heap,
+    module::{FileResolver, ModuleResolver, Source},
+    native::{Arity, NativeContext, NativeError},
     object::{
-        ObjBoundMethod, ObjClass, ObjClosure, ObjFun, ObjInstance, ObjNativeFun, ObjUpvalue,
-        Object, ObjectError, RefBoundMethod, RefClass, RefClosure, RefFun, RefInstance,
-        RefNativeFun, RefString, RefUpvalue,
+        Gc, ObjBoundMethod, ObjClass, ObjClosure, ObjFun, ObjInstance, ObjList, ObjMap,
+        ObjNativeFun, ObjUpvalue, Object, ObjectError, RefBoundMethod, RefClass, RefClosure,
+        RefInstance, RefList, RefMap, RefNativeFun, RefString, RefUpvalue,
     },
     opcode::Opcode,
-    stack::Stack,
-    table::Table,
+    scan::Span,
+    shape::Shapes,
     value::{Value, ValueError},
     InterpretError,
 };
@@ -25,8 +35,40 @@ use crate::{
 #[cfg(feature = "dbg-execution")]
 use crate::chunk::disassemble_instruction;
 
-/// The max number of values can be put onto the virtual machine's stack.
-const VM_STACK_SIZE: usize = 256;
+/// The Lox code that is run when a virtual machine is created. Runtime errors are caught as
+/// instances of the `Error` class, which have a `message`, the `line` where they occurred, and the
+/// `stack` of calls that were active as a list of `StackFrame` instances. The stack of an error
+/// that is thrown by Lox code is filled in when it's thrown.
+const PRELUDE: &str = r#"
+class Error {
+    init(message) {
+        this.message = message;
+        this.line = nil;
+        this.stack = nil;
+    }
+}
+
+class StackFrame {
+    init(function, file, line, column) {
+        this.function = function;
+        this.file = file;
+        this.line = line;
+        this.column = column;
+    }
+}
+"#;
+
+/// The global namespace of the builtins, which are copied into the namespace of every module.
+const BUILTINS_NAMESPACE: usize = 0;
+
+/// The global namespace of the scripts given to the virtual machine.
+const MAIN_NAMESPACE: usize = 1;
+
+/// The default max number of call frames can be handled by the virtual machine.
+const DEFAULT_MAX_FRAMES: usize = 4096;
+
+/// The default max number of values can be put onto the virtual machine's stack.
+const DEFAULT_MAX_STACK_SIZE: usize = DEFAULT_MAX_FRAMES * 256;
 
 /// An enumeration of potential errors occur when running the bytecodes.
 #[derive(Debug)]
@@ -43,6 +85,8 @@ pub enum RuntimeError {
     ObjectHasNoField,
     /// Can't find a variable in scope.
     UndefinedVariable(String),
+    /// Can't assign to a constant variable.
+    AssignToConst(String),
     /// Can't find a property in the instance.
     UndefinedProperty(String),
     /// Can't inherit objects that are not supported.
@@ -51,6 +95,54 @@ pub enum RuntimeError {
     InvalidCallee,
     /// Can't invoke objects that are not supported.
     InvalidMethodInvocation,
+    /// Can't index objects that are not supported.
+    ObjectNotIndexable,
+    /// Can't index using values that are not integers.
+    InvalidIndex,
+    /// Accessed an index outside of the list.
+    IndexOutOfBounds {
+        /// The accessed index.
+        index: i64,
+        /// The number of items in the list.
+        len: usize,
+    },
+    /// Can't pop an item from a list without any item.
+    PopFromEmptyList,
+    /// Can't find a key in the map.
+    UndefinedKey(String),
+    /// Can't use NaN as a map key.
+    InvalidMapKey,
+    /// A thrown value wasn't caught by any exception handler.
+    Uncaught(String),
+    /// Can't add more global variables.
+    TooManyGlobals,
+    /// Called a variadic native function with too few arguments.
+    TooFewArguments {
+        /// The least number of arguments of the function.
+        min: u8,
+        /// The number of arguments given.
+        argc: u8,
+    },
+    /// A native function returned an error.
+    Native {
+        /// The name of the native function.
+        name: String,
+        /// The returned error.
+        error: NativeError,
+    },
+    /// Can't write to an output sink.
+    Io(io::Error),
+    /// Called a function with more arguments than a function can have.
+    TooManyArguments,
+    /// Used a handle that was given out by another virtual machine.
+    ForeignHandle,
+    /// Can't load or compile an imported module.
+    ImportFailed {
+        /// The path given in the import statement.
+        path: String,
+        /// The reason for the failure.
+        reason: String,
+    },
     /// Called a function/method with incorrect number of arguments.
     InvalidArgumentsCount {
         /// The arity of the function.
@@ -71,13 +163,37 @@ impl fmt::Display for RuntimeError {
             Self::ObjectHasNoProperty => f.write_str("Only instances have properties."),
             Self::ObjectHasNoField => f.write_str("Only instances have fields."),
             Self::UndefinedVariable(name) => write!(f, "Undefined variable '{name}'."),
+            Self::AssignToConst(name) => write!(f, "Can't assign to const variable '{name}'."),
             Self::UndefinedProperty(name) => write!(f, "Undefined property '{name}'."),
             Self::InvalidSuperclass => f.write_str("Superclass must be a class."),
             Self::InvalidCallee => f.write_str("Can only call functions and classes."),
             Self::InvalidMethodInvocation => f.write_str("Only instances have methods."),
+            Self::ObjectNotIndexable => f.write_str("Only lists and maps can be indexed."),
+            Self::InvalidIndex => f.write_str("Index must be an integer."),
+            Self::IndexOutOfBounds { index, len } => {
+                write!(
+                    f,
+                    "Index {index} is out of bounds for a list of length {len}."
+                )
+            }
+            Self::PopFromEmptyList => f.write_str("Can't pop from an empty list."),
+            Self::UndefinedKey(key) => write!(f, "Undefined key '{key}'."),
+            Self::InvalidMapKey => f.write_str("Map key can't be NaN."),
             Self::InvalidArgumentsCount { arity, argc } => {
                 write!(f, "Expected {arity} arguments but got {argc}.",)
             }
+            Self::Uncaught(message) => write!(f, "Uncaught exception: {message}"),
+            Self::TooManyGlobals => f.write_str("Too many global variables."),
+            Self::TooFewArguments { min, argc } => {
+                write!(f, "Expected at least {min} arguments but got {argc}.")
+            }
+            Self::Native { name, error } => write!(f, "{name}: {error}"),
+            Self::Io(err) => write!(f, "Can't write output: {err}"),
+            Self::TooManyArguments => f.write_str("Can't have more than 255 arguments."),
+            Self::ForeignHandle => f.write_str("Handle belongs to another virtual machine."),
+            Self::ImportFailed { path, reason } => {
+                write!(f, "Can't import module '{path}': {reason}")
+            }
         }
     }
 }
@@ -94,16 +210,84 @@ impl From<ObjectError> for RuntimeError {
     }
 }
 
+/// A call that was active when a runtime error occurred. Displaying the frame gives its entry in
+/// the stack trace that is written to the error sink.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackFrame {
+    /// The name of the called function, or `None` for the top-level code of a script or module.
+    pub function: Option<String>,
+    /// The path of the script or module containing the function, or `<script>` if the script
+    /// wasn't loaded from a path.
+    pub file: String,
+    /// The line of the code being run, counted from 1.
+    pub line: usize,
+    /// The column of the code being run, counted in characters from 1.
+    pub column: usize,
+    /// The source line of the code being run followed by a line of carets under it, both are
+    /// indented by 4 spaces.
+    pub excerpt: Option<String>,
+}
+
+impl fmt::Display for StackFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self {
+            function,
+            file,
+            line,
+            column,
+            excerpt,
+        } = self;
+        match function {
+            None => write!(f, "[{file}:{line}:{column}] in script.")?,
+            Some(name) => write!(f, "[{file}:{line}:{column}] in {name}().")?,
+        }
+        if let Some(excerpt) = excerpt {
+            write!(f, "\n{excerpt}")?;
+        }
+        Ok(())
+    }
+}
+
 /// A bytecode virtual machine for the Lox programming language.
 pub struct VirtualMachine {
-    stack: Stack<Value, VM_STACK_SIZE>,
-    frames: Stack<CallFrame, MAX_FRAMES>,
+    stack: Vec<Value>,
+    max_stack_size: usize,
+    frames: Vec<CallFrame>,
+    max_frames: usize,
+    /// Pointer to the top most call frame. The frames are moved when their storage grows, so this
+    /// must be updated every time a frame is pushed or popped.
     current_frame: NonNull<CallFrame>,
     open_upvalues: Vec<RefUpvalue>,
-    globals: Table<Value>,
+    globals: Globals,
+    shapes: Shapes,
     grey_objects: Vec<Object>,
     heap: Heap,
     str_init: RefString,
+    str_message: RefString,
+    str_line: RefString,
+    str_stack: RefString,
+    str_function: RefString,
+    str_file: RefString,
+    str_column: RefString,
+    /// The class of the errors that are thrown when a runtime error is caught.
+    error_class: Option<RefClass>,
+    /// The class of the frames in the stack of a caught error.
+    stack_frame_class: Option<RefClass>,
+    /// The resolver for loading imported modules.
+    resolver: Box<dyn ModuleResolver>,
+    /// The global namespaces of the loaded modules, keyed by their resolved paths.
+    modules: HashMap<String, usize>,
+    /// The values of the handles given to the host, which are marked as roots until the handles
+    /// are dropped.
+    handles: Vec<Weak<Root>>,
+    /// The token that the handles use to check whether the virtual machine is alive.
+    token: Rc<()>,
+    /// The sink for the output of print statements.
+    stdout: Box<dyn Write>,
+    /// The sink for compilation and runtime errors.
+    stderr: Box<dyn Write>,
+    /// The flag to indicate that compiled code is optimized before it runs.
+    optimizing: bool,
 }
 
 impl Default for VirtualMachine {
@@ -115,51 +299,427 @@ impl Default for VirtualMachine {
 impl VirtualMachine {
     /// Create a new virtual machine that prints to the given output.
     pub fn new() -> Self {
+        Self::builder().build()
+    }
+
+    /// Create a builder for configuring a new virtual machine.
+    pub fn builder() -> VirtualMachineBuilder {
+        VirtualMachineBuilder::default()
+    }
+}
+
+/// A builder for configuring the limits of a virtual machine.
+pub struct VirtualMachineBuilder {
+    max_stack_size: usize,
+    max_frames: usize,
+    resolver: Box<dyn ModuleResolver>,
+    stdout: Box<dyn Write>,
+    stderr: Box<dyn Write>,
+    optimizing: bool,
+}
+
+impl Default for VirtualMachineBuilder {
+    fn default() -> Self {
+        Self {
+            max_stack_size: DEFAULT_MAX_STACK_SIZE,
+            max_frames: DEFAULT_MAX_FRAMES,
+            resolver: Box::new(FileResolver),
+            stdout: Box::new(io::stdout()),
+            stderr: Box::new(io::stderr()),
+            optimizing: false,
+        }
+    }
+}
+
+impl fmt::Debug for VirtualMachineBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VirtualMachineBuilder")
+            .field("max_stack_size", &self.max_stack_size)
+            .field("max_frames", &self.max_frames)
+            .field("optimizing", &self.optimizing)
+            .finish_non_exhaustive()
+    }
+}
+
+impl VirtualMachineBuilder {
+    /// Set the max number of values can be put onto the virtual machine's stack. The stack grows
+    /// on demand until it reaches this size.
+    pub fn max_stack_size(mut self, max_stack_size: usize) -> Self {
+        self.max_stack_size = max_stack_size;
+        self
+    }
+
+    /// Set the max number of call frames can be handled by the virtual machine. The call frames
+    /// grow on demand until they reach this number.
+    pub fn max_frames(mut self, max_frames: usize) -> Self {
+        self.max_frames = max_frames;
+        self
+    }
+
+    /// Set the resolver for loading the modules given in import statements. Modules are loaded
+    /// from the file system by default.
+    pub fn module_resolver(mut self, resolver: impl ModuleResolver + 'static) -> Self {
+        self.resolver = Box::new(resolver);
+        self
+    }
+
+    /// Set the sink for the output of print statements. The output is written to the standard
+    /// output by default.
+    pub fn stdout(mut self, stdout: impl Write + 'static) -> Self {
+        self.stdout = Box::new(stdout);
+        self
+    }
+
+    /// Set the sink for compilation and runtime errors. The errors are written to the standard
+    /// error by default.
+    pub fn stderr(mut self, stderr: impl Write + 'static) -> Self {
+        self.stderr = Box::new(stderr);
+        self
+    }
+
+    /// Set whether compiled code is optimized before it runs. The optimizer folds operations on
+    /// literals, simplifies jumps, and removes unreachable code. It's disabled by default.
+    pub fn optimize(mut self, optimizing: bool) -> Self {
+        self.optimizing = optimizing;
+        self
+    }
+
+    /// Create the virtual machine.
+    pub fn build(self) -> VirtualMachine {
         let mut heap = Heap::default();
         let str_init = heap.intern(String::from("init"));
-        let mut vm = Self {
-            stack: Stack::default(),
-            frames: Stack::default(),
+        let str_message = heap.intern(String::from("message"));
+        let str_line = heap.intern(String::from("line"));
+        let str_stack = heap.intern(String::from("stack"));
+        let str_function = heap.intern(String::from("function"));
+        let str_file = heap.intern(String::from("file"));
+        let str_column = heap.intern(String::from("column"));
+        let mut vm = VirtualMachine {
+            stack: Vec::new(),
+            max_stack_size: self.max_stack_size,
+            frames: Vec::new(),
+            max_frames: self.max_frames,
             current_frame: NonNull::dangling(),
             open_upvalues: Vec::new(),
-            globals: Table::default(),
+            globals: Globals::default(),
+            shapes: Shapes::default(),
             grey_objects: Vec::new(),
             heap,
             str_init,
+            str_message,
+            str_line,
+            str_stack,
+            str_function,
+            str_file,
+            str_column,
+            error_class: None,
+            stack_frame_class: None,
+            resolver: self.resolver,
+            modules: HashMap::new(),
+            handles: Vec::new(),
+            token: Rc::new(()),
+            stdout: self.stdout,
+            stderr: self.stderr,
+            optimizing: self.optimizing,
         };
-        vm.define_native("clock", 0, clock_native)
+        vm.globals.add_namespace();
+        vm.define_native("clock", Arity::Fixed(0), clock_native)
             .expect("Can't define native function.");
+        vm.interpret_in(BUILTINS_NAMESPACE, None, PRELUDE)
+            .expect("Can't run the prelude.");
+        vm.error_class = vm.builtin_class("Error");
+        vm.stack_frame_class = vm.builtin_class("StackFrame");
+        vm.add_module().expect("Too many global variables.");
         vm
     }
 }
 
 impl VirtualMachine {
-    /// Compile and execute the given source code.
+    /// Compile and execute the given source code. Modules imported by the code are resolved
+    /// relative to the current directory.
     pub fn interpret(&mut self, src: &str) -> Result<(), InterpretError> {
-        let parser = Parser::new(src, &mut self.heap);
-        let fun = parser.compile().ok_or(InterpretError::Compile)?;
-        self.run(fun).map_err(|err| {
-            eprintln!("{err}");
-            if let Err(err) = self.trace_calls() {
-                eprintln!("{err}");
-            };
-            self.stack.clear();
-            InterpretError::Runtime
+        self.interpret_in(MAIN_NAMESPACE, None, src)
+    }
+
+    /// Compile and execute the source code of the script at the given path. Modules imported by
+    /// the script are resolved relative to this path.
+    pub fn interpret_script(&mut self, path: &str, src: &str) -> Result<(), InterpretError> {
+        self.interpret_in(MAIN_NAMESPACE, Some(String::from(path)), src)
+    }
+
+    fn interpret_in(
+        &mut self,
+        namespace: usize,
+        path: Option<String>,
+        src: &str,
+    ) -> Result<(), InterpretError> {
+        let source = Rc::new(Source {
+            path,
+            text: String::from(src),
+        });
+        let fun = self
+            .compile_in(namespace, &source)
+            .map_err(InterpretError::Compile)?;
+        self.execute(fun)
+    }
+
+    /// Compile the given source code into bytecode that can be run by `load_bytecode` without
+    /// parsing the source code again. The path of the script is kept in the bytecode, and modules
+    /// imported by the script are resolved relative to it.
+    ///
+    /// Global variables are referred to by name in the bytecode, so it can be loaded by any
+    /// virtual machine. The bytecode is smallest when it's loaded by a virtual machine that hasn't
+    /// run anything else.
+    pub fn compile(&mut self, path: Option<&str>, src: &str) -> Result<Vec<u8>, InterpretError> {
+        let source = Rc::new(Source {
+            path: path.map(String::from),
+            text: String::from(src),
+        });
+        let fun = self
+            .compile_in(MAIN_NAMESPACE, &source)
+            .map_err(InterpretError::Compile)?;
+        Ok(bytecode::serialize(&fun, &self.globals))
+    }
+
+    /// Compile the given source code without running it, and write its instructions in
+    /// human-readable format, followed by the instructions of every function it declares.
+    pub fn disassemble(
+        &mut self,
+        src: &str,
+        out: &mut impl fmt::Write,
+    ) -> Result<(), InterpretError> {
+        let source = Rc::new(Source {
+            path: None,
+            text: String::from(src),
+        });
+        let fun = self
+            .compile_in(MAIN_NAMESPACE, &source)
+            .map_err(InterpretError::Compile)?;
+        chunk::disassemble(out, &fun, &self.globals).map_err(InterpretError::Write)
+    }
+
+    /// Verify and execute bytecode produced by `compile`. Bytecode that is malformed, or that was
+    /// produced by an incompatible version of the virtual machine, is rejected before it runs.
+    pub fn load_bytecode(&mut self, bytes: &[u8]) -> Result<(), InterpretError> {
+        let fun = bytecode::deserialize(bytes, &mut self.heap, &mut self.globals, MAIN_NAMESPACE)
+            .map_err(|err| {
+            writeln!(self.stderr, "{err}").ok();
+            InterpretError::Bytecode(err)
+        })?;
+        self.execute(fun)
+    }
+
+    /// Run the function compiled from a script, and report the error that stops it.
+    fn execute(&mut self, fun: ObjFun) -> Result<(), InterpretError> {
+        self.run(fun).map_err(|error| {
+            let trace = self.stack_trace();
+            writeln!(self.stderr, "{error}").ok();
+            for frame in &trace {
+                writeln!(self.stderr, "{frame}").ok();
+            }
+            self.reset();
+            InterpretError::Runtime { error, trace }
         })
     }
 
+    /// Get a class defined by the prelude.
+    fn builtin_class(&mut self, name: &str) -> Option<RefClass> {
+        let name = self.heap.intern(String::from(name));
+        let slot = self.globals.slot(BUILTINS_NAMESPACE, name)?;
+        // SAFETY: The slot was just given out by the globals.
+        let class = unsafe { self.globals.at(slot) }.value?;
+        class.as_class().ok()
+    }
+
+    /// Get the value of a global variable of the scripts. Return `None` if the variable isn't
+    /// defined.
+    pub fn get_global(&mut self, name: &str) -> Option<Handle> {
+        let name = self.heap.intern(String::from(name));
+        let slot = self.globals.find(MAIN_NAMESPACE, name)?;
+        // SAFETY: The slot was given out by the globals.
+        let value = unsafe { self.globals.at(slot) }.value?;
+        Some(self.handle(value))
+    }
+
+    /// Set the value of a global variable of the scripts, defining it if it doesn't exist.
+    pub fn set_global(&mut self, name: &str, value: &Handle) -> Result<(), RuntimeError> {
+        let value = value
+            .value_for(&self.token)
+            .ok_or(RuntimeError::ForeignHandle)?;
+        let name = self.heap.intern(String::from(name));
+        let slot = self
+            .globals
+            .slot(MAIN_NAMESPACE, name)
+            .ok_or(RuntimeError::TooManyGlobals)?;
+        // SAFETY: The slot was just given out by the globals.
+        let global = unsafe { self.globals.at_mut(slot) };
+        if global.is_const {
+            return Err(RuntimeError::AssignToConst(name.data.clone()));
+        }
+        global.value = Some(value);
+        Ok(())
+    }
+
+    /// Define a native function as a global variable. Like the other builtins, the function is
+    /// visible to the scripts and to all the modules they import, unless they define a variable
+    /// with the same name.
+    pub fn define_native<F>(
+        &mut self,
+        name: &str,
+        arity: Arity,
+        call: F,
+    ) -> Result<(), RuntimeError>
+    where
+        F: FnMut(&mut NativeContext<'_>, &[Handle]) -> Result<Handle, NativeError> + 'static,
+    {
+        let (name, name_ref) = self.alloc_string(String::from(name));
+        self.stack_push(Value::object(name))?;
+        let (fun, _) = self.alloc_native_fun(ObjNativeFun {
+            name: name_ref,
+            arity,
+            call: RefCell::new(Box::new(call)),
+        });
+        self.stack_push(Value::object(fun))?;
+        let slot = self
+            .globals
+            .slot(BUILTINS_NAMESPACE, name_ref)
+            .ok_or(RuntimeError::TooManyGlobals)?;
+        // SAFETY: The slot was just given out by the globals.
+        let builtin = unsafe { self.globals.at_mut(slot) };
+        if builtin.is_const {
+            return Err(RuntimeError::AssignToConst(name_ref.data.clone()));
+        }
+        let previous = builtin.value.replace(Value::object(fun));
+
+        // Copy the function into the modules that have been added, the variables they defined
+        // are kept.
+        for namespace in BUILTINS_NAMESPACE + 1..self.globals.namespace_count() {
+            let slot = self
+                .globals
+                .slot(namespace, name_ref)
+                .ok_or(RuntimeError::TooManyGlobals)?;
+            // SAFETY: The slot was just given out by the globals.
+            let global = unsafe { self.globals.at_mut(slot) };
+            if !global.is_const && (global.value.is_none() || global.value == previous) {
+                global.value = Some(Value::object(fun));
+            }
+        }
+
+        self.stack_pop();
+        self.stack_pop();
+        Ok(())
+    }
+
+    /// Allocate a string and return a handle to it.
+    pub fn new_string(&mut self, s: &str) -> Handle {
+        let (s, _) = self.alloc_string(String::from(s));
+        self.handle(Value::object(s))
+    }
+
+    /// Call a function, method, or class with the given arguments and return the result. The
+    /// virtual machine is reset if the call fails.
+    pub fn call(&mut self, callee: &Handle, args: &[Handle]) -> Result<Handle, RuntimeError> {
+        let argc = u8::try_from(args.len()).map_err(|_| RuntimeError::TooManyArguments)?;
+        let callee = callee
+            .value_for(&self.token)
+            .ok_or(RuntimeError::ForeignHandle)?;
+        let args = args
+            .iter()
+            .map(|arg| arg.value_for(&self.token))
+            .collect::<Option<Vec<_>>>()
+            .ok_or(RuntimeError::ForeignHandle)?;
+
+        let result = self.call_with_args(callee, &args, argc);
+        if result.is_err() {
+            self.reset();
+        }
+        result.map(|value| self.handle(value))
+    }
+
+    fn call_with_args(
+        &mut self,
+        callee: Value,
+        args: &[Value],
+        argc: u8,
+    ) -> Result<Value, RuntimeError> {
+        self.stack_push(callee)?;
+        for arg in args {
+            self.stack_push(*arg)?;
+        }
+        self.call_value(callee, argc)?;
+        // Native functions return right away without pushing a call frame.
+        if !self.frames.is_empty() {
+            self.run_frames()?;
+        }
+        Ok(self.stack_pop())
+    }
+
+    /// Create a handle to the given value, and keep the value as a root while the handle is
+    /// alive.
+    fn handle(&mut self, value: Value) -> Handle {
+        let handle = Handle::from_value(value, Rc::downgrade(&self.token));
+        if value.as_object().is_some() {
+            self.handles.push(handle.root());
+        }
+        handle
+    }
+
+    /// Compile the source code of the module with the given namespace. The diagnostics are
+    /// written to the error output.
+    fn compile_in(
+        &mut self,
+        namespace: usize,
+        source: &Rc<Source>,
+    ) -> Result<ObjFun, Vec<Diagnostic>> {
+        let parser = Parser::new(source, &mut self.heap, &mut self.globals, namespace)
+            .optimize(self.optimizing);
+        parser
+            .compile()
+            .inspect_err(|diagnostics| self.write_diagnostics(diagnostics))
+    }
+
+    fn write_diagnostics(&mut self, diagnostics: &[Diagnostic]) {
+        for diagnostic in diagnostics {
+            writeln!(self.stderr, "{diagnostic}").ok();
+        }
+    }
+
+    /// Remove all values and call frames left by a runtime error.
+    fn reset(&mut self) {
+        self.stack.clear();
+        self.frames.clear();
+        self.open_upvalues.clear();
+    }
+
     fn run(&mut self, fun: ObjFun) -> Result<(), RuntimeError> {
-        // Push the constant onto the stack so GC won't remove it while allocating the function.
-        for constant in &fun.chunk.constants {
-            self.stack_push(*constant)?;
+        self.call_script(fun)?;
+        self.run_frames()?;
+        // Discard the result of the script.
+        self.stack_pop();
+        Ok(())
+    }
+
+    /// Run until all call frames have returned. Execution continues from the exception handlers
+    /// when runtime errors are caught.
+    fn run_frames(&mut self) -> Result<(), RuntimeError> {
+        loop {
+            match self.exec() {
+                Ok(()) => return Ok(()),
+                // Continue running from the exception handler if there's one.
+                Err(err) => self.catch(err)?,
+            }
         }
-        let constant_count = fun.chunk.constants.len();
-        let (fun_object, fun_ref) = self.alloc_fun(fun);
-        // Remove all added constants.
-        self.stack_remove_top(constant_count);
+    }
+
+    /// Push a new call frame for the function compiled from a script.
+    fn call_script(&mut self, fun: ObjFun) -> Result<(), RuntimeError> {
+        // Allocate the function without running the GC. The constants of the function aren't
+        // reachable from any root until the function object is put onto the stack, and a chunk
+        // can hold more constants than what fits on the stack.
+        let (fun_object, fun_ref) = self.heap.alloc(fun, Object::Fun);
 
         // Push the function onto the stack so GC won't remove it while we allocating the closure.
-        self.stack_push(Value::Object(fun_object))?;
+        self.stack_push(Value::object(fun_object))?;
         // Create a closure for the script function. Note that script can't have upvalues.
         let (closure_object, closure_ref) = self.alloc_closure(ObjClosure {
             fun: fun_ref,
@@ -169,9 +729,29 @@ impl VirtualMachine {
         self.stack_pop();
 
         // Push the closure onto the stack so GC won't remove for the entire runtime.
-        self.stack_push(Value::Object(closure_object))?;
+        self.stack_push(Value::object(closure_object))?;
         // Start running the closure.
-        self.call_closure(closure_ref, 0).and_then(|_| self.exec())
+        self.call_closure(closure_ref, 0)
+    }
+
+    /// Add the global namespace of a new module, and define the builtins in it. Return `None` if
+    /// there's no slot left for the builtins, in which case the namespace isn't added.
+    fn add_module(&mut self) -> Option<usize> {
+        let namespace = self.globals.add_namespace();
+        for builtin in self.globals.slots(BUILTINS_NAMESPACE) {
+            // SAFETY: The slots were given out by the globals.
+            let (name, value) = {
+                let global = unsafe { self.globals.at(builtin) };
+                (global.name, global.value)
+            };
+            let Some(slot) = self.globals.slot(namespace, name) else {
+                self.globals.remove_last_namespace();
+                return None;
+            };
+            // SAFETY: The slot was just given out by the globals.
+            unsafe { self.globals.at_mut(slot) }.value = value;
+        }
+        Some(namespace)
     }
 
     fn exec(&mut self) -> Result<(), RuntimeError> {
@@ -185,27 +765,35 @@ impl VirtualMachine {
                         .ip
                         .offset_from(frame.closure.fun.chunk.instructions.as_ptr())
                 };
-                disassemble_instruction(&frame.closure.fun.chunk, offset as usize);
+                let mut out = String::new();
+                disassemble_instruction(
+                    &mut out,
+                    &frame.closure.fun.chunk,
+                    &self.globals,
+                    offset as usize,
+                )
+                .ok();
+                print!("{out}");
             }
 
             match Opcode::from(self.read_byte()?) {
-                Opcode::Const => self.constant()?,
-                Opcode::Nil => self.stack_push(Value::Nil)?,
-                Opcode::True => self.stack_push(Value::Bool(true))?,
-                Opcode::False => self.stack_push(Value::Bool(false))?,
+                Opcode::Const => self.constant(OperandWidth::Byte)?,
+                Opcode::Nil => self.stack_push(Value::NIL)?,
+                Opcode::True => self.stack_push(Value::bool(true))?,
+                Opcode::False => self.stack_push(Value::bool(false))?,
                 Opcode::Pop => {
                     self.stack_pop();
                 }
-                Opcode::GetLocal => self.get_local()?,
-                Opcode::SetLocal => self.set_local()?,
-                Opcode::GetGlobal => self.get_global()?,
-                Opcode::SetGlobal => self.set_global()?,
-                Opcode::DefineGlobal => self.define_global()?,
-                Opcode::GetUpvalue => self.get_upvalue()?,
-                Opcode::SetUpvalue => self.set_upvalue()?,
-                Opcode::GetProperty => self.get_property()?,
-                Opcode::SetProperty => self.set_property()?,
-                Opcode::GetSuper => self.get_super()?,
+                Opcode::GetLocal => self.get_local(OperandWidth::Byte)?,
+                Opcode::SetLocal => self.set_local(OperandWidth::Byte)?,
+                Opcode::GetGlobal => self.get_global_instruction(OperandWidth::Byte)?,
+                Opcode::SetGlobal => self.set_global_instruction(OperandWidth::Byte)?,
+                Opcode::DefineGlobal => self.define_global(OperandWidth::Byte)?,
+                Opcode::GetUpvalue => self.get_upvalue(OperandWidth::Byte)?,
+                Opcode::SetUpvalue => self.set_upvalue(OperandWidth::Byte)?,
+                Opcode::GetProperty => self.get_property(OperandWidth::Byte)?,
+                Opcode::SetProperty => self.set_property(OperandWidth::Byte)?,
+                Opcode::GetSuper => self.get_super(OperandWidth::Byte)?,
                 Opcode::NE => self.ne()?,
                 Opcode::EQ => self.eq()?,
                 Opcode::GT => self.gt()?,
@@ -223,26 +811,332 @@ impl VirtualMachine {
                 Opcode::JumpIfTrue => self.jump_if_true()?,
                 Opcode::JumpIfFalse => self.jump_if_false()?,
                 Opcode::Loop => self.jump(JumpDirection::Backward)?,
-                Opcode::Call => self.call()?,
-                Opcode::Invoke => self.invoke()?,
-                Opcode::SuperInvoke => self.super_invoke()?,
-                Opcode::Closure => self.closure()?,
+                Opcode::Call => self.call_instruction()?,
+                Opcode::Invoke => self.invoke(OperandWidth::Byte)?,
+                Opcode::SuperInvoke => self.super_invoke(OperandWidth::Byte)?,
+                Opcode::Closure => self.closure(OperandWidth::Byte)?,
                 Opcode::CloseUpvalue => self.close_upvalue()?,
                 Opcode::Ret => {
                     if self.ret()? {
                         break;
                     }
                 }
-                Opcode::Class => self.class()?,
+                Opcode::Class => self.class(OperandWidth::Byte)?,
                 Opcode::Inherit => self.inherit()?,
-                Opcode::Method => self.method()?,
+                Opcode::Method => self.method(OperandWidth::Byte)?,
+                Opcode::Stringify => self.stringify()?,
+                Opcode::ConstLong => self.constant(OperandWidth::Long)?,
+                Opcode::GetGlobalLong => self.get_global_instruction(OperandWidth::Long)?,
+                Opcode::SetGlobalLong => self.set_global_instruction(OperandWidth::Long)?,
+                Opcode::DefineGlobalLong => self.define_global(OperandWidth::Long)?,
+                Opcode::GetPropertyLong => self.get_property(OperandWidth::Long)?,
+                Opcode::SetPropertyLong => self.set_property(OperandWidth::Long)?,
+                Opcode::GetSuperLong => self.get_super(OperandWidth::Long)?,
+                Opcode::InvokeLong => self.invoke(OperandWidth::Long)?,
+                Opcode::SuperInvokeLong => self.super_invoke(OperandWidth::Long)?,
+                Opcode::ClosureLong => self.closure(OperandWidth::Long)?,
+                Opcode::ClassLong => self.class(OperandWidth::Long)?,
+                Opcode::MethodLong => self.method(OperandWidth::Long)?,
+                Opcode::GetLocalLong => self.get_local(OperandWidth::Short)?,
+                Opcode::SetLocalLong => self.set_local(OperandWidth::Short)?,
+                Opcode::GetUpvalueLong => self.get_upvalue(OperandWidth::Short)?,
+                Opcode::SetUpvalueLong => self.set_upvalue(OperandWidth::Short)?,
+                Opcode::DefineGlobalConst => self.define_global_const(OperandWidth::Byte)?,
+                Opcode::DefineGlobalConstLong => self.define_global_const(OperandWidth::Long)?,
+                Opcode::BuildList => self.build_list(OperandWidth::Byte)?,
+                Opcode::BuildListLong => self.build_list(OperandWidth::Short)?,
+                Opcode::GetIndex => self.get_index()?,
+                Opcode::SetIndex => self.set_index()?,
+                Opcode::BuildMap => self.build_map(OperandWidth::Byte)?,
+                Opcode::BuildMapLong => self.build_map(OperandWidth::Short)?,
+                Opcode::Try => self.try_()?,
+                Opcode::PopHandler => self.pop_handler()?,
+                Opcode::Throw => self.throw()?,
+                Opcode::EndFinally => self.end_finally()?,
+                Opcode::Import => self.import(OperandWidth::Byte)?,
+                Opcode::ImportLong => self.import(OperandWidth::Long)?,
+                Opcode::FinishImport => self.finish_import()?,
+            }
+        }
+        Ok(())
+    }
+
+    // Start running the imported module unless it has been loaded. Its namespace is kept in the
+    // current call frame, and the result of running it is pushed onto the stack, which is nil if it
+    // has been loaded.
+    fn import(&mut self, width: OperandWidth) -> Result<(), RuntimeError> {
+        let path = self.read_constant(width)?.as_string()?;
+        let import_failed = |err: std::io::Error| RuntimeError::ImportFailed {
+            path: path.data.clone(),
+            reason: err.to_string(),
+        };
+
+        let importer = Rc::clone(&self.frame().closure.fun.source);
+        let resolved = self
+            .resolver
+            .resolve(importer.path.as_deref(), &path.data)
+            .map_err(import_failed)?;
+        // A module that is still running because of a circular import only gives out the
+        // variables that it has defined so far.
+        if let Some(&namespace) = self.modules.get(&resolved) {
+            self.frame_mut().import = Some(namespace);
+            return self.stack_push(Value::NIL);
+        }
+
+        let source = Rc::new(Source {
+            text: self.resolver.load(&resolved).map_err(import_failed)?,
+            path: Some(resolved.clone()),
+        });
+        let namespace = self.add_module().ok_or(RuntimeError::TooManyGlobals)?;
+        let fun = self.compile_in(namespace, &source).map_err(|_| {
+            // Nothing can refer to the namespace of a module that can't be compiled.
+            self.globals.remove_last_namespace();
+            RuntimeError::ImportFailed {
+                path: path.data.clone(),
+                reason: String::from("Compile error."),
+            }
+        })?;
+        self.modules.insert(resolved, namespace);
+        self.frame_mut().import = Some(namespace);
+        self.call_script(fun)
+    }
+
+    // Copy the variables exported by the imported module into the namespace of the current module.
+    // Exported variables that haven't been defined are skipped.
+    fn finish_import(&mut self) -> Result<(), RuntimeError> {
+        // Discard the result of running the module.
+        self.stack_pop();
+        let namespace = self
+            .frame_mut()
+            .import
+            .take()
+            .expect("An import is finished right after it's started.");
+        let importer = self.frame().closure.fun.namespace;
+        for export in self.globals.slots(namespace) {
+            // SAFETY: The slots were given out by the globals.
+            let (name, value) = match unsafe { self.globals.at(export) } {
+                global if global.is_exported => (global.name, global.value),
+                _ => continue,
+            };
+            let Some(value) = value else {
+                continue;
+            };
+            let slot = self
+                .globals
+                .slot(importer, name)
+                .ok_or(RuntimeError::TooManyGlobals)?;
+            // SAFETY: The slot was just given out by the globals.
+            let global = unsafe { self.globals.at_mut(slot) };
+            if global.is_const {
+                return Err(RuntimeError::AssignToConst(name.data.clone()));
+            }
+            global.value = Some(value);
+        }
+        Ok(())
+    }
+
+    fn try_(&mut self) -> Result<(), RuntimeError> {
+        let offset = self.read_short()?;
+        let stack_len = self.stack.len();
+        let frame = self.frame_mut();
+        // SAFETY: The compiler should produce an offset that points to the handler's code.
+        let ip = unsafe { frame.ip.add(offset as usize) };
+        frame.handlers.push(Handler { ip, stack_len });
+        Ok(())
+    }
+
+    fn pop_handler(&mut self) -> Result<(), RuntimeError> {
+        self.frame_mut().handlers.pop();
+        Ok(())
+    }
+
+    fn throw(&mut self) -> Result<(), RuntimeError> {
+        // Fill in the stack of errors that don't have one yet, while the value is still on the
+        // stack so GC won't remove it.
+        if let Ok(instance) = self.stack_top(0).as_instance() {
+            let instance_ref = instance.borrow();
+            let has_empty_stack = self.is_error_class(instance_ref.class)
+                && instance_ref
+                    .field(&self.shapes, self.str_stack)
+                    .is_some_and(|stack| stack.is_nil());
+            drop(instance_ref);
+            if has_empty_stack {
+                let trace = self.stack_trace();
+                let stack = self.alloc_stack_frames(&trace)?;
+                let (shapes, str_stack) = (&mut self.shapes, self.str_stack);
+                self.heap.update(&instance, |instance| {
+                    instance.set_field(shapes, str_stack, stack);
+                });
+            }
+        }
+        let value = self.stack_pop();
+        self.throw_value(value)
+    }
+
+    /// Check whether the class is the `Error` class or one of its subclasses.
+    fn is_error_class(&self, class: RefClass) -> bool {
+        let Some(error_class) = self.error_class else {
+            return false;
+        };
+        let mut class = Some(class);
+        while let Some(current) = class {
+            if current.ptr_eq(&error_class) {
+                return true;
             }
+            class = current.borrow().superclass;
+        }
+        false
+    }
+
+    // Finish a finally block. At this moment, the top most item in the stack is the flag for
+    // whether the block was reached by an exception, and the second top most item is the thrown
+    // value.
+    fn end_finally(&mut self) -> Result<(), RuntimeError> {
+        let rethrow = self.stack_pop();
+        let value = self.stack_pop();
+        if rethrow.is_truthy() {
+            self.throw_value(value)?;
+        }
+        Ok(())
+    }
+
+    /// Continue running from the innermost exception handler with the thrown value on the stack.
+    fn throw_value(&mut self, value: Value) -> Result<(), RuntimeError> {
+        if !self.unwind()? {
+            return Err(RuntimeError::Uncaught(self.describe_exception(value)));
+        }
+        self.stack_push(value)
+    }
+
+    /// Handle a runtime error by throwing it as an instance of the `Error` class if there's an
+    /// exception handler, otherwise, return the error.
+    fn catch(&mut self, err: RuntimeError) -> Result<(), RuntimeError> {
+        let trace = self.stack_trace();
+        if !self.unwind()? {
+            return Err(err);
         }
+
+        // Keep the objects on the stack so GC won't remove them while we're allocating.
+        let class = self.error_class.expect("The Error class wasn't defined.");
+        let (message, _) = self.alloc_string(err.to_string());
+        self.stack_push(Value::object(message))?;
+        let (instance, instance_ref) = self.alloc_instance(ObjInstance::new(class));
+        self.stack_pop();
+
+        let (shapes, str_message, str_line) = (&mut self.shapes, self.str_message, self.str_line);
+        self.heap.update(&instance_ref, |instance| {
+            instance.set_field(shapes, str_message, Value::object(message));
+            if let Some(frame) = trace.first() {
+                let line = Value::number(frame.line as f64);
+                instance.set_field(shapes, str_line, line);
+            }
+        });
+        self.stack_push(Value::object(instance))?;
+
+        let stack = self.alloc_stack_frames(&trace)?;
+        let (shapes, str_stack) = (&mut self.shapes, self.str_stack);
+        self.heap.update(&instance_ref, |instance| {
+            instance.set_field(shapes, str_stack, stack);
+        });
         Ok(())
     }
 
-    fn super_invoke(&mut self) -> Result<(), RuntimeError> {
-        let method = self.read_constant()?.as_string()?;
+    /// Capture the calls that are currently active, starting from the innermost one.
+    fn stack_trace(&self) -> Vec<StackFrame> {
+        self.frames
+            .iter()
+            .rev()
+            .map(|frame| {
+                let fun = &frame.closure.fun;
+                let span = frame.span();
+                StackFrame {
+                    function: fun.name.map(|name| name.data.clone()),
+                    file: String::from(fun.source.name()),
+                    line: span.line as usize,
+                    column: span.column as usize,
+                    excerpt: fun
+                        .source
+                        .underline(span)
+                        .map(|(code, marker)| format!("    {code}\n    {marker}")),
+                }
+            })
+            .collect()
+    }
+
+    /// Create a list of `StackFrame` instances from the captured calls. The list isn't reachable
+    /// from any root once it's returned, so it must be stored before anything else is allocated.
+    fn alloc_stack_frames(&mut self, trace: &[StackFrame]) -> Result<Value, RuntimeError> {
+        let class = self
+            .stack_frame_class
+            .expect("The StackFrame class wasn't defined.");
+        // Keep the objects on the stack so GC won't remove them while we're allocating.
+        let (list, list_ref) = self.alloc_list(ObjList { items: Vec::new() });
+        self.stack_push(Value::object(list))?;
+        for frame in trace {
+            let (instance, instance_ref) = self.alloc_instance(ObjInstance::new(class));
+            self.stack_push(Value::object(instance))?;
+            let function = match &frame.function {
+                Some(name) => Value::object(self.alloc_string(name.clone()).0),
+                None => Value::NIL,
+            };
+            let (shapes, str_function) = (&mut self.shapes, self.str_function);
+            self.heap.update(&instance_ref, |instance| {
+                instance.set_field(shapes, str_function, function);
+            });
+            let (file, _) = self.alloc_string(frame.file.clone());
+
+            let shapes = &mut self.shapes;
+            let (str_file, str_line, str_column) = (self.str_file, self.str_line, self.str_column);
+            self.heap.update(&instance_ref, |instance| {
+                instance.set_field(shapes, str_file, Value::object(file));
+                instance.set_field(shapes, str_line, Value::number(frame.line as f64));
+                instance.set_field(shapes, str_column, Value::number(frame.column as f64));
+            });
+            self.heap
+                .update(&list_ref, |list| list.items.push(Value::object(instance)));
+            self.stack_pop();
+        }
+        Ok(self.stack_pop())
+    }
+
+    /// Remove the innermost exception handler along with the call frames and the stack values
+    /// above it, and jump to the handler's code. Return `false` if there's no handler.
+    fn unwind(&mut self) -> Result<bool, RuntimeError> {
+        let Some(depth) = self
+            .frames
+            .iter()
+            .rposition(|frame| !frame.handlers.is_empty())
+        else {
+            return Ok(false);
+        };
+        self.frames.truncate(depth + 1);
+        let frame = self.frames.last_mut().expect("Stack is empty.");
+        self.current_frame = NonNull::from(frame);
+
+        let handler = self
+            .frame_mut()
+            .handlers
+            .pop()
+            .expect("Handlers are empty.");
+        self.close_upvalues(handler.stack_len)?;
+        self.stack.truncate(handler.stack_len);
+        self.frame_mut().ip = handler.ip;
+        Ok(true)
+    }
+
+    /// Get the message of a thrown value. Instances that have a `message` field are described
+    /// by that field.
+    fn describe_exception(&self, value: Value) -> String {
+        if let Ok(instance) = value.as_instance() {
+            if let Some(message) = instance.borrow().field(&self.shapes, self.str_message) {
+                return message.to_string();
+            }
+        }
+        value.to_string()
+    }
+
+    fn super_invoke(&mut self, width: OperandWidth) -> Result<(), RuntimeError> {
+        let method = self.read_constant(width)?.as_string()?;
         let argc = self.read_byte()?;
 
         let superclass = self.stack_pop().as_class()?;
@@ -250,22 +1144,126 @@ impl VirtualMachine {
         Ok(())
     }
 
-    fn invoke(&mut self) -> Result<(), RuntimeError> {
-        let method = self.read_constant()?.as_string()?;
+    fn invoke(&mut self, width: OperandWidth) -> Result<(), RuntimeError> {
+        let method = self.read_constant(width)?.as_string()?;
         let argc = self.read_byte()?;
+        let fun = self.frame().closure.fun;
+        let cache = fun.chunk.caches.get(self.read_short()? as usize);
 
         let receiver = self.stack_top(argc as usize);
+        if let Ok(list) = receiver.as_list() {
+            return self.invoke_list(list, method, argc);
+        }
+        if let Ok(map) = receiver.as_map() {
+            return self.invoke_map(map, method, argc);
+        }
         let instance = receiver
             .as_instance()
             .map_err(|_| RuntimeError::InvalidMethodInvocation)?;
 
-        if let Some(field) = instance.borrow().fields.get(method) {
-            *self.stack_top_mut(argc as usize) = *field;
-            self.call_value(*field, argc)?;
+        let instance = instance.borrow();
+        if let Some(field) = find_field(&self.shapes, &instance, method, cache) {
+            drop(instance);
+            *self.stack_top_mut(argc as usize) = field;
+            self.call_value(field, argc)?;
         } else {
-            self.invoke_from_class(instance.borrow().class, method, argc)?;
+            let method = find_method(&instance, method, cache)
+                .ok_or_else(|| RuntimeError::UndefinedProperty(method.to_string()))?;
+            drop(instance);
+            self.call_closure(method, argc)?;
+        }
+
+        Ok(())
+    }
+
+    // Call a native method of a list. At this moment, the list and the arguments are on top of the
+    // stack, and they are replaced with the returned value.
+    fn invoke_list(
+        &mut self,
+        list: RefList,
+        name: RefString,
+        argc: u8,
+    ) -> Result<(), RuntimeError> {
+        let arity = match name.data.as_str() {
+            "push" | "remove" => 1,
+            "pop" | "len" => 0,
+            "insert" => 2,
+            _ => return Err(RuntimeError::UndefinedProperty(name.to_string())),
+        };
+        if argc != arity {
+            return Err(RuntimeError::InvalidArgumentsCount { arity, argc });
+        }
+
+        let mut args = [Value::NIL; 2];
+        args[..argc as usize].copy_from_slice(&self.stack[self.stack.len() - argc as usize..]);
+        let result = self
+            .heap
+            .update(&list, |list| -> Result<Value, RuntimeError> {
+                let len = list.items.len();
+                let result = match name.data.as_str() {
+                    "push" => {
+                        list.items.push(args[0]);
+                        Value::NIL
+                    }
+                    "pop" => list.items.pop().ok_or(RuntimeError::PopFromEmptyList)?,
+                    "len" => Value::number(len as f64),
+                    "insert" => {
+                        // Inserting at the end of the list is allowed.
+                        let index = list_index(&args[0], len + 1)?;
+                        list.items.insert(index, args[1]);
+                        Value::NIL
+                    }
+                    "remove" => {
+                        let index = list_index(&args[0], len)?;
+                        list.items.remove(index)
+                    }
+                    _ => unreachable!(),
+                };
+                Ok(result)
+            })?;
+
+        self.stack_remove_top(argc as usize + 1);
+        self.stack_push(result)?;
+        Ok(())
+    }
+
+    // Call a native method of a map. At this moment, the map and the arguments are on top of the
+    // stack, and they are replaced with the returned value.
+    fn invoke_map(&mut self, map: RefMap, name: RefString, argc: u8) -> Result<(), RuntimeError> {
+        let arity = match name.data.as_str() {
+            "has" | "remove" => 1,
+            "keys" | "values" | "len" => 0,
+            _ => return Err(RuntimeError::UndefinedProperty(name.to_string())),
+        };
+        if argc != arity {
+            return Err(RuntimeError::InvalidArgumentsCount { arity, argc });
         }
 
+        let result = match name.data.as_str() {
+            "has" => Value::bool(map.borrow().entries.get(*self.stack_top(0)).is_some()),
+            "remove" => {
+                let key = *self.stack_top(0);
+                self.heap
+                    .update(&map, |map| map.entries.del(key))
+                    .unwrap_or_default()
+            }
+            "len" => Value::number(map.borrow().entries.len() as f64),
+            "keys" | "values" => {
+                let items = map
+                    .borrow()
+                    .entries
+                    .iter()
+                    .map(|(key, value)| if name.data == "keys" { key } else { *value })
+                    .collect();
+                // The map is still on the stack, so the items can't be collected by the GC.
+                let (list, _) = self.alloc_list(ObjList { items });
+                Value::object(list)
+            }
+            _ => unreachable!(),
+        };
+
+        self.stack_remove_top(argc as usize + 1);
+        self.stack_push(result)?;
         Ok(())
     }
 
@@ -286,75 +1284,167 @@ impl VirtualMachine {
 
     // Bind a method to a class definition. At this moment, a closure object should be the top most
     // item in the stack, and a class definition object should be the second top most item.
-    fn method(&mut self) -> Result<(), RuntimeError> {
-        let name = self.read_constant()?.as_string()?;
+    fn method(&mut self, width: OperandWidth) -> Result<(), RuntimeError> {
+        let name = self.read_constant(width)?.as_string()?;
         let closure = self.stack_pop().as_closure()?;
         let class = self.stack_top(0).as_class()?;
-        class.borrow_mut().methods.set(name, closure);
+        self.heap
+            .update(&class, |class| class.set_method(name, closure));
         Ok(())
     }
 
-    fn bind_method(&mut self, class: RefClass, name: RefString) -> Result<bool, RuntimeError> {
-        match class.borrow().methods.get(name) {
-            Some(method) => {
-                let (bound, _) = self.alloc_bound_method(ObjBoundMethod {
-                    receiver: *self.stack_top(0),
-                    method: *method,
-                });
-                self.stack_pop();
-                self.stack_push(Value::Object(bound))?;
-                Ok(true)
-            }
-            None => Ok(false),
-        }
+    // Replace the receiver on top of the stack with a method bound to it.
+    fn bind_method(&mut self, method: RefClosure) -> Result<(), RuntimeError> {
+        let (bound, _) = self.alloc_bound_method(ObjBoundMethod {
+            receiver: *self.stack_top(0),
+            method,
+        });
+        self.stack_pop();
+        self.stack_push(Value::object(bound))?;
+        Ok(())
     }
 
-    fn get_property(&mut self) -> Result<(), RuntimeError> {
-        let name = self.read_constant()?.as_string()?;
+    fn get_property(&mut self, width: OperandWidth) -> Result<(), RuntimeError> {
+        let name = self.read_constant(width)?.as_string()?;
+        let fun = self.frame().closure.fun;
+        let cache = fun.chunk.caches.get(self.read_short()? as usize);
         let instance = self
             .stack_top(0)
             .as_instance()
             .map_err(|_| RuntimeError::ObjectHasNoProperty)?;
 
         let instance = instance.borrow();
-        if let Some(value) = instance.fields.get(name) {
-            self.stack_pop();
-            self.stack_push(*value)?;
-            Ok(())
-        } else if self.bind_method(instance.class, name)? {
+        if let Some(value) = find_field(&self.shapes, &instance, name, cache) {
+            *self.stack_top_mut(0) = value;
             Ok(())
+        } else if let Some(method) = find_method(&instance, name, cache) {
+            self.bind_method(method)
         } else {
             Err(RuntimeError::UndefinedProperty(name.to_string()))
         }
     }
 
-    fn set_property(&mut self) -> Result<(), RuntimeError> {
-        let name = self.read_constant()?.as_string()?;
+    fn set_property(&mut self, width: OperandWidth) -> Result<(), RuntimeError> {
+        let name = self.read_constant(width)?.as_string()?;
+        let fun = self.frame().closure.fun;
+        let cache = fun.chunk.caches.get(self.read_short()? as usize);
         let value = self.stack_pop();
         let instance = self
             .stack_top(0)
             .as_instance()
             .map_err(|_| RuntimeError::ObjectHasNoField)?;
 
-        instance.borrow_mut().fields.set(name, value);
-        self.stack_pop();
-        self.stack_push(value)?;
+        let shapes = &mut self.shapes;
+        self.heap.update(&instance, |instance| {
+            let shape = instance.shape;
+            match cache.map(Cell::get) {
+                Some(InlineCache::Field { shape: seen, slot }) if seen == shape => {
+                    instance.fields[slot] = value;
+                }
+                Some(InlineCache::Transition { from, to }) if from == shape => {
+                    instance.shape = to;
+                    instance.fields.push(value);
+                }
+                _ => {
+                    let update = match shapes.slot(shape, name) {
+                        Some(slot) => {
+                            instance.fields[slot] = value;
+                            InlineCache::Field { shape, slot }
+                        }
+                        None => {
+                            let to = shapes.transition(shape, name);
+                            instance.shape = to;
+                            instance.fields.push(value);
+                            InlineCache::Transition { from: shape, to }
+                        }
+                    };
+                    if let Some(cache) = cache {
+                        cache.set(update);
+                    }
+                }
+            }
+        });
+        *self.stack_top_mut(0) = value;
         Ok(())
     }
 
-    fn get_super(&mut self) -> Result<(), RuntimeError> {
-        let name = self.read_constant()?.as_string()?;
-        let superclass = self.stack_pop().as_class()?;
-        if !self.bind_method(superclass, name)? {
-            return Err(RuntimeError::UndefinedProperty(name.to_string()));
-        }
+    // Create a list from the `count` values on top of the stack. The values are kept on the stack
+    // while the list is allocated so the GC can see them.
+    fn build_list(&mut self, width: OperandWidth) -> Result<(), RuntimeError> {
+        let count = self.read_operand(width)?;
+        let items = self.stack[self.stack.len() - count..].to_vec();
+        let (list, _) = self.alloc_list(ObjList { items });
+        self.stack_remove_top(count);
+        self.stack_push(Value::object(list))?;
         Ok(())
     }
 
-    fn class(&mut self) -> Result<(), RuntimeError> {
-        let name = self.read_constant()?.as_string()?;
+    // Create a map from the `count` key-value pairs on top of the stack. The pairs are kept on the
+    // stack while the map is allocated so the GC can see them.
+    fn build_map(&mut self, width: OperandWidth) -> Result<(), RuntimeError> {
+        let count = self.read_operand(width)?;
+        let mut map = ObjMap::default();
+        for pair in self.stack[self.stack.len() - 2 * count..].chunks_exact(2) {
+            map.entries.set(map_key(pair[0])?, pair[1]);
+        }
+        let (map, _) = self.alloc_map(map);
+        self.stack_remove_top(2 * count);
+        self.stack_push(Value::object(map))?;
+        Ok(())
+    }
+
+    fn get_index(&mut self) -> Result<(), RuntimeError> {
+        let index = self.stack_pop();
+        let value = match self.stack_top(0).as_object() {
+            Some(Object::List(list)) => {
+                let list = list.borrow();
+                list.items[list_index(&index, list.items.len())?]
+            }
+            Some(Object::Map(map)) => *map
+                .borrow()
+                .entries
+                .get(index)
+                .ok_or_else(|| RuntimeError::UndefinedKey(index.to_string()))?,
+            _ => return Err(RuntimeError::ObjectNotIndexable),
+        };
+        *self.stack_top_mut(0) = value;
+        Ok(())
+    }
+
+    fn set_index(&mut self) -> Result<(), RuntimeError> {
+        let value = self.stack_pop();
+        let index = self.stack_pop();
+        match self.stack_top(0).as_object() {
+            Some(Object::List(list)) => {
+                let mut list = list.borrow_mut();
+                let index = list_index(&index, list.items.len())?;
+                list.items[index] = value;
+            }
+            Some(Object::Map(map)) => {
+                let key = map_key(index)?;
+                self.heap.update(&map, |map| map.entries.set(key, value));
+            }
+            _ => return Err(RuntimeError::ObjectNotIndexable),
+        }
+        *self.stack_top_mut(0) = value;
+        Ok(())
+    }
+
+    fn get_super(&mut self, width: OperandWidth) -> Result<(), RuntimeError> {
+        let name = self.read_constant(width)?.as_string()?;
+        let superclass = self.stack_pop().as_class()?;
+        let method = *superclass
+            .borrow()
+            .methods
+            .get(name)
+            .ok_or_else(|| RuntimeError::UndefinedProperty(name.to_string()))?;
+        self.bind_method(method)
+    }
+
+    fn class(&mut self, width: OperandWidth) -> Result<(), RuntimeError> {
+        let name = self.read_constant(width)?.as_string()?;
         let (class, _) = self.alloc_class(ObjClass::new(name));
-        self.stack_push(Value::Object(class))?;
+        self.stack_push(Value::object(class))?;
         Ok(())
     }
 
@@ -364,23 +1454,26 @@ impl VirtualMachine {
             .as_class()
             .map_err(|_| RuntimeError::InvalidSuperclass)?;
         let subclass = self.stack_top(0).as_class()?;
-        for (method_name, method) in superclass.borrow().methods.iter() {
-            subclass.borrow_mut().methods.set(method_name, *method);
-        }
+        self.heap.update(&subclass, |subclass| {
+            for (method_name, method) in superclass.borrow().methods.iter() {
+                subclass.set_method(method_name, *method);
+            }
+            subclass.superclass = Some(superclass);
+        });
         self.stack_pop();
         Ok(())
     }
 
     /// Get the value of the variable capture by an upvalue.
-    fn get_upvalue(&mut self) -> Result<(), RuntimeError> {
-        let upvalue_slot = self.read_byte()?;
-        let upvalue = self.frame().closure.upvalues[upvalue_slot as usize];
+    fn get_upvalue(&mut self, width: OperandWidth) -> Result<(), RuntimeError> {
+        let upvalue_slot = self.read_operand(width)?;
+        let upvalue = self.frame().closure.upvalues[upvalue_slot];
         match *upvalue.borrow() {
             // Value is on the stack.
             ObjUpvalue::Open(stack_slot) => {
                 // SAFETY: The compiler should produce safe byte codes such that we never
                 // access uninitialized data.
-                let value = unsafe { self.stack.at(stack_slot) };
+                let value = unsafe { self.stack.get_unchecked(stack_slot) };
                 self.stack_push(*value)?;
             }
             // Value is on the heap.
@@ -392,11 +1485,11 @@ impl VirtualMachine {
     }
 
     /// Set the value of the variable capture by an upvalue.
-    fn set_upvalue(&mut self) -> Result<(), RuntimeError> {
-        let upvalue_slot = self.read_byte()?;
+    fn set_upvalue(&mut self, width: OperandWidth) -> Result<(), RuntimeError> {
+        let upvalue_slot = self.read_operand(width)?;
         let value = *self.stack_top(0);
         let stack_slot = {
-            let mut upvalue = self.frame().closure.upvalues[upvalue_slot as usize].borrow_mut();
+            let mut upvalue = self.frame().closure.upvalues[upvalue_slot].borrow_mut();
             match upvalue.deref_mut() {
                 // Value is on the stack.
                 ObjUpvalue::Open(stack_slot) => Some(*stack_slot),
@@ -409,18 +1502,18 @@ impl VirtualMachine {
         };
         if let Some(slot) = stack_slot {
             // SAFETY: The compiler should produce safe code that access a safe part of the stack.
-            let v = unsafe { self.stack.at_mut(slot) };
+            let v = unsafe { self.stack.get_unchecked_mut(slot) };
             *v = value;
         }
         Ok(())
     }
 
-    fn closure(&mut self) -> Result<(), RuntimeError> {
-        let fun = self.read_constant()?.as_fun()?;
-        let mut upvalues = Vec::with_capacity(fun.upvalue_count as usize);
+    fn closure(&mut self, width: OperandWidth) -> Result<(), RuntimeError> {
+        let fun = self.read_constant(width)?.as_fun()?;
+        let mut upvalues = Vec::with_capacity(fun.upvalue_count);
         for _ in 0..fun.upvalue_count {
             let is_local = self.read_byte()? == 1;
-            let index = self.read_byte()? as usize;
+            let index = self.read_short()? as usize;
             if is_local {
                 upvalues.push(self.capture_upvalue(self.frame().slot + index)?);
             } else {
@@ -429,7 +1522,7 @@ impl VirtualMachine {
         }
 
         let (closure, _) = self.alloc_closure(ObjClosure { fun, upvalues });
-        self.stack_push(Value::Object(closure))?;
+        self.stack_push(Value::object(closure))?;
 
         Ok(())
     }
@@ -471,7 +1564,7 @@ impl VirtualMachine {
             // Hoist the variable up into the upvalue so it can live after the stack frame is pop.
             if let Some(slot) = stack_slot {
                 // SAFETY: The compiler should produce safe code that access a safe part of the stack.
-                let v = unsafe { self.stack.at(slot) };
+                let v = unsafe { self.stack.get_unchecked(slot) };
                 *upvalue = ObjUpvalue::Closed(*v);
             }
         }
@@ -490,19 +1583,15 @@ impl VirtualMachine {
         // that need to be closed over.
         self.close_upvalues(self.frame().slot)?;
         let frame = self.frames_pop();
-        if self.frames.len() == 0 {
-            // Have reach the end of the script if there's no stack frame left.
-            self.stack_pop();
-            return Ok(true);
-        }
         // Pop all data related to the stack frame.
         self.stack_remove_top(self.stack.len() - frame.slot);
         // Put the function result on the stack.
         self.stack_push(result)?;
-        Ok(false)
+        // Have reach the end of the script if there's no stack frame left.
+        Ok(self.frames.is_empty())
     }
 
-    fn call(&mut self) -> Result<(), RuntimeError> {
+    fn call_instruction(&mut self) -> Result<(), RuntimeError> {
         let argc = self.read_byte()?;
         let v = self.stack_top(argc as usize);
         self.call_value(*v, argc)?;
@@ -510,8 +1599,8 @@ impl VirtualMachine {
     }
 
     fn call_value(&mut self, callee: Value, argc: u8) -> Result<(), RuntimeError> {
-        match callee {
-            Value::Object(o) => self.call_object(o, argc),
+        match callee.as_object() {
+            Some(o) => self.call_object(o, argc),
             _ => Err(RuntimeError::InvalidCallee),
         }
     }
@@ -537,21 +1626,40 @@ impl VirtualMachine {
             closure: callee,
             ip: callee.fun.chunk.instructions.as_ptr(),
             slot: self.stack.len() - argc as usize - 1,
+            handlers: Vec::new(),
+            import: None,
         };
         self.frames_push(frame)?;
         Ok(())
     }
 
     fn call_native(&mut self, callee: RefNativeFun, argc: u8) -> Result<(), RuntimeError> {
-        if argc != callee.arity {
-            return Err(RuntimeError::InvalidArgumentsCount {
-                arity: callee.arity,
-                argc,
-            });
+        match callee.arity {
+            Arity::Fixed(arity) if argc != arity => {
+                return Err(RuntimeError::InvalidArgumentsCount { arity, argc });
+            }
+            Arity::AtLeast(min) if argc < min => {
+                return Err(RuntimeError::TooFewArguments { min, argc });
+            }
+            _ => {}
         }
         let argc = argc as usize;
-        let call = callee.call;
-        let res = call(self.stack.topn(argc));
+        let mut args = Vec::with_capacity(argc);
+        for distance in (0..argc).rev() {
+            let arg = *self.stack_top(distance);
+            args.push(self.handle(arg));
+        }
+        let res = {
+            let mut call = callee.call.borrow_mut();
+            call(&mut NativeContext::new(self), &args)
+        };
+        let res = res.map_err(|error| RuntimeError::Native {
+            name: callee.name.data.clone(),
+            error,
+        })?;
+        let res = res
+            .value_for(&self.token)
+            .ok_or(RuntimeError::ForeignHandle)?;
         self.stack_remove_top(argc + 1);
         self.stack_push(res)?;
         Ok(())
@@ -560,7 +1668,7 @@ impl VirtualMachine {
     fn call_class(&mut self, callee: RefClass, argc: u8) -> Result<(), RuntimeError> {
         // Allocate a new instance and put it on top of the stack.
         let (instance, _) = self.alloc_instance(ObjInstance::new(callee));
-        *self.stack_top_mut(argc.into()) = Value::Object(instance);
+        *self.stack_top_mut(argc.into()) = Value::object(instance);
         // Call the 'init' method if there's one
         if let Some(init) = callee.borrow().methods.get(self.str_init) {
             self.call_closure(*init, argc)?;
@@ -609,59 +1717,84 @@ impl VirtualMachine {
     }
 
     /// Get a local variable.
-    fn get_local(&mut self) -> Result<(), RuntimeError> {
-        let slot = self.read_byte()? as usize;
+    fn get_local(&mut self, width: OperandWidth) -> Result<(), RuntimeError> {
+        let slot = self.read_operand(width)?;
         let frame_slot = self.frame().slot;
         // SAFETY: The compiler should produce safe code that access a safe part of the stack.
-        let value = unsafe { self.stack.at(frame_slot + slot) };
+        let value = unsafe { self.stack.get_unchecked(frame_slot + slot) };
         self.stack_push(*value)?;
         Ok(())
     }
 
     /// Set a local variable.
-    fn set_local(&mut self) -> Result<(), RuntimeError> {
-        let slot = self.read_byte()? as usize;
+    fn set_local(&mut self, width: OperandWidth) -> Result<(), RuntimeError> {
+        let slot = self.read_operand(width)?;
         let frame_slot = self.frame().slot;
         let value = *self.stack_top(0);
         // SAFETY: The compiler should produce safe code that access a safe part of the stack.
-        let v = unsafe { self.stack.at_mut(frame_slot + slot) };
+        let v = unsafe { self.stack.get_unchecked_mut(frame_slot + slot) };
         *v = value;
         Ok(())
     }
 
     /// Get a global variable or return a runtime error if it was not found.
-    fn get_global(&mut self) -> Result<(), RuntimeError> {
-        let name = self.read_constant()?.as_string()?;
-        let value = self
-            .globals
-            .get(name)
-            .ok_or_else(|| RuntimeError::UndefinedVariable(name.to_string()))?;
-        self.stack_push(*value)?;
+    fn get_global_instruction(&mut self, width: OperandWidth) -> Result<(), RuntimeError> {
+        let slot = self.read_operand(width)?;
+        // SAFETY: The compiler only emits slots that were given out by the globals.
+        let global = unsafe { self.globals.at(slot) };
+        let value = global
+            .value
+            .ok_or_else(|| RuntimeError::UndefinedVariable(global.name.to_string()))?;
+        self.stack_push(value)?;
         Ok(())
     }
 
     /// Set a global variable or return a runtime error if it was not found.
-    fn set_global(&mut self) -> Result<(), RuntimeError> {
-        let name = self.read_constant()?.as_string()?;
-        let value = self.stack_top(0);
-        if self.globals.get(name).is_none() {
-            return Err(RuntimeError::UndefinedVariable(name.to_string()));
+    fn set_global_instruction(&mut self, width: OperandWidth) -> Result<(), RuntimeError> {
+        let slot = self.read_operand(width)?;
+        let value = *self.stack_top(0);
+        // SAFETY: The compiler only emits slots that were given out by the globals.
+        let global = unsafe { self.globals.at_mut(slot) };
+        if global.value.is_none() {
+            return Err(RuntimeError::UndefinedVariable(global.name.to_string()));
+        }
+        if global.is_const {
+            return Err(RuntimeError::AssignToConst(global.name.to_string()));
         }
-        self.globals.set(name, *value);
+        global.value = Some(value);
         Ok(())
     }
 
-    /// Declare a variable with some initial value.
-    fn define_global(&mut self) -> Result<(), RuntimeError> {
-        let name = self.read_constant()?.as_string()?;
+    /// Declare a variable with some initial value. A constant can't be redeclared as a variable.
+    fn define_global(&mut self, width: OperandWidth) -> Result<(), RuntimeError> {
+        let slot = self.read_operand(width)?;
         let value = self.stack_pop();
-        self.globals.set(name, value);
+        // SAFETY: The compiler only emits slots that were given out by the globals.
+        let global = unsafe { self.globals.at_mut(slot) };
+        if global.is_const {
+            return Err(RuntimeError::AssignToConst(global.name.to_string()));
+        }
+        global.value = Some(value);
+        Ok(())
+    }
+
+    /// Declare a constant with some initial value. A constant can't be redeclared.
+    fn define_global_const(&mut self, width: OperandWidth) -> Result<(), RuntimeError> {
+        let slot = self.read_operand(width)?;
+        let value = self.stack_pop();
+        // SAFETY: The compiler only emits slots that were given out by the globals.
+        let global = unsafe { self.globals.at_mut(slot) };
+        if global.is_const {
+            return Err(RuntimeError::AssignToConst(global.name.to_string()));
+        }
+        global.value = Some(value);
+        global.is_const = true;
         Ok(())
     }
 
     /// Read the constant id from the next byte and load the constant with the found id.
-    fn constant(&mut self) -> Result<(), RuntimeError> {
-        let constant = self.read_constant()?;
+    fn constant(&mut self, width: OperandWidth) -> Result<(), RuntimeError> {
+        let constant = self.read_constant(width)?;
         self.stack_push(constant)?;
         Ok(())
     }
@@ -669,42 +1802,42 @@ impl VirtualMachine {
     fn ne(&mut self) -> Result<(), RuntimeError> {
         let rhs = self.stack_pop();
         let lhs = self.stack_top_mut(0);
-        *lhs = Value::Bool((*lhs).ne(&rhs));
+        *lhs = Value::bool((*lhs).ne(&rhs));
         Ok(())
     }
 
     fn eq(&mut self) -> Result<(), RuntimeError> {
         let rhs = self.stack_pop();
         let lhs = self.stack_top_mut(0);
-        *lhs = Value::Bool((*lhs).eq(&rhs));
+        *lhs = Value::bool((*lhs).eq(&rhs));
         Ok(())
     }
 
     fn gt(&mut self) -> Result<(), RuntimeError> {
         let rhs = self.stack_pop();
         let lhs = self.stack_top_mut(0);
-        *lhs = Value::Bool((*lhs).gt(&rhs)?);
+        *lhs = Value::bool((*lhs).gt(&rhs)?);
         Ok(())
     }
 
     fn ge(&mut self) -> Result<(), RuntimeError> {
         let rhs = self.stack_pop();
         let lhs = self.stack_top_mut(0);
-        *lhs = Value::Bool((*lhs).ge(&rhs)?);
+        *lhs = Value::bool((*lhs).ge(&rhs)?);
         Ok(())
     }
 
     fn lt(&mut self) -> Result<(), RuntimeError> {
         let rhs = self.stack_pop();
         let lhs = self.stack_top_mut(0);
-        *lhs = Value::Bool((*lhs).lt(&rhs)?);
+        *lhs = Value::bool((*lhs).lt(&rhs)?);
         Ok(())
     }
 
     fn le(&mut self) -> Result<(), RuntimeError> {
         let rhs = self.stack_pop();
         let lhs = self.stack_top_mut(0);
-        *lhs = Value::Bool((*lhs).le(&rhs)?);
+        *lhs = Value::bool((*lhs).le(&rhs)?);
         Ok(())
     }
 
@@ -713,15 +1846,15 @@ impl VirtualMachine {
         // deaalocate the objects when we allocate a new object for the result.
         let rhs = self.stack_top(0);
         let lhs = self.stack_top(1);
-        let res = match (*lhs, rhs) {
+        let res = match (lhs.as_object(), rhs.as_object()) {
             // Operations on objects might allocate a new one.
-            (Value::Object(o1), Value::Object(o2)) => match (o1, o2) {
+            (Some(o1), Some(o2)) => match (o1, o2) {
                 (Object::String(s1), Object::String(s2)) => {
                     let mut s = String::with_capacity(s1.data.len() + s1.data.len());
                     s.push_str(s1.data.as_ref());
                     s.push_str(s2.data.as_ref());
                     let (object, _) = self.alloc_string(s);
-                    Value::Object(object)
+                    Value::object(object)
                 }
                 _ => {
                     return Err(RuntimeError::Value(
@@ -772,10 +1905,20 @@ impl VirtualMachine {
         Ok(())
     }
 
+    fn stringify(&mut self) -> Result<(), RuntimeError> {
+        // Strings are left untouched. Other values are kept on the stack while the string is
+        // allocated so the GC can see them.
+        let value = self.stack_top(0);
+        if value.as_string().is_err() {
+            let (object, _) = self.alloc_string(value.to_string());
+            *self.stack_top_mut(0) = Value::object(object);
+        }
+        Ok(())
+    }
+
     fn print(&mut self) -> Result<(), RuntimeError> {
         let val = self.stack_pop();
-        println!("{val}");
-        Ok(())
+        writeln!(self.stdout, "{val}").map_err(RuntimeError::Io)
     }
 
     fn gc(&mut self) {
@@ -804,7 +1947,14 @@ impl VirtualMachine {
     fn mark_sweep(&mut self) {
         self.mark_roots();
         while let Some(grey_object) = self.grey_objects.pop() {
-            grey_object.mark_references(&mut self.grey_objects)
+            grey_object.mark_references(&mut self.grey_objects);
+            grey_object.mark_shapes(&mut self.shapes);
+        }
+        // The shapes that are no longer used are freed, and the remaining ones keep the names of
+        // their fields alive. Strings don't reference other objects, so they don't need tracing.
+        self.shapes.sweep();
+        for name in self.shapes.names() {
+            name.mark();
         }
         // SAFETY: We make sure that the sweep step has correctly mark all reachable objects, so
         // sweep can be run safely.
@@ -813,11 +1963,29 @@ impl VirtualMachine {
 
     fn mark_roots(&mut self) {
         self.grey_objects.clear();
-        if self.str_init.mark() {
-            self.grey_objects.push(Object::String(self.str_init));
+        for s in [
+            self.str_init,
+            self.str_message,
+            self.str_line,
+            self.str_stack,
+            self.str_function,
+            self.str_file,
+            self.str_column,
+        ] {
+            if s.mark() {
+                self.grey_objects.push(Object::String(s));
+            }
+        }
+        for class in [self.error_class, self.stack_frame_class]
+            .into_iter()
+            .flatten()
+        {
+            if class.mark() {
+                self.grey_objects.push(Object::Class(class));
+            }
         }
         for value in &self.stack {
-            if let Value::Object(o) = value {
+            if let Some(o) = value.as_object() {
                 o.mark(&mut self.grey_objects);
             }
         }
@@ -831,11 +1999,18 @@ impl VirtualMachine {
                 self.grey_objects.push(Object::Upvalue(*upvalue));
             }
         }
-        for (k, v) in self.globals.iter() {
-            if k.mark() {
-                self.grey_objects.push(Object::String(k))
+        for global in self.globals.iter() {
+            if global.name.mark() {
+                self.grey_objects.push(Object::String(global.name))
             }
-            if let Value::Object(o) = v {
+            if let Some(o) = global.value.and_then(|value| value.as_object()) {
+                o.mark(&mut self.grey_objects);
+            }
+        }
+        // Forget the values of the handles that have been dropped.
+        self.handles.retain(|root| root.strong_count() > 0);
+        for root in self.handles.iter().filter_map(Weak::upgrade) {
+            if let Some(o) = root.value.as_object() {
                 o.mark(&mut self.grey_objects);
             }
         }
@@ -855,12 +2030,19 @@ impl VirtualMachine {
         unsafe { self.frame_mut().read_short() }
     }
 
-    /// Read the next byte in the stream of bytecode instructions and return the constant at the
-    /// index given by the byte.
-    fn read_constant(&mut self) -> Result<Value, RuntimeError> {
+    /// Read the index operand of the current instruction in the stream of bytecode instructions.
+    fn read_operand(&mut self, width: OperandWidth) -> Result<usize, RuntimeError> {
+        // SAFETY: The compiler should produce correct byte codes
+        // so we never read an out-of-bound index.
+        unsafe { self.frame_mut().read_operand(width) }
+    }
+
+    /// Read the constant index in the stream of bytecode instructions and return the constant at
+    /// that index.
+    fn read_constant(&mut self, width: OperandWidth) -> Result<Value, RuntimeError> {
         // SAFETY: The compiler should produce correct byte codes
         // so we never read an out-of-bound index.
-        unsafe { self.frame_mut().read_constant() }
+        unsafe { self.frame_mut().read_constant(width) }
     }
 
     fn frame(&self) -> &CallFrame {
@@ -873,27 +2055,26 @@ impl VirtualMachine {
 
     fn frames_push(&mut self, frame: CallFrame) -> Result<usize, RuntimeError> {
         let frame_count = self.frames.len();
-        if frame_count == MAX_FRAMES {
+        if frame_count == self.max_frames {
             return Err(RuntimeError::StackOverflow);
         }
         self.frames.push(frame);
-        self.current_frame =
-            unsafe { NonNull::new_unchecked(self.frames.top_mut(0) as *mut CallFrame) };
+        // The frames might have been moved, so we always point to the new top.
+        self.current_frame = NonNull::from(self.frames.last_mut().expect("Stack is empty."));
         Ok(frame_count)
     }
 
     fn frames_pop(&mut self) -> CallFrame {
-        let ret = self.frames.pop();
-        if self.frames.len() > 0 {
-            self.current_frame =
-                unsafe { NonNull::new_unchecked(self.frames.top_mut(0) as *mut CallFrame) };
+        let ret = self.frames.pop().expect("Stack is empty.");
+        if let Some(frame) = self.frames.last_mut() {
+            self.current_frame = NonNull::from(frame);
         }
         ret
     }
 
     fn stack_push(&mut self, value: Value) -> Result<(), RuntimeError> {
         let stack_size = self.stack.len();
-        if stack_size == VM_STACK_SIZE {
+        if stack_size == self.max_stack_size {
             return Err(RuntimeError::StackOverflow);
         }
         self.stack.push(value);
@@ -901,53 +2082,26 @@ impl VirtualMachine {
     }
 
     fn stack_pop(&mut self) -> Value {
-        self.stack.pop()
+        // SAFETY: The compiler should produce correct byte codes
+        // so we never pop from an empty stack.
+        unsafe { self.stack.pop().unwrap_unchecked() }
     }
 
     fn stack_top(&self, n: usize) -> &Value {
-        self.stack.top(n)
+        // SAFETY: The compiler should produce correct byte codes
+        // so we never read an out-of-bound index.
+        unsafe { self.stack.get_unchecked(self.stack.len() - n - 1) }
     }
 
     fn stack_top_mut(&mut self, n: usize) -> &mut Value {
-        self.stack.top_mut(n)
+        let index = self.stack.len() - n - 1;
+        // SAFETY: The compiler should produce correct byte codes
+        // so we never read an out-of-bound index.
+        unsafe { self.stack.get_unchecked_mut(index) }
     }
 
     fn stack_remove_top(&mut self, n: usize) {
-        self.stack.remove(n);
-    }
-
-    fn trace_calls(&self) -> Result<(), RuntimeError> {
-        for frame in self.frames.into_iter().rev() {
-            let offset = unsafe {
-                frame
-                    .ip
-                    .offset_from(frame.closure.fun.chunk.instructions.as_ptr())
-                    as usize
-            };
-            let line = frame.closure.fun.chunk.get_line(offset - 1);
-            match &frame.closure.fun.name {
-                None => eprintln!("{line} in script."),
-                Some(s) => eprintln!("{line} in {}().", s.data),
-            }
-        }
-        Ok(())
-    }
-
-    fn define_native(
-        &mut self,
-        name: &str,
-        arity: u8,
-        call: fn(&[Value]) -> Value,
-    ) -> Result<(), RuntimeError> {
-        let (name, name_ref) = self.alloc_string(String::from(name));
-        self.stack_push(Value::Object(name))?;
-        let (fun, _) = self.alloc_native_fun(ObjNativeFun { arity, call });
-        self.stack_push(Value::Object(fun))?;
-        self.globals.set(name_ref, *self.stack_top(0));
-
-        self.stack_pop();
-        self.stack_pop();
-        Ok(())
+        self.stack.truncate(self.stack.len() - n);
     }
 
     fn alloc_string(&mut self, s: String) -> (Object, RefString) {
@@ -966,11 +2120,6 @@ impl VirtualMachine {
         self.heap.alloc(closure, Object::Closure)
     }
 
-    fn alloc_fun(&mut self, fun: ObjFun) -> (Object, RefFun) {
-        self.gc();
-        self.heap.alloc(fun, Object::Fun)
-    }
-
     fn alloc_native_fun(&mut self, native_fun: ObjNativeFun) -> (Object, RefNativeFun) {
         self.gc();
         self.heap.alloc(native_fun, Object::NativeFun)
@@ -991,22 +2140,112 @@ impl VirtualMachine {
         self.heap.alloc(method, Object::BoundMethod)
     }
 
+    fn alloc_list(&mut self, list: ObjList) -> (Object, RefList) {
+        self.gc();
+        self.heap.alloc(RefCell::new(list), Object::List)
+    }
+
+    fn alloc_map(&mut self, map: ObjMap) -> (Object, RefMap) {
+        self.gc();
+        self.heap.alloc(RefCell::new(map), Object::Map)
+    }
+
     #[cfg(feature = "dbg-execution")]
     fn trace_stack(&self) {
         print!("          ");
-        for value in self.stack.into_iter() {
+        for value in self.stack.iter() {
             print!("[ {value} ]");
         }
         println!();
     }
 }
 
-fn clock_native(_args: &[Value]) -> Value {
+/// Get the value of a field of an instance, using the slot remembered by the inline cache of the
+/// instruction if the instance has the same shape.
+fn find_field(
+    shapes: &Shapes,
+    instance: &ObjInstance,
+    name: RefString,
+    cache: Option<&Cell<InlineCache>>,
+) -> Option<Value> {
+    match cache.map(Cell::get) {
+        Some(InlineCache::Field { shape, slot }) if shape == instance.shape => {
+            return Some(instance.fields[slot]);
+        }
+        Some(InlineCache::Method { shape, .. }) if shape == instance.shape => return None,
+        _ => {}
+    }
+    let slot = shapes.slot(instance.shape, name)?;
+    if let Some(cache) = cache {
+        cache.set(InlineCache::Field {
+            shape: instance.shape,
+            slot,
+        });
+    }
+    Some(instance.fields[slot])
+}
+
+/// Get a method of the class of an instance that doesn't have a field with the same name, using
+/// the method remembered by the inline cache of the instruction if it was found in the same
+/// version of the class.
+fn find_method(
+    instance: &ObjInstance,
+    name: RefString,
+    cache: Option<&Cell<InlineCache>>,
+) -> Option<RefClosure> {
+    let class = instance.class;
+    let class_ref = class.borrow();
+    let cached = match cache.map(Cell::get) {
+        Some(InlineCache::Method {
+            class: seen,
+            version,
+            method,
+            ..
+        }) if Gc::ptr_eq(&seen, &class) && version == class_ref.version => method,
+        _ => *class_ref.methods.get(name)?,
+    };
+    if let Some(cache) = cache {
+        cache.set(InlineCache::Method {
+            shape: instance.shape,
+            class,
+            version: class_ref.version,
+            method: cached,
+        });
+    }
+    Some(cached)
+}
+
+/// Convert a value into an index of a list with the given length.
+fn list_index(index: &Value, len: usize) -> Result<usize, RuntimeError> {
+    let Some(n) = index.as_number() else {
+        return Err(RuntimeError::InvalidIndex);
+    };
+    if n.fract() != 0.0 {
+        return Err(RuntimeError::InvalidIndex);
+    }
+    if n < 0.0 || n >= len as f64 {
+        return Err(RuntimeError::IndexOutOfBounds {
+            index: n as i64,
+            len,
+        });
+    }
+    Ok(n as usize)
+}
+
+/// Check that a value can be used as a key of a map.
+fn map_key(key: Value) -> Result<Value, RuntimeError> {
+    match key.as_number() {
+        Some(n) if n.is_nan() => Err(RuntimeError::InvalidMapKey),
+        _ => Ok(key),
+    }
+}
+
+fn clock_native(_ctx: &mut NativeContext<'_>, _args: &[Handle]) -> Result<Handle, NativeError> {
     let start = std::time::SystemTime::now();
     let since_epoch = start
         .duration_since(std::time::UNIX_EPOCH)
         .expect("Time went backwards");
-    Value::Number(since_epoch.as_secs_f64())
+    Ok(Handle::from(since_epoch.as_secs_f64()))
 }
 
 #[derive(Debug)]
@@ -1014,9 +2253,24 @@ struct CallFrame {
     closure: RefClosure,
     ip: *const u8,
     slot: usize,
+    /// A stack of exception handlers installed by the function, the last one is the innermost.
+    handlers: Vec<Handler>,
+    /// The namespace of the module being imported by the function, whose exported variables are
+    /// copied once the module finishes running.
+    import: Option<usize>,
 }
 
 impl CallFrame {
+    /// Get the source location of the instruction that was just read.
+    fn span(&self) -> Span {
+        // SAFETY: The instruction pointer always points into the chunk of the closure.
+        let offset = unsafe {
+            self.ip
+                .offset_from(self.closure.fun.chunk.instructions.as_ptr()) as usize
+        };
+        self.closure.fun.chunk.get_span(offset - 1)
+    }
+
     /// Read the next byte in the stream of bytecode instructions.
     unsafe fn read_byte(&mut self) -> Result<u8, RuntimeError> {
         let byte = *self.ip;
@@ -1032,15 +2286,42 @@ impl CallFrame {
         Ok(hi << 8 | lo)
     }
 
-    /// Read the next byte in the stream of bytecode instructions and return the constant at the
-    /// index given by the byte.
-    unsafe fn read_constant(&mut self) -> Result<Value, RuntimeError> {
-        let constant_id = *self.ip;
-        self.ip = self.ip.add(1);
-        Ok(*self.closure.fun.chunk.constants.at(constant_id as usize))
+    /// Read the next 3 bytes in the stream of bytecode instructions.
+    unsafe fn read_long(&mut self) -> Result<u32, RuntimeError> {
+        let hi = *self.ip as u32;
+        let mid = *self.ip.add(1) as u32;
+        let lo = *self.ip.add(2) as u32;
+        self.ip = self.ip.add(3);
+        Ok(hi << 16 | mid << 8 | lo)
+    }
+
+    /// Read the index operand of the current instruction in the stream of bytecode instructions.
+    unsafe fn read_operand(&mut self, width: OperandWidth) -> Result<usize, RuntimeError> {
+        let index = match width {
+            OperandWidth::Byte => self.read_byte()? as usize,
+            OperandWidth::Short => self.read_short()? as usize,
+            OperandWidth::Long => self.read_long()? as usize,
+        };
+        Ok(index)
+    }
+
+    /// Read the constant index in the stream of bytecode instructions and return the constant at
+    /// that index.
+    unsafe fn read_constant(&mut self, width: OperandWidth) -> Result<Value, RuntimeError> {
+        let constant_id = self.read_operand(width)?;
+        Ok(*self.closure.fun.chunk.constants.get_unchecked(constant_id))
     }
 }
 
+/// An exception handler installed by a try statement.
+#[derive(Debug)]
+struct Handler {
+    /// The instruction where the execution continues when a value is thrown.
+    ip: *const u8,
+    /// The stack height when the handler was installed.
+    stack_len: usize,
+}
+
 /// An enumeration that determine whether to jump forward or backward along the stream of
 /// bytecode instructions.
 pub(crate) enum JumpDirection {
@@ -1049,3 +2330,1871 @@ pub(crate) enum JumpDirection {
     /// Jump backward.
     Backward,
 }
+
+/// An enumeration that determine the number of bytes used by the index operand of an
+/// instruction.
+#[derive(Clone, Copy)]
+pub(crate) enum OperandWidth {
+    /// The index is stored in a single byte.
+    Byte,
+    /// The index is stored in 2 bytes. Used for the slots of local variables and upvalues, and for
+    /// counts of values.
+    Short,
+    /// The index is stored in 3 bytes. Used for the indices of constants.
+    Long,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::{Cell, RefCell},
+        io::{self, Write},
+        rc::Rc,
+    };
+
+    use crate::{
+        module::MemoryResolver, value::Value, Arity, BytecodeError, Handle, InterpretError,
+        NativeError, RuntimeError,
+    };
+
+    use super::{StackFrame, VirtualMachine, MAIN_NAMESPACE};
+
+    /// The stack size of the thread on which a test is run. The compiler keeps its states in
+    /// fixed-size stacks, which need more space than the default thread's stack size.
+    const TEST_THREAD_STACK_SIZE: usize = 8 * 1024 * 1024;
+
+    /// Run the test on a thread whose stack is as large as the main thread's stack.
+    fn run_test<F: FnOnce() + Send + 'static>(test: F) {
+        std::thread::Builder::new()
+            .stack_size(TEST_THREAD_STACK_SIZE)
+            .spawn(test)
+            .expect("Can't spawn test thread.")
+            .join()
+            .unwrap_or_else(|err| std::panic::resume_unwind(err));
+    }
+
+    /// A sink whose content can be read after it's given to the virtual machine.
+    #[derive(Clone, Default)]
+    struct Buffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Buffer {
+        fn take(&self) -> String {
+            String::from_utf8(self.0.take()).expect("Invalid UTF-8 output.")
+        }
+    }
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Get the value of a global variable after the virtual machine has finished running.
+    fn global(vm: &mut VirtualMachine, name: &str) -> Value {
+        let name = vm.heap.intern(String::from(name));
+        let slot = vm
+            .globals
+            .slot(MAIN_NAMESPACE, name)
+            .expect("Too many global variables.");
+        // SAFETY: The slot was just given out by the globals.
+        unsafe { vm.globals.at(slot) }
+            .value
+            .expect("Undefined global variable.")
+    }
+
+    #[test]
+    fn break_and_continue_in_for_loop() {
+        run_test(|| {
+            let mut vm = VirtualMachine::new();
+            let src = r#"
+                var sum = 0;
+                for (var i = 0; i < 10; i = i + 1) {
+                  if (i == 5) break;
+                  if (i == 2) continue;
+                  var j = i;
+                  sum = sum + j;
+                }
+            "#;
+            assert!(vm.interpret(src).is_ok());
+            assert_eq!(Value::number(8.0), global(&mut vm, "sum"));
+        });
+    }
+
+    #[test]
+    fn break_and_continue_in_while_loop() {
+        run_test(|| {
+            let mut vm = VirtualMachine::new();
+            let src = r#"
+                var i = 0;
+                var count = 0;
+                while (true) {
+                  i = i + 1;
+                  if (i > 9) break;
+                  if (i == 3 or i == 6) continue;
+                  var n = 1;
+                  count = count + n;
+                }
+            "#;
+            assert!(vm.interpret(src).is_ok());
+            assert_eq!(Value::number(10.0), global(&mut vm, "i"));
+            assert_eq!(Value::number(7.0), global(&mut vm, "count"));
+        });
+    }
+
+    #[test]
+    fn break_discards_nested_locals() {
+        run_test(|| {
+            let mut vm = VirtualMachine::new();
+            let src = r#"
+                fun f() {
+                  var a = 1;
+                  while (true) {
+                    var b = 2;
+                    {
+                      var c = 3;
+                      while (true) { var d = 4; break; }
+                      break;
+                    }
+                  }
+                  var e = 5;
+                  return a + e;
+                }
+                var result = f();
+            "#;
+            assert!(vm.interpret(src).is_ok());
+            assert_eq!(Value::number(6.0), global(&mut vm, "result"));
+        });
+    }
+
+    #[test]
+    fn break_and_continue_close_upvalues() {
+        run_test(|| {
+            let mut vm = VirtualMachine::new();
+            let src = r#"
+                var f;
+                while (true) {
+                  var x = "captured";
+                  fun g() { return x; }
+                  f = g;
+                  break;
+                }
+                var broken = f();
+
+                var h;
+                var count = 0;
+                for (var i = 0; i < 3; i = i + 1) {
+                  var k = i * 10;
+                  fun g() { return k; }
+                  if (i == 1) {
+                    h = g;
+                    continue;
+                  }
+                  count = count + 1;
+                }
+                var continued = h();
+            "#;
+            assert!(vm.interpret(src).is_ok());
+            let captured = global(&mut vm, "broken");
+            assert_eq!("captured", captured.as_string().unwrap().data);
+            assert_eq!(Value::number(10.0), global(&mut vm, "continued"));
+            assert_eq!(Value::number(2.0), global(&mut vm, "count"));
+        });
+    }
+
+    #[test]
+    fn break_and_continue_outside_of_loop() {
+        run_test(|| {
+            let mut vm = VirtualMachine::new();
+            assert!(matches!(
+                vm.interpret("break;"),
+                Err(InterpretError::Compile(_))
+            ));
+            assert!(matches!(
+                vm.interpret("fun f() { continue; }"),
+                Err(InterpretError::Compile(_))
+            ));
+            assert!(matches!(
+                vm.interpret("while (false) { fun f() { break; } }"),
+                Err(InterpretError::Compile(_))
+            ));
+        });
+    }
+
+    #[test]
+    fn switch_runs_first_matching_case_only() {
+        run_test(|| {
+            let mut vm = VirtualMachine::new();
+            let src = r#"
+                var drink = "tea";
+                var result = "none";
+                var count = 0;
+                switch (drink) {
+                  case "coffee":
+                    result = "coffee";
+                    count = count + 1;
+                  case "tea":
+                    var r = "tea";
+                    result = r;
+                    count = count + 1;
+                  case "tea":
+                    result = "second tea";
+                    count = count + 1;
+                  default:
+                    result = "default";
+                    count = count + 1;
+                }
+            "#;
+            assert!(vm.interpret(src).is_ok());
+            let result = global(&mut vm, "result");
+            assert_eq!("tea", result.as_string().unwrap().data);
+            assert_eq!(Value::number(1.0), global(&mut vm, "count"));
+        });
+    }
+
+    #[test]
+    fn switch_without_matching_case() {
+        run_test(|| {
+            let mut vm = VirtualMachine::new();
+            let src = r#"
+                var result = "untouched";
+                switch (3) {
+                  case 1: result = "one";
+                  case 2: result = "two";
+                }
+                switch (nil) {}
+            "#;
+            assert!(vm.interpret(src).is_ok());
+            let result = global(&mut vm, "result");
+            assert_eq!("untouched", result.as_string().unwrap().data);
+        });
+    }
+
+    #[test]
+    fn switch_with_default_only() {
+        run_test(|| {
+            let mut vm = VirtualMachine::new();
+            let src = r#"
+                var result = 0;
+                switch (true) {
+                  default:
+                    var a = 1;
+                    var b = 2;
+                    result = a + b;
+                }
+            "#;
+            assert!(vm.interpret(src).is_ok());
+            assert_eq!(Value::number(3.0), global(&mut vm, "result"));
+        });
+    }
+
+    #[test]
+    fn switch_nested() {
+        run_test(|| {
+            let mut vm = VirtualMachine::new();
+            let src = r#"
+                fun classify(x, y) {
+                  var before = "before";
+                  switch (x) {
+                    case 1:
+                      switch (y) {
+                        case 1: return "1-1";
+                        case 2: return "1-2";
+                        default: return "1-?";
+                      }
+                    case 2:
+                      var inner = "2-";
+                      switch (y) {
+                        case 1: inner = inner + "1";
+                        default: inner = inner + "?";
+                      }
+                      return inner;
+                  }
+                  return before;
+                }
+                var a = classify(1, 2);
+                var b = classify(1, 3);
+                var c = classify(2, 1);
+                var d = classify(2, 5);
+                var e = classify(3, 1);
+            "#;
+            assert!(vm.interpret(src).is_ok());
+            for (name, expected) in [
+                ("a", "1-2"),
+                ("b", "1-?"),
+                ("c", "2-1"),
+                ("d", "2-?"),
+                ("e", "before"),
+            ] {
+                let value = global(&mut vm, name);
+                assert_eq!(expected, value.as_string().unwrap().data);
+            }
+        });
+    }
+
+    #[test]
+    fn switch_inside_loop() {
+        run_test(|| {
+            let mut vm = VirtualMachine::new();
+            let src = r#"
+                var sum = 0;
+                for (var i = 0; i < 10; i = i + 1) {
+                  switch (i) {
+                    case 2: continue;
+                    case 5: break;
+                    default: sum = sum + i;
+                  }
+                  if (i == 5) break;
+                }
+            "#;
+            assert!(vm.interpret(src).is_ok());
+            assert_eq!(Value::number(8.0), global(&mut vm, "sum"));
+        });
+    }
+
+    #[test]
+    fn switch_with_misplaced_default() {
+        run_test(|| {
+            let mut vm = VirtualMachine::new();
+            let src = "switch (1) { default: print 1; case 1: print 2; }";
+            assert!(matches!(vm.interpret(src), Err(InterpretError::Compile(_))));
+            let src = "switch (1) { default: print 1; default: print 2; }";
+            assert!(matches!(vm.interpret(src), Err(InterpretError::Compile(_))));
+            let src = "switch (1) { print 1; }";
+            assert!(matches!(vm.interpret(src), Err(InterpretError::Compile(_))));
+        });
+    }
+
+    #[test]
+    fn string_interpolation() {
+        run_test(|| {
+            let mut vm = VirtualMachine::new();
+            let src = r#"
+                var drink = "Tea";
+                var steep = 4;
+                var cool = 2;
+                var message = "${drink} will be ready in ${steep + cool} minutes.";
+                var only = "${steep / 8}";
+                var adjacent = "${nil}${true}${false}";
+                var empty = "";
+                class Cup {}
+                var object = "a ${Cup} and a ${Cup()}";
+            "#;
+            assert!(vm.interpret(src).is_ok());
+            for (name, expected) in [
+                ("message", "Tea will be ready in 6 minutes."),
+                ("only", "0.5"),
+                ("adjacent", "niltruefalse"),
+                ("empty", ""),
+                ("object", "a Cup and a Cup instance"),
+            ] {
+                let value = global(&mut vm, name);
+                assert_eq!(expected, value.as_string().unwrap().data);
+            }
+        });
+    }
+
+    #[test]
+    fn string_interpolation_nested() {
+        run_test(|| {
+            let mut vm = VirtualMachine::new();
+            let src = r#"
+                var a = 1;
+                var b = "two";
+                var quoted = "[${"a" + "b"}]";
+                var nested = "outer ${"inner ${a + 1} and ${"${b}"}"} end";
+                fun f() { { return "}"; } }
+                var braces = "${f()}{${"{"}}";
+            "#;
+            assert!(vm.interpret(src).is_ok());
+            for (name, expected) in [
+                ("quoted", "[ab]"),
+                ("nested", "outer inner 2 and two end"),
+                ("braces", "}{{}"),
+            ] {
+                let value = global(&mut vm, name);
+                assert_eq!(expected, value.as_string().unwrap().data);
+            }
+        });
+    }
+
+    #[test]
+    fn string_interpolation_unterminated() {
+        run_test(|| {
+            let mut vm = VirtualMachine::new();
+            for src in [
+                r#"print "${1";"#,
+                r#"print "${1}"#,
+                r#"print "${1 2}";"#,
+                r#"print "${}";"#,
+            ] {
+                assert!(matches!(vm.interpret(src), Err(InterpretError::Compile(_))));
+            }
+        });
+    }
+
+    #[test]
+    fn constants_beyond_single_byte_index() {
+        run_test(|| {
+            let mut vm = VirtualMachine::new();
+            // Each global definition adds 2 constants to the script's chunk, so everything
+            // declared after them has to be accessed using a long constant index.
+            let mut src = String::new();
+            for i in 0..300 {
+                src.push_str(&format!("var g{i} = {i};\n"));
+            }
+            src.push_str("class Base { name() { return \"base\"; } }\n");
+            // Fill the method's chunk with constants before the super access.
+            let mut body = String::from("var t = 0;");
+            for i in 1..300 {
+                body.push_str(&format!("t = t + {i};"));
+            }
+            src.push_str(&format!(
+                "class Derived < Base {{ sum() {{ {body} return \"${{super.name()}}${{t}}\"; }} \
+                 name() {{ {body} var m = super.name; return m() + \"!\"; }} }}\n"
+            ));
+            src.push_str(
+                r#"
+                var d = Derived();
+                d.field = g299;
+                var field = d.field;
+                var sum = d.sum();
+                var name = d.name();
+                fun last() { return g299; }
+                g0 = last() + 1;
+                var first = g0;
+                "#,
+            );
+            assert!(vm.interpret(&src).is_ok());
+
+            assert_eq!(Value::number(299.0), global(&mut vm, "field"));
+            assert_eq!(Value::number(300.0), global(&mut vm, "first"));
+            let sum = global(&mut vm, "sum");
+            assert_eq!("base44850", sum.as_string().unwrap().data);
+            let name = global(&mut vm, "name");
+            assert_eq!("base!", name.as_string().unwrap().data);
+        });
+    }
+
+    #[test]
+    fn deep_recursion_grows_the_stacks() {
+        let mut vm = VirtualMachine::new();
+        let src = r#"
+            fun count(n) {
+                if (n == 0) return 0;
+                return 1 + count(n - 1);
+            }
+            var depth = count(2000);
+        "#;
+        assert!(vm.interpret(src).is_ok());
+        assert_eq!(Value::number(2000.0), global(&mut vm, "depth"));
+    }
+
+    #[test]
+    fn stack_limits_are_configurable() {
+        let src = r#"
+            fun count(n) {
+                if (n == 0) return 0;
+                return 1 + count(n - 1);
+            }
+            var depth = count(100);
+        "#;
+        let mut vm = VirtualMachine::builder().max_frames(64).build();
+        assert!(matches!(
+            vm.interpret(src),
+            Err(InterpretError::Runtime { .. })
+        ));
+        let mut vm = VirtualMachine::builder().max_stack_size(128).build();
+        assert!(matches!(
+            vm.interpret(src),
+            Err(InterpretError::Runtime { .. })
+        ));
+        let mut vm = VirtualMachine::builder()
+            .max_frames(128)
+            .max_stack_size(512)
+            .build();
+        assert!(vm.interpret(src).is_ok());
+        assert_eq!(Value::number(100.0), global(&mut vm, "depth"));
+    }
+
+    #[test]
+    fn runs_after_stack_overflow() {
+        let mut vm = VirtualMachine::builder().max_frames(16).build();
+        let src = r#"
+            fun f() { f(); }
+            f();
+        "#;
+        assert!(matches!(
+            vm.interpret(src),
+            Err(InterpretError::Runtime { .. })
+        ));
+        assert!(vm.interpret("var ok = true;").is_ok());
+        assert_eq!(Value::bool(true), global(&mut vm, "ok"));
+    }
+
+    #[test]
+    fn locals_and_upvalues_beyond_single_byte_slot() {
+        let mut vm = VirtualMachine::new();
+        let locals: String = (0..300).map(|i| format!("var l{i} = {i};")).collect();
+        let captures = (0..300)
+            .map(|i| format!("l{i}"))
+            .collect::<Vec<_>>()
+            .join(" + ");
+        let src = format!(
+            "fun outer() {{ {locals} fun inner() {{ l299 = {captures}; }} inner(); return l299; }}\n\
+             var sum = outer();"
+        );
+        assert!(vm.interpret(&src).is_ok());
+        assert_eq!(Value::number(44850.0), global(&mut vm, "sum"));
+    }
+
+    #[test]
+    fn const_declarations() {
+        let mut vm = VirtualMachine::new();
+        let src = r#"
+            const limit = 3;
+            var total = 0;
+            {
+                const step = 2;
+                fun add() { total = total + step * limit; }
+                add();
+                add();
+            }
+            for (var i = 0; i < limit; i = i + 1) {
+                const square = i * i;
+                total = total + square;
+            }
+        "#;
+        assert!(vm.interpret(src).is_ok());
+        assert_eq!(Value::number(3.0), global(&mut vm, "limit"));
+        assert_eq!(Value::number(17.0), global(&mut vm, "total"));
+    }
+
+    #[test]
+    fn const_assignment_compile_errors() {
+        let mut vm = VirtualMachine::new();
+        for src in [
+            "const a;",
+            "const a = 1; a = 2;",
+            "{ const a = 1; a = 2; }",
+            "{ const a = 1; fun f() { a = 2; } }",
+            "{ const a = 1; fun f() { fun g() { a = 2; } } }",
+            "const a = 1; fun f() { a = 2; }",
+        ] {
+            assert!(matches!(vm.interpret(src), Err(InterpretError::Compile(_))));
+        }
+    }
+
+    #[test]
+    fn const_assignment_runtime_errors() {
+        let mut vm = VirtualMachine::new();
+        assert!(vm.interpret("fun f() { a = 2; } const a = 1;").is_ok());
+        for src in ["f();", "var a = 4;", "const a = 5;"] {
+            assert!(matches!(
+                vm.interpret(src),
+                Err(InterpretError::Runtime { .. })
+            ));
+        }
+        // The compiler knows about the constant once it has been defined.
+        assert!(matches!(
+            vm.interpret("a = 3;"),
+            Err(InterpretError::Compile(_))
+        ));
+        assert_eq!(Value::number(1.0), global(&mut vm, "a"));
+    }
+
+    #[test]
+    fn globals_are_shared_between_scripts() {
+        let mut vm = VirtualMachine::new();
+        assert!(vm.interpret("fun get() { return later; }").is_ok());
+        assert!(matches!(
+            vm.interpret("get();"),
+            Err(InterpretError::Runtime { .. })
+        ));
+        assert!(matches!(
+            vm.interpret("later = 1;"),
+            Err(InterpretError::Runtime { .. })
+        ));
+        assert!(vm.interpret("var later = 1; var got = get();").is_ok());
+        assert_eq!(Value::number(1.0), global(&mut vm, "got"));
+        assert!(vm.interpret("later = 2; got = get();").is_ok());
+        assert_eq!(Value::number(2.0), global(&mut vm, "got"));
+    }
+
+    #[test]
+    fn list_literals_and_indexing() {
+        let mut vm = VirtualMachine::new();
+        let src = r#"
+            var xs = [1, 2, [3, 4]];
+            var first = xs[0];
+            var nested = xs[2][1];
+            xs[1] = xs[0] = 5;
+            var sum = xs[0] + xs[1];
+            var empty = [];
+            var len = empty.len();
+        "#;
+        assert!(vm.interpret(src).is_ok());
+        assert_eq!(Value::number(1.0), global(&mut vm, "first"));
+        assert_eq!(Value::number(4.0), global(&mut vm, "nested"));
+        assert_eq!(Value::number(10.0), global(&mut vm, "sum"));
+        assert_eq!(Value::number(0.0), global(&mut vm, "len"));
+    }
+
+    #[test]
+    fn list_methods() {
+        let mut vm = VirtualMachine::new();
+        let src = r#"
+            var xs = [];
+            for (var i = 0; i < 5; i = i + 1) {
+                xs.push(i);
+            }
+            var popped = xs.pop();
+            xs.insert(0, 10);
+            xs.insert(xs.len(), 20);
+            var removed = xs.remove(1);
+            var str = "${xs}";
+        "#;
+        assert!(vm.interpret(src).is_ok());
+        assert_eq!(Value::number(4.0), global(&mut vm, "popped"));
+        assert_eq!(Value::number(0.0), global(&mut vm, "removed"));
+        let str = global(&mut vm, "str").as_string().unwrap();
+        assert_eq!("[10, 1, 2, 3, 20]", str.data);
+    }
+
+    #[test]
+    fn list_runtime_errors() {
+        let mut vm = VirtualMachine::new();
+        for src in [
+            "[1, 2][2];",
+            "[1, 2][-1];",
+            "[1, 2][0.5];",
+            r#"[1, 2]["0"];"#,
+            "[].pop();",
+            "[].remove(0);",
+            "[].insert(1, 0);",
+            "[].push();",
+            "[].unknown();",
+            "var x = 1; x[0];",
+            "var x = 1; x[0] = 1;",
+        ] {
+            assert!(matches!(
+                vm.interpret(src),
+                Err(InterpretError::Runtime { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn heap_size_counts_the_items_of_lists() {
+        let mut vm = VirtualMachine::new();
+        assert!(vm.interpret("var xs = [];").is_ok());
+        let size = vm.heap.size();
+        assert!(vm
+            .interpret("for (var i = 0; i < 1000; i = i + 1) xs.push(i);")
+            .is_ok());
+        assert!(vm.heap.size() >= size + 1000 * std::mem::size_of::<Value>());
+    }
+
+    #[test]
+    fn list_that_contains_itself() {
+        let mut vm = VirtualMachine::new();
+        let src = r#"
+            var xs = [1];
+            xs.push(xs);
+            var str = "${xs} ${[xs]}";
+            var shared = [2];
+            var str_shared = "${[shared, shared]}";
+        "#;
+        assert!(vm.interpret(src).is_ok());
+        let str = global(&mut vm, "str").as_string().unwrap();
+        assert_eq!("[1, [...]] [[1, [...]]]", str.data);
+        let str = global(&mut vm, "str_shared").as_string().unwrap();
+        assert_eq!("[[2], [2]]", str.data);
+    }
+
+    #[test]
+    fn list_literals_beyond_single_byte_count() {
+        let mut vm = VirtualMachine::new();
+        let items: Vec<_> = (0..300).map(|i| i.to_string()).collect();
+        let src = format!(
+            "var xs = [{}]; var len = xs.len(); var last = xs[299];",
+            items.join(", ")
+        );
+        assert!(vm.interpret(&src).is_ok());
+        assert_eq!(Value::number(300.0), global(&mut vm, "len"));
+        assert_eq!(Value::number(299.0), global(&mut vm, "last"));
+    }
+
+    #[test]
+    fn list_compile_errors() {
+        let mut vm = VirtualMachine::new();
+        for src in [
+            "[1, 2;",
+            "var xs = [1]; xs[0;",
+            "var xs = [1]; xs[0] + 1 = 2;",
+        ] {
+            assert!(matches!(vm.interpret(src), Err(InterpretError::Compile(_))));
+        }
+    }
+
+    #[test]
+    fn map_literals_and_indexing() {
+        let mut vm = VirtualMachine::new();
+        let src = r#"
+            class Key {}
+            var key = Key();
+            var m = {"a": 1, 2: "two", nil: 3, true: 4, key: 5};
+            var a = m["a"];
+            var two = m[2];
+            var none = m[nil];
+            var yes = m[true];
+            var object = m[key];
+            m["a"] = m["a"] + 10;
+            m[-0] = 6;
+            var zero = m[0];
+            var len = m.len();
+            var empty = {}.len();
+        "#;
+        assert!(vm.interpret(src).is_ok());
+        assert_eq!(Value::number(1.0), global(&mut vm, "a"));
+        let two = global(&mut vm, "two").as_string().unwrap();
+        assert_eq!("two", two.data);
+        assert_eq!(Value::number(3.0), global(&mut vm, "none"));
+        assert_eq!(Value::number(4.0), global(&mut vm, "yes"));
+        assert_eq!(Value::number(5.0), global(&mut vm, "object"));
+        assert_eq!(Value::number(6.0), global(&mut vm, "zero"));
+        assert_eq!(Value::number(6.0), global(&mut vm, "len"));
+        assert_eq!(Value::number(0.0), global(&mut vm, "empty"));
+    }
+
+    #[test]
+    fn map_literals_beyond_single_byte_count() {
+        let mut vm = VirtualMachine::new();
+        let entries: Vec<_> = (0..300).map(|i| format!("{i}: {i}")).collect();
+        let src = format!(
+            "var m = {{{}}}; var len = m.len(); var last = m[299];",
+            entries.join(", ")
+        );
+        assert!(vm.interpret(&src).is_ok());
+        assert_eq!(Value::number(300.0), global(&mut vm, "len"));
+        assert_eq!(Value::number(299.0), global(&mut vm, "last"));
+    }
+
+    #[test]
+    fn map_that_contains_itself() {
+        let mut vm = VirtualMachine::new();
+        let src = r#"
+            var m = {};
+            m["self"] = m;
+            var str = "${m}";
+        "#;
+        assert!(vm.interpret(src).is_ok());
+        let str = global(&mut vm, "str").as_string().unwrap();
+        assert_eq!("{self: {...}}", str.data);
+    }
+
+    #[test]
+    fn heap_size_counts_the_entries_of_maps() {
+        let mut vm = VirtualMachine::new();
+        assert!(vm.interpret("var m = {};").is_ok());
+        let size = vm.heap.size();
+        assert!(vm
+            .interpret("for (var i = 0; i < 1000; i = i + 1) m[i] = i;")
+            .is_ok());
+        assert!(vm.heap.size() >= size + 1000 * 2 * std::mem::size_of::<Value>());
+    }
+
+    #[test]
+    fn map_methods() {
+        let mut vm = VirtualMachine::new();
+        let src = r#"
+            var m = {"a": 1, "b": 2, "c": 3};
+            var has = m.has("a");
+            var removed = m.remove("a");
+            var missing = m.remove("a");
+            var has_removed = m.has("a");
+            var total = 0;
+            var keys = m.keys();
+            var values = m.values();
+            for (var i = 0; i < keys.len(); i = i + 1) {
+                total = total + m[keys[i]] + values[i];
+            }
+        "#;
+        assert!(vm.interpret(src).is_ok());
+        assert_eq!(Value::bool(true), global(&mut vm, "has"));
+        assert_eq!(Value::number(1.0), global(&mut vm, "removed"));
+        assert_eq!(Value::NIL, global(&mut vm, "missing"));
+        assert_eq!(Value::bool(false), global(&mut vm, "has_removed"));
+        assert_eq!(Value::number(10.0), global(&mut vm, "total"));
+    }
+
+    #[test]
+    fn map_errors() {
+        let mut vm = VirtualMachine::new();
+        for src in [
+            r#"print {"a": 1}["b"];"#,
+            "var m = {}; m[0/0] = 1;",
+            "var m = {0/0: 1};",
+            "var m = {}; m.has();",
+            "var m = {}; m.unknown();",
+        ] {
+            assert!(matches!(
+                vm.interpret(src),
+                Err(InterpretError::Runtime { .. })
+            ));
+        }
+        for src in [r#"var m = {"a" 1};"#, r#"var m = {"a": 1;"#] {
+            assert!(matches!(vm.interpret(src), Err(InterpretError::Compile(_))));
+        }
+    }
+
+    #[test]
+    fn lambda_expressions() {
+        let mut vm = VirtualMachine::new();
+        let src = r#"
+            fun apply(f, x) { return f(x); }
+            var doubled = apply(fun (x) { return x * 2; }, 4);
+            var incremented = apply((x) => x + 1, 4);
+            var sum = ((a, b) => a + b)(1, 2);
+            var answer = (() => 42)();
+            var grouped = (1 + 2) * 3;
+            fun counter() {
+                var n = 0;
+                return () => {
+                    n = n + 1;
+                    return n;
+                };
+            }
+            var count = counter();
+            count();
+            var counted = count();
+            var curried = ((x) => (y) => x * y)(3)(5);
+        "#;
+        assert!(vm.interpret(src).is_ok());
+        assert_eq!(Value::number(8.0), global(&mut vm, "doubled"));
+        assert_eq!(Value::number(5.0), global(&mut vm, "incremented"));
+        assert_eq!(Value::number(3.0), global(&mut vm, "sum"));
+        assert_eq!(Value::number(42.0), global(&mut vm, "answer"));
+        assert_eq!(Value::number(9.0), global(&mut vm, "grouped"));
+        assert_eq!(Value::number(2.0), global(&mut vm, "counted"));
+        assert_eq!(Value::number(15.0), global(&mut vm, "curried"));
+    }
+
+    #[test]
+    fn lambda_compile_errors() {
+        let mut vm = VirtualMachine::new();
+        for src in [
+            "var f = fun { return 1; };",
+            "var f = fun (a) return a;",
+            "var f = (a, 1) => a;",
+            "var f = (a) => ;",
+            "var f = (a, a) => a;",
+        ] {
+            assert!(matches!(vm.interpret(src), Err(InterpretError::Compile(_))));
+        }
+    }
+
+    #[test]
+    fn throw_and_catch() {
+        let mut vm = VirtualMachine::new();
+        let src = r#"
+            var thrown;
+            try { throw "boom"; } catch (e) { thrown = e; }
+            fun inner() { throw Error("inner"); }
+            fun outer() { var a = 1; inner(); return a; }
+            var message;
+            try { outer(); } catch (e) { message = e.message; }
+            var rethrown;
+            try {
+                try { throw 1; } catch (e) { throw e + 1; }
+            } catch (e) {
+                rethrown = e;
+            }
+            var captured = [];
+            try {
+                try { throw 1; } catch (e) { captured.push(() => e); throw 2; }
+            } catch (e) {
+                captured.push(() => e);
+            }
+            var first = captured[0]();
+            var second = captured[1]();
+        "#;
+        assert!(vm.interpret(src).is_ok());
+        let thrown = global(&mut vm, "thrown").as_string().unwrap();
+        assert_eq!("boom", thrown.data);
+        let message = global(&mut vm, "message").as_string().unwrap();
+        assert_eq!("inner", message.data);
+        assert_eq!(Value::number(2.0), global(&mut vm, "rethrown"));
+        assert_eq!(Value::number(1.0), global(&mut vm, "first"));
+        assert_eq!(Value::number(2.0), global(&mut vm, "second"));
+    }
+
+    #[test]
+    fn runtime_errors_are_catchable() {
+        let mut vm = VirtualMachine::new();
+        let src = r#"
+            var message;
+            var line;
+            try {
+                nil.field;
+            } catch (e) {
+                message = e.message;
+                line = e.line;
+            }
+            fun recurse() { recurse(); }
+            var overflow;
+            try { recurse(); } catch (e) { overflow = e.message; }
+        "#;
+        assert!(vm.interpret(src).is_ok());
+        let message = global(&mut vm, "message").as_string().unwrap();
+        assert_eq!("Only instances have properties.", message.data);
+        assert_eq!(Value::number(5.0), global(&mut vm, "line"));
+        let overflow = global(&mut vm, "overflow").as_string().unwrap();
+        assert_eq!("Stack overflow.", overflow.data);
+    }
+
+    #[test]
+    fn finally_blocks() {
+        let mut vm = VirtualMachine::new();
+        let src = r#"
+            var log = [];
+            try { log.push(1); } finally { log.push(2); }
+            try { throw 3; } catch (e) { log.push(e); } finally { log.push(4); }
+            try {
+                try { throw 6; } finally { var a = 5; log.push(a); }
+            } catch (e) {
+                log.push(e);
+            }
+            try {
+                try { throw 0; } catch (e) { throw 8; } finally { log.push(7); }
+            } catch (e) {
+                log.push(e);
+            }
+            var str = "${log}";
+        "#;
+        assert!(vm.interpret(src).is_ok());
+        let str = global(&mut vm, "str").as_string().unwrap();
+        assert_eq!("[1, 2, 3, 4, 5, 6, 7, 8]", str.data);
+    }
+
+    #[test]
+    fn finally_blocks_run_on_return() {
+        let mut vm = VirtualMachine::new();
+        let src = r#"
+            var log = [];
+            fun f() {
+                var x = "outer";
+                try {
+                    var x = "inner";
+                    return 1;
+                } finally {
+                    log.push(x);
+                }
+            }
+            fun g() {
+                try {
+                    try { return 2; } finally { log.push("a"); }
+                } catch (e) {
+                    log.push("unreachable");
+                } finally {
+                    log.push("b");
+                }
+            }
+            fun h() {
+                try { throw 3; } catch (e) { return e; } finally { log.push("c"); }
+            }
+            fun i() {
+                try { return 4; } finally { try { throw 5; } catch (e) { log.push(e); } }
+            }
+            var results = [f(), g(), h(), i()];
+            var str = "${results} ${log}";
+        "#;
+        vm.interpret(src).unwrap();
+        let str = global(&mut vm, "str").as_string().unwrap();
+        assert_eq!("[1, 2, 3, 4] [outer, a, b, c, 5]", str.data);
+    }
+
+    #[test]
+    fn finally_blocks_run_on_break() {
+        let mut vm = VirtualMachine::new();
+        let src = r#"
+            var log = [];
+            for (var i = 0; i < 5; i = i + 1) {
+                try {
+                    if (i == 2) break;
+                    log.push(i);
+                } finally {
+                    log.push("f");
+                }
+            }
+            while (true) {
+                try {
+                    try { break; } finally { log.push("inner"); }
+                } finally {
+                    log.push("outer");
+                }
+            }
+            var caught = false;
+            try { throw nil; } catch (e) { caught = true; }
+            var str = "${log}";
+        "#;
+        vm.interpret(src).unwrap();
+        let str = global(&mut vm, "str").as_string().unwrap();
+        assert_eq!("[0, f, 1, f, f, inner, outer]", str.data);
+        assert_eq!(Value::bool(true), global(&mut vm, "caught"));
+    }
+
+    #[test]
+    fn finally_blocks_run_on_continue() {
+        let mut vm = VirtualMachine::new();
+        let src = r#"
+            var log = [];
+            for (var i = 0; i < 3; i = i + 1) {
+                try {
+                    if (i == 1) continue;
+                    log.push(i);
+                } catch (e) {
+                } finally {
+                    log.push("f");
+                }
+            }
+            for (var i = 0; i < 2; i = i + 1) {
+                try {
+                    throw i;
+                } catch (e) {
+                    continue;
+                } finally {
+                    log.push(i);
+                }
+            }
+            var str = "${log}";
+        "#;
+        vm.interpret(src).unwrap();
+        let str = global(&mut vm, "str").as_string().unwrap();
+        assert_eq!("[0, f, f, 2, f, 0, 1]", str.data);
+    }
+
+    #[test]
+    fn handlers_are_removed_when_leaving_try_blocks() {
+        let mut vm = VirtualMachine::new();
+        let src = r#"
+            var sum = 0;
+            for (var i = 0; i < 5; i = i + 1) {
+                try {
+                    if (i == 1) continue;
+                    if (i == 3) break;
+                    sum = sum + i;
+                } catch (e) {}
+            }
+            fun early() { try { return 1; } catch (e) {} }
+            early();
+            var caught = false;
+            try { throw nil; } catch (e) { caught = true; }
+        "#;
+        assert!(vm.interpret(src).is_ok());
+        assert_eq!(Value::number(2.0), global(&mut vm, "sum"));
+        assert_eq!(Value::bool(true), global(&mut vm, "caught"));
+        assert!(matches!(
+            vm.interpret("throw 1;"),
+            Err(InterpretError::Runtime { .. })
+        ));
+    }
+
+    #[test]
+    fn try_compile_errors() {
+        let mut vm = VirtualMachine::new();
+        for src in [
+            "try {}",
+            "try {} catch {}",
+            "try {} catch (e {}",
+            "try {} finally",
+            "throw;",
+        ] {
+            assert!(matches!(vm.interpret(src), Err(InterpretError::Compile(_))));
+        }
+    }
+
+    #[test]
+    fn modules_export_names() {
+        let resolver = MemoryResolver::new()
+            .module(
+                "lib/math.lox",
+                r#"
+                import "util.lox";
+                var hidden = 1;
+                export const pi = 3;
+                export fun square(x) { return x * x; }
+                export class Point { init(x) { this.x = x; } }
+                "#,
+            )
+            .module("lib/util.lox", "export var util = 1;");
+        let mut vm = VirtualMachine::builder().module_resolver(resolver).build();
+        let src = r#"
+            import "lib/math.lox";
+            var area = pi * square(2);
+            var x = Point(5).x;
+        "#;
+        assert!(vm.interpret_script("main.lox", src).is_ok());
+        assert_eq!(Value::number(12.0), global(&mut vm, "area"));
+        assert_eq!(Value::number(5.0), global(&mut vm, "x"));
+        // Only the exported names of the imported module are visible.
+        for src in ["print hidden;", "print util;"] {
+            assert!(matches!(
+                vm.interpret(src),
+                Err(InterpretError::Runtime { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn modules_have_their_own_globals() {
+        let resolver = MemoryResolver::new().module(
+            "a.lox",
+            r#"
+            var secret = 1;
+            export fun get() { return secret; }
+            export fun set(value) { secret = value; }
+            "#,
+        );
+        let mut vm = VirtualMachine::builder().module_resolver(resolver).build();
+        let src = r#"
+            var secret = 2;
+            import "a.lox";
+            set(3);
+            var value = get();
+        "#;
+        assert!(vm.interpret(src).is_ok());
+        assert_eq!(Value::number(3.0), global(&mut vm, "value"));
+        assert_eq!(Value::number(2.0), global(&mut vm, "secret"));
+    }
+
+    #[test]
+    fn modules_are_run_once() {
+        let resolver = MemoryResolver::new()
+            .module("a.lox", r#"export var items = []; items.push("a");"#)
+            .module("b.lox", r#"import "./a.lox"; items.push("b");"#)
+            // A circular import only sees the variables defined before it.
+            .module("c.lox", r#"export fun c() { return 1; } import "d.lox";"#)
+            .module(
+                "d.lox",
+                r#"import "c.lox"; export fun d() { return c() + 1; }"#,
+            );
+        let mut vm = VirtualMachine::builder().module_resolver(resolver).build();
+        let src = r#"
+            import "a.lox";
+            import "b.lox";
+            import "a.lox";
+            var count = items.len();
+            import "c.lox";
+            import "d.lox";
+            var sum = c() + d();
+        "#;
+        assert!(vm.interpret(src).is_ok());
+        assert_eq!(Value::number(2.0), global(&mut vm, "count"));
+        assert_eq!(Value::number(3.0), global(&mut vm, "sum"));
+    }
+
+    #[test]
+    fn import_errors() {
+        let resolver = MemoryResolver::new()
+            .module("a.lox", "export var a = 1;")
+            .module("broken.lox", "export var;");
+        let mut vm = VirtualMachine::builder().module_resolver(resolver).build();
+        for src in [
+            r#"import "missing.lox";"#,
+            r#"import "broken.lox";"#,
+            r#"const a = 0; import "a.lox";"#,
+        ] {
+            assert!(matches!(
+                vm.interpret(src),
+                Err(InterpretError::Runtime { .. })
+            ));
+        }
+        let src = r#"
+            var caught = false;
+            try { import "missing.lox"; } catch (e) { caught = true; }
+        "#;
+        assert!(vm.interpret(src).is_ok());
+        assert_eq!(Value::bool(true), global(&mut vm, "caught"));
+
+        // The namespace of a module that can't be compiled is removed.
+        let resolver = MemoryResolver::new().module("broken.lox", "export var a = 1; var;");
+        let mut vm = VirtualMachine::builder().module_resolver(resolver).build();
+        vm.interpret("var caught = false;").unwrap();
+        let namespaces = vm.globals.namespace_count();
+        let globals = vm.globals.iter().count();
+        let src = r#"try { import "broken.lox"; } catch (e) { caught = true; }"#;
+        assert!(vm.interpret(src).is_ok());
+        assert_eq!(Value::bool(true), global(&mut vm, "caught"));
+        assert_eq!(namespaces, vm.globals.namespace_count());
+        assert_eq!(globals, vm.globals.iter().count());
+    }
+
+    #[test]
+    fn import_compile_errors() {
+        let mut vm = VirtualMachine::new();
+        for src in [
+            "import a;",
+            r#"import "a.lox""#,
+            "export print 1;",
+            "{ export var a = 1; }",
+            "fun f() { export var a = 1; }",
+        ] {
+            assert!(matches!(vm.interpret(src), Err(InterpretError::Compile(_))));
+        }
+    }
+
+    #[test]
+    fn host_gets_and_sets_globals() {
+        let mut vm = VirtualMachine::new();
+        let src = r#"
+            var a = 1;
+            var s = "hi";
+            const c = true;
+        "#;
+        assert!(vm.interpret(src).is_ok());
+        assert_eq!(Some(1.0), vm.get_global("a").and_then(|a| a.as_number()));
+        assert_eq!(Some(true), vm.get_global("c").and_then(|c| c.as_bool()));
+        let s = vm.get_global("s").unwrap();
+        assert_eq!(Some(String::from("hi")), s.as_string());
+        assert_eq!("hi", s.to_string());
+        let globals = vm.globals.iter().count();
+        assert!(vm.get_global("missing").is_none());
+        assert_eq!(globals, vm.globals.iter().count());
+
+        assert!(vm.set_global("b", &Handle::from(2.0)).is_ok());
+        let name = vm.new_string("lox");
+        assert!(vm.set_global("name", &name).is_ok());
+        assert!(vm
+            .interpret(r#"var sum = a + b; var greet = s + " " + name;"#)
+            .is_ok());
+        assert_eq!(Value::number(3.0), global(&mut vm, "sum"));
+        let greet = vm.get_global("greet").unwrap();
+        assert_eq!(Some(String::from("hi lox")), greet.as_string());
+        assert!(matches!(
+            vm.set_global("c", &Handle::nil()),
+            Err(RuntimeError::AssignToConst(_))
+        ));
+    }
+
+    #[test]
+    fn host_calls_lox_functions() {
+        let mut vm = VirtualMachine::new();
+        let src = r#"
+            fun add(a, b) { return a + b; }
+            var twice = (f) => (x) => f(f(x));
+            var inc = (x) => x + 1;
+            class Pair {
+                init(a, b) { this.a = a; this.b = b; }
+            }
+            fun fail() { throw Error("failed"); }
+        "#;
+        assert!(vm.interpret(src).is_ok());
+        let add = vm.get_global("add").unwrap();
+        let sum = vm.call(&add, &[Handle::from(1.0), Handle::from(2.0)]);
+        assert_eq!(Some(3.0), sum.unwrap().as_number());
+
+        // Handles returned by calls can be passed back to the virtual machine.
+        let twice = vm.get_global("twice").unwrap();
+        let inc = vm.get_global("inc").unwrap();
+        let inc_twice = vm.call(&twice, &[inc]).unwrap();
+        let result = vm.call(&inc_twice, &[Handle::from(1.0)]).unwrap();
+        assert_eq!(Some(3.0), result.as_number());
+
+        let pair = vm.get_global("Pair").unwrap();
+        let instance = vm.call(&pair, &[Handle::from(1.0), Handle::nil()]);
+        assert_eq!("Pair instance", instance.unwrap().to_string());
+        let clock = vm.get_global("clock").unwrap();
+        assert!(vm.call(&clock, &[]).unwrap().as_number().is_some());
+
+        assert!(matches!(
+            vm.call(&add, &[]),
+            Err(RuntimeError::InvalidArgumentsCount { arity: 2, argc: 0 })
+        ));
+        assert!(matches!(
+            vm.call(&Handle::from(1.0), &[]),
+            Err(RuntimeError::InvalidCallee)
+        ));
+        let fail = vm.get_global("fail").unwrap();
+        assert!(matches!(
+            vm.call(&fail, &[]),
+            Err(RuntimeError::Uncaught(_))
+        ));
+        // The virtual machine can still be used after a failed call.
+        let sum = vm.call(&add, &[Handle::from(2.0), Handle::from(2.0)]);
+        assert_eq!(Some(4.0), sum.unwrap().as_number());
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn handles_are_gc_roots() {
+        let mut vm = VirtualMachine::new();
+        assert!(vm.interpret("var list = [1, 2];").is_ok());
+        let list = vm.get_global("list").unwrap();
+        let copy = list.clone();
+        assert!(vm.interpret("list = nil;").is_ok());
+        vm.mark_sweep();
+        assert_eq!("[1, 2]", list.to_string());
+
+        // The value is no longer a root once all handles to it are dropped.
+        drop(list);
+        vm.mark_sweep();
+        assert_eq!(1, vm.handles.len());
+        drop(copy);
+        vm.mark_sweep();
+        assert!(vm.handles.is_empty());
+    }
+
+    #[test]
+    fn handles_belong_to_their_virtual_machine() {
+        let mut vm = VirtualMachine::new();
+        let mut other = VirtualMachine::new();
+        assert!(vm.interpret(r#"var s = "lox"; fun f() {}"#).is_ok());
+        let s = vm.get_global("s").unwrap();
+        let f = vm.get_global("f").unwrap();
+        assert!(matches!(
+            other.set_global("s", &s),
+            Err(RuntimeError::ForeignHandle)
+        ));
+        assert!(matches!(
+            other.call(&f, &[]),
+            Err(RuntimeError::ForeignHandle)
+        ));
+        // Values without objects can be used anywhere.
+        assert!(other.set_global("n", &Handle::from(1.0)).is_ok());
+
+        drop(vm);
+        assert_eq!(None, s.as_string());
+        assert_eq!("<detached>", s.to_string());
+    }
+
+    #[test]
+    fn natives_capture_state_and_allocate_strings() {
+        let resolver = MemoryResolver::new().module("a.lox", "export var next = counter();");
+        let mut vm = VirtualMachine::builder().module_resolver(resolver).build();
+        let count = Rc::new(Cell::new(0.0));
+        let counter = Rc::clone(&count);
+        vm.define_native("counter", Arity::Fixed(0), move |_, _| {
+            counter.set(counter.get() + 1.0);
+            Ok(Handle::from(counter.get()))
+        })
+        .unwrap();
+        vm.define_native("join", Arity::AtLeast(1), |ctx, args| {
+            let parts: Option<Vec<String>> = args.iter().map(Handle::as_string).collect();
+            let parts = parts.ok_or("Arguments must be strings.")?;
+            Ok(ctx.new_string(&parts.join(" ")))
+        })
+        .unwrap();
+
+        let src = r#"
+            counter();
+            import "a.lox";
+            var s = join("a", "b", "c");
+            var t = join("a");
+            var same = join("a", "b") == "a b";
+        "#;
+        assert!(vm.interpret(src).is_ok());
+        assert_eq!(2.0, count.get());
+        assert_eq!(Value::number(2.0), global(&mut vm, "next"));
+        let s = vm.get_global("s").unwrap();
+        assert_eq!(Some(String::from("a b c")), s.as_string());
+        let t = vm.get_global("t").unwrap();
+        assert_eq!(Some(String::from("a")), t.as_string());
+        assert_eq!(Value::bool(true), global(&mut vm, "same"));
+    }
+
+    #[test]
+    fn natives_keep_the_variables_of_scripts() {
+        let mut vm = VirtualMachine::new();
+        vm.interpret("const answer = 1; var clock = 2;").unwrap();
+        vm.define_native("answer", Arity::Fixed(0), |_, _| Ok(Handle::from(42.0)))
+            .unwrap();
+        vm.define_native("clock", Arity::Fixed(0), |_, _| Ok(Handle::from(0.0)))
+            .unwrap();
+        assert_eq!(Value::number(1.0), global(&mut vm, "answer"));
+        assert_eq!(Value::number(2.0), global(&mut vm, "clock"));
+
+        // The modules imported afterwards get the functions.
+        let mut vm = VirtualMachine::builder()
+            .module_resolver(MemoryResolver::new().module("a.lox", "export var x = answer();"))
+            .build();
+        vm.define_native("answer", Arity::Fixed(0), |_, _| Ok(Handle::from(42.0)))
+            .unwrap();
+        vm.interpret(r#"import "a.lox"; var y = answer();"#)
+            .unwrap();
+        assert_eq!(Value::number(42.0), global(&mut vm, "x"));
+        assert_eq!(Value::number(42.0), global(&mut vm, "y"));
+    }
+
+    #[test]
+    fn native_errors() {
+        let mut vm = VirtualMachine::new();
+        vm.define_native("fail", Arity::AtLeast(1), |_, args| {
+            Err(NativeError::new(format!("failed with {}", args[0])))
+        })
+        .unwrap();
+        let fail = vm.get_global("fail").unwrap();
+        let err = vm.call(&fail, &[Handle::from(1.0)]).unwrap_err();
+        assert_eq!("fail: failed with 1", err.to_string());
+        assert!(matches!(
+            vm.call(&fail, &[]),
+            Err(RuntimeError::TooFewArguments { min: 1, argc: 0 })
+        ));
+        assert!(matches!(
+            vm.interpret("fail(1);"),
+            Err(InterpretError::Runtime { .. })
+        ));
+
+        let src = r#"
+            var message;
+            var line;
+            try {
+                fail(2);
+            } catch (e) {
+                message = e.message;
+                line = e.line;
+            }
+        "#;
+        assert!(vm.interpret(src).is_ok());
+        let message = vm.get_global("message").unwrap();
+        assert_eq!(
+            Some(String::from("fail: failed with 2")),
+            message.as_string()
+        );
+        assert_eq!(Value::number(5.0), global(&mut vm, "line"));
+    }
+
+    #[test]
+    fn output_is_written_to_sinks() {
+        let stdout = Buffer::default();
+        let stderr = Buffer::default();
+        let mut vm = VirtualMachine::builder()
+            .stdout(stdout.clone())
+            .stderr(stderr.clone())
+            .build();
+
+        assert!(vm.interpret("print 1; print \"a\" + \"b\";").is_ok());
+        assert_eq!("1\nab\n", stdout.take());
+        assert_eq!("", stderr.take());
+
+        assert!(vm.interpret("print 1 +;").is_err());
+        assert_eq!("", stdout.take());
+        assert_eq!(
+            "[<script>:1:10] Error at ';': Expect expression.\n",
+            stderr.take()
+        );
+
+        let src = r#"
+            fun f() {
+                print x;
+            }
+            f();
+        "#;
+        assert!(vm.interpret(src).is_err());
+        assert_eq!(
+            "Undefined variable 'x'.\n\
+             [<script>:3:23] in f().\n    print x;\n          ^\n\
+             [<script>:5:14] in script.\n    f();\n     ^\n",
+            stderr.take()
+        );
+    }
+
+    #[test]
+    fn compile_errors_show_the_module_path() {
+        let stderr = Buffer::default();
+        let mut vm = VirtualMachine::builder()
+            .module_resolver(MemoryResolver::new().module("broken.lox", "var;"))
+            .stderr(stderr.clone())
+            .build();
+        assert!(vm.interpret_script("main.lox", "var 1;").is_err());
+        assert_eq!(
+            "[main.lox:1:5] Error at '1': Expect variable name.\n",
+            stderr.take()
+        );
+        assert!(vm.interpret(r#"import "broken.lox";"#).is_err());
+        assert!(stderr
+            .take()
+            .starts_with("[broken.lox:1:4] Error at ';': Expect variable name.\n"));
+    }
+
+    #[test]
+    fn compile_errors_are_returned() {
+        let mut vm = VirtualMachine::builder().stderr(io::sink()).build();
+        let Err(InterpretError::Compile(diagnostics)) = vm.interpret("var 1;\nprint é;") else {
+            panic!("Expect compile errors.");
+        };
+        let messages: Vec<_> = diagnostics
+            .iter()
+            .map(|d| (d.line, d.column, d.message.as_str()))
+            .collect();
+        assert_eq!(
+            vec![
+                (1, 5, "Expect variable name."),
+                (2, 7, "Unexpected character.")
+            ],
+            messages
+        );
+    }
+
+    #[test]
+    fn runtime_errors_point_at_the_source() {
+        let stderr = Buffer::default();
+        let resolver = MemoryResolver::new().module(
+            "lib/shapes.lox",
+            "export fun area(w, h) {\n\treturn w * h;\n}\n",
+        );
+        let mut vm = VirtualMachine::builder()
+            .module_resolver(resolver)
+            .stderr(stderr.clone())
+            .build();
+        let src = "import \"lib/shapes.lox\";\nprint area(2, \"é\") + 1;\n";
+        assert!(vm.interpret_script("main.lox", src).is_err());
+        assert_eq!(
+            "Operands must be numbers.\n\
+             [lib/shapes.lox:2:11] in area().\n    return w * h;\n             ^\n\
+             [main.lox:2:11] in script.\n    print area(2, \"é\") + 1;\n              ^\n",
+            stderr.take()
+        );
+    }
+
+    #[test]
+    fn runtime_errors_carry_a_stack_trace() {
+        let stderr = Buffer::default();
+        let mut vm = VirtualMachine::builder().stderr(stderr.clone()).build();
+        let src = "fun f(a) {\n  return -a;\n}\nf(\"x\");\n";
+        let Err(InterpretError::Runtime { error, trace }) = vm.interpret(src) else {
+            panic!("Expect a runtime error.");
+        };
+        assert_eq!("Operand must be a number.", error.to_string());
+        assert_eq!(
+            vec![
+                StackFrame {
+                    function: Some(String::from("f")),
+                    file: String::from("<script>"),
+                    line: 2,
+                    column: 10,
+                    excerpt: Some(String::from("    return -a;\n           ^")),
+                },
+                StackFrame {
+                    function: None,
+                    file: String::from("<script>"),
+                    line: 4,
+                    column: 2,
+                    excerpt: Some(String::from("    f(\"x\");\n     ^")),
+                },
+            ],
+            trace
+        );
+
+        let mut output = format!("{error}\n");
+        for frame in &trace {
+            output.push_str(&format!("{frame}\n"));
+        }
+        assert_eq!(output, stderr.take());
+    }
+
+    #[test]
+    fn caught_errors_have_a_stack() {
+        let mut vm = VirtualMachine::new();
+        let src = r#"
+            fun describe(e) {
+                var s = "";
+                for (var i = 0; i < e.stack.len(); i = i + 1) {
+                    var frame = e.stack[i];
+                    s = s + "${frame.function}@${frame.file}:${frame.line}:${frame.column} ";
+                }
+                return s;
+            }
+            fun fail() { nil.field; }
+            fun raise() { throw Error("raised"); }
+            var failed;
+            var raised;
+            var rethrown;
+            try { fail(); } catch (e) { failed = describe(e); }
+            try { raise(); } catch (e) { raised = describe(e); }
+            try {
+                try { raise(); } catch (e) { throw e; }
+            } catch (e) {
+                rethrown = describe(e);
+            }
+        "#;
+        assert!(vm.interpret(src).is_ok());
+        let described = |vm: &mut VirtualMachine, name| vm.get_global(name).unwrap().to_string();
+        assert_eq!(
+            "fail@<script>:10:30 nil@<script>:15:23 ",
+            described(&mut vm, "failed")
+        );
+        assert_eq!(
+            "raise@<script>:11:27 nil@<script>:16:24 ",
+            described(&mut vm, "raised")
+        );
+        assert_eq!(
+            "raise@<script>:11:27 nil@<script>:18:28 ",
+            described(&mut vm, "rethrown")
+        );
+    }
+
+    #[test]
+    fn only_errors_get_a_stack() {
+        let mut vm = VirtualMachine::new();
+        let src = r#"
+            class NotAnError { init() { this.stack = nil; } }
+            class Base < Error {}
+            class Derived < Base {}
+            var plain;
+            var derived;
+            try { throw NotAnError(); } catch (e) { plain = e.stack; }
+            try { throw Derived("derived"); } catch (e) { derived = e.stack.len(); }
+        "#;
+        assert!(vm.interpret(src).is_ok());
+        assert_eq!(Value::NIL, global(&mut vm, "plain"));
+        assert_eq!(Value::number(1.0), global(&mut vm, "derived"));
+    }
+
+    #[test]
+    fn bytecode_runs_without_the_source() {
+        let src = r#"
+            class Greeter {
+                init(name) { this.name = name; }
+                greet() { return "Hello, ${this.name}!"; }
+            }
+            var greeter = Greeter("bytecode");
+            print greeter.greet();
+            fun fail() { return -greeter; }
+            fail();
+        "#;
+        let bytes = VirtualMachine::new()
+            .compile(Some("greet.lox"), src)
+            .unwrap();
+
+        let stdout = Buffer::default();
+        let stderr = Buffer::default();
+        let mut vm = VirtualMachine::builder()
+            .stdout(stdout.clone())
+            .stderr(stderr.clone())
+            .build();
+        assert!(matches!(
+            vm.load_bytecode(&bytes),
+            Err(InterpretError::Runtime { .. })
+        ));
+        assert_eq!("Hello, bytecode!\n", stdout.take());
+        assert_eq!(
+            "Operand must be a number.\n\
+             [greet.lox:8:33] in fail().\n\
+             [greet.lox:9:17] in script.\n",
+            stderr.take()
+        );
+
+        assert!(matches!(
+            vm.load_bytecode(&bytes[..bytes.len() - 1]),
+            Err(InterpretError::Bytecode(BytecodeError::Truncated))
+        ));
+        assert_eq!("Unexpected end of bytecode.\n", stderr.take());
+    }
+
+    #[test]
+    fn disassembly_includes_nested_functions() {
+        let src = r#"
+            class Point {
+                init(x) { this.x = x; }
+                getX() { return this.x; }
+            }
+            fun outer() {
+                fun inner() { return 1; }
+                return inner;
+            }
+        "#;
+        let mut vm = VirtualMachine::new();
+        let mut out = String::new();
+        vm.disassemble(src, &mut out).unwrap();
+        let headers: Vec<_> = out.lines().filter(|line| line.starts_with("==")).collect();
+        assert_eq!(
+            vec![
+                "== <script> ==",
+                "== <fn init> ==",
+                "== <fn getX> ==",
+                "== <fn outer> ==",
+                "== <fn inner> ==",
+            ],
+            headers
+        );
+        assert!(out.contains("OP_GET_PROPERTY     0 x"));
+        assert!(out.contains("OP_SET_PROPERTY     0 x"));
+        // Global variables are shown by their names next to their slots.
+        assert!(out.contains("OP_DEFINE_GLOBAL    6 Point"));
+        assert!(out.contains("OP_GET_GLOBAL       6 Point"));
+        assert!(out.contains("OP_DEFINE_GLOBAL    7 outer"));
+    }
+
+    #[test]
+    fn optimized_code_gives_the_same_output() {
+        let src = r#"
+            var n = 2 * 3 - 1;
+            fun describe(x) {
+                if (!(x > 3) and x != nil) return "small";
+                return "big ${x / 2}";
+            }
+            while (!(n < 0)) {
+                print describe(n);
+                n = n - 2;
+            }
+            print "a" + "b" == "ab";
+            print -n;
+        "#;
+        let outputs: Vec<_> = [false, true]
+            .into_iter()
+            .map(|optimizing| {
+                let stdout = Buffer::default();
+                let mut vm = VirtualMachine::builder()
+                    .stdout(stdout.clone())
+                    .optimize(optimizing)
+                    .build();
+                vm.interpret(src).unwrap();
+                stdout.take()
+            })
+            .collect();
+        assert_eq!("big 2.5\nsmall\nsmall\ntrue\n1\n", outputs[0]);
+        assert_eq!(outputs[0], outputs[1]);
+    }
+
+    #[test]
+    fn cached_call_sites_see_inherited_and_overridden_methods() {
+        let stdout = Buffer::default();
+        let mut vm = VirtualMachine::builder().stdout(stdout.clone()).build();
+        let src = r#"
+            class A { f() { return "A"; } }
+            class B < A {}
+            class C < A { f() { return "C"; } }
+            var xs = [A(), B(), C(), A(), C(), B()];
+            for (var i = 0; i < 6; i = i + 1) {
+                var x = xs[i];
+                var g = x.f;
+                print x.f() + g();
+            }
+        "#;
+        vm.interpret(src).unwrap();
+        assert_eq!("AA\nAA\nCC\nAA\nCC\nAA\n", stdout.take());
+    }
+
+    #[test]
+    fn cached_call_sites_see_fields_shadowing_methods() {
+        let stdout = Buffer::default();
+        let mut vm = VirtualMachine::builder().stdout(stdout.clone()).build();
+        let src = r#"
+            class A { f() { return "method"; } }
+            fun field() { return "field"; }
+            fun call(x) { return x.f(); }
+            var a = A();
+            print call(a);
+            a.f = field;
+            print call(a);
+            print call(A());
+        "#;
+        vm.interpret(src).unwrap();
+        assert_eq!("method\nfield\nmethod\n", stdout.take());
+    }
+
+    #[test]
+    fn cached_field_accesses_work_across_instance_layouts() {
+        let mut vm = VirtualMachine::new();
+        let src = r#"
+            class P {}
+            fun get(p) { return p.x; }
+            fun set(p, v) { p.x = v; }
+            var p1 = P();
+            p1.x = 1;
+            var p2 = P();
+            p2.a = 0; p2.b = 0; p2.c = 0; p2.d = 0; p2.e = 0; p2.f = 0; p2.g = 0;
+            p2.x = 2;
+            var sum = get(p1) + get(p2) + get(p1) + get(p2);
+            set(p1, 10);
+            set(p2, 20);
+            set(p1, 30);
+            var p3 = P();
+            set(p3, 40);
+            var total = p1.x + p2.x + get(p3);
+        "#;
+        vm.interpret(src).unwrap();
+        assert_eq!(Value::number(6.0), global(&mut vm, "sum"));
+        assert_eq!(Value::number(90.0), global(&mut vm, "total"));
+    }
+
+    #[test]
+    fn cached_methods_are_invalidated_when_redefined() {
+        let stdout = Buffer::default();
+        let mut vm = VirtualMachine::builder().stdout(stdout.clone()).build();
+        let src = r#"
+            class A { f() { return 1; } }
+            class B { f() { return 2; } }
+            var a = A();
+            fun call() { return a.f(); }
+            fun get() { var g = a.f; return g(); }
+            print call();
+            print get();
+        "#;
+        vm.interpret(src).unwrap();
+
+        let a_class = global(&mut vm, "A").as_class().unwrap();
+        let b_class = global(&mut vm, "B").as_class().unwrap();
+        let name = vm.heap.intern(String::from("f"));
+        let method = *b_class.borrow().methods.get(name).unwrap();
+        vm.heap
+            .update(&a_class, |class| class.set_method(name, method));
+
+        vm.interpret("print call(); print get();").unwrap();
+        assert_eq!("1\n1\n2\n2\n", stdout.take());
+    }
+
+    #[test]
+    fn instances_with_the_same_fields_share_a_shape() {
+        let mut vm = VirtualMachine::new();
+        let src = r#"
+            class P {
+                init(x, y) {
+                    this.x = x;
+                    this.y = y;
+                }
+            }
+            var a = P(1, 2);
+            var b = P(3, 4);
+            var c = P(5, 6);
+            c.z = 7;
+            c.x = c.x + c.z;
+            var sum = a.x + a.y + b.x + b.y + c.x + c.y + c.z;
+        "#;
+        vm.interpret(src).unwrap();
+        assert_eq!(Value::number(35.0), global(&mut vm, "sum"));
+
+        let a = global(&mut vm, "a").as_instance().unwrap();
+        let b = global(&mut vm, "b").as_instance().unwrap();
+        let c = global(&mut vm, "c").as_instance().unwrap();
+        assert_eq!(a.borrow().shape, b.borrow().shape);
+        assert_ne!(a.borrow().shape, c.borrow().shape);
+        assert_eq!(3, c.borrow().fields.len());
+    }
+
+    #[test]
+    fn shapes_that_are_no_longer_used_are_freed() {
+        let mut vm = VirtualMachine::new();
+        assert!(vm
+            .interpret("class P {} var kept = P(); kept.a = 1;")
+            .is_ok());
+        let names = vm.shapes.names().count();
+
+        // The script is unreachable once it has run, so are the shapes in its inline caches.
+        assert!(vm
+            .interpret("var p = P(); p.b = 2; p.c = 3; p = nil;")
+            .is_ok());
+        assert_eq!(names + 2, vm.shapes.names().count());
+        vm.mark_sweep();
+        assert_eq!(names, vm.shapes.names().count());
+
+        assert!(vm
+            .interpret("var q = P(); q.b = 4; q.c = 5; var sum = kept.a + q.b + q.c;")
+            .is_ok());
+        assert_eq!(Value::number(10.0), global(&mut vm, "sum"));
+    }
+}